@@ -0,0 +1,78 @@
+//! Compiler benchmarks on large generated MML
+//!
+//! Each phase is measured from `Compiler::perf()` so parsing, channel
+//! compilation and writing can be tracked separately.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::io::Cursor;
+use std::time::Duration;
+use tempfile::tempdir;
+use vgmck::compiler::perf::PerfCounters;
+use vgmck::Compiler;
+
+/// Generate a song with `bars` bars on four PSG and six OPN2 channels
+fn generate_mml(bars: usize) -> String {
+    let mut mml = String::from(
+        "#TITLE Benchmark\n\
+         #EX-PSG ABCD\n\
+         #EX-OPN2 EFGHIJ\n\
+         @v0 = { 15 14 13 12 | 11 10 }\n\
+         @EN0 = { 0 4 7 }\n\
+         @P0 = { -1 0 1 }\n",
+    );
+    let phrases = ["cdefgab>c<", "c8e8g8>c8<g8e8c4", "[c16d16]4 e4", "a8.b16>c4<g4"];
+    for bar in 0..bars {
+        for (i, ch) in "ABCDEFGHIJ".chars().enumerate() {
+            let phrase = phrases[(bar + i) % phrases.len()];
+            mml.push_str(&format!("{} v15 @v0 o{} l8 {}\n", ch, 3 + i % 3, phrase));
+            if i % 4 == 0 {
+                mml.push_str(&format!("{} EN0 @P0 c4 ENOF\n", ch));
+            }
+        }
+    }
+    mml
+}
+
+/// Picks one phase out of the perf counters
+type Phase = fn(&PerfCounters) -> Duration;
+
+/// Compile `mml` `iters` times, summing one phase of the perf counters
+fn time_phase(mml: &str, iters: u64, phase: Phase) -> Duration {
+    let dir = tempdir().unwrap();
+    let output = dir.path().join("bench.vgm");
+    let mut total = Duration::ZERO;
+    for _ in 0..iters {
+        let mut compiler = Compiler::new();
+        compiler.report = false;
+        compiler.compile(Cursor::new(mml), &output).unwrap();
+        total += phase(compiler.perf());
+    }
+    total
+}
+
+fn bench_phases(c: &mut Criterion) {
+    let phases: [(&str, Phase); 4] = [
+        ("parse", |p| p.parse),
+        ("compile", |p| p.compile),
+        ("write", |p| p.write),
+        ("total", PerfCounters::total),
+    ];
+
+    for (name, phase) in phases {
+        let mut group = c.benchmark_group(name);
+        for bars in [16, 128] {
+            let mml = generate_mml(bars);
+            group.bench_with_input(BenchmarkId::from_parameter(bars), &mml, |b, mml| {
+                b.iter_custom(|iters| time_phase(mml, iters, phase))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_phases
+}
+criterion_main!(benches);
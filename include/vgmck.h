@@ -0,0 +1,59 @@
+/*
+ * vgmck C interface
+ *
+ * Build the library with the ffi feature, as a shared or static library:
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ *     cargo rustc --release --lib --features ffi --crate-type staticlib
+ *
+ * Buffers and strings returned here are owned by the library: give them
+ * back with vgmck_free_bytes() and vgmck_free_string(), not free().
+ */
+
+#ifndef VGMCK_H
+#define VGMCK_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return codes */
+#define VGMCK_OK 0
+#define VGMCK_ERROR 1
+/* A required pointer was null or the input was not UTF-8 */
+#define VGMCK_INVALID_ARGUMENT 2
+
+/*
+ * Compile MML text to a VGM file in memory.
+ *
+ * On success returns VGMCK_OK and stores the file in *out and its size in
+ * *len. On failure returns VGMCK_ERROR with *out set to NULL. If errors is
+ * not NULL, *errors gets the warnings and errors, one "Warning: ..." or
+ * "Error: ..." line each, or NULL if there were none. #INCLUDE paths are
+ * relative to the working directory.
+ */
+int vgmck_compile(const char *mml, uint8_t **out, size_t *len, char **errors);
+
+/*
+ * Compile an MML file to a VGM file in memory, resolving #INCLUDE paths
+ * relative to it. Returns and stores the same as vgmck_compile().
+ */
+int vgmck_compile_file(const char *path, uint8_t **out, size_t *len, char **errors);
+
+/* Free a VGM file returned by vgmck_compile() or vgmck_compile_file() */
+void vgmck_free_bytes(uint8_t *data, size_t len);
+
+/* Free a message string returned through errors */
+void vgmck_free_string(char *text);
+
+/* The library version, a static string not to be freed */
+const char *vgmck_version(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* VGMCK_H */
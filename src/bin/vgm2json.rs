@@ -1,18 +1,17 @@
 //! VGM to JSON converter
 
 use clap::Parser;
-use flate2::read::GzDecoder;
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::Write;
 use std::path::PathBuf;
-use vgmck::vgm::{VgmJson, VgmReader};
+use vgmck::vgm::{self, VgmJson};
 
 #[derive(Parser, Debug)]
 #[command(name = "vgm2json")]
 #[command(version = "0.1.0")]
-#[command(about = "Convert VGM/VGZ files to JSON", long_about = None)]
+#[command(about = "Convert VGM/VGZ (or GYM/DRO) files to JSON", long_about = None)]
 struct Args {
-    /// Input VGM or VGZ file
+    /// Input VGM, VGZ, GYM or DRO file
     input: PathBuf,
 
     /// Output JSON file (writes to stdout if not specified)
@@ -27,18 +26,11 @@ struct Args {
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
-    // Read input file
-    let data = read_vgm_file(&args.input)?;
+    // Read input file, converting GYM and DRO files to VGM
+    let data = vgm::read_file(&args.input)?;
 
-    // Parse VGM
-    let mut reader = VgmReader::new(&data);
-
-    let header = reader.parse_header()?;
-    let gd3 = reader.parse_gd3(&header)?;
-    let commands = reader.parse_commands(&header)?;
-
-    // Create JSON structure
-    let vgm_json = VgmJson::new(&header, gd3.as_ref(), commands);
+    // Parse VGM into the JSON structure
+    let vgm_json = VgmJson::parse(&data)?;
 
     // Serialize to JSON
     let json_string = if args.compact {
@@ -61,37 +53,3 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
-
-/// Read a VGM or VGZ file, decompressing if necessary
-fn read_vgm_file(path: &PathBuf) -> io::Result<Vec<u8>> {
-    let mut file = File::open(path)?;
-
-    // Check if it's a gzip file by extension or magic
-    let is_gzip = path
-        .extension()
-        .map(|ext| ext.eq_ignore_ascii_case("vgz") || ext.eq_ignore_ascii_case("gz"))
-        .unwrap_or(false);
-
-    if is_gzip {
-        // Decompress gzip data
-        let mut decoder = GzDecoder::new(file);
-        let mut data = Vec::new();
-        decoder.read_to_end(&mut data)?;
-        Ok(data)
-    } else {
-        // Read raw VGM data
-        let mut data = Vec::new();
-        file.read_to_end(&mut data)?;
-
-        // Check for gzip magic (0x1f 0x8b) even if extension doesn't indicate it
-        if data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b {
-            let cursor = std::io::Cursor::new(data);
-            let mut decoder = GzDecoder::new(cursor);
-            let mut decompressed = Vec::new();
-            decoder.read_to_end(&mut decompressed)?;
-            Ok(decompressed)
-        } else {
-            Ok(data)
-        }
-    }
-}
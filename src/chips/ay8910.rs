@@ -1,10 +1,20 @@
 //! AY-3-8910 sound chip driver
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, second_half, ChipOptions, MacroCommand, MacroResult, NoiseTarget, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('T', "type", "Chip type (0=AY8910, 3=AY8930, 16=YM2149, ...)"),
+    OptionDescription::new('S', "envelope-shift", "Octave shift between envelope and note"),
+    OptionDescription::new('l', "legacy-output", "Legacy output flag"),
+    OptionDescription::new('s', "single-output", "Single output flag"),
+    OptionDescription::new('d', "discrete-output", "Discrete output flag"),
+    OptionDescription::new('r', "raw-output", "Raw output flag"),
+];
+
 /// AY-3-8910 chip
 pub struct Ay8910 {
     clock: i32,
@@ -19,6 +29,7 @@ pub struct Ay8910 {
     opt_s_flag: bool, // s option
     opt_d_flag: bool, // d option
     opt_r_flag: bool, // r option
+    command: u8,     // VGM write command
 }
 
 impl Ay8910 {
@@ -36,11 +47,20 @@ impl Ay8910 {
             opt_s_flag: false,
             opt_d_flag: false,
             opt_r_flag: false,
+            command: 0xA0,
+        }
+    }
+
+    /// SSG part of a Yamaha FM chip, written with the FM chip's command
+    pub(super) fn ssg(command: u8) -> Self {
+        Self {
+            command,
+            ..Self::new()
         }
     }
 
     fn poke(&self, address: u8, data: u8, writer: &mut VgmWriter) {
-        let _ = writer.write_data(&[0xA0, address, data]);
+        let _ = writer.write_data(&[self.command, address, data]);
     }
 }
 
@@ -71,6 +91,23 @@ impl SoundChip for Ay8910 {
         1
     }
 
+    fn noise_target(&self) -> NoiseTarget {
+        // The noise period register, shared by the three channels
+        NoiseTarget::Macro(MacroCommand::Sample)
+    }
+
+    fn group_sizes(&self) -> &'static [usize] {
+        &[6, 2]
+    }
+
+    fn second_chip_channel(&self, chip_sub: usize, chan_sub: usize) -> Option<usize> {
+        second_half(self.group_sizes(), chip_sub, chan_sub)
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        16.0
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
@@ -87,6 +124,10 @@ impl SoundChip for Ay8910 {
         self.opt_r_flag = options.get('r') != 0;
     }
 
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
     fn file_begin(&mut self, _writer: &mut VgmWriter) {
         self.ena = [0; 2];
         let spec_val = if self.spec { 1 } else { 0 };
@@ -134,36 +175,36 @@ impl SoundChip for Ay8910 {
         is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 if is_dynamic && self.vol == (value as u8) {
-                    return None;
+                    return MacroResult::NoEvent;
                 }
                 self.vol = (value & 15) as u8;
                 // event_type 0x21 = volume, value1 = volume, value2 = env shape (0 = none)
-                Some(ChipEvent::new(0x21, self.vol as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x21, self.vol as i32, 0))
             }
             MacroCommand::Tone => {
                 // event_type 0x22 = tone/enable control
-                Some(ChipEvent::new(0x22, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x22, value as i32, 0))
             }
             MacroCommand::Multiply => {
                 self.vol = 0x1F;
                 self.mul = value as i32;
-                None
+                MacroResult::NoEvent
             }
             MacroCommand::VolumeEnv => {
                 self.vol = 0x1F;
                 let env_shape = if value > 0 { 13 } else { 9 };
                 self.mul = (value as i32).abs() * if value > 0 { -1 } else { 1 };
-                Some(ChipEvent::new(0x21, self.vol as i32, env_shape))
+                MacroResult::Event(ChipEvent::new(0x21, self.vol as i32, env_shape))
             }
             MacroCommand::Sample => {
                 // Noise period register
-                Some(ChipEvent::new(0x06, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x06, value as i32, 0))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
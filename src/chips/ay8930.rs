@@ -2,7 +2,7 @@
 //!
 //! Enhanced AY-3-8910 with 16-bit tone periods and extended envelopes
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, second_half, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
@@ -13,6 +13,15 @@ const ENH: [u8; 6] = [0x0B, 0x10, 0x12, 0x8B, 0x90, 0x92];
 /// Enhanced envelope mode registers
 const ENM: [u8; 6] = [0x0D, 0x14, 0x15, 0x8D, 0x94, 0x95];
 
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('S', "envelope-shift", "Octave shift between envelope and note"),
+    OptionDescription::new('l', "legacy-output", "Legacy output flag"),
+    OptionDescription::new('s', "single-output", "Single output flag"),
+    OptionDescription::new('d', "discrete-output", "Discrete output flag"),
+    OptionDescription::new('r', "raw-output", "Raw output flag"),
+];
+
 /// AY8930 chip (enhanced AY-3-8910)
 pub struct Ay8930 {
     clock: i32,
@@ -86,6 +95,18 @@ impl SoundChip for Ay8930 {
         0
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[6]
+    }
+
+    fn second_chip_channel(&self, chip_sub: usize, chan_sub: usize) -> Option<usize> {
+        second_half(self.group_sizes(), chip_sub, chan_sub)
+    }
+
+    fn max_volume(&self) -> i16 {
+        31
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
@@ -101,6 +122,10 @@ impl SoundChip for Ay8930 {
         self.opt_flags = opt_l | (opt_s_flag << 1) | (opt_d << 2) | (opt_r << 3);
     }
 
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
     fn file_begin(&mut self, writer: &mut VgmWriter) {
         // Reset state
         self.ena = [0; 2];
@@ -149,33 +174,33 @@ impl SoundChip for Ay8930 {
         is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 if is_dynamic && self.vol == (value as u8 & 31) {
-                    return None;
+                    return MacroResult::NoEvent;
                 }
                 self.vol = (value & 31) as u8;
                 // type 0x21 = volume
-                Some(ChipEvent::new(0x21, self.vol as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x21, self.vol as i32, 0))
             }
             MacroCommand::Tone => {
                 // type 0x22 = tone/enable control
-                Some(ChipEvent::new(0x22, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x22, value as i32, 0))
             }
             MacroCommand::Multiply => {
                 self.vol = 0x3F;
                 self.mul = value as i32;
-                None
+                MacroResult::NoEvent
             }
             MacroCommand::VolumeEnv => {
                 self.vol = 0x3F;
                 let env_shape = if value > 0 { 13 } else { 9 };
                 self.mul = (value as i32).abs() * if value > 0 { -1 } else { 1 };
                 // value2 = envelope shape
-                Some(ChipEvent::new(0x21, self.vol as i32, env_shape))
+                MacroResult::Event(ChipEvent::new(0x21, self.vol as i32, env_shape))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
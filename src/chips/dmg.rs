@@ -1,11 +1,21 @@
 //! GameBoy DMG sound chip driver
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, second_half, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::compiler::envelope::MacroEnvStorage;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+/// Options shared with the 2A03, which has short noise as well
+pub(super) const SHORT_NOISE_OPTION: OptionDescription =
+    OptionDescription::new('n', "short-noise", "Noise channels start with short (periodic) noise, as N:1");
+
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('v', "volume", "Master volume (0-7, default 7)"),
+    SHORT_NOISE_OPTION,
+];
+
 /// Noise frequency table
 const NOISE_TABLE: [u8; 16] = [1, 9, 2, 10, 3, 5, 13, 6, 14, 7, 15, 11, 4, 8, 12, 0];
 
@@ -15,6 +25,8 @@ pub struct Dmg {
     dual: bool,
     pan: [u8; 2],
     vol: u8,
+    short_noise: bool, // Noise channel uses the 7-bit LFSR (`N:1`)
+    master_vol: u8,    // NR50 value, both outputs
 }
 
 impl Dmg {
@@ -24,6 +36,8 @@ impl Dmg {
             dual: false,
             pan: [0xFF, 0xFF],
             vol: 0xF0,
+            short_noise: false,
+            master_vol: 0x77,
         }
     }
 }
@@ -55,11 +69,30 @@ impl SoundChip for Dmg {
         1
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[4, 2, 2]
+    }
+
+    fn second_chip_channel(&self, chip_sub: usize, chan_sub: usize) -> Option<usize> {
+        second_half(self.group_sizes(), chip_sub, chan_sub)
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        32.0
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 4194304;
         }
+        self.short_noise = options.noise == Some(1) || options.get('n') != 0;
+        let volume = options.get_opt('v').unwrap_or(7).clamp(0, 7) as u8;
+        self.master_vol = volume << 4 | volume;
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
     }
 
     fn file_begin(&mut self, writer: &mut VgmWriter) {
@@ -68,12 +101,12 @@ impl SoundChip for Dmg {
 
         // Initialize sound system
         let _ = writer.write_data(&[0xB3, 0x16, 0xFF]); // NR52 - Master control
-        let _ = writer.write_data(&[0xB3, 0x14, 0x77]); // NR50 - Master volume
+        let _ = writer.write_data(&[0xB3, 0x14, self.master_vol]); // NR50 - Master volume
         let _ = writer.write_data(&[0xB3, 0x15, 0xFF]); // NR51 - Panning
 
         if self.dual {
             let _ = writer.write_data(&[0xB3, 0x96, 0xFF]); // Second chip NR52
-            let _ = writer.write_data(&[0xB3, 0x94, 0x77]); // Second chip NR50
+            let _ = writer.write_data(&[0xB3, 0x94, self.master_vol]); // Second chip NR50
             let _ = writer.write_data(&[0xB3, 0x95, 0xFF]); // Second chip NR51
         }
     }
@@ -100,18 +133,18 @@ impl SoundChip for Dmg {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Panning => {
                 // event_type 0xFFF0 = stereo command
-                Some(ChipEvent::new(0xFFF0, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0xFFF0, value as i32, 0))
             }
             MacroCommand::Volume => {
                 // For wave channel (chip_sub==1), volume is different
                 // event_type 0xFFF1 = volume (handled in send based on chip_sub)
                 let new_vol = (self.vol & 0x0F) | ((value as u8) << 4);
                 self.vol = new_vol;
-                Some(ChipEvent::new(0xFFF1, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0xFFF1, value as i32, 0))
             }
             MacroCommand::VolumeEnv => {
                 // Volume envelope
@@ -121,17 +154,17 @@ impl SoundChip for Dmg {
                     (value as u8) | 8
                 };
                 self.vol = (self.vol & 0xF0) | env_val;
-                None
+                MacroResult::NoEvent
             }
             MacroCommand::Waveform => {
                 // Wave table select - needs macro env access
-                Some(ChipEvent::new(0xFFF2, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0xFFF2, value as i32, 0))
             }
             MacroCommand::Tone => {
                 // Duty cycle for square channels
-                Some(ChipEvent::new(0xFFF3, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0xFFF3, value as i32, 0))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
@@ -217,7 +250,11 @@ impl SoundChip for Dmg {
                     note = (NOISE_TABLE[(note & 15) as usize] as i32) | (((15 - octave) as i32) << 4);
                 }
 
-                let period = (note ^ 0x7FF) as u16;
+                let mut period = (note ^ 0x7FF) as u16;
+                if a == 2 && self.short_noise {
+                    // Width bit of NR43: 7-bit LFSR
+                    period |= 0x08;
+                }
                 let vol_reg = vol | if a == 1 { 0x80 } else { 0 };
 
                 // Write volume/envelope register
@@ -237,6 +274,9 @@ impl SoundChip for Dmg {
                 if a == 2 {
                     // Noise channel - direct write to register
                     note = (NOISE_TABLE[(note & 15) as usize] as i32) | (((15 - octave) as i32) << 4);
+                    if self.short_noise {
+                        note |= 0x08;
+                    }
                     let _ = writer.write_data(&[0xB3, ((c << 7) | 0x12) as u8, note as u8]);
                 } else {
                     let period = (note ^ 0x7FF) as u16;
@@ -3,12 +3,17 @@
 //! 6 channels of wavetable sound, with noise on channels 4-5
 //! LFO/FM capability (channel 1 modulates channel 0)
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, second_half, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::compiler::envelope::MacroEnvStorage;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('v', "volume", "Master volume (0-15, default 15)"),
+];
+
 /// Maximum sub-channels per chip_sub type
 const MAXSUB: [usize; 3] = [6, 1, 2];
 
@@ -23,6 +28,7 @@ pub struct HuC6280 {
     mult: [i32; 2],                // FM multiplier
     wave: [[i32; 6]; 2],           // Wave index per channel
     fixfm: [bool; 2],              // Fixed FM frequency mode
+    master_vol: u8,                // Master volume register, both outputs
 }
 
 impl HuC6280 {
@@ -37,6 +43,7 @@ impl HuC6280 {
             mult: [4, 4],
             wave: [[-1; 6]; 2],
             fixfm: [false, false],
+            master_vol: 0xFF,
         }
     }
 
@@ -88,11 +95,33 @@ impl SoundChip for HuC6280 {
         0
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[12, 2, 4]
+    }
+
+    fn second_chip_channel(&self, chip_sub: usize, chan_sub: usize) -> Option<usize> {
+        second_half(self.group_sizes(), chip_sub, chan_sub)
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        32.0
+    }
+
+    fn max_volume(&self) -> i16 {
+        31
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 3579545;
         }
+        let volume = options.get_opt('v').unwrap_or(15).clamp(0, 15);
+        self.master_vol = (volume << 4 | volume) as u8;
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
     }
 
     fn file_begin(&mut self, writer: &mut VgmWriter) {
@@ -148,7 +177,7 @@ impl SoundChip for HuC6280 {
         // Initialize chips
         let chip_count = if self.dual { 2 } else { 1 };
         for c in 0..chip_count {
-            self.mem_write(c, 0, 1, 0xFF, writer); // Master volume
+            self.mem_write(c, 0, 1, self.master_vol as i32, writer); // Master volume
 
             // LFO control
             let lfo_val = if self.usesub[1] > c { 0x80 } else { 0x00 };
@@ -205,11 +234,11 @@ impl SoundChip for HuC6280 {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 // type 3 = volume
-                Some(ChipEvent::new(3, (value & 31) as i32, 0))
+                MacroResult::Event(ChipEvent::new(3, (value & 31) as i32, 0))
             }
             MacroCommand::Panning => {
                 // type 4 = stereo
@@ -218,29 +247,29 @@ impl SoundChip for HuC6280 {
                 } else {
                     0xFF ^ ((value as i32) << 4)
                 };
-                Some(ChipEvent::new(4, pan, 0))
+                MacroResult::Event(ChipEvent::new(4, pan, 0))
             }
             MacroCommand::Tone => {
                 // type 5 = FM tone (only for chip_sub==1)
-                Some(ChipEvent::new(5, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(5, value as i32, 0))
             }
             MacroCommand::Multiply => {
                 // type 6 = FM multiplier (only for chip_sub==1)
-                Some(ChipEvent::new(6, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(6, value as i32, 0))
             }
             MacroCommand::ModWaveform => {
                 // type 7 = modulator waveform (only for chip_sub==1)
-                Some(ChipEvent::new(7, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(7, value as i32, 0))
             }
             MacroCommand::Waveform => {
                 // type 8 = carrier waveform
-                Some(ChipEvent::new(8, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(8, value as i32, 0))
             }
             MacroCommand::Global => {
                 // type 9 = global stereo
-                Some(ChipEvent::new(9, value as i32, 0))
+                MacroResult::Event(ChipEvent::global(9, value as i32, 0))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
@@ -5,13 +5,16 @@ pub mod ay8930;
 pub mod dmg;
 pub mod huc6280;
 pub mod nes_apu;
+pub mod null;
 pub mod opl2;
 pub mod opl3;
 pub mod opl4;
 pub mod opll;
+pub mod opn;
 pub mod opn2;
 pub mod pokey;
 pub mod qsound;
+pub mod segapcm;
 pub mod sn76489;
 pub mod t6w28;
 
@@ -20,6 +23,7 @@ use crate::error::{Error, Result};
 use crate::compiler::envelope::MacroEnvStorage;
 use crate::vgm::VgmWriter;
 use std::collections::HashMap;
+use std::path::PathBuf;
 
 /// Chip ID constants (matching VGM spec)
 pub mod chip_id {
@@ -55,10 +59,12 @@ pub mod chip_id {
     pub const K053260: u8 = 29;
     pub const POKEY: u8 = 30;
     pub const QSOUND: u8 = 31;
+    /// Not a VGM chip (the NULL driver)
+    pub const NULL: u8 = 0xFF;
 }
 
 /// Macro command types
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MacroCommand {
     Volume = 0,
     Panning = 1,
@@ -75,10 +81,104 @@ pub enum MacroCommand {
     Midi = 12,
 }
 
+/// What a chip made of a macro value
+#[derive(Debug, Clone)]
+pub enum MacroResult {
+    /// An event to send
+    Event(ChipEvent),
+    /// The value was taken but needs no event now: it did not change, or
+    /// goes out with the next note
+    NoEvent,
+    /// The chip (or this channel of it) has no such control; the value is
+    /// ignored
+    Unsupported,
+}
+
+impl MacroResult {
+    /// The event to send, if any
+    pub fn event(self) -> Option<ChipEvent> {
+        match self {
+            Self::Event(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+/// Where a chip's channels take `@NMAP` noise values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseTarget {
+    /// In place of the note value, as `@EN F` values are (noise channels)
+    Note,
+    /// Through a macro command, with the note played as written (a noise
+    /// generator shared by the tone channels)
+    Macro(MacroCommand),
+}
+
+/// Second chip channel of drivers whose groups give the first half of their
+/// channels to the first chip and the rest to the second
+pub(crate) fn second_half(group_sizes: &[usize], chip_sub: usize, chan_sub: usize) -> Option<usize> {
+    let half = group_sizes.get(chip_sub)? / 2;
+    (chan_sub < half).then_some(chan_sub + half)
+}
+
+/// Sample data of an `@S` macro, for chips with sample memory
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    /// File contents
+    pub data: Vec<u8>,
+    /// Loop start in bytes (None plays once)
+    pub loop_start: Option<usize>,
+}
+
+/// Sample memory of a chip and what its samples take of it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SampleMemory {
+    /// Bytes the chip can address
+    pub capacity: usize,
+    /// Bytes taken, including padding and gaps left by alignment
+    pub used: usize,
+    /// Bytes each sample takes with its padding, by `@S` number, in the
+    /// order they are stored
+    pub samples: Vec<(usize, usize)>,
+}
+
+impl SampleMemory {
+    /// Whether the samples fit in the chip's memory
+    pub fn fits(&self) -> bool {
+        self.used <= self.capacity
+    }
+}
+
+/// The register a chip's DAC stream writes `#WAV` samples to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DacTarget {
+    /// Chip type in the stream setup command (VGM chip id)
+    pub chip_type: u8,
+    /// Port of the register
+    pub port: u8,
+    /// Register each sample is written to
+    pub register: u8,
+    /// Data block type holding the samples
+    pub bank: u8,
+    /// Bits the DAC takes; samples are shifted down from 8 bits to fit
+    pub bits: u8,
+}
+
 /// Chip configuration options
 #[derive(Debug, Clone, Default)]
 pub struct ChipOptions {
     pub values: HashMap<char, i32>,
+    /// Options given as text (`W=wave.bin`, `R="my rom.bin"`), such as file paths
+    pub strings: HashMap<char, String>,
+    /// Directory file path options are resolved from (the MML file's)
+    pub base_path: Option<PathBuf>,
+    /// Basic octave from `o:n`, replacing the chip's own in note
+    /// calculation (applied by `ChipInstance::basic_octave` for every chip)
+    pub basic_octave: Option<i32>,
+    /// Noise type from `N:n` the noise channels start with, for chips with
+    /// more than one: 1 selects the short (periodic) noise of the 2A03 and
+    /// DMG; on the T6W28 it is the noise mode `@` sets
+    pub noise: Option<i32>,
 }
 
 impl ChipOptions {
@@ -90,11 +190,58 @@ impl ChipOptions {
         self.values.get(&key).copied().unwrap_or(0)
     }
 
+    /// Value of an option, if it was given
+    pub fn get_opt(&self, key: char) -> Option<i32> {
+        self.values.get(&key).copied()
+    }
+
     pub fn set(&mut self, key: char, value: i32) {
         self.values.insert(key, value);
     }
+
+    /// Text of an option given as text, if it was
+    pub fn get_str(&self, key: char) -> Option<&str> {
+        self.strings.get(&key).map(String::as_str)
+    }
+
+    pub fn set_str(&mut self, key: char, value: &str) {
+        self.strings.insert(key, value.to_string());
+    }
+
+    /// File named by a text option, relative to `base_path`
+    pub fn get_path(&self, key: char) -> Option<PathBuf> {
+        let path = self.get_str(key)?;
+        Some(match &self.base_path {
+            Some(base) => base.join(path),
+            None => PathBuf::from(path),
+        })
+    }
+}
+
+/// Long name of a chip option letter, for `#OPTION` and `--list-chips`
+#[derive(Debug, Clone, Copy)]
+pub struct OptionDescription {
+    /// Option letter used in `#EX-` lines
+    pub letter: char,
+    /// Long name used with `#OPTION`
+    pub name: &'static str,
+    /// One-line description
+    pub description: &'static str,
+}
+
+impl OptionDescription {
+    pub const fn new(letter: char, name: &'static str, description: &'static str) -> Self {
+        Self {
+            letter,
+            name,
+            description,
+        }
+    }
 }
 
+/// The clock option every chip understands
+pub const CLOCK_OPTION: OptionDescription = OptionDescription::new('H', "clock", "Clock rate in Hz");
+
 /// Sound chip trait
 pub trait SoundChip: Send + Sync {
     /// Get chip name (e.g., "PSG", "OPN2")
@@ -112,9 +259,68 @@ pub trait SoundChip: Send + Sync {
     /// Get basic octave number
     fn basic_octave(&self) -> i32;
 
+    /// Divider relating note values to tone frequency, for exact `f=` pitches:
+    /// period chips play `clock_div / (value * divider)` Hz, others
+    /// `value * clock_div * 2^octave / divider` Hz (0 if not supported)
+    fn pitch_divider(&self) -> f64 {
+        0.0
+    }
+
+    /// Number of channels in each channel group, counting those of a
+    /// second chip (empty if not known)
+    fn group_sizes(&self) -> &'static [usize] {
+        &[]
+    }
+
+    /// Channel of a group that plays on the second chip where the given one
+    /// plays on the first, for `#WIDE` (None if the chip has no second chip)
+    fn second_chip_channel(&self, _chip_sub: usize, _chan_sub: usize) -> Option<usize> {
+        None
+    }
+
+    /// Where `@NMAP` noise values go
+    fn noise_target(&self) -> NoiseTarget {
+        NoiseTarget::Note
+    }
+
+    /// Loudest volume value, used to scale normalized (0-15) volumes
+    fn max_volume(&self) -> i16 {
+        15
+    }
+
     /// Enable chip with options
+    ///
+    /// Drivers with more than one noise type start their noise channels
+    /// with `options.noise` when it is set.
     fn enable(&mut self, options: &ChipOptions);
 
+    /// Options understood by `enable`, with their long names
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        &[CLOCK_OPTION]
+    }
+
+    /// The chip plays samples from its own memory, filled by `set_samples`
+    fn uses_samples(&self) -> bool {
+        false
+    }
+
+    /// Called before `file_begin` with the samples of the `@S` macros, by macro number
+    fn set_samples(&mut self, _samples: &[Option<Sample>]) {}
+
+    /// Memory taken by the samples given to `set_samples`, for chips that
+    /// use samples
+    fn sample_memory(&self) -> Option<SampleMemory> {
+        None
+    }
+
+    /// The DAC `@s` streams `#WAV` samples to, if the chip has one
+    fn dac_target(&self) -> Option<DacTarget> {
+        None
+    }
+
+    /// Called after `file_begin` when `@s` is used, to switch the DAC on
+    fn dac_begin(&mut self, _writer: &mut VgmWriter) {}
+
     /// Called at start of file output
     fn file_begin(&mut self, writer: &mut VgmWriter);
 
@@ -127,19 +333,32 @@ pub trait SoundChip: Send + Sync {
     /// Called when starting a channel
     fn start_channel(&mut self, channel: usize);
 
+    /// Names of the hardware channels a channel of a group plays on, once
+    /// every channel has been started
+    ///
+    /// Chips that pair or share hardware channels between groups (OPL3 4-op,
+    /// OPN2 supplementary channels) return them, so the compiler can reject
+    /// two channels writing the same registers.
+    fn hardware_channels(&self, _chip_sub: usize, _chan_sub: usize) -> Vec<String> {
+        Vec::new()
+    }
+
     /// Called when starting a channel with chip_sub/chan_sub info
     fn start_channel_with_info(&mut self, _chip_sub: usize, _chan_sub: usize) {
         // Default: do nothing
     }
 
     /// Set a macro value
+    ///
+    /// Commands the chip has no use for return `MacroResult::Unsupported`,
+    /// which the compiler warns about once per channel.
     fn set_macro(
         &mut self,
         channel: usize,
         is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent>;
+    ) -> MacroResult;
 
     /// Note on event
     fn note_on(
@@ -193,35 +412,68 @@ impl ChipInstance {
             options: ChipOptions::new(),
         }
     }
+
+    /// Basic octave used in note calculation: `o:n` if given, else the chip's
+    pub fn basic_octave(&self) -> i32 {
+        self.options.basic_octave.unwrap_or_else(|| self.chip.basic_octave())
+    }
+}
+
+/// A chip that `#EX-` can enable
+pub struct ChipInfo {
+    /// Canonical name, used as the chip's key in messages and event dumps
+    pub name: &'static str,
+    /// Other names the chip answers to, such as those other tools use
+    pub aliases: &'static [&'static str],
+    create: fn() -> Box<dyn SoundChip>,
+}
+
+impl ChipInfo {
+    /// Whether the chip answers to a name (letter case is ignored)
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+    }
+
+    /// Create an instance of the chip
+    pub fn create(&self) -> ChipInstance {
+        ChipInstance::new((self.create)())
+    }
 }
 
-/// Create a chip instance by name
+/// Available chips, in the order `--list-chips` shows them
+static CHIPS: &[ChipInfo] = &[
+    ChipInfo { name: "PSG", aliases: &["SN76489"], create: || Box::new(sn76489::Sn76489::new()) },
+    ChipInfo { name: "OPN", aliases: &["YM2203"], create: || Box::new(opn::Opn::new()) },
+    ChipInfo { name: "OPN2", aliases: &["YM2612", "GENESIS", "MEGADRIVE"], create: || Box::new(opn2::Opn2::new()) },
+    ChipInfo { name: "OPLL", aliases: &["YM2413"], create: || Box::new(opll::Opll::new()) },
+    ChipInfo { name: "OPL2", aliases: &["YM3812"], create: || Box::new(opl2::Opl2::new()) },
+    ChipInfo { name: "OPL3", aliases: &["YMF262"], create: || Box::new(opl3::Opl3::new()) },
+    ChipInfo { name: "OPL4", aliases: &["YMF278B"], create: || Box::new(opl4::Opl4::new()) },
+    ChipInfo { name: "AY8910", aliases: &["GI-AY"], create: || Box::new(ay8910::Ay8910::new()) },
+    ChipInfo { name: "AY8930", aliases: &[], create: || Box::new(ay8930::Ay8930::new()) },
+    ChipInfo { name: "2A03", aliases: &["FAMICOM", "NES"], create: || Box::new(nes_apu::NesApu::new()) },
+    ChipInfo { name: "DMG", aliases: &["GB", "GAMEBOY"], create: || Box::new(dmg::Dmg::new()) },
+    ChipInfo { name: "HuC6280", aliases: &["PCE"], create: || Box::new(huc6280::HuC6280::new()) },
+    ChipInfo { name: "Pokey", aliases: &[], create: || Box::new(pokey::Pokey::new()) },
+    ChipInfo { name: "QSound", aliases: &[], create: || Box::new(qsound::QSound::new()) },
+    ChipInfo { name: "SegaPCM", aliases: &[], create: || Box::new(segapcm::SegaPcm::new()) },
+    ChipInfo { name: "T6W28", aliases: &["NGP"], create: || Box::new(t6w28::T6w28::new()) },
+    ChipInfo { name: "NULL", aliases: &[], create: || Box::new(null::NullChip::new()) },
+];
+
+/// Find a chip by its name or one of its aliases
+pub fn find_chip(name: &str) -> Option<&'static ChipInfo> {
+    CHIPS.iter().find(|info| info.is_named(name))
+}
+
+/// Create a chip instance by name or alias
 pub fn create_chip(name: &str) -> Result<ChipInstance> {
-    let chip: Box<dyn SoundChip> = match name {
-        "PSG" => Box::new(sn76489::Sn76489::new()),
-        "OPN2" => Box::new(opn2::Opn2::new()),
-        "OPLL" => Box::new(opll::Opll::new()),
-        "OPL2" => Box::new(opl2::Opl2::new()),
-        "OPL3" => Box::new(opl3::Opl3::new()),
-        "OPL4" => Box::new(opl4::Opl4::new()),
-        "AY8910" | "GI-AY" => Box::new(ay8910::Ay8910::new()),
-        "AY8930" => Box::new(ay8930::Ay8930::new()),
-        "2A03" | "FAMICOM" => Box::new(nes_apu::NesApu::new()),
-        "DMG" | "GAMEBOY" => Box::new(dmg::Dmg::new()),
-        "HuC6280" => Box::new(huc6280::HuC6280::new()),
-        "Pokey" => Box::new(pokey::Pokey::new()),
-        "QSound" => Box::new(qsound::QSound::new()),
-        "T6W28" => Box::new(t6w28::T6w28::new()),
-        _ => return Err(Error::UnknownChip(name.to_string())),
-    };
-
-    Ok(ChipInstance::new(chip))
-}
-
-/// List all available chip names
-pub fn list_chips() -> Vec<&'static str> {
-    vec![
-        "PSG", "OPN2", "OPLL", "OPL2", "OPL3", "OPL4", "AY8910", "AY8930", "2A03", "DMG",
-        "HuC6280", "Pokey", "QSound", "T6W28",
-    ]
+    find_chip(name)
+        .map(ChipInfo::create)
+        .ok_or_else(|| Error::UnknownChip(name.to_string()))
+}
+
+/// List all available chips
+pub fn list_chips() -> &'static [ChipInfo] {
+    CHIPS
 }
@@ -1,16 +1,19 @@
 //! NES APU (2A03) sound chip driver
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, second_half, ChipOptions, DacTarget, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+const OPTIONS: &[OptionDescription] = &[CLOCK_OPTION, super::dmg::SHORT_NOISE_OPTION];
+
 /// NES APU (2A03) chip
 pub struct NesApu {
     clock: i32,
     enable: [u8; 2],       // Channel enable state per chip
     dutyvol: [[u8; 2]; 2], // Duty/volume for square channels
     dual: bool,            // Dual chip mode
+    short_noise: bool,     // Noise channel plays short (periodic) noise (`N:1`)
 }
 
 impl NesApu {
@@ -20,6 +23,7 @@ impl NesApu {
             enable: [0, 0],
             dutyvol: [[0x30, 0x30], [0x30, 0x30]],
             dual: false,
+            short_noise: false,
         }
     }
 }
@@ -51,11 +55,33 @@ impl SoundChip for NesApu {
         2
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[4, 2, 2]
+    }
+
+    fn second_chip_channel(&self, chip_sub: usize, chan_sub: usize) -> Option<usize> {
+        second_half(self.group_sizes(), chip_sub, chan_sub)
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        16.0
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 1789772;
         }
+        self.short_noise = options.noise == Some(1) || options.get('n') != 0;
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
+    fn dac_target(&self) -> Option<DacTarget> {
+        // $4011, the 7-bit DMC level, played as a raw DAC
+        Some(DacTarget { chip_type: chip_id::NES_APU, port: 0, register: 0x11, bank: 0x07, bits: 7 })
     }
 
     fn file_begin(&mut self, _writer: &mut VgmWriter) {
@@ -84,19 +110,19 @@ impl SoundChip for NesApu {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 // Packed: address in event_type (negative = internal), value/mask in value1/value2
                 // For square channels: set volume (lower nibble)
                 // event_type = 0xFFFF - 3 = duty/volume command
-                Some(ChipEvent::new(0xFFFD, value as i32, 0xF0))
+                MacroResult::Event(ChipEvent::new(0xFFFD, value as i32, 0xF0))
             }
             MacroCommand::Tone => {
                 // Duty cycle select for square channels
-                Some(ChipEvent::new(0xFFFD, (value << 6) as i32, 0x3F))
+                MacroResult::Event(ChipEvent::new(0xFFFD, (value << 6) as i32, 0x3F))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
@@ -137,6 +163,8 @@ impl SoundChip for NesApu {
         if c > 0 {
             self.dual = true;
         }
+        // Mode bit of the noise period register
+        let noise_mode = if a == 2 && self.short_noise { 0x80 } else { 0 };
 
         match event.event_type {
             0xFFFC => {
@@ -155,7 +183,7 @@ impl SoundChip for NesApu {
             0xFFFE => {
                 // Note change
                 let period = if a == 2 {
-                    (event.value1 as u16) | ((event.value2 as u16) << 7)
+                    (event.value1 as u16) | ((event.value2 as u16) << 7) | noise_mode
                 } else {
                     (event.value1 - 1) as u16
                 };
@@ -177,7 +205,7 @@ impl SoundChip for NesApu {
 
                 // Write period
                 let period = if a == 2 {
-                    (event.value1 as u16) | ((event.value2 as u16) << 7)
+                    (event.value1 as u16) | ((event.value2 as u16) << 7) | noise_mode
                 } else {
                     (event.value1 - 1) as u16
                 };
@@ -0,0 +1,93 @@
+//! Null chip driver
+//!
+//! Accepts every note and macro and writes nothing. Useful for muting
+//! parts, timing the compiler core and tests that should not depend on
+//! register behavior.
+
+use super::{chip_id, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip};
+use crate::compiler::event::ChipEvent;
+use crate::vgm::VgmWriter;
+
+/// Chip that produces no output
+#[derive(Default)]
+pub struct NullChip;
+
+impl NullChip {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl SoundChip for NullChip {
+    fn name(&self) -> &'static str {
+        "NULL"
+    }
+
+    fn chip_id(&self) -> u8 {
+        chip_id::NULL
+    }
+
+    fn clock_div(&self) -> i32 {
+        0
+    }
+
+    fn note_bits(&self) -> i32 {
+        0
+    }
+
+    fn basic_octave(&self) -> i32 {
+        0
+    }
+
+    fn enable(&mut self, _options: &ChipOptions) {}
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        &[]
+    }
+
+    fn file_begin(&mut self, _writer: &mut VgmWriter) {}
+
+    fn file_end(&mut self, _writer: &mut VgmWriter) {}
+
+    fn loop_start(&mut self, _writer: &mut VgmWriter) {}
+
+    fn start_channel(&mut self, _channel: usize) {}
+
+    fn set_macro(
+        &mut self,
+        _channel: usize,
+        _is_dynamic: bool,
+        _command: MacroCommand,
+        _value: i16,
+    ) -> MacroResult {
+        MacroResult::NoEvent
+    }
+
+    fn note_on(
+        &mut self,
+        _channel: usize,
+        _note: i32,
+        _octave: i32,
+        _duration: i32,
+    ) -> Option<ChipEvent> {
+        None
+    }
+
+    fn note_change(&mut self, _channel: usize, _note: i32, _octave: i32) -> Option<ChipEvent> {
+        None
+    }
+
+    fn note_off(&mut self, _channel: usize, _note: i32, _octave: i32) -> Option<ChipEvent> {
+        None
+    }
+
+    fn rest(&mut self, _channel: usize, _duration: i32) -> Option<ChipEvent> {
+        None
+    }
+
+    fn direct(&mut self, _channel: usize, _address: u16, _value: u8) -> Option<ChipEvent> {
+        None
+    }
+
+    fn send(&mut self, _event: &ChipEvent, _channel: usize, _chip_sub: usize, _chan_sub: usize, _writer: &mut VgmWriter) {}
+}
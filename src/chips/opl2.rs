@@ -1,11 +1,23 @@
 //! YM3812 (OPL2) sound chip driver
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::compiler::envelope::MacroEnvStorage;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+/// Options shared with the OPL3 and OPL4, which have the same depth bits
+pub(super) const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('v', "deep-vibrato", "Start with 14 cent vibrato depth (else 7 cents), as @G bit 0"),
+    OptionDescription::new('t', "deep-tremolo", "Start with 4.8 dB tremolo depth (else 1 dB), as @G bit 1"),
+];
+
+/// Depth bits of register 0xBD set by the `v` and `t` options
+pub(super) fn depth_bits(options: &ChipOptions) -> u8 {
+    ((options.get('v') != 0) as u8) << 6 | ((options.get('t') != 0) as u8) << 7
+}
+
 /// Operator offset table
 const OPER: [usize; 9] = [0, 1, 2, 8, 9, 10, 16, 17, 18];
 
@@ -17,6 +29,7 @@ pub struct Opl2 {
     subc: [usize; 2],
     instr: [[usize; 18]; 6],
     vol: [[i32; 18]; 6],
+    depth: u8,
 }
 
 impl Opl2 {
@@ -28,6 +41,7 @@ impl Opl2 {
             subc: [0, 0],
             instr: [[0; 18]; 6],
             vol: [[0; 18]; 6],
+            depth: 0,
         }
     }
 
@@ -106,11 +120,28 @@ impl SoundChip for Opl2 {
         7
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[18, 2, 2, 2, 2, 2]
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        (1 << 23) as f64
+    }
+
+    fn max_volume(&self) -> i16 {
+        63
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 3579545;
         }
+        self.depth = depth_bits(options);
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
     }
 
     fn file_begin(&mut self, writer: &mut VgmWriter) {
@@ -136,7 +167,7 @@ impl SoundChip for Opl2 {
 
             // Set rhythm mode if needed
             let rhythm = if self.subc[1] > i { 0x20 } else { 0x00 };
-            self.write_opl(i, 0xBD, rhythm, writer);
+            self.write_opl(i, 0xBD, rhythm | self.depth, writer);
         }
     }
 
@@ -173,23 +204,23 @@ impl SoundChip for Opl2 {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 // type 4 = volume
-                Some(ChipEvent::new(4, (63 & !value) as i32, 0))
+                MacroResult::Event(ChipEvent::new(4, (63 & !value) as i32, 0))
             }
             MacroCommand::Tone => {
                 // type 3 = instrument
-                Some(ChipEvent::new(3, (value & 255) as i32, 0))
+                MacroResult::Event(ChipEvent::new(3, (value & 255) as i32, 0))
             }
             MacroCommand::Global => {
                 // type 5 = global (tremolo/vibrato depth)
                 let data1 = ((value & 3) << 6) as i32;
                 let data2 = ((value & 12) << 4) as i32;
-                Some(ChipEvent::new(5, data1, data2))
+                MacroResult::Event(ChipEvent::global(5, data1, data2))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
@@ -273,10 +304,12 @@ impl SoundChip for Opl2 {
                 // Full volume set handled in send_with_macro_env
             }
             5 => {
-                // Global setting
-                let bd = self.memory[c][0xBD].max(0) as u8;
-                self.write_opl(c, 0xBD, (bd & 0x3F) | (event.value1 as u8), writer);
-                self.write_opl(c, 0x08, event.value2 as u8, writer);
+                // Global setting, on both chips of a dual setup
+                for c in 0..if self.dual != 0 { 2 } else { 1 } {
+                    let bd = self.memory[c][0xBD].max(0) as u8;
+                    self.write_opl(c, 0xBD, (bd & 0x3F) | (event.value1 as u8), writer);
+                    self.write_opl(c, 0x08, event.value2 as u8, writer);
+                }
             }
             _ => {}
         }
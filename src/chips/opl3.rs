@@ -1,6 +1,6 @@
 //! YMF262 (OPL3) sound chip driver
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, opl2, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip};
 use crate::compiler::event::ChipEvent;
 use crate::compiler::envelope::MacroEnvStorage;
 use crate::vgm::header::offset;
@@ -25,6 +25,7 @@ pub struct Opl3 {
     drum: [u8; 2],
     sam: [u16; 2],
     tone: u16,
+    depth: u8,
 }
 
 impl Opl3 {
@@ -38,6 +39,49 @@ impl Opl3 {
             drum: [0, 0],
             sam: [0, 0],
             tone: 0xC000,
+            depth: 0,
+        }
+    }
+
+    /// Physical channels (`id << 6 | channel`, id = chip << 1 | port) of the
+    /// two-op and four-op channels, for the channels in use
+    ///
+    /// The first channel pairs go to the four-op channels, the rest are two
+    /// two-op channels each; rhythm mode takes channels 6-8 of a chip.
+    fn assignment(use_count: &[usize; 3]) -> (Vec<u8>, Vec<u8>) {
+        // Rhythm channels for second chip
+        let mut a2op = vec![0x46, 0x47, 0x48];
+        let mut a4op = Vec::new();
+
+        // First chip rhythm channels (if not in rhythm mode)
+        if use_count[2] < 1 {
+            a2op.extend([0x06, 0x07, 0x08]);
+        }
+
+        // Channel pairs - 2-op or 4-op based on use_count[1]
+        let pairs = [0x00, 0x01, 0x02, 0x40, 0x41, 0x42, 0x80, 0x81, 0x82, 0xC0, 0xC1, 0xC2];
+        for (i, pair) in pairs.into_iter().enumerate() {
+            if use_count[1] <= i {
+                a2op.extend([pair, pair + 3]);
+            } else {
+                a4op.push(pair);
+            }
+        }
+
+        // Last rhythm channels
+        a2op.extend([0xC6, 0xC7, 0xC8]);
+        if use_count[2] < 2 {
+            a2op.extend([0x86, 0x87, 0x88]);
+        }
+        (a2op, a4op)
+    }
+
+    /// Name of a physical channel
+    fn channel_name(c: u8) -> String {
+        let number = (c >> 6 & 1) * 9 + (c & 15) + 1;
+        match c >> 7 {
+            0 => format!("channel {}", number),
+            _ => format!("second chip channel {}", number),
         }
     }
 
@@ -137,79 +181,36 @@ impl SoundChip for Opl3 {
         0
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[36, 12, 2]
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        (1 << 25) as f64
+    }
+
+    fn max_volume(&self) -> i16 {
+        63
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 14318180;
         }
+        self.depth = opl2::depth_bits(options);
     }
 
-    fn file_begin(&mut self, writer: &mut VgmWriter) {
-        let mut a2 = 0usize;
-        let mut a4 = 0usize;
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        opl2::OPTIONS
+    }
 
+    fn file_begin(&mut self, writer: &mut VgmWriter) {
         // Assignment of operators and dual chips
         self.dual = self.use_count[2] > 1;
-
-        // Rhythm channels for second chip
-        self.a2op[a2] = 0x46; a2 += 1;
-        self.a2op[a2] = 0x47; a2 += 1;
-        self.a2op[a2] = 0x48; a2 += 1;
-
-        // First chip rhythm channels (if not in rhythm mode)
-        if self.use_count[2] < 1 {
-            self.a2op[a2] = 0x06; a2 += 1;
-            self.a2op[a2] = 0x07; a2 += 1;
-            self.a2op[a2] = 0x08; a2 += 1;
-        }
-
-        // Channel pairs - 2-op or 4-op based on use_count[1]
-        if self.use_count[1] < 1 { self.a2op[a2] = 0x00; a2 += 1; self.a2op[a2] = 0x03; a2 += 1; }
-        else { self.a4op[a4] = 0x00; a4 += 1; }
-
-        if self.use_count[1] < 2 { self.a2op[a2] = 0x01; a2 += 1; self.a2op[a2] = 0x04; a2 += 1; }
-        else { self.a4op[a4] = 0x01; a4 += 1; }
-
-        if self.use_count[1] < 3 { self.a2op[a2] = 0x02; a2 += 1; self.a2op[a2] = 0x05; a2 += 1; }
-        else { self.a4op[a4] = 0x02; a4 += 1; }
-
-        if self.use_count[1] < 4 { self.a2op[a2] = 0x40; a2 += 1; self.a2op[a2] = 0x43; a2 += 1; }
-        else { self.a4op[a4] = 0x40; a4 += 1; }
-
-        if self.use_count[1] < 5 { self.a2op[a2] = 0x41; a2 += 1; self.a2op[a2] = 0x44; a2 += 1; }
-        else { self.a4op[a4] = 0x41; a4 += 1; }
-
-        if self.use_count[1] < 6 { self.a2op[a2] = 0x42; a2 += 1; self.a2op[a2] = 0x45; a2 += 1; }
-        else { self.a4op[a4] = 0x42; a4 += 1; }
-
-        if self.use_count[1] < 7 { self.a2op[a2] = 0x80; a2 += 1; self.a2op[a2] = 0x83; a2 += 1; }
-        else { self.a4op[a4] = 0x80; a4 += 1; }
-
-        if self.use_count[1] < 8 { self.a2op[a2] = 0x81; a2 += 1; self.a2op[a2] = 0x84; a2 += 1; }
-        else { self.a4op[a4] = 0x81; a4 += 1; }
-
-        if self.use_count[1] < 9 { self.a2op[a2] = 0x82; a2 += 1; self.a2op[a2] = 0x85; a2 += 1; }
-        else { self.a4op[a4] = 0x82; a4 += 1; }
-
-        if self.use_count[1] < 10 { self.a2op[a2] = 0xC0; a2 += 1; self.a2op[a2] = 0xC3; a2 += 1; }
-        else { self.a4op[a4] = 0xC0; a4 += 1; }
-
-        if self.use_count[1] < 11 { self.a2op[a2] = 0xC1; a2 += 1; self.a2op[a2] = 0xC4; a2 += 1; }
-        else { self.a4op[a4] = 0xC1; a4 += 1; }
-
-        if self.use_count[1] < 12 { self.a2op[a2] = 0xC2; a2 += 1; self.a2op[a2] = 0xC5; a2 += 1; }
-        else { self.a4op[a4] = 0xC2; }
-
-        // Last rhythm channels
-        self.a2op[a2] = 0xC6; a2 += 1;
-        self.a2op[a2] = 0xC7; a2 += 1;
-        self.a2op[a2] = 0xC8; a2 += 1;
-
-        if self.use_count[2] < 2 {
-            self.a2op[a2] = 0x86; a2 += 1;
-            self.a2op[a2] = 0x87; a2 += 1;
-            self.a2op[a2] = 0x88;
-        }
+        let (a2op, a4op) = Self::assignment(&self.use_count);
+        self.a2op[..a2op.len()].copy_from_slice(&a2op);
+        self.a4op[..a4op.len()].copy_from_slice(&a4op);
 
         // Check if dual chip needed
         for x in 0..self.use_count[0] {
@@ -238,7 +239,11 @@ impl SoundChip for Opl3 {
         self.poke(3, 0x04, conn2, writer);
 
         // Reset drum/sample state
-        self.drum = [0, 0];
+        self.drum = [self.depth; 2];
+        if self.depth != 0 {
+            self.poke(0, 0xBD, self.depth, writer);
+            self.poke(2, 0xBD, self.depth, writer);
+        }
         self.sam = [0, 0];
         self.tone = 0xC000;
     }
@@ -257,6 +262,17 @@ impl SoundChip for Opl3 {
 
     fn start_channel(&mut self, _channel: usize) {}
 
+    fn hardware_channels(&self, chip_sub: usize, chan_sub: usize) -> Vec<String> {
+        let (a2op, a4op) = Self::assignment(&self.use_count);
+        let channels = match chip_sub {
+            // Two-op channels past the free ones keep the table's initial channel 0
+            0 => vec![a2op.get(chan_sub).copied().unwrap_or(0)],
+            1 => a4op.get(chan_sub).map_or(Vec::new(), |&c| vec![c, c + 3]),
+            _ => (6..9).map(|c| (chan_sub as u8) << 7 | c).collect(),
+        };
+        channels.into_iter().map(Self::channel_name).collect()
+    }
+
     fn start_channel_with_info(&mut self, chip_sub: usize, chan_sub: usize) {
         let b = chan_sub + 1;
         if self.use_count[chip_sub] < b {
@@ -270,12 +286,12 @@ impl SoundChip for Opl3 {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 // type 3 = volume/panning
                 self.tone = (self.tone & !0x3F00) | (((63 & !value) as u16) << 8);
-                Some(ChipEvent::new(0x403, self.tone as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x403, self.tone as i32, 0))
             }
             MacroCommand::Panning => {
                 // Panning
@@ -287,22 +303,22 @@ impl SoundChip for Opl3 {
                     0xC000u16
                 };
                 self.tone = (self.tone & !0xC000) | pan;
-                Some(ChipEvent::new(0x403, self.tone as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x403, self.tone as i32, 0))
             }
             MacroCommand::Tone => {
                 // Instrument
                 self.tone = (self.tone & !0xFF) | ((value as u16) & 255);
-                Some(ChipEvent::new(0x405, self.tone as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x405, self.tone as i32, 0))
             }
             MacroCommand::Global => {
                 // Global (tremolo/vibrato depth)
-                Some(ChipEvent::new(0x406, value as i32, 0))
+                MacroResult::Event(ChipEvent::global(0x406, value as i32, 0))
             }
             MacroCommand::Sample => {
                 // Rhythm sample
-                Some(ChipEvent::new(0x404, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x404, value as i32, 0))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
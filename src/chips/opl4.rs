@@ -2,7 +2,7 @@
 //!
 //! OPL4 = OPL3 (FM synthesis) + Wavetable PCM
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, opl2, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip};
 use crate::compiler::event::ChipEvent;
 use crate::compiler::envelope::MacroEnvStorage;
 use crate::vgm::header::offset;
@@ -24,6 +24,7 @@ pub struct Opl4 {
     drum: [u8; 2],
     sam: [u16; 2],
     tone: u16,
+    depth: u8,
 }
 
 impl Opl4 {
@@ -37,6 +38,7 @@ impl Opl4 {
             drum: [0, 0],
             sam: [0, 0],
             tone: 0xC000,
+            depth: 0,
         }
     }
 
@@ -146,11 +148,20 @@ impl SoundChip for Opl4 {
         0
     }
 
+    fn max_volume(&self) -> i16 {
+        63
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 33868800;
         }
+        self.depth = opl2::depth_bits(options);
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        opl2::OPTIONS
     }
 
     fn file_begin(&mut self, writer: &mut VgmWriter) {
@@ -255,7 +266,11 @@ impl SoundChip for Opl4 {
         self.poke(10, 0x02, 0x20, writer);
 
         // Reset drum/sample state
-        self.drum = [0, 0];
+        self.drum = [self.depth; 2];
+        if self.depth != 0 {
+            self.poke(0, 0xBD, self.depth, writer);
+            self.poke(2, 0xBD, self.depth, writer);
+        }
         self.sam = [0, 0];
         self.tone = 0xC000;
     }
@@ -287,12 +302,12 @@ impl SoundChip for Opl4 {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 // FM volume/panning
                 self.tone = (self.tone & !0x3F00) | (((63 & !value) as u16) << 8);
-                Some(ChipEvent::new(0x403, self.tone as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x403, self.tone as i32, 0))
             }
             MacroCommand::Panning => {
                 // Panning
@@ -304,22 +319,22 @@ impl SoundChip for Opl4 {
                     0xC000u16
                 };
                 self.tone = (self.tone & !0xC000) | pan;
-                Some(ChipEvent::new(0x403, self.tone as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x403, self.tone as i32, 0))
             }
             MacroCommand::Tone => {
                 // Instrument
                 self.tone = (self.tone & !0xFF) | ((value as u16) & 255);
-                Some(ChipEvent::new(0x405, self.tone as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x405, self.tone as i32, 0))
             }
             MacroCommand::Global => {
                 // Global (tremolo/vibrato depth)
-                Some(ChipEvent::new(0x406, value as i32, 0))
+                MacroResult::Event(ChipEvent::global(0x406, value as i32, 0))
             }
             MacroCommand::Sample => {
                 // FM rhythm sample
-                Some(ChipEvent::new(0x404, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0x404, value as i32, 0))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
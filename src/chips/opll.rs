@@ -1,11 +1,16 @@
 //! YM2413 (OPLL) sound chip driver
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::compiler::envelope::MacroEnvStorage;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('v', "vrc7", "Play on a Konami VRC7 (sets bit 31 of the header clock)"),
+];
+
 /// YM2413 OPLL chip
 pub struct Opll {
     clock: i32,
@@ -13,6 +18,7 @@ pub struct Opll {
     drum: bool,          // Rhythm mode enabled
     sus: u8,             // Sustain mode
     mem: [[i16; 64]; 2], // Register memory cache
+    vrc7: bool,          // Played on a VRC7
 }
 
 impl Opll {
@@ -23,6 +29,7 @@ impl Opll {
             drum: false,
             sus: 0,
             mem: [[256; 64]; 2],
+            vrc7: false,
         }
     }
 
@@ -73,11 +80,24 @@ impl SoundChip for Opll {
         7
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[18, 2]
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        (1 << 22) as f64
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 3579545;
         }
+        self.vrc7 = options.get('v') != 0;
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
     }
 
     fn file_begin(&mut self, writer: &mut VgmWriter) {
@@ -110,7 +130,7 @@ impl SoundChip for Opll {
         } else {
             self.clock as u32
         };
-        header.write_u32(offset::YM2413_CLOCK, clock_val);
+        header.write_u32(offset::YM2413_CLOCK, clock_val | (self.vrc7 as u32) << 31);
     }
 
     fn loop_start(&mut self, _writer: &mut VgmWriter) {}
@@ -133,23 +153,23 @@ impl SoundChip for Opll {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 // Volume command - event_type encodes register, value2 is mask, value1 is data
-                Some(ChipEvent::new(0xF3, (0x0F & !value) as i32, 0xF0))
+                MacroResult::Event(ChipEvent::new(0xF3, (0x0F & !value) as i32, 0xF0))
             }
             MacroCommand::Tone | MacroCommand::Sample => {
                 // Tone/instrument select
                 if (value & !0x1F) != 0 {
                     self.sus = 0;
-                    None
+                    MacroResult::NoEvent
                 } else {
                     self.sus = (value & 0x10) as u8;
-                    Some(ChipEvent::new(0xF3, ((value & 15) << 4) as i32, 0x0F))
+                    MacroResult::Event(ChipEvent::new(0xF3, ((value & 15) << 4) as i32, 0x0F))
                 }
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
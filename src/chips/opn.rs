@@ -0,0 +1,199 @@
+//! YM2203 (OPN) sound chip driver
+//!
+//! Three FM channels driven by the OPN2 code and three SSG channels driven
+//! by the AY-3-8910 code, both written through the YM2203 command. Channel
+//! group 0 is FM and group 1 is SSG: `#EX-OPN ABC,DEF`.
+
+use super::ay8910::Ay8910;
+use super::opn2::Opn2;
+use super::{chip_id, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
+use crate::compiler::envelope::MacroEnvStorage;
+use crate::compiler::event::ChipEvent;
+use crate::vgm::header::offset;
+use crate::vgm::VgmWriter;
+
+/// VGM write command of the YM2203
+const COMMAND: u8 = 0x55;
+
+/// Channels in each group
+const CHANNELS: usize = 3;
+
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('S', "envelope-shift", "Octave shift between SSG envelope and note"),
+];
+
+/// YM2203 OPN chip
+pub struct Opn {
+    clock: i32,
+    fm: Opn2,
+    ssg: Ay8910,
+    group: usize, // Channel group being compiled (0 = FM, 1 = SSG)
+}
+
+impl Opn {
+    pub fn new() -> Self {
+        Self {
+            clock: 3993600,
+            fm: Opn2::ym2203(),
+            ssg: Ay8910::ssg(COMMAND),
+            group: 0,
+        }
+    }
+
+    /// The part playing the channel group being compiled
+    fn part(&self) -> &dyn SoundChip {
+        if self.group == 0 {
+            &self.fm
+        } else {
+            &self.ssg
+        }
+    }
+
+    fn part_mut(&mut self) -> &mut dyn SoundChip {
+        if self.group == 0 {
+            &mut self.fm
+        } else {
+            &mut self.ssg
+        }
+    }
+}
+
+impl Default for Opn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoundChip for Opn {
+    fn name(&self) -> &'static str {
+        "OPN"
+    }
+
+    fn chip_id(&self) -> u8 {
+        chip_id::YM2203
+    }
+
+    fn clock_div(&self) -> i32 {
+        self.part().clock_div()
+    }
+
+    fn note_bits(&self) -> i32 {
+        self.part().note_bits()
+    }
+
+    fn basic_octave(&self) -> i32 {
+        self.part().basic_octave()
+    }
+
+    fn group_sizes(&self) -> &'static [usize] {
+        &[3, 3]
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        self.part().pitch_divider()
+    }
+
+    fn max_volume(&self) -> i16 {
+        self.part().max_volume()
+    }
+
+    fn enable(&mut self, options: &ChipOptions) {
+        self.clock = options.get('H');
+        if self.clock == 0 {
+            self.clock = 3993600;
+        }
+        // The SSG runs at half the master clock
+        let mut part_options = options.clone();
+        part_options.set('H', self.clock);
+        self.fm.enable(&part_options);
+        part_options.set('H', self.clock / 2);
+        self.ssg.enable(&part_options);
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
+    fn file_begin(&mut self, writer: &mut VgmWriter) {
+        self.fm.file_begin(writer);
+        self.ssg.file_begin(writer);
+    }
+
+    fn file_end(&mut self, writer: &mut VgmWriter) {
+        writer.header_mut().write_u32(offset::YM2203_CLOCK, self.clock as u32);
+    }
+
+    fn loop_start(&mut self, _writer: &mut VgmWriter) {}
+
+    fn start_channel(&mut self, _channel: usize) {}
+
+    fn start_channel_with_info(&mut self, chip_sub: usize, chan_sub: usize) {
+        self.group = chip_sub.min(1);
+        self.part_mut().start_channel_with_info(0, chan_sub);
+    }
+
+    fn set_macro(
+        &mut self,
+        channel: usize,
+        is_dynamic: bool,
+        command: MacroCommand,
+        value: i16,
+    ) -> MacroResult {
+        // The FM channels have no panning
+        if self.group == 0 && command == MacroCommand::Panning {
+            return MacroResult::Unsupported;
+        }
+        self.part_mut().set_macro(channel, is_dynamic, command, value)
+    }
+
+    fn note_on(&mut self, channel: usize, note: i32, octave: i32, duration: i32) -> Option<ChipEvent> {
+        self.part_mut().note_on(channel, note, octave, duration)
+    }
+
+    fn note_change(&mut self, channel: usize, note: i32, octave: i32) -> Option<ChipEvent> {
+        self.part_mut().note_change(channel, note, octave)
+    }
+
+    fn note_off(&mut self, channel: usize, note: i32, octave: i32) -> Option<ChipEvent> {
+        self.part_mut().note_off(channel, note, octave)
+    }
+
+    fn rest(&mut self, channel: usize, duration: i32) -> Option<ChipEvent> {
+        self.part_mut().rest(channel, duration)
+    }
+
+    fn direct(&mut self, channel: usize, address: u16, value: u8) -> Option<ChipEvent> {
+        self.part_mut().direct(channel, address, value)
+    }
+
+    fn send(&mut self, event: &ChipEvent, channel: usize, chip_sub: usize, chan_sub: usize, writer: &mut VgmWriter) {
+        if chan_sub >= CHANNELS {
+            return;
+        }
+        if chip_sub == 0 {
+            self.fm.send(event, channel, 0, chan_sub, writer);
+        } else {
+            self.ssg.send(event, channel, 0, chan_sub, writer);
+        }
+    }
+
+    fn send_with_macro_env(
+        &mut self,
+        event: &ChipEvent,
+        channel: usize,
+        chip_sub: usize,
+        chan_sub: usize,
+        writer: &mut VgmWriter,
+        macro_env: &MacroEnvStorage,
+    ) {
+        if chan_sub >= CHANNELS {
+            return;
+        }
+        if chip_sub == 0 {
+            self.fm.send_with_macro_env(event, channel, 0, chan_sub, writer, macro_env);
+        } else {
+            self.ssg.send(event, channel, 0, chan_sub, writer);
+        }
+    }
+}
@@ -1,11 +1,16 @@
 //! YM2612 (OPN2) sound chip driver
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, ChipOptions, DacTarget, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::compiler::envelope::MacroEnvStorage;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('L', "lfo", "LFO frequency the song starts with (0=off, 1-8 for 3.98-72.2 Hz)"),
+];
+
 /// YM2612 OPN2 chip
 pub struct Opn2 {
     clock: i32,
@@ -16,6 +21,10 @@ pub struct Opn2 {
     mem: Vec<i16>,    // Register memory cache
     vol: [u8; 12],    // Volume per channel
     pan: [u8; 12],    // Panning per channel
+    command: u8,      // VGM write command of the first port
+    ports: usize,     // Register ports per chip
+    prescaler: i32,   // Master clock cycles per FM sample
+    lfo: u8,          // LFO register written at the start, 0 for none
 }
 
 impl Opn2 {
@@ -29,6 +38,47 @@ impl Opn2 {
             mem: vec![-1; 0x400],
             vol: [127; 12],
             pan: [0xC0; 12],
+            command: 0x52,
+            ports: 2,
+            prescaler: 144,
+            lfo: 0,
+        }
+    }
+
+    /// FM part of a YM2203 (OPN): one port of three channels, at twice the
+    /// sample rate of the YM2612 for the same clock
+    pub(super) fn ym2203() -> Self {
+        Self {
+            clock: 3993600,
+            command: 0x55,
+            ports: 1,
+            prescaler: 72,
+            ..Self::new()
+        }
+    }
+
+    /// Channel assignment table for a number of supplementary channels
+    ///
+    /// Melody channels take entries from the front and supplementary channels
+    /// from the back; a supplementary channel takes a chip's FM3 or FM6 away
+    /// from the melody channels. Entries are `chip << 3 | port << 2 | channel`.
+    fn assignment(sup: usize) -> [u8; 12] {
+        let mut assign = [0, 1, 4, 5, 8, 9, 12, 13, 14, 10, 6, 2];
+        let melody = [0u8, 1, 2, 4, 5, 6, 8, 9, 10, 12, 13, 14]
+            .into_iter()
+            .filter(|&c| c & 3 != 2 || (c >> 2) as usize >= sup);
+        for (slot, c) in assign.iter_mut().zip(melody) {
+            *slot = c;
+        }
+        assign
+    }
+
+    /// Index into the assignment table of a channel of a group
+    fn slot(chip_sub: usize, chan_sub: usize) -> usize {
+        if chip_sub != 0 {
+            11 - chan_sub
+        } else {
+            chan_sub
         }
     }
 
@@ -37,9 +87,11 @@ impl Opn2 {
         // Write if value changed, or if it's a frequency register (0xA0-0xAF)
         if (self.mem[address] != data as i16 || (address & 0xA0) == 0xA0)
             && (self.dual || (address & 0x200) == 0)
+            && (self.ports > 1 || (address & 0x100) == 0)
         {
             self.mem[address] = data as i16;
-            let cmd = if (address & 0x200) != 0 { 0xA2 } else { 0x52 }
+            // The second chip's commands are 0x50 above the first's
+            let cmd = if (address & 0x200) != 0 { self.command + 0x50 } else { self.command }
                 | ((address >> 8) & 1) as u8;
             let _ = writer.write_data(&[cmd, (address & 0xFF) as u8, data]);
         }
@@ -151,51 +203,52 @@ impl SoundChip for Opn2 {
         7
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[12, 4]
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        (self.prescaler << 21) as f64
+    }
+
+    fn max_volume(&self) -> i16 {
+        127
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 7670454;
         }
+        // The YM2203 has no LFO
+        let lfo = options.get('L');
+        self.lfo = if self.ports > 1 && lfo > 0 { 0x08 | (lfo - 1).min(7) as u8 } else { 0 };
     }
 
-    fn file_begin(&mut self, _writer: &mut VgmWriter) {
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
+    fn dac_target(&self) -> Option<DacTarget> {
+        Some(DacTarget { chip_type: chip_id::YM2612, port: 0, register: 0x2A, bank: 0x00, bits: 8 })
+    }
+
+    fn dac_begin(&mut self, writer: &mut VgmWriter) {
+        // The DAC replaces FM channel 6
+        self.opn2_put(0x2B, 0x80, writer);
+    }
+
+    fn file_begin(&mut self, writer: &mut VgmWriter) {
         // Reset state (but preserve nor/sup from channel parsing)
         self.mem.fill(-1);
         self.vol = [127; 12];
         self.pan = [0xC0; 12];
 
         // Build channel assignment based on supplementary channels used
-        let mut i = 0;
-        self.assign[i] = 0;
-        i += 1;
-        self.assign[i] = 1;
-        i += 1;
-        if self.sup < 1 {
-            self.assign[i] = 2;
-            i += 1;
-        }
-        self.assign[i] = 4;
-        i += 1;
-        self.assign[i] = 5;
-        i += 1;
-        if self.sup < 2 {
-            self.assign[i] = 6;
-            i += 1;
-        }
-        self.assign[i] = 8;
-        i += 1;
-        self.assign[i] = 9;
-        i += 1;
-        if self.sup < 3 {
-            self.assign[i] = 10;
-            i += 1;
-        }
-        self.assign[i] = 12;
-        i += 1;
-        self.assign[i] = 13;
-        i += 1;
-        if self.sup < 4 {
-            self.assign[i] = 14;
+        self.assign = Self::assignment(self.sup);
+
+        if self.lfo != 0 {
+            self.opn2_put(0x22, self.lfo, writer);
         }
     }
 
@@ -215,6 +268,15 @@ impl SoundChip for Opn2 {
 
     fn start_channel(&mut self, _channel: usize) {}
 
+    fn hardware_channels(&self, chip_sub: usize, chan_sub: usize) -> Vec<String> {
+        let c = Self::assignment(self.sup)[Self::slot(chip_sub, chan_sub)];
+        let fm = (c >> 2 & 1) * 3 + (c & 3) + 1;
+        match c >> 3 {
+            0 => vec![format!("FM{}", fm)],
+            _ => vec![format!("second chip FM{}", fm)],
+        }
+    }
+
     fn start_channel_with_info(&mut self, chip_sub: usize, chan_sub: usize) {
         let y = chan_sub + 1;
         if chip_sub != 0 {
@@ -232,9 +294,9 @@ impl SoundChip for Opn2 {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
-            MacroCommand::Volume => Some(ChipEvent::new(0x6000, (value ^ 127) as i32, 0)),
+            MacroCommand::Volume => MacroResult::Event(ChipEvent::new(0x6000, (value ^ 127) as i32, 0)),
             MacroCommand::Panning => {
                 let pan = if value < 0 {
                     0x80
@@ -243,11 +305,11 @@ impl SoundChip for Opn2 {
                 } else {
                     0xC0
                 };
-                Some(ChipEvent::new(0x7000, pan, 0))
+                MacroResult::Event(ChipEvent::new(0x7000, pan, 0))
             }
-            MacroCommand::Tone => Some(ChipEvent::new(0x5000, (value & 255) as i32, 0)),
-            MacroCommand::Global => Some(ChipEvent::new(0x1022, value as i32, 0)),
-            _ => None,
+            MacroCommand::Tone => MacroResult::Event(ChipEvent::new(0x5000, (value & 255) as i32, 0)),
+            MacroCommand::Global => MacroResult::Event(ChipEvent::global(0x1022, value as i32, 0)),
+            _ => MacroResult::Unsupported,
         }
     }
 
@@ -278,9 +340,7 @@ impl SoundChip for Opn2 {
     }
 
     fn send(&mut self, event: &ChipEvent, _channel: usize, chip_sub: usize, chan_sub: usize, writer: &mut VgmWriter) {
-        let cs = chan_sub;
-        let mo = chip_sub != 0;
-        let ch = if mo { 12 - cs } else { cs };
+        let ch = Self::slot(chip_sub, chan_sub);
 
         match event.event_type >> 12 {
             0 => {
@@ -345,9 +405,8 @@ impl SoundChip for Opn2 {
         writer: &mut VgmWriter,
         macro_env: &MacroEnvStorage,
     ) {
-        let cs = chan_sub;
         let mo = chip_sub != 0;
-        let ch = if mo { 12 - cs } else { cs };
+        let ch = Self::slot(chip_sub, chan_sub);
 
         // Get operator data from macro env
         let oper_idx = event.value2 as usize;
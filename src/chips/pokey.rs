@@ -5,11 +5,18 @@
 //! - chip_sub=1: 16-bit mode (channels 0+1 or 2+3)
 //! - chip_sub=2: High-pass filter mode
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('p', "poly9", "9-bit poly-counters (else 17-bit)"),
+    OptionDescription::new('c', "clock15k", "15 KHz base clock (else 64 KHz)"),
+    OptionDescription::new('x', "direct-multiply", "Direct multiply mode"),
+];
+
 /// Pokey chip (Atari)
 pub struct Pokey {
     clock: i32,
@@ -73,6 +80,10 @@ impl SoundChip for Pokey {
         2
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[4, 2, 2]
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
@@ -84,6 +95,10 @@ impl SoundChip for Pokey {
         self.audctl = (self.opt_c | (self.opt_p << 7)) as u8;
     }
 
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
     fn file_begin(&mut self, writer: &mut VgmWriter) {
         // Reset state
         self.stat = [[0x10; 4]; 3];
@@ -138,30 +153,30 @@ impl SoundChip for Pokey {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Volume => {
                 if (self.audc & 0x0F) != (value as u8 & 0x0F) {
                     self.audc = (self.audc & 0xE0) | (value as u8 & 0x0F);
                     // type 0xFD = volume/distortion
-                    Some(ChipEvent::new(0xFD, self.audc as i32, 0))
+                    MacroResult::Event(ChipEvent::new(0xFD, self.audc as i32, 0))
                 } else {
-                    None
+                    MacroResult::NoEvent
                 }
             }
             MacroCommand::Tone => {
                 if (self.audc >> 5) != (value as u8 & 0x07) {
                     self.audc = (self.audc & 0x0F) | ((value as u8 & 0x07) << 5);
-                    Some(ChipEvent::new(0xFD, self.audc as i32, 0))
+                    MacroResult::Event(ChipEvent::new(0xFD, self.audc as i32, 0))
                 } else {
-                    None
+                    MacroResult::NoEvent
                 }
             }
             MacroCommand::Multiply => {
                 self.mul = value;
-                None
+                MacroResult::NoEvent
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
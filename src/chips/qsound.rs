@@ -2,11 +2,16 @@
 //!
 //! 16-channel sample playback chip used by Capcom
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('P', "pan", "Panning every channel starts with (-16 to +16)"),
+];
+
 /// QSound chip (Capcom)
 pub struct QSound {
     clock: i32,
@@ -14,6 +19,7 @@ pub struct QSound {
     key: [bool; 16],     // Key state per channel
     per: [bool; 16],     // Periodic/fixed pitch mode
     mru_sam: i32,        // Most recently used sample
+    start_pan: Option<i32>, // Panning written to every channel at the start
 }
 
 impl QSound {
@@ -24,6 +30,7 @@ impl QSound {
             key: [false; 16],
             per: [false; 16],
             mru_sam: -1,
+            start_pan: None,
         }
     }
 
@@ -64,19 +71,38 @@ impl SoundChip for QSound {
         7
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[16]
+    }
+
+    /// Volumes go to a 16-bit register, ranging 0-4095
+    fn max_volume(&self) -> i16 {
+        4095
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
             self.clock = 4000000;
         }
+        self.start_pan = options.get_opt('P').map(|pan| pan.clamp(-16, 16));
     }
 
-    fn file_begin(&mut self, _writer: &mut VgmWriter) {
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
+    fn file_begin(&mut self, writer: &mut VgmWriter) {
         // Reset state
         self.vol = [0; 16];
         self.key = [false; 16];
         self.per = [false; 16];
         self.mru_sam = -1;
+        if let Some(pan) = self.start_pan {
+            for ch in 0..16 {
+                self.qs_write(ch | 0x80, (pan + 0x0120) as u16, writer);
+            }
+        }
         // Note: Sample data blocks would be written here if sample loading was implemented
     }
 
@@ -97,22 +123,22 @@ impl SoundChip for QSound {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Sample => {
                 // type 0xFFFC = sample select (negated: ~0)
-                Some(ChipEvent::new(0xFFFC, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0xFFFC, value as i32, 0))
             }
             MacroCommand::Volume => {
                 // type 0xFFFD = volume (negated: ~1)
-                Some(ChipEvent::new(0xFFFD, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(0xFFFD, value as i32, 0))
             }
             MacroCommand::Panning => {
                 // Panning - direct register write
                 // value2 will be set based on chan_sub in send()
-                Some(ChipEvent::new(0xFFFB, value as i32 + 0x0120, 0))
+                MacroResult::Event(ChipEvent::new(0xFFFB, value as i32 + 0x0120, 0))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
@@ -0,0 +1,316 @@
+//! Sega PCM sound chip driver
+//!
+//! 16-channel 8-bit sample playback chip of Sega's arcade boards (Out Run,
+//! After Burner, ...). Samples come from `@S` macros and are packed into a
+//! sample ROM that is written to the VGM file as a data block.
+
+use super::{chip_id, ChipOptions, MacroCommand, MacroResult, Sample, SampleMemory, OptionDescription, SoundChip, CLOCK_OPTION};
+use crate::compiler::event::ChipEvent;
+use crate::vgm::header::offset;
+use crate::vgm::VgmWriter;
+
+const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('P', "pan", "Panning channels start with (-127 to +127)"),
+];
+
+/// Size of a ROM bank; a sample cannot cross a bank boundary
+const BANK_SIZE: usize = 0x10000;
+
+/// Banks addressable with the interface register below (512 KB)
+const MAX_BANKS: usize = 8;
+
+/// Interface register: bank number in bits 4-6 of the flags, 64 KB banks
+const INTERFACE: u32 = 0x0070_000C;
+
+/// Unsigned 8-bit silence, used to pad samples to a whole page
+const SILENCE: u8 = 0x80;
+
+/// Event types (direct writes use the RAM address)
+const EV_KEY_ON: u16 = 0xFF00;
+const EV_NOTE_CHANGE: u16 = 0xFF01;
+const EV_KEY_OFF: u16 = 0xFF02;
+const EV_VOLUME: u16 = 0xFF03;
+const EV_PANNING: u16 = 0xFF04;
+const EV_SAMPLE: u16 = 0xFF05;
+
+/// Where a sample lives in the ROM
+#[derive(Debug, Clone, Copy)]
+struct SampleSlot {
+    bank: u8,
+    /// Start address within the bank
+    start: u16,
+    /// Last page within the bank
+    end_page: u8,
+    /// Loop address within the bank (None plays once)
+    loop_addr: Option<u16>,
+}
+
+/// Sega PCM chip
+pub struct SegaPcm {
+    clock: i32,
+    rom: Vec<u8>,
+    slots: Vec<Option<SampleSlot>>,
+    /// Bytes each packed sample takes, by macro number
+    sizes: Vec<(usize, usize)>,
+    sample: [usize; 16],
+    vol: [i32; 16],
+    pan: [i32; 16],
+    key: [bool; 16],
+    start_pan: i32,
+}
+
+impl SegaPcm {
+    pub fn new() -> Self {
+        Self {
+            clock: 4000000,
+            rom: Vec::new(),
+            slots: Vec::new(),
+            sizes: Vec::new(),
+            sample: [0; 16],
+            vol: [127; 16],
+            pan: [0; 16],
+            key: [false; 16],
+            start_pan: 0,
+        }
+    }
+
+    fn poke(&self, address: u16, data: u8, writer: &mut VgmWriter) {
+        let _ = writer.write_data(&[0xC0, address as u8, (address >> 8) as u8, data]);
+    }
+
+    /// Write the left and right volume of a channel
+    fn write_volume(&self, ch: usize, writer: &mut VgmWriter) {
+        let pan = self.pan[ch].clamp(-127, 127);
+        let left = self.vol[ch] * (127 - pan.max(0)) / 127;
+        let right = self.vol[ch] * (127 + pan.min(0)) / 127;
+        let base = (ch * 8) as u16;
+        self.poke(base + 2, left as u8 & 0x7F, writer);
+        self.poke(base + 3, right as u8 & 0x7F, writer);
+    }
+
+    /// Pack the samples into 64 KB banks, padding each to a whole page
+    ///
+    /// The ROM may grow past `MAX_BANKS`; the compiler checks
+    /// `sample_memory` before the file is written.
+    fn build_rom(&mut self, samples: &[Option<Sample>]) {
+        self.rom.clear();
+        self.sizes.clear();
+        self.slots = vec![None; samples.len()];
+        for (number, (slot, sample)) in self.slots.iter_mut().zip(samples).enumerate() {
+            let Some(sample) = sample.as_ref().filter(|s| !s.data.is_empty()) else {
+                continue;
+            };
+            let data = &sample.data[..sample.data.len().min(BANK_SIZE)];
+            let padded = data.len().next_multiple_of(256);
+            if self.rom.len() % BANK_SIZE + padded > BANK_SIZE {
+                self.rom.resize(self.rom.len().next_multiple_of(BANK_SIZE), SILENCE);
+            }
+            self.sizes.push((number, padded));
+
+            let (bank, start) = (self.rom.len() / BANK_SIZE, self.rom.len() % BANK_SIZE);
+            let loop_start = sample.loop_start.filter(|&l| l < data.len());
+            self.rom.extend_from_slice(data);
+            // Looped samples continue their loop into the padding
+            for i in 0..padded - data.len() {
+                let byte = loop_start.map_or(SILENCE, |l| data[l + i % (data.len() - l)]);
+                self.rom.push(byte);
+            }
+            *slot = Some(SampleSlot {
+                bank: bank as u8,
+                start: start as u16,
+                end_page: ((start + padded - 1) >> 8) as u8,
+                loop_addr: loop_start.map(|l| (start + l) as u16),
+            });
+        }
+    }
+
+    fn key_on(&mut self, ch: usize, delta: i32, writer: &mut VgmWriter) {
+        let Some(slot) = self.slots.get(self.sample[ch]).copied().flatten() else {
+            return;
+        };
+        let base = (ch * 8) as u16;
+        self.write_volume(ch, writer);
+        self.poke(base + 7, delta.clamp(0, 255) as u8, writer);
+        let loop_addr = slot.loop_addr.unwrap_or(slot.start);
+        self.poke(base + 4, loop_addr as u8, writer);
+        self.poke(base + 5, (loop_addr >> 8) as u8, writer);
+        self.poke(base + 6, slot.end_page, writer);
+        self.poke(0x80 + base + 4, slot.start as u8, writer);
+        self.poke(0x80 + base + 5, (slot.start >> 8) as u8, writer);
+        let no_loop = if slot.loop_addr.is_some() { 0 } else { 2 };
+        self.poke(0x80 + base + 6, slot.bank << 4 | no_loop, writer);
+        self.key[ch] = true;
+    }
+}
+
+impl Default for SegaPcm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoundChip for SegaPcm {
+    fn name(&self) -> &'static str {
+        "SegaPCM"
+    }
+
+    fn chip_id(&self) -> u8 {
+        chip_id::SEGA_PCM
+    }
+
+    fn clock_div(&self) -> i32 {
+        self.clock
+    }
+
+    fn note_bits(&self) -> i32 {
+        8
+    }
+
+    fn basic_octave(&self) -> i32 {
+        6
+    }
+
+    fn group_sizes(&self) -> &'static [usize] {
+        &[16]
+    }
+
+    fn max_volume(&self) -> i16 {
+        127
+    }
+
+    fn enable(&mut self, options: &ChipOptions) {
+        self.clock = options.get('H');
+        if self.clock == 0 {
+            self.clock = 4000000;
+        }
+        self.start_pan = options.get('P').clamp(-127, 127);
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
+    fn uses_samples(&self) -> bool {
+        true
+    }
+
+    fn set_samples(&mut self, samples: &[Option<Sample>]) {
+        self.build_rom(samples);
+    }
+
+    fn sample_memory(&self) -> Option<SampleMemory> {
+        Some(SampleMemory {
+            capacity: MAX_BANKS * BANK_SIZE,
+            used: self.rom.len(),
+            samples: self.sizes.clone(),
+        })
+    }
+
+    fn file_begin(&mut self, writer: &mut VgmWriter) {
+        if !self.rom.is_empty() {
+            // Data block type 0x80: total ROM size, start address, then data
+            let size = self.rom.len().next_power_of_two().max(BANK_SIZE) as u32;
+            let _ = writer.write_data(&[0x67, 0x66, 0x80]);
+            let _ = writer.write_data(&(self.rom.len() as u32 + 8).to_le_bytes());
+            let _ = writer.write_data(&size.to_le_bytes());
+            let _ = writer.write_data(&0u32.to_le_bytes());
+            let _ = writer.write_data(&self.rom);
+        }
+        // All channels start keyed off
+        for ch in 0..16u16 {
+            self.poke(0x80 + ch * 8 + 6, 1, writer);
+        }
+        self.sample = [0; 16];
+        self.vol = [127; 16];
+        self.pan = [self.start_pan; 16];
+        self.key = [false; 16];
+    }
+
+    fn file_end(&mut self, writer: &mut VgmWriter) {
+        let header = writer.header_mut();
+        header.write_u32(offset::SEGA_PCM_CLOCK, self.clock as u32);
+        header.write_u32(offset::SEGA_PCM_INTERFACE, INTERFACE);
+    }
+
+    fn loop_start(&mut self, _writer: &mut VgmWriter) {}
+
+    fn start_channel(&mut self, _channel: usize) {}
+
+    fn set_macro(
+        &mut self,
+        _channel: usize,
+        _is_dynamic: bool,
+        command: MacroCommand,
+        value: i16,
+    ) -> MacroResult {
+        match command {
+            MacroCommand::Volume => MacroResult::Event(ChipEvent::new(EV_VOLUME, value as i32, 0)),
+            MacroCommand::Panning => MacroResult::Event(ChipEvent::new(EV_PANNING, value as i32, 0)),
+            MacroCommand::Sample => MacroResult::Event(ChipEvent::new(EV_SAMPLE, value as i32, 0)),
+            _ => MacroResult::Unsupported,
+        }
+    }
+
+    fn note_on(
+        &mut self,
+        _channel: usize,
+        note: i32,
+        _octave: i32,
+        _duration: i32,
+    ) -> Option<ChipEvent> {
+        Some(ChipEvent::new(EV_KEY_ON, note, 0))
+    }
+
+    fn note_change(&mut self, _channel: usize, note: i32, _octave: i32) -> Option<ChipEvent> {
+        Some(ChipEvent::new(EV_NOTE_CHANGE, note, 0))
+    }
+
+    fn note_off(&mut self, _channel: usize, _note: i32, _octave: i32) -> Option<ChipEvent> {
+        Some(ChipEvent::new(EV_KEY_OFF, 0, 0))
+    }
+
+    fn rest(&mut self, _channel: usize, _duration: i32) -> Option<ChipEvent> {
+        Some(ChipEvent::new(EV_KEY_OFF, 0, 0))
+    }
+
+    fn direct(&mut self, _channel: usize, address: u16, value: u8) -> Option<ChipEvent> {
+        Some(ChipEvent::new(address & 0x7FF, value as i32, 0))
+    }
+
+    fn send(&mut self, event: &ChipEvent, _channel: usize, _chip_sub: usize, chan_sub: usize, writer: &mut VgmWriter) {
+        let ch = chan_sub & 15;
+        let base = (ch * 8) as u16;
+
+        match event.event_type {
+            EV_KEY_ON => self.key_on(ch, event.value1, writer),
+            EV_NOTE_CHANGE => {
+                if self.key[ch] {
+                    self.poke(base + 7, event.value1.clamp(0, 255) as u8, writer);
+                }
+            }
+            EV_KEY_OFF => {
+                if self.key[ch] {
+                    let bank = self.slots.get(self.sample[ch]).copied().flatten().map_or(0, |s| s.bank);
+                    self.poke(0x80 + base + 6, bank << 4 | 3, writer);
+                }
+                self.key[ch] = false;
+            }
+            EV_VOLUME | EV_PANNING => {
+                if event.event_type == EV_VOLUME {
+                    self.vol[ch] = event.value1.clamp(0, 127);
+                } else {
+                    self.pan[ch] = event.value1;
+                }
+                if self.key[ch] {
+                    self.write_volume(ch, writer);
+                }
+            }
+            EV_SAMPLE => self.sample[ch] = (event.value1 & 255) as usize,
+            _ => {
+                // Direct RAM write
+                self.poke(event.event_type, event.value1 as u8, writer);
+            }
+        }
+    }
+}
@@ -1,10 +1,21 @@
 //! SN76489 (PSG) sound chip driver
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, second_half, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip, CLOCK_OPTION};
 use crate::compiler::event::ChipEvent;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
 
+/// Options shared with the T6W28, which has the same noise generator
+pub(super) const OPTIONS: &[OptionDescription] = &[
+    CLOCK_OPTION,
+    OptionDescription::new('F', "feedback", "Noise feedback pattern (9=SMS2/GG/MD, 3=SC-3000/BBC, 6=SN76494)"),
+    OptionDescription::new('S', "shift-width", "Noise shift register width (16=SMS2/GG/MD, 15=SC-3000/BBC)"),
+    OptionDescription::new('d', "divider", "Enable the /8 clock divider"),
+    OptionDescription::new('f', "freq0-max", "Frequency 0 is 0x400"),
+    OptionDescription::new('n', "negate", "Negate the output"),
+    OptionDescription::new('s', "stereo", "Enable Game Gear stereo"),
+];
+
 /// SN76489 PSG chip
 pub struct Sn76489 {
     clock: i32,
@@ -74,6 +85,18 @@ impl SoundChip for Sn76489 {
         0
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[6, 2]
+    }
+
+    fn second_chip_channel(&self, chip_sub: usize, chan_sub: usize) -> Option<usize> {
+        second_half(self.group_sizes(), chip_sub, chan_sub)
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        32.0
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
@@ -93,6 +116,10 @@ impl SoundChip for Sn76489 {
         self.flag_d = options.get('d') == 0; // inverted in original
     }
 
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        OPTIONS
+    }
+
     fn file_begin(&mut self, _writer: &mut VgmWriter) {
         // Reset state
         for i in 0..2 {
@@ -140,11 +167,11 @@ impl SoundChip for Sn76489 {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
-            MacroCommand::Volume => Some(ChipEvent::new(2, value as i32, 0)),
-            MacroCommand::Panning => Some(ChipEvent::new(1, value as i32, 0)),
-            _ => None,
+            MacroCommand::Volume => MacroResult::Event(ChipEvent::new(2, value as i32, 0)),
+            MacroCommand::Panning => MacroResult::Event(ChipEvent::new(1, value as i32, 0)),
+            _ => MacroResult::Unsupported,
         }
     }
 
@@ -214,7 +241,7 @@ impl SoundChip for Sn76489 {
                 }
                 if x != self.stereo[c] {
                     let stereo_cmd = if c > 0 { 0x3F } else { 0x4F };
-                    let _ = writer.write_data(&[stereo_cmd, event.value1 as u8]);
+                    let _ = writer.write_data(&[stereo_cmd, x]);
                     self.stereo[c] = x;
                     self.ltone[c] = -1;
                 }
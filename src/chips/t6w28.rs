@@ -3,7 +3,7 @@
 //! Similar to SN76489 but with stereo output (separate L/R channels)
 //! Used in Neo Geo Pocket
 
-use super::{chip_id, ChipOptions, MacroCommand, SoundChip};
+use super::{chip_id, ChipOptions, MacroCommand, MacroResult, OptionDescription, SoundChip};
 use crate::compiler::event::ChipEvent;
 use crate::vgm::header::offset;
 use crate::vgm::VgmWriter;
@@ -19,6 +19,7 @@ pub struct T6w28 {
     tone: [i32; 4],       // Tone period per channel
     noteon: [bool; 4],    // Key state per channel
     noise: i32,           // Noise mode
+    noise_default: Option<i32>, // Noise mode before the first `@` (`N:n`)
 }
 
 impl T6w28 {
@@ -33,6 +34,7 @@ impl T6w28 {
             tone: [0; 4],
             noteon: [false; 4],
             noise: -1,
+            noise_default: None,
         }
     }
 }
@@ -64,6 +66,14 @@ impl SoundChip for T6w28 {
         0
     }
 
+    fn group_sizes(&self) -> &'static [usize] {
+        &[3, 1]
+    }
+
+    fn pitch_divider(&self) -> f64 {
+        32.0
+    }
+
     fn enable(&mut self, options: &ChipOptions) {
         self.clock = options.get('H');
         if self.clock == 0 {
@@ -87,6 +97,11 @@ impl SoundChip for T6w28 {
             | ((opt_neg_lfsr as u8) << 1)
             | ((opt_sw_neg as u8) << 2)
             | ((opt_disable_freq_reg3 as u8) << 3);
+        self.noise_default = options.noise;
+    }
+
+    fn option_descriptions(&self) -> &'static [OptionDescription] {
+        super::sn76489::OPTIONS
     }
 
     fn file_begin(&mut self, _writer: &mut VgmWriter) {
@@ -117,21 +132,21 @@ impl SoundChip for T6w28 {
         _is_dynamic: bool,
         command: MacroCommand,
         value: i16,
-    ) -> Option<ChipEvent> {
+    ) -> MacroResult {
         match command {
             MacroCommand::Tone => {
                 // type 5 = noise mode
-                Some(ChipEvent::new(5, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(5, value as i32, 0))
             }
             MacroCommand::Volume => {
                 // type 2 = volume
-                Some(ChipEvent::new(2, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(2, value as i32, 0))
             }
             MacroCommand::Panning => {
                 // type 1 = stereo/panning
-                Some(ChipEvent::new(1, value as i32, 0))
+                MacroResult::Event(ChipEvent::new(1, value as i32, 0))
             }
-            _ => None,
+            _ => MacroResult::Unsupported,
         }
     }
 
@@ -192,6 +207,10 @@ impl SoundChip for T6w28 {
             }
             3 => {
                 // Note on/change
+                if let (true, -1, Some(mode)) = (a != 0, self.noise, self.noise_default) {
+                    self.noise = mode;
+                    let _ = writer.write_data(&[0x30, 0xE3 | ((mode << 2) as u8)]);
+                }
                 if self.tone[c] != v {
                     self.tone[c] = v;
                     let cmd = if a != 0 { 0x30 } else { 0x50 };
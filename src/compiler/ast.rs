@@ -0,0 +1,439 @@
+//! Structured song description
+//!
+//! Builds songs in Rust code instead of MML text. A [`Song`] renders itself
+//! as MML (its `Display` output), and `Compiler::compile_song` compiles that
+//! rendering, so a song built here behaves exactly like the same song typed
+//! by hand. [`Command::Mml`] and [`Directive::Other`] cover anything without
+//! a dedicated variant.
+//!
+//! Text is written without escaping, so [`Song::validate`] rejects a
+//! newline in any field, which would end the line early, and a `"` in any
+//! field but the raw MML of those two, which would end a quoted name.
+
+use std::fmt;
+
+/// A whole song: global directives, macro envelopes and tracks
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Song {
+    pub directives: Vec<Directive>,
+    pub envelopes: Vec<Envelope>,
+    pub tracks: Vec<Track>,
+}
+
+impl Song {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a global directive
+    pub fn directive(mut self, directive: Directive) -> Self {
+        self.directives.push(directive);
+        self
+    }
+
+    /// Add a macro envelope definition
+    pub fn envelope(mut self, envelope: Envelope) -> Self {
+        self.envelopes.push(envelope);
+        self
+    }
+
+    /// Add a track
+    pub fn track(mut self, track: Track) -> Self {
+        self.tracks.push(track);
+        self
+    }
+
+    /// Check that every text field renders as the MML it stands for,
+    /// returning a message naming the first one that does not
+    pub fn validate(&self) -> Result<(), String> {
+        for directive in &self.directives {
+            match directive {
+                Directive::Title(text) => check("#TITLE", text, false)?,
+                Directive::Composer(text) => check("#COMPOSER", text, false)?,
+                Directive::Chip { chip, channels, options } => {
+                    for text in [chip, channels, options] {
+                        check("#EX- chip", text, false)?;
+                    }
+                }
+                Directive::Rate(_) => {}
+                Directive::Other { name, args } => {
+                    check("directive name", name, false)?;
+                    check(&format!("#{}", name), args, true)?;
+                }
+            }
+        }
+        for envelope in &self.envelopes {
+            check("envelope kind", &envelope.kind, false)?;
+            if let Some(text) = &envelope.text {
+                check(&format!("{}{}", envelope.kind, envelope.id), text, false)?;
+            }
+        }
+        for track in &self.tracks {
+            check("track channels", &track.channels, false)?;
+            check_commands(&track.channels, &track.commands)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the text of a field: no newline, and no `"` unless it is raw MML
+fn check(field: &str, text: &str, raw: bool) -> Result<(), String> {
+    if text.contains(['\n', '\r']) {
+        return Err(format!("{} {:?} has a line break", field, text));
+    }
+    if !raw && text.contains('"') {
+        return Err(format!("{} {:?} has a `\"`", field, text));
+    }
+    Ok(())
+}
+
+fn check_commands(channels: &str, commands: &[Command]) -> Result<(), String> {
+    for command in commands {
+        match command {
+            Command::Repeat { commands, .. } | Command::Tuplet(commands) => check_commands(channels, commands)?,
+            Command::Macro { name, .. } => check(&format!("macro command of track {}", channels), name, false)?,
+            Command::Mml(text) => check(&format!("MML of track {}", channels), text, true)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Song {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for directive in &self.directives {
+            writeln!(f, "{}", directive)?;
+        }
+        for envelope in &self.envelopes {
+            writeln!(f, "{}", envelope)?;
+        }
+        for track in &self.tracks {
+            writeln!(f, "{}", track)?;
+        }
+        Ok(())
+    }
+}
+
+/// A global `#` directive
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive {
+    /// `#TITLE`
+    Title(String),
+    /// `#COMPOSER`
+    Composer(String),
+    /// `#EX-chip channels options`: enable a sound chip on channels
+    Chip { chip: String, channels: String, options: String },
+    /// `#RATE`
+    Rate(i32),
+    /// Any other directive, by name (without `#`) and arguments
+    Other { name: String, args: String },
+}
+
+impl Directive {
+    /// `#EX-chip channels` without options
+    pub fn chip(chip: &str, channels: &str) -> Self {
+        Self::Chip {
+            chip: chip.to_string(),
+            channels: channels.to_string(),
+            options: String::new(),
+        }
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Title(text) => write!(f, "#TITLE {}", text),
+            Self::Composer(text) => write!(f, "#COMPOSER {}", text),
+            Self::Chip { chip, channels, options } if options.is_empty() => write!(f, "#EX-{} {}", chip, channels),
+            Self::Chip { chip, channels, options } => write!(f, "#EX-{} {} {}", chip, channels, options),
+            Self::Rate(rate) => write!(f, "#RATE {}", rate),
+            Self::Other { name, args } => write!(f, "#{} {}", name, args),
+        }
+    }
+}
+
+/// A macro envelope definition, e.g. `@v0 = { 15 12 | 10 }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    /// Macro name, e.g. `@v`, `@EN` or `@S`
+    pub kind: String,
+    /// Macro number (0-255)
+    pub id: u8,
+    /// Name or file name (for sample macros)
+    pub text: Option<String>,
+    /// Values in order
+    pub values: Vec<i64>,
+    /// Index of the value the envelope loops back to (`|`)
+    pub loop_at: Option<usize>,
+}
+
+impl Envelope {
+    pub fn new(kind: &str, id: u8, values: Vec<i64>) -> Self {
+        Self {
+            kind: kind.to_string(),
+            id,
+            text: None,
+            values,
+            loop_at: None,
+        }
+    }
+
+    /// Loop back to a value at the end of the envelope
+    pub fn loop_at(mut self, index: usize) -> Self {
+        self.loop_at = Some(index);
+        self
+    }
+}
+
+impl fmt::Display for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{} = {{", self.kind, self.id)?;
+        if let Some(text) = &self.text {
+            write!(f, " \"{}\"", text)?;
+        }
+        for (i, value) in self.values.iter().enumerate() {
+            if self.loop_at == Some(i) {
+                write!(f, " |")?;
+            }
+            write!(f, " {}", value)?;
+        }
+        write!(f, " }}")
+    }
+}
+
+/// The commands played on one or more channels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    /// Channel letters, e.g. `A` or `ABC`
+    pub channels: String,
+    pub commands: Vec<Command>,
+}
+
+impl Track {
+    pub fn new(channels: &str) -> Self {
+        Self {
+            channels: channels.to_string(),
+            commands: Vec::new(),
+        }
+    }
+
+    /// Add a command
+    pub fn push(mut self, command: Command) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Add several commands
+    pub fn extend(mut self, commands: impl IntoIterator<Item = Command>) -> Self {
+        self.commands.extend(commands);
+        self
+    }
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.channels)?;
+        for command in &self.commands {
+            write!(f, " {}", command)?;
+        }
+        Ok(())
+    }
+}
+
+/// Note length: note value (4 = quarter) and dots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Length {
+    pub value: u32,
+    pub dots: u32,
+}
+
+impl Length {
+    pub fn dotted(value: u32, dots: u32) -> Self {
+        Self { value, dots }
+    }
+}
+
+impl From<u32> for Length {
+    fn from(value: u32) -> Self {
+        Self { value, dots: 0 }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.value, ".".repeat(self.dots as usize))
+    }
+}
+
+/// Optional length written after a note, rest or tie
+struct OptLength(Option<Length>);
+
+impl fmt::Display for OptLength {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(length) => write!(f, "{}", length),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A note: scale letter, sharps (positive) or flats (negative), length and
+/// bend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    /// Scale letter `a`-`j`
+    pub letter: char,
+    pub accidental: i32,
+    /// Length (None uses the default length)
+    pub length: Option<Length>,
+    /// Bend in cents (`~`)
+    pub bend: i32,
+}
+
+impl Note {
+    pub fn new(letter: char) -> Self {
+        Self {
+            letter,
+            accidental: 0,
+            length: None,
+            bend: 0,
+        }
+    }
+
+    pub fn sharp(mut self) -> Self {
+        self.accidental += 1;
+        self
+    }
+
+    pub fn flat(mut self) -> Self {
+        self.accidental -= 1;
+        self
+    }
+
+    pub fn length(mut self, length: impl Into<Length>) -> Self {
+        self.length = Some(length.into());
+        self
+    }
+
+    pub fn bend(mut self, cents: i32) -> Self {
+        self.bend = cents;
+        self
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let accidental = if self.accidental < 0 { "-" } else { "+" };
+        let accidentals = accidental.repeat(self.accidental.unsigned_abs() as usize);
+        write!(f, "{}{}{}", self.letter, accidentals, OptLength(self.length))?;
+        if self.bend != 0 {
+            write!(f, "~{:+}", self.bend)?;
+        }
+        Ok(())
+    }
+}
+
+/// A channel command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Note(Note),
+    /// `r`
+    Rest(Option<Length>),
+    /// `^`: extend the previous note
+    Tie(Option<Length>),
+    /// `&`: join the previous note to the next
+    Slur,
+    /// `o`
+    Octave(i32),
+    /// `>`
+    OctaveUp,
+    /// `<`
+    OctaveDown,
+    /// `l`
+    DefaultLength(Length),
+    /// `t`
+    Tempo(i32),
+    /// `v`
+    Volume(i32),
+    /// `P`
+    Panning(i32),
+    /// `@`
+    Tone(i32),
+    /// `D`
+    Detune(i32),
+    /// `K`
+    Transpose(i32),
+    /// `L`: song loop point
+    LoopPoint,
+    /// `[ ... ]N`: play the commands `count` times
+    Repeat { commands: Vec<Command>, count: u32 },
+    /// `{ ... }`: play the commands at 2/3 length
+    Tuplet(Vec<Command>),
+    /// Macro command with a value, e.g. `@v` or `@EN` (name as in MML)
+    Macro { name: String, value: i64 },
+    /// `x address,value`: direct register write
+    Direct { address: u32, value: u8 },
+    /// MML text, written as is
+    Mml(String),
+}
+
+impl Command {
+    /// Note with a length
+    pub fn note(letter: char, length: u32) -> Self {
+        Self::Note(Note::new(letter).length(length))
+    }
+
+    /// Macro command by MML name
+    pub fn macro_command(name: &str, value: i64) -> Self {
+        Self::Macro { name: name.to_string(), value }
+    }
+}
+
+impl From<Note> for Command {
+    fn from(note: Note) -> Self {
+        Self::Note(note)
+    }
+}
+
+/// Commands separated by spaces
+struct Commands<'a>(&'a [Command]);
+
+impl fmt::Display for Commands<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, command) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", command)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Note(note) => write!(f, "{}", note),
+            Self::Rest(length) => write!(f, "r{}", OptLength(*length)),
+            Self::Tie(length) => write!(f, "^{}", OptLength(*length)),
+            Self::Slur => write!(f, "&"),
+            Self::Octave(octave) => write!(f, "o{}", octave),
+            Self::OctaveUp => write!(f, ">"),
+            Self::OctaveDown => write!(f, "<"),
+            Self::DefaultLength(length) => write!(f, "l{}", length),
+            Self::Tempo(tempo) => write!(f, "t{}", tempo),
+            Self::Volume(volume) => write!(f, "v{}", volume),
+            Self::Panning(pan) => write!(f, "P{}", pan),
+            Self::Tone(tone) => write!(f, "@{}", tone),
+            Self::Detune(detune) => write!(f, "D{}", detune),
+            Self::Transpose(transpose) => write!(f, "K{}", transpose),
+            Self::LoopPoint => write!(f, "L"),
+            Self::Repeat { commands, count } => write!(f, "[{}]{}", Commands(commands), count),
+            Self::Tuplet(commands) => write!(f, "{{{}}}", Commands(commands)),
+            Self::Macro { name, value } => write!(f, "{}{}", name, value),
+            Self::Direct { address, value } => write!(f, "x{},{}", address, value),
+            Self::Mml(text) => write!(f, "{}", text),
+        }
+    }
+}
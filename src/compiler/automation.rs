@@ -0,0 +1,111 @@
+//! Time-based automation lanes (`#AUTOMATE`)
+//!
+//! A lane sets a macro value of channels at absolute song times,
+//! independent of their notes: `#AUTOMATE C volume 0:00=15 0:08=8 0:12=15`
+//! fades channel C down over eight seconds and back up over four. Values
+//! between points change linearly, one step per frame, and the last value
+//! holds until something else changes it.
+
+use crate::chips::MacroCommand;
+
+/// Samples per second in song time
+const SAMPLE_RATE: f64 = 44100.0;
+
+/// A point of a lane: time in samples and value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Point {
+    pub time: i64,
+    pub value: i16,
+}
+
+/// An automation lane
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lane {
+    /// Channel letters the lane plays on
+    pub channels: String,
+    /// Macro the lane sets
+    pub command: MacroCommand,
+    /// Points in time order
+    pub points: Vec<Point>,
+}
+
+/// Macro set by a lane parameter name (long name or MML command)
+fn parameter(name: &str) -> Option<MacroCommand> {
+    Some(match name {
+        "volume" | "v" => MacroCommand::Volume,
+        "panning" | "pan" | "P" => MacroCommand::Panning,
+        "tone" | "@" => MacroCommand::Tone,
+        "global" | "@G" => MacroCommand::Global,
+        "multiply" | "M" => MacroCommand::Multiply,
+        _ => return None,
+    })
+}
+
+/// Parse a time as `m:ss.fff` or seconds, into samples
+fn parse_time(text: &str) -> Option<i64> {
+    let (minutes, seconds) = match text.split_once(':') {
+        Some((m, s)) => (m.parse::<u32>().ok()?, s),
+        None => (0, text),
+    };
+    let seconds: f64 = seconds.parse().ok().filter(|s: &f64| *s >= 0.0)?;
+    Some(((minutes as f64 * 60.0 + seconds) * SAMPLE_RATE).round() as i64)
+}
+
+/// Parse the parameters of `#AUTOMATE channels parameter time=value ...`
+pub fn parse(params: &str) -> Result<Lane, String> {
+    let mut words = params.split_whitespace();
+    let channels = words.next().unwrap_or("").to_string();
+    if channels.is_empty() {
+        return Err("expected channels, a parameter and time=value points".to_string());
+    }
+    let name = words.next().unwrap_or("");
+    let command = parameter(name).ok_or_else(|| {
+        format!("unknown parameter '{}' (volume, panning, tone, global or multiply)", name)
+    })?;
+
+    let mut points = Vec::new();
+    for word in words {
+        let point = word.split_once('=').and_then(|(time, value)| {
+            Some(Point {
+                time: parse_time(time)?,
+                value: value.parse().ok()?,
+            })
+        });
+        match point {
+            Some(point) => points.push(point),
+            None => return Err(format!("expected time=value, got '{}'", word)),
+        }
+    }
+    if points.is_empty() {
+        return Err("no time=value points".to_string());
+    }
+    points.sort_by_key(|p| p.time);
+    Ok(Lane { channels, command, points })
+}
+
+impl Lane {
+    /// The values to set and when: each point, and a step per frame
+    /// between points wherever the interpolated value changes
+    pub fn steps(&self, frame: i64) -> Vec<(i64, i16)> {
+        let frame = frame.max(1);
+        let mut steps: Vec<(i64, i16)> = Vec::new();
+        for pair in self.points.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            let span = to.time - from.time;
+            let mut time = from.time;
+            while time < to.time {
+                let delta = (to.value as i64 - from.value as i64) * (time - from.time);
+                let value = from.value as i64 + (delta as f64 / span as f64).round() as i64;
+                if steps.last().map(|&(_, v)| v as i64) != Some(value) {
+                    steps.push((time, value as i16));
+                }
+                time += frame;
+            }
+        }
+        let last = self.points[self.points.len() - 1];
+        if steps.last().map(|&(_, v)| v) != Some(last.value) {
+            steps.push((last.time, last.value));
+        }
+        steps
+    }
+}
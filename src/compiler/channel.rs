@@ -1,5 +1,7 @@
 //! Channel state management
 
+use std::path::PathBuf;
+
 /// Channel definition and state
 #[derive(Debug, Clone)]
 pub struct Channel {
@@ -15,6 +17,36 @@ pub struct Channel {
     pub loop_point: i64,
     /// Total duration in samples
     pub duration: i64,
+    /// Channel this one was duplicated from (for `?X` / `@[ ]` matching)
+    pub source: Option<usize>,
+    /// Panning forced onto every `P` command (used by #PSG-WIDEN)
+    pub pan_override: Option<i16>,
+    /// Extra detune added on top of the `D` command
+    pub detune_offset: i64,
+    /// For the echo of a channel (`@echo`): volume steps it is quieter by
+    /// and samples it plays behind
+    pub echo: Option<(i16, i64)>,
+    /// For the copy of a `#WIDE` channel on the second chip: chip units its
+    /// pitch alternates either side of the note by, every frame
+    pub wide: Option<i64>,
+    /// Source line of each text segment, in order
+    pub lines: Vec<LineSegment>,
+}
+
+/// Where a segment of channel text came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineSegment {
+    /// Offset of the segment in the channel text
+    pub offset: usize,
+    /// Source line number
+    pub line: usize,
+    /// Source file (None for standard input)
+    pub file: Option<PathBuf>,
+    /// Source column (1-based) of the segment start, if the segment is a
+    /// verbatim copy of the source line (no text macros)
+    pub column: Option<usize>,
+    /// The source line, when `column` is known
+    pub source: Option<String>,
 }
 
 impl Channel {
@@ -26,12 +58,49 @@ impl Channel {
             text: String::new(),
             loop_point: -1,
             duration: 0,
+            source: None,
+            pan_override: None,
+            detune_offset: 0,
+            echo: None,
+            wide: None,
+            lines: Vec::new(),
         }
     }
 
     pub fn append_text(&mut self, text: &str) {
         self.text.push_str(text);
     }
+
+    /// Whether the channel replays text already compiled on its source
+    /// channel (an `@echo` or `#WIDE` copy)
+    pub fn is_replay(&self) -> bool {
+        self.echo.is_some() || self.wide.is_some()
+    }
+}
+
+/// Starting settings of a channel, from `#DEFAULT`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelDefaults {
+    /// Octave (`o`)
+    pub octave: Option<i32>,
+    /// Default note length (`l`), as note value and dots
+    pub length: Option<(i32, i32)>,
+    /// Quantize in frames (`q`)
+    pub quantize: Option<i64>,
+    /// Tempo (`t`)
+    pub tempo: Option<i32>,
+}
+
+impl ChannelDefaults {
+    /// Settings of `self`, falling back to `other` where unset
+    pub fn or(self, other: ChannelDefaults) -> Self {
+        Self {
+            octave: self.octave.or(other.octave),
+            length: self.length.or(other.length),
+            quantize: self.quantize.or(other.quantize),
+            tempo: self.tempo.or(other.tempo),
+        }
+    }
 }
 
 /// Channel state during compilation
@@ -0,0 +1,161 @@
+//! Chords split across a channel group (`#GROUP`)
+//!
+//! `#GROUP X = ABC` plays the lines of channel X on A, B and C. A chord
+//! written between quotes, `'ceg'4`, gives its first note to A, the second
+//! to B and the third to C, and a channel left without a note rests for
+//! the chord's length, so no voice holds over into the next chord. The rest
+//! of the line plays on every channel. `<` and `>` in a chord move the notes
+//! after them, and the octave is back where it was once the chord ends.
+
+/// A note of a chord, with the octave moves written before it
+#[derive(Debug, Default)]
+struct Note {
+    moves: String,
+    name: String,
+}
+
+impl Note {
+    /// The octave moves that undo the ones before the note
+    fn undo(&self) -> String {
+        self.moves.chars().rev().map(|c| if c == '<' { '>' } else { '<' }).collect()
+    }
+}
+
+/// Parse `#GROUP X = channels` into the group letter and its channel letters
+pub fn parse_group(params: &str) -> Result<(char, Vec<char>), String> {
+    let (name, channels) = params.split_once('=').unwrap_or((params, ""));
+    let mut name = name.trim().chars();
+    let (Some(name), None) = (name.next(), name.next()) else {
+        return Err("expected a channel letter, '=' and channels, such as X = ABC".to_string());
+    };
+    let channels: Vec<char> = channels.chars().filter(|c| !c.is_whitespace()).collect();
+    if channels.is_empty() {
+        return Err("no channels".to_string());
+    }
+    if let Some((i, &c)) = channels.iter().enumerate().find(|&(i, c)| channels[..i].contains(c)) {
+        return Err(format!("channel {} is given twice (as voice {})", c, i + 1));
+    }
+    Ok((name, channels))
+}
+
+/// Split the chords of channel text between `count` voices, returning the
+/// text of each voice and what was wrong with the chords
+pub fn split(text: &str, count: usize) -> (Vec<String>, Vec<String>) {
+    let mut voices = vec![String::new(); count];
+    let mut problems = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    // A quote right after a note raises its octave instead of opening a chord
+    let mut after_note = false;
+
+    while let Some((start, c)) = chars.next() {
+        match c {
+            // Comments and lyrics are kept as written
+            ';' => {
+                for voice in voices.iter_mut() {
+                    voice.push_str(&text[start..]);
+                }
+                break;
+            }
+            '"' => {
+                let end = text[start + 1..].find('"').map_or(text.len(), |i| start + i + 2);
+                for voice in voices.iter_mut() {
+                    voice.push_str(&text[start..end]);
+                }
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                after_note = false;
+                continue;
+            }
+            '\'' if !after_note => {
+                let mut notes: Vec<Note> = Vec::new();
+                let mut moves = String::new();
+                let mut closed = false;
+                for (_, c) in chars.by_ref() {
+                    match c {
+                        '\'' => {
+                            closed = true;
+                            break;
+                        }
+                        'a'..='j' => notes.push(Note {
+                            moves: moves.clone(),
+                            name: c.to_string(),
+                        }),
+                        '+' | '-' if !notes.is_empty() => notes.last_mut().unwrap().name.push(c),
+                        '<' | '>' => moves.push(c),
+                        c if c.is_whitespace() => {}
+                        _ => problems.push(format!("'{}' in a chord (only notes, '<' and '>' are)", c)),
+                    }
+                }
+                let mut length = String::new();
+                while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit() || c == '.') {
+                    length.push(c);
+                }
+                let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+                let chord = &text[start..end];
+                if !closed {
+                    problems.push(format!("chord {} is not closed with '", chord));
+                }
+                if notes.len() > count {
+                    problems.push(format!(
+                        "chord {} has {} notes for {} channels, the last {} dropped",
+                        chord,
+                        notes.len(),
+                        count,
+                        notes.len() - count
+                    ));
+                }
+                for (voice, note) in voices.iter_mut().zip(notes.iter().map(Some).chain(std::iter::repeat(None))) {
+                    match note {
+                        Some(note) => {
+                            voice.push_str(&note.moves);
+                            voice.push_str(&note.name);
+                            voice.push_str(&length);
+                            voice.push_str(&note.undo());
+                        }
+                        None => {
+                            voice.push('r');
+                            voice.push_str(&length);
+                        }
+                    }
+                }
+                after_note = false;
+                continue;
+            }
+            _ => {}
+        }
+        for voice in voices.iter_mut() {
+            voice.push(c);
+        }
+        after_note = matches!(c, 'a'..='j') || (after_note && matches!(c, '+' | '-' | '\''));
+    }
+    (voices, problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(parse_group("X = ABC"), Ok(('X', vec!['A', 'B', 'C'])));
+        assert!(parse_group("XY = ABC").is_err());
+        assert!(parse_group("X = ABA").is_err());
+        assert!(parse_group("X =").is_err());
+
+        let (voices, problems) = split("l8 'ceg'4. 'd+f'2 c' \"don't\" 'c>c<' ; 'x'", 3);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(
+            voices,
+            [
+                "l8 c4. d+2 c' \"don't\" c ; 'x'",
+                "l8 e4. f2 c' \"don't\" >c< ; 'x'",
+                "l8 g4. r2 c' \"don't\" r ; 'x'",
+            ]
+        );
+
+        let (voices, problems) = split("'cegb'4 'c", 2);
+        assert_eq!(voices, ["c4 c", "e4 r"]);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("4 notes for 2 channels"), "{:?}", problems);
+        assert!(problems[1].contains("not closed"), "{:?}", problems);
+    }
+}
@@ -0,0 +1,102 @@
+//! Conditional blocks (`#IFDEF`, `#IFNDEF`, `#ELSE`, `#ENDIF`)
+//!
+//! Names are defined with `#DEFINE NAME` or on the command line with
+//! `-D NAME`. Lines in a block whose condition does not hold are skipped
+//! entirely, directives included, so one file can hold several
+//! arrangements of a song. Blocks nest, and each file closes its own.
+
+use std::collections::BTreeSet;
+
+/// An open block
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    /// Line of the `#IFDEF` or `#IFNDEF`
+    line: usize,
+    /// Whether the enclosing blocks are reading lines
+    outer: bool,
+    /// Whether the condition holds (inverted by `#ELSE`)
+    taking: bool,
+    /// Whether `#ELSE` was seen
+    seen_else: bool,
+}
+
+/// The open blocks of a file
+#[derive(Debug, Default)]
+pub struct Conditions {
+    blocks: Vec<Block>,
+}
+
+impl Conditions {
+    /// Whether lines are read at this point
+    pub fn active(&self) -> bool {
+        self.blocks.last().is_none_or(|block| block.outer && block.taking)
+    }
+
+    /// Handle a directive if it is a conditional one: None if it is not,
+    /// else the result (an error message for a misplaced one)
+    pub fn apply(&mut self, command: &str, param: &str, line: usize, defines: &BTreeSet<String>) -> Option<Result<(), String>> {
+        let outer = self.active();
+        match command {
+            "IFDEF" | "IFNDEF" => {
+                let name = param.split_whitespace().next().unwrap_or("");
+                if name.is_empty() {
+                    return Some(Err("expected a name".to_string()));
+                }
+                let taking = defines.contains(name) == (command == "IFDEF");
+                self.blocks.push(Block { line, outer, taking, seen_else: false });
+                Some(Ok(()))
+            }
+            "ELSE" => Some(match self.blocks.last_mut() {
+                Some(block) if !block.seen_else => {
+                    block.taking = !block.taking;
+                    block.seen_else = true;
+                    Ok(())
+                }
+                Some(_) => Err("second #ELSE in the same block".to_string()),
+                None => Err("#ELSE without #IFDEF".to_string()),
+            }),
+            "ENDIF" => Some(match self.blocks.pop() {
+                Some(_) => Ok(()),
+                None => Err("#ENDIF without #IFDEF".to_string()),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Lines of the blocks left open, outermost first
+    pub fn unclosed(&self) -> Vec<usize> {
+        self.blocks.iter().map(|block| block.line).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conditions() {
+        let defines = BTreeSet::from(["SMS".to_string()]);
+        let mut conditions = Conditions::default();
+        let apply = |conditions: &mut Conditions, command: &str, param: &str| {
+            conditions.apply(command, param, 1, &defines).unwrap()
+        };
+
+        apply(&mut conditions, "IFDEF", "GENESIS").unwrap();
+        assert!(!conditions.active());
+        apply(&mut conditions, "IFDEF", "SMS").unwrap();
+        assert!(!conditions.active(), "an inner block of a skipped one is skipped");
+        apply(&mut conditions, "ELSE", "").unwrap();
+        assert!(!conditions.active());
+        apply(&mut conditions, "ENDIF", "").unwrap();
+        apply(&mut conditions, "ELSE", "").unwrap();
+        assert!(conditions.active());
+        assert!(apply(&mut conditions, "ELSE", "").is_err());
+        apply(&mut conditions, "IFNDEF", "SMS").unwrap();
+        assert!(!conditions.active());
+        assert_eq!(conditions.unclosed(), [1, 1]);
+        apply(&mut conditions, "ENDIF", "").unwrap();
+        apply(&mut conditions, "ENDIF", "").unwrap();
+        assert!(apply(&mut conditions, "ENDIF", "").is_err());
+        assert!(conditions.apply("DEFINE", "X", 1, &defines).is_none());
+    }
+}
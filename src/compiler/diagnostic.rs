@@ -0,0 +1,251 @@
+//! Compiler diagnostics
+//!
+//! Every warning is recorded as a [`Diagnostic`] with a stable code, so
+//! editor plugins and CI can match on it. Diagnostics print as text
+//! (`channel A (line 3, bar 2:1): message`, the same text as
+//! `Compiler::warnings`) or as JSON lines with `--diagnostics-format json`.
+
+use crate::error::Error;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Stable diagnostic codes
+///
+/// Codes are never reused or renumbered; `L` codes come from the channel
+/// text, other `W` codes from directives, `W0000` is any other warning and
+/// `E0000` a fatal error.
+pub mod code {
+    /// Warning without a code of its own
+    pub const WARNING: &str = "W0000";
+    /// Fatal error that stopped compilation
+    pub const ERROR: &str = "E0000";
+    /// Commands after `!` are never played
+    pub const UNREACHABLE_AFTER_STOP: &str = "L0001";
+    /// `[` without a closing `]`
+    pub const UNTERMINATED_LOOP: &str = "L0002";
+    /// `{` without a closing `}`
+    pub const UNTERMINATED_TUPLET: &str = "L0003";
+    /// `]` without an opening `[`
+    pub const UNMATCHED_LOOP_END: &str = "L0004";
+    /// `}` without an opening `{`
+    pub const UNMATCHED_TUPLET_END: &str = "L0005";
+    /// Loop with a repeat count of 0 or 1
+    pub const SINGLE_PASS_LOOP: &str = "L0006";
+    /// Conditional opened inside an open `?(..)` block
+    pub const NESTED_CONDITION: &str = "L0007";
+    /// `?(..)` without a closing `?`
+    pub const UNTERMINATED_CONDITION_GROUP: &str = "L0008";
+    /// `?X` without a closing `?.`
+    pub const UNTERMINATED_CONDITION: &str = "L0009";
+    /// Malformed number in channel text
+    pub const MALFORMED_NUMBER: &str = "L0010";
+    /// Character or command name the channel text has no meaning for
+    pub const UNKNOWN_COMMAND: &str = "L0011";
+    /// Command without an argument it needs
+    pub const MISSING_ARGUMENT: &str = "L0012";
+    /// Envelope, sample list, `#WAV` sample or `@INST` instrument used without a definition
+    pub const UNDEFINED_MACRO: &str = "L0013";
+    /// Command value outside the range the command or chip accepts
+    pub const OUT_OF_RANGE: &str = "L0014";
+    /// Macro command the channel's chip has no use for
+    pub const UNSUPPORTED_MACRO: &str = "L0015";
+    /// `~` pitch bend too small to change the chip's value for the note
+    pub const BEND_TOO_FINE: &str = "L0016";
+    /// `#` directive the compiler does not know
+    pub const UNKNOWN_DIRECTIVE: &str = "W0001";
+    /// `#TEXTn` for a GD3 field that does not exist
+    pub const UNKNOWN_GD3_FIELD: &str = "W0002";
+
+    /// Codes that stop compilation in strict mode
+    pub const STRICT: &[&str] = &[
+        UNKNOWN_DIRECTIVE,
+        UNKNOWN_GD3_FIELD,
+        UNKNOWN_COMMAND,
+        UNDEFINED_MACRO,
+        OUT_OF_RANGE,
+        MALFORMED_NUMBER,
+    ];
+}
+
+/// How serious a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// How diagnostics are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiagnosticFormat {
+    /// `Warning: message` lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for DiagnosticFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown diagnostics format '{}' (expected text or json)", s)),
+        }
+    }
+}
+
+/// Where in the MML source a diagnostic points
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SourceSpan {
+    /// Line number (1-based)
+    pub line: usize,
+    /// Column of the first byte (1-based), when the channel text maps
+    /// directly onto the source line (not through a text macro)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    /// Column after the last byte
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_column: Option<usize>,
+}
+
+/// A warning or error with its code and location
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// Channel letter, for diagnostics about channel text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<char>,
+    /// Source file (None for standard input or when unknown)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub span: Option<SourceSpan>,
+    /// Where in the music the channel text is, as `measure:beat` (`#METER`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<String>,
+    /// How to fix it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<String>,
+    /// The source line the span points into
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+impl Diagnostic {
+    /// Warning without a code or location of its own
+    pub fn warning(message: String) -> Self {
+        Self {
+            code: code::WARNING,
+            severity: Severity::Warning,
+            message,
+            channel: None,
+            file: None,
+            span: None,
+            position: None,
+            suggestion: None,
+            snippet: None,
+        }
+    }
+
+    /// Fatal error
+    pub fn error(message: String) -> Self {
+        Self {
+            code: code::ERROR,
+            severity: Severity::Error,
+            ..Self::warning(message)
+        }
+    }
+
+    /// Fatal error, with its location if it has one
+    pub fn from_error(error: &Error) -> Self {
+        match error {
+            Error::MmlSyntax { file, line, column, message } => Self {
+                file: file.clone(),
+                span: Some(SourceSpan {
+                    line: *line,
+                    column: *column,
+                    end_column: column.map(|c| c + 1),
+                }),
+                ..Self::error(message.clone())
+            },
+            _ => Self::error(error.to_string()),
+        }
+    }
+
+    /// The diagnostic as the error compilation fails with, keeping its location
+    pub fn into_error(self) -> Error {
+        let mut message = match (self.channel, &self.position) {
+            (Some(channel), Some(bar)) => format!("channel {} (bar {}): {}", channel, bar, self.message),
+            (Some(channel), None) => format!("channel {}: {}", channel, self.message),
+            (None, _) => self.message,
+        };
+        if self.code != code::ERROR {
+            message.push_str(&format!(" [{}]", self.code));
+        }
+        Error::MmlSyntax {
+            file: self.file,
+            line: self.span.map_or(0, |s| s.line),
+            column: self.span.and_then(|s| s.column),
+            message,
+        }
+    }
+
+    /// The diagnostic as one line of JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// The diagnostic as text for a terminal: the message, then the source
+    /// line with the span underlined and the suggestion, when known
+    ///
+    /// ```text
+    /// channel A (line 2, bar 1:2): unknown command `%1`
+    ///   2 | A c %1 d
+    ///     |      ^^ remove it
+    /// ```
+    pub fn render(&self) -> String {
+        let mut text = self.to_string();
+        let (Some(snippet), Some(span)) = (&self.snippet, self.span) else {
+            return text;
+        };
+        let Some(column) = span.column else {
+            return text;
+        };
+        let gutter = " ".repeat(span.line.to_string().len());
+        // Keep tabs so the underline lines up with the source
+        let indent: String = snippet
+            .get(..column - 1)
+            .unwrap_or("")
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = span.end_column.map_or(1, |end| end.saturating_sub(column).max(1));
+        text.push_str(&format!("\n  {} | {}\n  {} | {}{}", span.line, snippet, gutter, indent, "^".repeat(width)));
+        if let Some(suggestion) = &self.suggestion {
+            text.push_str(&format!(" {}", suggestion));
+        }
+        text
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.channel, self.span, &self.position) {
+            (Some(channel), Some(span), Some(bar)) => {
+                write!(f, "channel {} (line {}, bar {}): {}", channel, span.line, bar, self.message)
+            }
+            (Some(channel), Some(span), None) => write!(f, "channel {} (line {}): {}", channel, span.line, self.message),
+            (Some(channel), None, Some(bar)) => write!(f, "channel {} (bar {}): {}", channel, bar, self.message),
+            (Some(channel), None, None) => write!(f, "channel {}: {}", channel, self.message),
+            (None, Some(span), _) => write!(f, "line {}: {}", span.line, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
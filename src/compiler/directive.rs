@@ -0,0 +1,410 @@
+//! Global `#` directive table
+//!
+//! Every directive understood by the compiler is listed here together with
+//! a short description of its arguments. The parser dispatches through this
+//! table, and editors can use it for completion and documentation.
+
+use super::envelope::VolumeMode;
+use super::event::NoteOffOverlap;
+use super::meter::Meter;
+use super::tuning::Quantization;
+use super::{gd3, Compiler, DEFAULT_LOOP_LCM_CAP};
+use crate::error::Result;
+use crate::vgm::playback::MAX_FADE_SECONDS;
+
+/// Handler called with the directive suffix (for prefix directives) and parameter
+type Handler = fn(&mut Compiler, &str, &str) -> Result<()>;
+
+/// A global `#` directive
+pub struct Directive {
+    /// Name after `#` (for prefix directives, the fixed part of the name)
+    pub name: &'static str,
+    /// Argument synopsis
+    pub args: &'static str,
+    /// One-line description
+    pub description: &'static str,
+    /// The name is completed by a suffix (e.g. `#EX-PSG`, `#TEXT3`)
+    pub prefix: bool,
+    handler: Handler,
+}
+
+impl Directive {
+    const fn new(name: &'static str, args: &'static str, description: &'static str, handler: Handler) -> Self {
+        Self {
+            name,
+            args,
+            description,
+            prefix: false,
+            handler,
+        }
+    }
+
+    const fn prefix(name: &'static str, args: &'static str, description: &'static str, handler: Handler) -> Self {
+        Self {
+            name,
+            args,
+            description,
+            prefix: true,
+            handler,
+        }
+    }
+
+    /// Run the directive
+    pub(crate) fn apply(&self, compiler: &mut Compiler, suffix: &str, param: &str) -> Result<()> {
+        (self.handler)(compiler, suffix, param)
+    }
+}
+
+/// Set a GD3 field in both languages
+fn gd3_both(compiler: &mut Compiler, en: usize, jp: usize, text: &str) {
+    compiler.add_gd3(en, text);
+    compiler.add_gd3(jp, text);
+}
+
+fn num(compiler: &mut Compiler, param: &str) -> i64 {
+    let mut pos = 0;
+    compiler.read_number(param, &mut pos)
+}
+
+static DIRECTIVES: &[Directive] = &[
+    Directive::new("TITLE", "text", "Song title (English and Japanese)", |c, _, p| {
+        gd3_both(c, gd3::TITLE_EN, gd3::TITLE_JP, p);
+        Ok(())
+    }),
+    Directive::new("TITLE-E", "text", "Song title (English)", |c, _, p| {
+        c.add_gd3(gd3::TITLE_EN, p);
+        Ok(())
+    }),
+    Directive::new("TITLE-J", "text", "Song title (Japanese)", |c, _, p| {
+        c.add_gd3(gd3::TITLE_JP, p);
+        Ok(())
+    }),
+    Directive::new("GAME", "text", "Game name (English and Japanese)", |c, _, p| {
+        gd3_both(c, gd3::GAME_EN, gd3::GAME_JP, p);
+        Ok(())
+    }),
+    Directive::new("GAME-E", "text", "Game name (English)", |c, _, p| {
+        c.add_gd3(gd3::GAME_EN, p);
+        Ok(())
+    }),
+    Directive::new("GAME-J", "text", "Game name (Japanese)", |c, _, p| {
+        c.add_gd3(gd3::GAME_JP, p);
+        Ok(())
+    }),
+    Directive::new("SYSTEM", "text", "System name (English and Japanese)", |c, _, p| {
+        gd3_both(c, gd3::SYSTEM_EN, gd3::SYSTEM_JP, p);
+        Ok(())
+    }),
+    Directive::new("SYSTEM-E", "text", "System name (English)", |c, _, p| {
+        c.add_gd3(gd3::SYSTEM_EN, p);
+        Ok(())
+    }),
+    Directive::new("SYSTEM-J", "text", "System name (Japanese)", |c, _, p| {
+        c.add_gd3(gd3::SYSTEM_JP, p);
+        Ok(())
+    }),
+    Directive::new("COMPOSER", "text", "Composer (English and Japanese)", |c, _, p| {
+        gd3_both(c, gd3::COMPOSER_EN, gd3::COMPOSER_JP, p);
+        Ok(())
+    }),
+    Directive::new("COMPOSER-E", "text", "Composer (English)", |c, _, p| {
+        c.add_gd3(gd3::COMPOSER_EN, p);
+        Ok(())
+    }),
+    Directive::new("COMPOSER-J", "text", "Composer (Japanese)", |c, _, p| {
+        c.add_gd3(gd3::COMPOSER_JP, p);
+        Ok(())
+    }),
+    Directive::new("PROGRAMER", "text", "Converter credit (alias of #PROGRAMMER)", |c, _, p| {
+        c.add_gd3(gd3::CONVERTER, p);
+        Ok(())
+    }),
+    Directive::new("PROGRAMMER", "text", "Converter credit", |c, _, p| {
+        c.add_gd3(gd3::CONVERTER, p);
+        Ok(())
+    }),
+    Directive::new("CONVERTER", "text", "Converter credit (alias of #PROGRAMMER, after the GD3 field name)", |c, _, p| {
+        c.add_gd3(gd3::CONVERTER, p);
+        Ok(())
+    }),
+    Directive::new("DATE", "text", "Release date", |c, _, p| {
+        c.add_gd3(gd3::DATE, p);
+        Ok(())
+    }),
+    Directive::new("NOTES", "text", "Notes text", |c, _, p| {
+        c.add_gd3(gd3::NOTES, p);
+        Ok(())
+    }),
+    Directive::new("COPYRIGHT", "text", "Copyright line, added to the notes", |c, _, p| {
+        c.add_gd3(gd3::NOTES, &format!("Copyright: {}", p));
+        Ok(())
+    }),
+    Directive::new("LICENSE", "text", "License line, added to the notes", |c, _, p| {
+        c.add_gd3(gd3::NOTES, &format!("License: {}", p));
+        Ok(())
+    }),
+    Directive::prefix("TEXT", "text", "GD3 field by number (#TEXT0 - #TEXT10)", |c, s, p| {
+        c.add_gd3_text(s, p);
+        Ok(())
+    }),
+    Directive::new("RATE", "hz", "Frame rate; positive enables rate scaling, negative disables it", |c, _, p| {
+        let value = num(c, p);
+        if value.unsigned_abs() > 44100 {
+            c.warn(format!("#RATE: {} Hz is above 44100 Hz; clamped to 44100", p.trim()));
+        }
+        let rate = value.clamp(-44100, 44100) as i32;
+        if rate < 0 {
+            c.framerate = 44100 / (-rate);
+            c.recording_rate = 0;
+        } else if rate > 0 {
+            c.framerate = 44100 / rate;
+            c.recording_rate = rate;
+        }
+        Ok(())
+    }),
+    Directive::new("VOLUME", "n", "Global volume adjustment (-64 to +192, 32 steps = 2x)", |c, _, p| {
+        c.volume_mod = num(c, p) as i16;
+        Ok(())
+    }),
+    Directive::new("LOOP-LCM", "[max seconds]", "Extend the loop until channels with different loop lengths line up", |c, _, p| {
+        c.loop_lcm = Some(match p.trim() {
+            "" => DEFAULT_LOOP_LCM_CAP,
+            _ => num(c, p).max(1) * 44100,
+        });
+        Ok(())
+    }),
+    Directive::new("LOOP-MODE", "UNROLL|COMPACT", "Write a loop section made of repeats in full, or as one copy played more times", |c, _, p| {
+        match p.trim().parse() {
+            Ok(mode) => c.loop_mode = mode,
+            Err(message) => c.warn(format!("#LOOP-MODE: {}", message)),
+        }
+        Ok(())
+    }),
+    Directive::new("LOOP-COUNT", "n|FOREVER", "Times players play the loop, in the header and the .m3u playlist", |c, _, p| {
+        match p.trim().parse() {
+            Ok(count) => c.playback.loop_count = Some(count),
+            Err(message) => c.warn(format!("#LOOP-COUNT: {}", message)),
+        }
+        Ok(())
+    }),
+    Directive::new("FADE-HINT", "seconds", "Fade-out after the last loop, in the .m3u playlist", |c, _, p| {
+        match p.trim().parse::<f64>() {
+            Ok(seconds) if (0.0..=MAX_FADE_SECONDS).contains(&seconds) => c.playback.fade = Some((seconds * 44100.0).round() as u64),
+            Ok(seconds) if seconds > MAX_FADE_SECONDS => {
+                c.warn(format!("#FADE-HINT: {} seconds is longer than {} seconds, ignored", p.trim(), MAX_FADE_SECONDS))
+            }
+            _ => c.warn(format!("#FADE-HINT: expected a number of seconds, got '{}'", p.trim())),
+        }
+        Ok(())
+    }),
+    Directive::new("LOOP-BASE", "n", "Loop base header (reduces loop count)", |c, _, p| {
+        c.loop_base = num(c, p) as i8;
+        Ok(())
+    }),
+    Directive::new("LOOP-MODIFIER", "n", "Loop modifier header (multiply by N/16)", |c, _, p| {
+        c.loop_mod = num(c, p) as u8;
+        Ok(())
+    }),
+    Directive::new("SCALE", "letters", "Scale letters (a-j, `.` for gaps, one step each)", |c, _, p| {
+        c.parse_scale(p);
+        Ok(())
+    }),
+    Directive::new("EQUAL-TEMPERAMENT", "", "Apply equal temperament after #SCALE", |c, _, _| {
+        c.make_equal_temperament();
+        Ok(())
+    }),
+    Directive::new("JUST-INTONATION", "num den ...", "Set note pitches by rational numbers", |c, _, p| {
+        c.parse_just_intonation(p);
+        Ok(())
+    }),
+    Directive::new("SCALE-CENTS", "cents ...", "Set note pitches in cents above the first step of #SCALE", |c, _, p| {
+        c.parse_scale_cents(p);
+        Ok(())
+    }),
+    Directive::new("PITCH-QUANTIZE", "DOWN|NEAREST|DIFFUSE", "How note values are rounded to the chip's note bits", |c, _, p| {
+        match Quantization::parse(p.trim()) {
+            Some(quantization) => c.quantization = quantization,
+            None => c.warn(format!("#PITCH-QUANTIZE: unknown mode '{}'", p)),
+        }
+        Ok(())
+    }),
+    Directive::new("PITCH-CHANGE", "decihertz", "Base frequency of \"C\" notes", |c, _, p| {
+        c.base_freq = num(c, p) as f64 * 10.0;
+        Ok(())
+    }),
+    Directive::new("INCLUDE", "path", "Include another MML file", |c, _, p| {
+        let include_path = c.resolve_path(p);
+        c.dependencies.push(include_path.clone());
+        if let Err(e) = c.read_input_from_path(&include_path) {
+            c.warn(format!("Failed to include '{}': {}", p, e));
+        }
+        Ok(())
+    }),
+    Directive::new("INSTLIB", "path", "Read the @INST instruments of an instrument library file", |c, _, p| {
+        let path = c.resolve_path(p);
+        c.dependencies.push(path.clone());
+        let library = std::mem::replace(&mut c.reading_library, true);
+        if let Err(e) = c.read_input_from_path(&path) {
+            c.warn(format!("Failed to read instrument library '{}': {}", p, e));
+        }
+        c.reading_library = library;
+        Ok(())
+    }),
+    Directive::new("REMAP", "[X->Y ...]", "Read channel letters as other channels from here on (to the end of the file)", |c, _, p| {
+        c.parse_remap(p);
+        Ok(())
+    }),
+    Directive::new("GROUP", "X = channels", "Play lines of channel X on the channels, one note of each 'ceg' chord on each", |c, _, p| {
+        c.parse_group(p);
+        Ok(())
+    }),
+    Directive::new("CHANNEL-FILE", "channels path", "Append the lines of a file to channels", |c, _, p| {
+        c.read_channel_file(p)
+    }),
+    Directive::new("LOADINST", "n path [voice]", "Load an FM patch (.opm/.dmp/.fui/.tfi) into @x<n>", |c, _, p| {
+        c.load_instrument(p);
+        Ok(())
+    }),
+    Directive::new("WAV", "n path", "Load a WAV file as sample n, streamed to a chip DAC with `@s n`", |c, _, p| {
+        c.load_wav(p);
+        Ok(())
+    }),
+    Directive::new("EOF", "", "Stop reading input", |_, _, _| Ok(())),
+    // Handled while reading lines
+    Directive::new("TRACK", "n", "Start the lines of song n; `-t n` picks the song compiled (default the first)", |_, _, _| Ok(())),
+    Directive::new("DEFINE", "NAME", "Define a name for #IFDEF (also `-D NAME` on the command line)", |c, _, p| {
+        match p.split_whitespace().next() {
+            Some(name) => {
+                c.defines.insert(name.to_string());
+            }
+            None => c.warn("#DEFINE: expected a name".to_string()),
+        }
+        Ok(())
+    }),
+    // Handled while reading lines, even in skipped blocks
+    Directive::new("IFDEF", "NAME", "Read the lines up to #ELSE or #ENDIF only if NAME is defined", |_, _, _| Ok(())),
+    Directive::new("IFNDEF", "NAME", "Read the lines up to #ELSE or #ENDIF only if NAME is not defined", |_, _, _| Ok(())),
+    Directive::new("ELSE", "", "Read the lines up to #ENDIF only if the #IFDEF's lines were skipped", |_, _, _| Ok(())),
+    Directive::new("ENDIF", "", "End an #IFDEF or #IFNDEF block", |_, _, _| Ok(())),
+    Directive::new("DEBUG-INPUT-LINES", "0|1", "Display input lines as they are read", |c, _, p| {
+        c.debug_input_lines = num(c, p) != 0;
+        Ok(())
+    }),
+    Directive::new("UNOFFICIAL", "", "Enable unofficial VGM features (currently no-op)", |_, _, _| Ok(())),
+    Directive::new("TIE-SLIDE", "frames", "Slide between `&`-tied pitches (0 = instant change)", |c, _, p| {
+        c.tie_slide = num(c, p).max(0);
+        Ok(())
+    }),
+    Directive::new("MONO", "", "Force all panning to center", |c, _, _| {
+        c.mono = true;
+        Ok(())
+    }),
+    Directive::new("NORMALIZE-OCTAVE", "", "Shift each chip's octaves so `o4 c` sounds at standard pitch", |c, _, _| {
+        c.normalize_octave = true;
+        Ok(())
+    }),
+    Directive::new("DEFAULT", "[channels] o l q t", "Starting octave, length, quantize and tempo of channels", |c, _, p| {
+        c.parse_defaults(p);
+        Ok(())
+    }),
+    Directive::new("SFX", "name = channels", "Compile channels to <output>.<name>.vgm instead of the song", |c, _, p| {
+        c.parse_sfx(p);
+        Ok(())
+    }),
+    Directive::new("LYRICS", "NOTES|LRC", "Store channel lyrics in the GD3 notes or in <output>.lrc", |c, _, p| {
+        match p.to_ascii_uppercase().as_str() {
+            "NOTES" => c.lyrics_file = false,
+            "LRC" => c.lyrics_file = true,
+            _ => c.warn(format!("#LYRICS: unknown destination '{}'", p)),
+        }
+        Ok(())
+    }),
+    Directive::new("VOLUME-MODE", "ABSOLUTE|NORMALIZED", "Volumes in chip units or scaled from 0-15", |c, _, p| {
+        match p.to_ascii_uppercase().as_str() {
+            "ABSOLUTE" => c.volume_mode = VolumeMode::Absolute,
+            "NORMALIZED" => c.volume_mode = VolumeMode::Normalized,
+            _ => c.warn(format!("#VOLUME-MODE: unknown mode '{}'", p)),
+        }
+        Ok(())
+    }),
+    Directive::new("NOTE-OFF-OVERLAP", "OFF-BEFORE-ON|DROP-OFF", "A note-off on the sample of the next note-on: written first, or dropped", |c, _, p| {
+        match NoteOffOverlap::parse(p.trim()) {
+            Some(overlap) => c.events.set_note_off_overlap(overlap),
+            None => c.warn(format!("#NOTE-OFF-OVERLAP: unknown policy '{}'", p)),
+        }
+        Ok(())
+    }),
+    Directive::new("PSG-WIDEN", "channels [detune [pan]]", "Duplicate PSG channels onto a second chip in stereo", |c, _, p| {
+        let mut parts = p.split_whitespace();
+        let channels = parts.next().unwrap_or("");
+        let detune = num(c, parts.next().unwrap_or(""));
+        // The PSG pans hard left, hard right or to both sides
+        let pan = match parts.next().map(|pan| num(c, pan)) {
+            None => 1,
+            Some(pan @ -1..=1) => pan as i16,
+            Some(pan) => {
+                c.warn(format!("#PSG-WIDEN: pan {} is out of range (-1 to 1); using 1", pan));
+                1
+            }
+        };
+        for ch in channels.chars() {
+            if let Some(idx) = c.input_channel_index(ch) {
+                c.psg_widen.push((idx, detune, pan));
+            }
+        }
+        Ok(())
+    }),
+    Directive::new("WIDE", "chan=channels depth=n", "Play channels on the second chip too, pitch alternating by n every frame", |c, _, p| {
+        c.parse_wide(p);
+        Ok(())
+    }),
+    Directive::new("TRANSPOSE", "[channels] semitones", "Transpose all channels, or the listed ones (e.g. A-D) on top of that; `K` adds to it", |c, _, p| {
+        c.parse_transpose(p);
+        Ok(())
+    }),
+    Directive::new("LATENCY", "channels=offset ...", "Shift channel events by samples or `ms` to compensate for latency", |c, _, p| {
+        c.parse_latency(p);
+        Ok(())
+    }),
+    Directive::new("METER", "beats/unit", "Time signature of the measure:beat positions in warnings and --dump-events (default 4/4)", |c, _, p| {
+        match Meter::parse(p) {
+            Some(meter) => c.meter = meter,
+            None => c.warn(format!("#METER: expected a time signature such as 3/4, got '{}'", p)),
+        }
+        Ok(())
+    }),
+    Directive::new("GROOVE", "n = ticks ...", "Relative lengths of repeating steps, applied with `@g n` (e.g. 7 5 for swing)", |c, _, p| {
+        c.parse_groove(p);
+        Ok(())
+    }),
+    Directive::new("AUTOMATE", "channels parameter time=value ...", "Set volume, panning, ... at song times (m:ss.fff), interpolated per frame", |c, _, p| {
+        c.parse_automation(p);
+        Ok(())
+    }),
+    Directive::new("OPTION", "chip name=value ...", "Set options of an enabled chip by long name (see --list-chips)", |c, _, p| {
+        c.parse_chip_option(p);
+        Ok(())
+    }),
+    Directive::prefix("EX-", "channels [options]", "Enable a sound chip (e.g. #EX-PSG ABC,N)", |c, s, p| {
+        c.parse_chip_enable(s, p)
+    }),
+];
+
+/// All supported global directives
+pub fn directives() -> &'static [Directive] {
+    DIRECTIVES
+}
+
+/// Look up the directive for a command name, returning it with its suffix
+pub fn find(command: &str) -> Option<(&'static Directive, &str)> {
+    DIRECTIVES
+        .iter()
+        .find(|d| !d.prefix && d.name == command)
+        .map(|d| (d, ""))
+        .or_else(|| {
+            DIRECTIVES
+                .iter()
+                .filter(|d| d.prefix)
+                .find_map(|d| command.strip_prefix(d.name).map(|suffix| (d, suffix)))
+        })
+}
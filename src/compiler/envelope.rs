@@ -6,7 +6,7 @@
 pub const MAX_ENVELOPE_DATA: usize = 2048;
 
 /// Number of macro types
-pub const MAX_MACRO_TYPES: usize = 13;
+pub const MAX_MACRO_TYPES: usize = 16;
 
 /// Macro command types (matching original MC_* constants)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -25,6 +25,9 @@ pub enum MacroType {
     Sample = 10,     // @S
     SampleList = 11, // @SL
     Midi = 12,       // @MIDI
+    PitchEnv = 13,   // @EP
+    Vibrato = 14,    // @MP
+    NoiseMap = 15,   // @NMAP
 }
 
 impl MacroType {
@@ -44,6 +47,9 @@ impl MacroType {
             Self::Sample => "@S",
             Self::SampleList => "@SL",
             Self::Midi => "",
+            Self::PitchEnv => "",
+            Self::Vibrato => "",
+            Self::NoiseMap => "",
         }
     }
 
@@ -63,6 +69,9 @@ impl MacroType {
             Self::Sample => "@S",
             Self::SampleList => "@SL",
             Self::Midi => "@MIDI",
+            Self::PitchEnv => "@EP",
+            Self::Vibrato => "@MP",
+            Self::NoiseMap => "@NMAP",
         }
     }
 
@@ -88,6 +97,9 @@ impl MacroType {
             "@S" => Some(Self::Sample),
             "@SL" => Some(Self::SampleList),
             "@MIDI" => Some(Self::Midi),
+            "@EP" => Some(Self::PitchEnv),
+            "@MP" => Some(Self::Vibrato),
+            "@NMAP" => Some(Self::NoiseMap),
             _ => None,
         }
     }
@@ -125,11 +137,76 @@ impl MacroType {
             Self::Sample,
             Self::SampleList,
             Self::Midi,
+            Self::PitchEnv,
+            Self::Vibrato,
+            Self::NoiseMap,
         ]
         .into_iter()
     }
 }
 
+/// Pitch offset of a vibrato (`@MP = delay depth speed`) a number of frames
+/// into a note: a triangle wave of `depth` chip units either side of the
+/// note, taking `speed` frames per cycle, after `delay` frames
+pub fn vibrato_offset(params: &[i16], frame: i64) -> Option<i64> {
+    let param = |i: usize| params.get(i).copied().unwrap_or(0) as i64;
+    let (delay, depth, speed) = (param(0), param(1), param(2).max(1));
+    if depth == 0 || frame < delay {
+        return None;
+    }
+    let x = 4 * depth * ((frame - delay) % speed) / speed;
+    Some(if x < depth {
+        x
+    } else if x < 3 * depth {
+        2 * depth - x
+    } else {
+        x - 4 * depth
+    })
+}
+
+/// How arpeggio (@EN) envelope values are interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArpMode {
+    /// Offsets from the played note (default)
+    #[default]
+    Relative,
+    /// Absolute note numbers (prefix `A`)
+    Absolute,
+    /// Raw chip note values, e.g. noise periods (prefix `F`)
+    Fixed,
+}
+
+/// How volume values map to chip volumes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VolumeMode {
+    /// Values are chip units, passed through unchanged (default)
+    #[default]
+    Absolute,
+    /// Values are 0-15, scaled to the chip's volume range
+    Normalized,
+}
+
+impl VolumeMode {
+    /// Convert a volume value to chip units
+    pub fn scale(self, value: i16, max_volume: i16) -> i16 {
+        match self {
+            VolumeMode::Absolute => value,
+            VolumeMode::Normalized => ((value as i32 * max_volume as i32 + 7) / 15) as i16,
+        }
+    }
+}
+
+/// One velocity layer of a sample list entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleLayer {
+    /// Sample ID sent with the note
+    pub sample: i16,
+    /// Volume set with the note (-1 to leave volume unchanged)
+    pub volume: i16,
+    /// Pitch offset in scale steps
+    pub pitch: i16,
+}
+
 /// Macro envelope data
 ///
 /// Corresponds to MacroEnv struct in original
@@ -143,6 +220,12 @@ pub struct MacroEnvelope {
     pub data: Vec<i16>,
     /// Optional text label (for samples)
     pub text: String,
+    /// Tuple entries of a sample list, referenced from `data` as `-1 - index`
+    pub layers: Vec<Vec<SampleLayer>>,
+    /// Interpretation of arpeggio values
+    pub arp_mode: ArpMode,
+    /// Volume mapping of a volume envelope (None follows #VOLUME-MODE)
+    pub volume_mode: Option<VolumeMode>,
 }
 
 impl MacroEnvelope {
@@ -152,6 +235,9 @@ impl MacroEnvelope {
             loop_end: 0,
             data: Vec::with_capacity(MAX_ENVELOPE_DATA),
             text: String::new(),
+            layers: Vec::new(),
+            arp_mode: ArpMode::Relative,
+            volume_mode: None,
         }
     }
 
@@ -161,6 +247,9 @@ impl MacroEnvelope {
         self.loop_end = 0;
         self.data.clear();
         self.text.clear();
+        self.layers.clear();
+        self.arp_mode = ArpMode::Relative;
+        self.volume_mode = None;
     }
 
     /// Get the length of the envelope data
@@ -199,6 +288,20 @@ impl MacroEnvelope {
         }
     }
 
+    /// Look up a sample list entry, picking the layer for the given accent level
+    ///
+    /// Plain entries act as a single layer with no volume or pitch change.
+    pub fn sample_layer(&self, index: usize, accent: usize) -> SampleLayer {
+        let value = self.data.get(index).copied().unwrap_or(0);
+        if value >= 0 {
+            return SampleLayer { sample: value, volume: -1, pitch: 0 };
+        }
+        match self.layers.get((-1 - value as i32) as usize) {
+            Some(layers) if !layers.is_empty() => layers[accent.min(layers.len() - 1)],
+            _ => SampleLayer { sample: 0, volume: -1, pitch: 0 },
+        }
+    }
+
     /// Get last value
     pub fn last(&self) -> Option<i16> {
         if self.loop_end > 0 {
@@ -221,5 +324,9 @@ pub type MacroEnvStorage = [[MacroEnvelope; 256]; MAX_MACRO_TYPES];
 
 /// Create default macro envelope storage
 pub fn create_macro_env_storage() -> Box<MacroEnvStorage> {
-    Box::new(std::array::from_fn(|_| std::array::from_fn(|_| MacroEnvelope::new())))
+    // Built on the heap a macro type at a time: the whole table is too
+    // large to go through the stack of a thread
+    let types: Vec<[MacroEnvelope; 256]> =
+        (0..MAX_MACRO_TYPES).map(|_| std::array::from_fn(|_| MacroEnvelope::new())).collect();
+    types.into_boxed_slice().try_into().unwrap_or_else(|_| unreachable!("one table per macro type"))
 }
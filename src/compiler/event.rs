@@ -1,6 +1,8 @@
 //! Event generation and management
 
+use crate::chips::MacroCommand;
 use std::collections::BTreeMap;
+use std::fmt;
 
 /// Event data types
 #[derive(Debug, Clone)]
@@ -9,6 +11,8 @@ pub enum EventData {
     Chip(ChipEvent),
     /// Raw VGM command byte
     Raw(u8),
+    /// Start of a `#WAV` sample on the chip's DAC stream, by number (None stops the stream)
+    Dac(Option<u8>),
 }
 
 /// Chip-specific event data
@@ -20,6 +24,9 @@ pub struct ChipEvent {
     pub value1: i32,
     /// Secondary value
     pub value2: i32,
+    /// Whether the event sets state of the whole chip (LFO, vibrato depth,
+    /// ...) rather than of its channel
+    pub global: bool,
 }
 
 impl ChipEvent {
@@ -28,6 +35,51 @@ impl ChipEvent {
             event_type,
             value1,
             value2,
+            global: false,
+        }
+    }
+
+    /// An event setting state of the whole chip: whichever channel sends
+    /// it, it is written only when the chip's value changes, and even when
+    /// that channel is muted
+    pub fn global(event_type: u16, value1: i32, value2: i32) -> Self {
+        Self { global: true, ..Self::new(event_type, value1, value2) }
+    }
+}
+
+/// The MML command an event came from, independent of the chip's encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventSource {
+    /// Note start: key number (octave * notes per octave + note) and sounding length in samples
+    NoteOn { key: i32, duration: i64 },
+    /// Pitch change of the sounding note (ties, slides, arpeggios, pitch envelopes) in chip units
+    NoteChange { value: i32, octave: i32 },
+    /// Note release
+    NoteOff,
+    /// Rest
+    Rest,
+    /// Macro command or envelope step
+    Macro { command: MacroCommand, value: i16 },
+    /// Direct register write (`x`)
+    Direct { address: u16, value: u8 },
+    /// Raw VGM byte (`y`)
+    Raw,
+    /// `@s`: `#WAV` sample streamed to the chip's DAC (negative stops the stream)
+    DacSample(i16),
+}
+
+impl fmt::Display for EventSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::NoteOn { key, duration } => write!(f, "note on key {} for {} samples", key, duration),
+            Self::NoteChange { value, octave } => write!(f, "pitch {} octave {}", value, octave),
+            Self::NoteOff => write!(f, "note off"),
+            Self::Rest => write!(f, "rest"),
+            Self::Macro { command, value } => write!(f, "{:?} {}", command, value),
+            Self::Direct { address, value } => write!(f, "write ${:X} = ${:02X}", address, value),
+            Self::Raw => write!(f, "raw byte"),
+            Self::DacSample(n) if n < 0 => write!(f, "DAC stop"),
+            Self::DacSample(n) => write!(f, "DAC sample {}", n),
         }
     }
 }
@@ -39,37 +91,145 @@ pub struct Event {
     pub time: i64,
     /// Channel index (-1 for global/raw)
     pub channel: i8,
+    /// MML command the event came from
+    pub source: EventSource,
     /// Event data
     pub data: EventData,
 }
 
 impl Event {
-    pub fn new(time: i64, channel: i8, data: EventData) -> Self {
+    pub fn new(time: i64, channel: i8, source: EventSource, data: EventData) -> Self {
         Self {
             time,
             channel,
+            source,
             data,
         }
     }
 
-    pub fn chip(time: i64, channel: i8, event_type: u16, value1: i32, value2: i32) -> Self {
-        Self::new(
-            time,
-            channel,
-            EventData::Chip(ChipEvent::new(event_type, value1, value2)),
-        )
+    pub fn chip(time: i64, channel: i8, source: EventSource, event: ChipEvent) -> Self {
+        Self::new(time, channel, source, EventData::Chip(event))
     }
 
     pub fn raw(time: i64, value: u8) -> Self {
-        Self::new(time, -1, EventData::Raw(value))
+        Self::new(time, -1, EventSource::Raw, EventData::Raw(value))
+    }
+
+    /// Whether the event sets state of its channel's whole chip
+    pub fn is_global(&self) -> bool {
+        matches!(self.data, EventData::Chip(ChipEvent { global: true, .. }))
+    }
+}
+
+/// A compiled event as seen by embedders, from `Compiler::events()`
+#[derive(Debug, Clone)]
+pub struct CompiledEvent<'a> {
+    /// Time in samples (44100 Hz)
+    pub time: i64,
+    /// Channel letter (None for raw bytes)
+    pub channel: Option<char>,
+    /// Name of the chip the channel is assigned to, as in `#EX-`
+    pub chip: Option<&'a str>,
+    /// MML command the event came from
+    pub source: EventSource,
+    /// Chip-specific encoding, as sent to the chip driver
+    pub data: &'a EventData,
+}
+
+impl CompiledEvent<'_> {
+    /// One-line description, e.g. `A OPN2: note on key 48 for 20947 samples`
+    pub fn describe(&self) -> String {
+        match (self.channel, self.chip) {
+            (Some(channel), Some(chip)) => format!("{} {}: {}", channel, chip, self.source),
+            _ => self.source.to_string(),
+        }
+    }
+}
+
+/// An event as listed by `Compiler::dump_events()`, for debugging timing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRecord {
+    /// Time in samples (44100 Hz)
+    pub time: i64,
+    /// Frame the event falls in, at the song's frame rate
+    pub frame: i64,
+    /// Measure and beat of the event on its channel's tempo (`#METER`), as
+    /// `measure:beat` (None for raw bytes)
+    pub position: Option<String>,
+    /// Channel letter (None for raw bytes)
+    pub channel: Option<char>,
+    /// Name of the chip the channel is assigned to, as in `#EX-`
+    pub chip: Option<String>,
+    /// MML command the event came from
+    pub source: EventSource,
+    /// What the event does, e.g. `note on o4 c (key 48) for 20947 samples`
+    pub description: String,
+}
+
+impl fmt::Display for EventRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>9} {:>6} {:>8}  ", self.time, self.frame, self.position.as_deref().unwrap_or(""))?;
+        match (self.channel, &self.chip) {
+            (Some(channel), Some(chip)) => write!(f, "{} {}: {}", channel, chip, self.description),
+            _ => write!(f, "{}", self.description),
+        }
     }
 }
 
+/// What is written when a channel's note-off falls on the same sample as
+/// its next note-on (`#NOTE-OFF-OVERLAP`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoteOffOverlap {
+    /// The note-off, then the note-on, so that the next note attacks
+    /// again (default)
+    #[default]
+    OffBeforeOn,
+    /// Only the note-on: the notes join as if slurred, where the chip
+    /// lets a note-on change the pitch of a sounding note
+    DropOff,
+}
+
+impl NoteOffOverlap {
+    /// Parse a `#NOTE-OFF-OVERLAP` policy name
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "OFF-BEFORE-ON" => Some(NoteOffOverlap::OffBeforeOn),
+            "DROP-OFF" => Some(NoteOffOverlap::DropOff),
+            _ => None,
+        }
+    }
+}
+
+/// An event in the queue, with its place among the note-ons and
+/// note-offs of its channel
+///
+/// A channel's events are inserted note by note, so a note-off belongs to
+/// the last note-on inserted before it: the `n`th note-on has key `2n - 1`
+/// and the note-offs after it `2n`. A note-off with the key just below a
+/// note-on's is the previous note's, not its own.
+#[derive(Debug, Clone)]
+struct Queued {
+    key: Option<u32>,
+    event: Event,
+}
+
 /// Time-sorted event queue
+///
+/// Inserting is O(log n) in the number of distinct times, and events at
+/// the same time stay in the order they were inserted, which is the order
+/// their writes reach the chip. A note-off therefore comes before the
+/// next note-on at the same sample unless `NoteOffOverlap::DropOff` drops
+/// it.
 #[derive(Debug, Default)]
 pub struct EventQueue {
     /// Events grouped by time
-    events: BTreeMap<i64, Vec<Event>>,
+    events: BTreeMap<i64, Vec<Queued>>,
+    /// Samples added to the time of each channel's events (`#LATENCY`)
+    latency: Vec<i64>,
+    /// Note-ons inserted for each channel
+    notes: Vec<u32>,
+    /// What a note-off at the sample of the next note-on becomes
+    overlap: NoteOffOverlap,
 }
 
 impl EventQueue {
@@ -77,27 +237,134 @@ impl EventQueue {
         Self::default()
     }
 
-    /// Insert an event into the queue
-    pub fn insert(&mut self, event: Event) {
-        self.events
-            .entry(event.time)
-            .or_default()
-            .push(event);
+    /// Shift the events later inserted for a channel by a number of samples
+    pub fn set_latency(&mut self, channel: usize, samples: i64) {
+        if self.latency.len() <= channel {
+            self.latency.resize(channel + 1, 0);
+        }
+        self.latency[channel] = samples;
+    }
+
+    /// Samples the events of a channel are shifted by
+    pub fn latency(&self, channel: usize) -> i64 {
+        self.latency.get(channel).copied().unwrap_or(0)
+    }
+
+    /// Set what a note-off at the sample of its channel's next note-on
+    /// becomes, for the events inserted from now on
+    pub fn set_note_off_overlap(&mut self, overlap: NoteOffOverlap) {
+        self.overlap = overlap;
+    }
+
+    /// Insert an event into the queue, shifted by its channel's latency (but
+    /// never before the start of the song)
+    pub fn insert(&mut self, mut event: Event) {
+        if let Ok(channel) = usize::try_from(event.channel) {
+            event.time = (event.time + self.latency(channel)).max(0);
+        }
+        self.push(event);
+    }
+
+    /// Put an event at its time, after the events already there, applying
+    /// the note-off overlap policy
+    fn push(&mut self, event: Event) {
+        let Ok(channel) = usize::try_from(event.channel) else {
+            self.events.entry(event.time).or_default().push(Queued { key: None, event });
+            return;
+        };
+        if self.notes.len() <= channel {
+            self.notes.resize(channel + 1, 0);
+        }
+        let notes = &mut self.notes[channel];
+        let key = match event.source {
+            EventSource::NoteOn { .. } => {
+                *notes += 1;
+                Some(2 * *notes - 1)
+            }
+            EventSource::NoteOff => Some(2 * *notes),
+            _ => None,
+        };
+
+        let slot = self.events.entry(event.time).or_default();
+        if let (NoteOffOverlap::DropOff, Some(key)) = (self.overlap, key) {
+            // The previous note's note-off, met by this note-on, is left out
+            // (the first note has no previous note)
+            if key % 2 == 1 && key > 1 {
+                slot.retain(|queued| queued.event.channel != event.channel || queued.key != Some(key - 1));
+            }
+        }
+        slot.push(Queued { key, event });
+    }
+
+    /// Repeat the events of a channel from `start` to `end` back to back,
+    /// each copy starting where the last ended, up to `until`
+    ///
+    /// Events at `end` itself (the key-off of the last note) are copied
+    /// too, and land with the start of the next copy. Only those are kept
+    /// at `until`, where the song loops back to the events that follow.
+    pub fn repeat(&mut self, channel: i8, start: i64, end: i64, until: i64) {
+        let length = end - start;
+        if length <= 0 {
+            return;
+        }
+        let body: Vec<Event> = self
+            .events
+            .range(start..=end)
+            .flat_map(|(_, events)| events)
+            .map(|queued| &queued.event)
+            .filter(|event| event.channel == channel)
+            .cloned()
+            .collect();
+        let mut shift = length;
+        while start + shift < until {
+            for event in &body {
+                let time = event.time + shift;
+                if time > until || (time == until && event.time != end) {
+                    break;
+                }
+                self.push(Event { time, ..event.clone() });
+            }
+            shift += length;
+        }
     }
 
     /// Get all events in time order
     pub fn iter(&self) -> impl Iterator<Item = &Event> {
-        self.events.values().flatten()
+        self.events.values().flatten().map(|queued| &queued.event)
+    }
+
+    /// Get all events in time order, to change them without moving them
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Event> {
+        self.events.values_mut().flatten().map(|queued| &mut queued.event)
+    }
+
+    /// Drop the events after `end`
+    pub fn truncate(&mut self, end: i64) {
+        self.events.split_off(&(end + 1));
+    }
+
+    /// Keep only the events `keep` returns true for
+    pub fn retain(&mut self, mut keep: impl FnMut(&Event) -> bool) {
+        self.events.retain(|_, events| {
+            events.retain(|queued| keep(&queued.event));
+            !events.is_empty()
+        });
     }
 
     /// Get events at a specific time
-    pub fn at_time(&self, time: i64) -> Option<&Vec<Event>> {
-        self.events.get(&time)
+    pub fn at_time(&self, time: i64) -> impl Iterator<Item = &Event> {
+        self.events.get(&time).into_iter().flatten().map(|queued| &queued.event)
     }
 
     /// Clear all events
     pub fn clear(&mut self) {
         self.events.clear();
+        self.notes.clear();
+    }
+
+    /// Number of events in the queue
+    pub fn len(&self) -> usize {
+        self.events.values().map(Vec::len).sum()
     }
 
     /// Check if queue is empty
@@ -110,3 +377,73 @@ impl EventQueue {
         self.events.keys().next_back().copied()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insertion_order() {
+        let mut queue = EventQueue::new();
+        queue.set_latency(1, 5);
+        for (time, value) in [(10, 1), (0, 2), (10, 3), (5, 4)] {
+            queue.insert(Event::raw(time, value));
+        }
+        queue.insert(Event::new(5, 1, EventSource::Rest, EventData::Raw(5)));
+
+        let order: Vec<(i64, u8)> = queue
+            .iter()
+            .map(|event| match event.data {
+                EventData::Raw(value) => (event.time, value),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(order, [(0, 2), (5, 4), (10, 1), (10, 3), (10, 5)]);
+        assert_eq!(queue.len(), 5);
+        assert_eq!(queue.last_time(), Some(10));
+    }
+
+    /// The sources of a channel's events, in queue order
+    fn sources(queue: &EventQueue) -> Vec<(i64, &'static str)> {
+        queue
+            .iter()
+            .map(|event| match event.source {
+                EventSource::NoteOn { .. } => (event.time, "on"),
+                EventSource::NoteOff => (event.time, "off"),
+                _ => (event.time, "other"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_note_off_overlap() {
+        let note_on = |time| Event::new(time, 0, EventSource::NoteOn { key: 48, duration: 10 }, EventData::Raw(0));
+        let note_off = |time| Event::new(time, 0, EventSource::NoteOff, EventData::Raw(0));
+        let volume = |time| Event::new(time, 0, EventSource::Rest, EventData::Raw(0));
+
+        let song = |overlap| {
+            let mut queue = EventQueue::new();
+            queue.set_note_off_overlap(overlap);
+            // A note-off meeting the next note-on
+            queue.insert(note_on(0));
+            queue.insert(note_off(10));
+            queue.insert(volume(10));
+            queue.insert(note_on(10));
+            // A note cut to nothing by `@q`, then a rest
+            queue.insert(note_on(20));
+            queue.insert(note_off(20));
+            queue.insert(note_on(30));
+            queue.insert(note_off(40));
+            sources(&queue)
+        };
+        assert_eq!(
+            song(NoteOffOverlap::OffBeforeOn),
+            [(0, "on"), (10, "off"), (10, "other"), (10, "on"), (20, "on"), (20, "off"), (30, "on"), (40, "off")]
+        );
+        assert_eq!(
+            song(NoteOffOverlap::DropOff),
+            [(0, "on"), (10, "other"), (10, "on"), (20, "on"), (20, "off"), (30, "on"), (40, "off")]
+        );
+        assert_eq!(NoteOffOverlap::parse("drop-off"), Some(NoteOffOverlap::DropOff));
+    }
+}
@@ -0,0 +1,135 @@
+//! Per-channel event density (`--heatmap`)
+//!
+//! One row per channel and one column per few frames, each cell shaded by
+//! how many events fall in it. A channel that stops early or never plays,
+//! such as one whose envelope is undefined, shows as a blank stretch
+//! without opening the song in a player.
+
+use std::fmt;
+
+/// Columns the song is fitted into when no column length is given
+pub const WIDTH: i64 = 64;
+
+/// Samples per second in VGM timing
+const SAMPLE_RATE: i64 = 44100;
+
+/// Shades from an empty cell to the busiest one
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+/// Event counts of each channel over time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heatmap {
+    /// Frames each column covers
+    pub frames_per_column: i64,
+    /// Samples per frame
+    pub frame_samples: i64,
+    /// Length of the song in samples
+    pub total_samples: i64,
+    /// Each channel's letter with its event counts, by column
+    pub rows: Vec<(char, Vec<u64>)>,
+}
+
+impl Heatmap {
+    /// Count `(channel, time)` events of the channels into columns of
+    /// `frames_per_column` frames (0 fits the song in `WIDTH` columns);
+    /// events of other channels are left out
+    pub fn new(
+        events: impl IntoIterator<Item = (char, i64)>,
+        channels: &[char],
+        total_samples: i64,
+        frame_samples: i64,
+        frames_per_column: i64,
+    ) -> Self {
+        let frame_samples = frame_samples.max(1);
+        let frames = (total_samples.max(0) + frame_samples - 1) / frame_samples;
+        let frames_per_column = match frames_per_column {
+            n if n > 0 => n,
+            _ => ((frames + WIDTH - 1) / WIDTH).max(1),
+        };
+        let columns = ((frames + frames_per_column - 1) / frames_per_column).max(1) as usize;
+
+        let mut rows: Vec<(char, Vec<u64>)> = channels.iter().map(|&c| (c, vec![0; columns])).collect();
+        for (channel, time) in events {
+            if let Some((_, counts)) = rows.iter_mut().find(|(c, _)| *c == channel) {
+                // Key-offs at the very end count in the last column
+                let column = (time.max(0) / frame_samples / frames_per_column) as usize;
+                counts[column.min(columns - 1)] += 1;
+            }
+        }
+        Self {
+            frames_per_column,
+            frame_samples,
+            total_samples,
+            rows,
+        }
+    }
+
+    /// Events in the busiest cell
+    pub fn max(&self) -> u64 {
+        self.rows.iter().flat_map(|(_, counts)| counts).copied().max().unwrap_or(0)
+    }
+}
+
+/// Shade of a cell, the busiest cell being the darkest
+fn shade(count: u64, max: u64) -> char {
+    let levels = SHADES.len() as u64 - 1;
+    let level = (count * levels).div_ceil(max.max(1)).min(levels);
+    SHADES[level as usize] as char
+}
+
+/// Time in samples as `m:ss`
+fn format_time(samples: i64) -> String {
+    let seconds = samples / SAMPLE_RATE;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+impl fmt::Display for Heatmap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let max = self.max();
+        let column_ms = self.frames_per_column * self.frame_samples * 1000 / SAMPLE_RATE;
+        writeln!(
+            f,
+            "Events per {} frame{} ({}.{:03} s) per column, busiest cell {} (\"{}\")",
+            self.frames_per_column,
+            if self.frames_per_column == 1 { "" } else { "s" },
+            column_ms / 1000,
+            column_ms % 1000,
+            max,
+            std::str::from_utf8(SHADES).unwrap_or_default()
+        )?;
+        for (channel, counts) in &self.rows {
+            let cells: String = counts.iter().map(|&count| shade(count, max)).collect();
+            writeln!(f, "{} |{}|", channel, cells)?;
+        }
+        let columns = self.rows.first().map_or(0, |(_, counts)| counts.len());
+        let end = format_time(self.total_samples);
+        writeln!(f, "   {:<width$}{}", "0:00", end, width = columns.saturating_sub(end.len()).max(5))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heatmap() {
+        // Ten frames of 100 samples, two frames per column
+        let events = [('A', 0), ('A', 50), ('A', 150), ('A', 1000), ('B', 420), ('Z', 0)];
+        let heatmap = Heatmap::new(events, &['A', 'B', 'C'], 1000, 100, 2);
+        assert_eq!(
+            heatmap.rows,
+            [('A', vec![3, 0, 0, 0, 1]), ('B', vec![0, 0, 1, 0, 0]), ('C', vec![0; 5])]
+        );
+        assert_eq!(heatmap.max(), 3);
+
+        let text = heatmap.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("Events per 2 frames (0.004 s) per column, busiest cell 3"), "{}", text);
+        assert_eq!(&lines[1..4], ["A |@   -|", "B |  -  |", "C |     |"]);
+
+        // Without a column length the song is fitted into WIDTH columns
+        let heatmap = Heatmap::new([], &['A'], 44100 * 60, 735, 0);
+        assert_eq!(heatmap.frames_per_column, 57);
+        assert_eq!(heatmap.rows[0].1.len(), 64);
+    }
+}
@@ -0,0 +1,130 @@
+//! Named instruments (`@INST`) and instrument libraries (`#INSTLIB`)
+//!
+//! An `@INST "name" { ... }` block gives a name to the macro commands that
+//! make up an instrument, one per line: envelopes defined in place, which
+//! take the highest free envelope numbers, and commands such as `@x3` or
+//! `@2` selecting what is already defined.
+//!
+//! ```mml
+//! @INST "piano" {
+//!     @v = { 15 13 11 | 9 }
+//!     @x = { 1 0 0 ... }
+//!     @2
+//! }
+//! A @"piano" o4 c d e
+//! ```
+//!
+//! `@"piano"` in channel text plays those commands. `#INSTLIB file.vli`
+//! reads the `@INST` blocks of a library file shared by several songs.
+
+/// An `@INST` block being read
+#[derive(Debug, Clone, Default)]
+pub struct Block {
+    pub name: String,
+    /// Macro commands by name and value, in order
+    pub commands: Vec<(String, i64)>,
+    /// `{` left open by the envelopes of the block
+    depth: usize,
+}
+
+impl Block {
+    /// Whether the line continues an envelope that started on a line before
+    pub fn in_envelope(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// Split a line of the block at the `}` closing it, returning the part
+    /// inside and whether the block ends there. `;` starts a comment.
+    pub fn split_line<'a>(&mut self, line: &'a str) -> (&'a str, bool) {
+        let line = line.split(';').next().unwrap_or_default();
+        for (i, c) in line.char_indices() {
+            match c {
+                '{' => self.depth += 1,
+                '}' if self.depth == 0 => return (line[..i].trim(), true),
+                '}' => self.depth -= 1,
+                _ => {}
+            }
+        }
+        (line.trim(), false)
+    }
+}
+
+/// Parse the line `@INST "name" {` starting a block, returning the block
+/// and the text after its `{`, or an error message
+pub fn parse_start(line: &str) -> Option<Result<(Block, &str), String>> {
+    let rest = line.strip_prefix("@INST")?.trim_start();
+    let Some((name, rest)) = rest.strip_prefix('"').and_then(|r| r.split_once('"')) else {
+        return Some(Err("expected a quoted name, e.g. `@INST \"piano\" {`".to_string()));
+    };
+    let Some(body) = rest.trim_start().strip_prefix('{') else {
+        return Some(Err(format!("expected `{{` after the name \"{}\"", name)));
+    };
+    Some(Ok((Block { name: name.to_string(), ..Block::default() }, body)))
+}
+
+/// Split a line defining an envelope in a block, such as `@v = { ... }`,
+/// into the envelope name (`@v`) and the rest of the line, or None if it
+/// does not define one
+pub fn envelope_definition(line: &str) -> Option<(&str, &str)> {
+    let name_len = line.bytes().take(7).take_while(|&b| b >= b'@' && b != b'{').count();
+    let rest = line[name_len..].trim_start();
+    (line.starts_with('@') && (rest.starts_with('=') || rest.starts_with('{'))).then(|| (&line[..name_len], rest))
+}
+
+/// The lines of a song other than its envelopes and `@INST` blocks, which
+/// are the same for two versions of a song that differ only in their
+/// instruments
+pub fn without_instruments(mml: &str) -> String {
+    let mut block: Option<Block> = None;
+    let mut lines = String::new();
+    for line in mml.lines() {
+        let line = line.trim_start_matches('\u{FEFF}').trim();
+        let (mut open, body) = match block.take() {
+            Some(open) => (open, line),
+            None => match parse_start(line) {
+                Some(Ok(start)) => start,
+                _ if line.starts_with(|c| matches!(c, '@' | '-' | '+' | '$' | '[' | ']' | '{' | ',' | '|' | '0'..='9')) => continue,
+                _ => {
+                    lines.push_str(line);
+                    lines.push('\n');
+                    continue;
+                }
+            },
+        };
+        if !open.split_line(body).1 {
+            block = Some(open);
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block() {
+        let (mut block, rest) = parse_start("@INST \"bass\" { @v = { 15 12 } ; soft").unwrap().unwrap();
+        assert_eq!(block.name, "bass");
+        assert_eq!(block.split_line(rest), ("@v = { 15 12 }", false));
+        assert_eq!(block.split_line("@x = { 1 2"), ("@x = { 1 2", false));
+        assert!(block.in_envelope());
+        assert_eq!(block.split_line("3 4 }"), ("3 4 }", false));
+        assert_eq!(block.split_line("@2 }"), ("@2", true));
+
+        assert!(parse_start("@INST bass {").unwrap().is_err());
+        assert!(parse_start("@v0 = { 1 }").is_none());
+        assert_eq!(envelope_definition("@EN = { 0 4 7 }"), Some(("@EN", "= { 0 4 7 }")));
+        assert_eq!(envelope_definition("@x{ 1 }"), Some(("@x", "{ 1 }")));
+        assert_eq!(envelope_definition("@x3"), None);
+    }
+
+    #[test]
+    fn test_without_instruments() {
+        let song = "#TITLE x\n@v0 = { 15 12\n  9 }\n@INST \"bass\" {\n @v = { 1 }\n}\nA @v0 c\n";
+        let tweaked = "#TITLE x\n@v0 = { 15 }\n@INST \"bass\" { @2 }\nA @v0 c\n";
+        assert_eq!(without_instruments(song), "#TITLE x\nA @v0 c\n");
+        assert_eq!(without_instruments(song), without_instruments(tweaked));
+        assert_ne!(without_instruments(song), without_instruments("#TITLE x\nA @v0 d\n"));
+    }
+}
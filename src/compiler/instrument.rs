@@ -0,0 +1,495 @@
+//! FM instrument import and export
+//!
+//! Converts OPN2 `@x` operator macros to and from instrument files used by
+//! trackers. The `@x` layout is four operators of seven register bytes
+//! (`$30`-`$90`, in register order: operator 1, 3, 2, 4), then the
+//! feedback/algorithm byte (`$B0`) and the panning/LFO sensitivity byte
+//! (`$B4`). Supported formats:
+//!
+//! - VOPM `.opm`: text bank holding every instrument of the song
+//! - DefleMask `.dmp`: version 11 Genesis FM preset, one per instrument
+//! - Furnace `.fui`: `FINS` instrument with a name and FM feature, one per instrument
+//! - TFM Music Maker `.tfi`: import only
+//!
+//! The files store operators in operator order (1, 2, 3, 4).
+
+use super::envelope::{MacroEnvStorage, MacroType};
+use crate::error::{Error, Result};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Number of `@x` values making up an OPN2 voice
+const OPN2_VOICE_LEN: usize = 30;
+
+/// Operator order of instrument files (operator 1, 2, 3, 4; VOPM's M1 C1
+/// M2 C2) as indices into register order
+const OPM_ORDER: [usize; 4] = [0, 2, 1, 3];
+
+/// One FM operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FmOperator {
+    /// Detune in register form (bit 2 = negative)
+    pub dt: u8,
+    pub mul: u8,
+    pub tl: u8,
+    /// Key scale
+    pub ks: u8,
+    pub ar: u8,
+    /// Amplitude modulation enable
+    pub am: bool,
+    /// First decay rate
+    pub dr: u8,
+    /// Second decay rate
+    pub sr: u8,
+    /// Decay level
+    pub sl: u8,
+    pub rr: u8,
+    /// SSG-EG (bit 3 = enable)
+    pub ssg_eg: u8,
+}
+
+impl FmOperator {
+    /// Detune as a signed value (-3 to +3)
+    pub fn detune(&self) -> i8 {
+        let amount = (self.dt & 3) as i8;
+        if self.dt & 4 != 0 {
+            -amount
+        } else {
+            amount
+        }
+    }
+
+    /// Set the detune from the 0-6 form of trackers (3 = no detune)
+    fn set_centered_detune(&mut self, value: u8) {
+        let detune = (value & 7) as i8 - 3;
+        self.dt = if detune < 0 { 4 | (-detune) as u8 } else { detune as u8 };
+    }
+}
+
+/// A four-operator FM voice
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FmVoice {
+    pub alg: u8,
+    pub fb: u8,
+    /// Amplitude modulation sensitivity
+    pub ams: u8,
+    /// Frequency modulation sensitivity
+    pub fms: u8,
+    /// Operators in register order (1, 3, 2, 4)
+    pub ops: [FmOperator; 4],
+}
+
+impl FmVoice {
+    /// Decode an OPN2 `@x` macro, or None if it is too short to be a voice
+    pub fn from_opn2(data: &[i16]) -> Option<Self> {
+        if data.len() < OPN2_VOICE_LEN {
+            return None;
+        }
+        let b = |i: usize| data[i] as u8;
+        let mut voice = FmVoice {
+            alg: b(28) & 7,
+            fb: (b(28) >> 3) & 7,
+            ams: (b(29) >> 4) & 3,
+            fms: b(29) & 7,
+            ..Default::default()
+        };
+        for (i, op) in voice.ops.iter_mut().enumerate() {
+            let r = |j: usize| b(i * 7 + j);
+            *op = FmOperator {
+                dt: (r(0) >> 4) & 7,
+                mul: r(0) & 15,
+                tl: r(1) & 127,
+                ks: r(2) >> 6,
+                ar: r(2) & 31,
+                am: r(3) & 0x80 != 0,
+                dr: r(3) & 31,
+                sr: r(4) & 31,
+                sl: r(5) >> 4,
+                rr: r(5) & 15,
+                ssg_eg: r(6) & 15,
+            };
+        }
+        Some(voice)
+    }
+
+    /// Encode as an OPN2 `@x` macro
+    pub fn to_opn2(&self) -> Vec<i16> {
+        let mut data = Vec::with_capacity(OPN2_VOICE_LEN);
+        for op in &self.ops {
+            data.extend_from_slice(&[
+                (op.dt << 4 | op.mul) as i16,
+                op.tl as i16,
+                (op.ks << 6 | op.ar) as i16,
+                ((op.am as u8) << 7 | op.dr) as i16,
+                op.sr as i16,
+                (op.sl << 4 | op.rr) as i16,
+                op.ssg_eg as i16,
+            ]);
+        }
+        data.push((self.fb << 3 | self.alg) as i16);
+        data.push((self.ams << 4 | self.fms) as i16);
+        data
+    }
+}
+
+/// Instrument file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstrumentFormat {
+    /// VOPM bank
+    Opm,
+    /// DefleMask preset
+    Dmp,
+    /// Furnace instrument
+    Fui,
+}
+
+impl InstrumentFormat {
+    /// File extension
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Opm => "opm",
+            Self::Dmp => "dmp",
+            Self::Fui => "fui",
+        }
+    }
+}
+
+impl FromStr for InstrumentFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "opm" => Ok(Self::Opm),
+            "dmp" => Ok(Self::Dmp),
+            "fui" => Ok(Self::Fui),
+            _ => Err(format!("unknown instrument format '{}' (expected opm, dmp or fui)", s)),
+        }
+    }
+}
+
+impl fmt::Display for InstrumentFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+impl InstrumentFormat {
+    /// Format of a file, by extension (None for `.tfi` and unknown extensions)
+    fn of_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+fn instrument_error(path: &Path, message: &str) -> Error {
+    Error::Instrument(format!("{}: {}", path.display(), message))
+}
+
+/// Read a VOPM bank; `number` picks the `@:` entry, otherwise the first one is used
+pub fn parse_opm(text: &str, number: Option<usize>) -> Option<FmVoice> {
+    let mut voice = None;
+    for line in text.lines() {
+        let line = line.trim();
+        let Some((key, values)) = line.split_once(':') else {
+            continue;
+        };
+        if key == "@" {
+            let id = values.split_whitespace().next().and_then(|n| n.parse::<usize>().ok());
+            if voice.is_some() {
+                break;
+            }
+            if number.is_none() || id == number {
+                voice = Some(FmVoice::default());
+            }
+            continue;
+        }
+        let Some(voice) = voice.as_mut() else {
+            continue;
+        };
+        let v: Vec<u8> = values.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+        match key {
+            "CH" if v.len() >= 5 => {
+                voice.fb = v[1] & 7;
+                voice.alg = v[2] & 7;
+                voice.ams = v[3] & 3;
+                voice.fms = v[4] & 7;
+            }
+            "M1" | "C1" | "M2" | "C2" if v.len() >= 11 => {
+                let slot = ["M1", "C1", "M2", "C2"].iter().position(|&k| k == key).unwrap();
+                voice.ops[OPM_ORDER[slot]] = FmOperator {
+                    ar: v[0] & 31,
+                    dr: v[1] & 31,
+                    sr: v[2] & 31,
+                    rr: v[3] & 15,
+                    sl: v[4] & 15,
+                    tl: v[5] & 127,
+                    ks: v[6] & 3,
+                    mul: v[7] & 15,
+                    dt: v[8] & 7,
+                    am: v[10] != 0,
+                    ssg_eg: 0,
+                };
+            }
+            _ => {}
+        }
+    }
+    voice
+}
+
+/// Read a DefleMask Genesis FM preset (version 11)
+pub fn parse_dmp(data: &[u8]) -> Option<FmVoice> {
+    if data.len() < 7 + 4 * 11 || data[0] != 11 || data[2] != 1 {
+        return None;
+    }
+    let mut voice = FmVoice { fms: data[3] & 7, fb: data[4] & 7, alg: data[5] & 7, ams: data[6] & 3, ..Default::default() };
+    for (&i, b) in OPM_ORDER.iter().zip(data[7..].chunks(11)) {
+        let op = &mut voice.ops[i];
+        *op = FmOperator {
+            mul: b[0] & 15,
+            tl: b[1] & 127,
+            ar: b[2] & 31,
+            dr: b[3] & 31,
+            sl: b[4] & 15,
+            rr: b[5] & 15,
+            am: b[6] != 0,
+            ks: b[7] & 3,
+            sr: b[9] & 31,
+            ssg_eg: b[10] & 15,
+            dt: 0,
+        };
+        op.set_centered_detune(b[8] & 15);
+    }
+    Some(voice)
+}
+
+/// Read the FM feature of a Furnace `FINS` instrument
+pub fn parse_fui(data: &[u8]) -> Option<FmVoice> {
+    if !data.starts_with(b"FINS") {
+        return None;
+    }
+    let mut pos = 8;
+    while pos + 4 <= data.len() {
+        let code = &data[pos..pos + 2];
+        let len = u16::from_le_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let body = data.get(pos + 4..pos + 4 + len)?;
+        pos += 4 + len;
+        if code == b"EN" {
+            break;
+        }
+        if code != b"FM" {
+            continue;
+        }
+
+        // The base data grew over format versions; operators are at the end
+        let ops = (*body.first()? & 15) as usize;
+        if ops != 4 || body.len() < 1 + 2 + 4 * 8 {
+            return None;
+        }
+        let op_start = body.len() - 4 * 8;
+        let mut voice = FmVoice {
+            alg: (body[1] >> 4) & 7,
+            fb: body[1] & 7,
+            ams: (body[2] >> 3) & 3,
+            fms: body[2] & 7,
+            ..Default::default()
+        };
+        for (&i, b) in OPM_ORDER.iter().zip(body[op_start..].chunks(8)) {
+            let op = &mut voice.ops[i];
+            *op = FmOperator {
+                mul: b[0] & 15,
+                tl: b[1] & 127,
+                ks: b[2] >> 6,
+                ar: b[2] & 31,
+                am: b[3] & 0x80 != 0,
+                dr: b[3] & 31,
+                sr: b[4] & 31,
+                sl: b[5] >> 4,
+                rr: b[5] & 15,
+                ssg_eg: b[6] & 15,
+                dt: 0,
+            };
+            op.set_centered_detune((b[0] >> 4) & 7);
+        }
+        return Some(voice);
+    }
+    None
+}
+
+/// Read a TFM Music Maker instrument
+pub fn parse_tfi(data: &[u8]) -> Option<FmVoice> {
+    if data.len() < 2 + 4 * 10 {
+        return None;
+    }
+    let mut voice = FmVoice { alg: data[0] & 7, fb: data[1] & 7, ..Default::default() };
+    for (&i, b) in OPM_ORDER.iter().zip(data[2..].chunks(10)) {
+        let op = &mut voice.ops[i];
+        *op = FmOperator {
+            mul: b[0] & 15,
+            tl: b[2] & 127,
+            ks: b[3] & 3,
+            ar: b[4] & 31,
+            dr: b[5] & 31,
+            sr: b[6] & 31,
+            rr: b[7] & 15,
+            sl: b[8] & 15,
+            ssg_eg: b[9] & 15,
+            am: false,
+            dt: 0,
+        };
+        op.set_centered_detune(b[1]);
+    }
+    Some(voice)
+}
+
+/// Load a voice from an instrument file, picking the format by extension
+///
+/// `number` selects the `@:` entry of a VOPM bank.
+pub fn load(path: &Path, number: Option<usize>) -> Result<FmVoice> {
+    let is_tfi = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("tfi"));
+    let voice = match InstrumentFormat::of_path(path) {
+        Some(InstrumentFormat::Opm) => parse_opm(&std::fs::read_to_string(path)?, number),
+        Some(InstrumentFormat::Dmp) => parse_dmp(&std::fs::read(path)?),
+        Some(InstrumentFormat::Fui) => parse_fui(&std::fs::read(path)?),
+        None if is_tfi => parse_tfi(&std::fs::read(path)?),
+        None => return Err(instrument_error(path, "unknown instrument format (expected .opm, .dmp, .fui or .tfi)")),
+    };
+    voice.ok_or_else(|| instrument_error(path, "no FM voice found"))
+}
+
+/// Every `@x` macro that holds an OPN2 voice, by macro number
+pub fn fm_voices(macro_env: &MacroEnvStorage) -> Vec<(usize, FmVoice)> {
+    macro_env[MacroType::Option as usize]
+        .iter()
+        .enumerate()
+        .filter_map(|(id, env)| FmVoice::from_opn2(&env.data).map(|voice| (id, voice)))
+        .collect()
+}
+
+/// Write voices as a VOPM bank
+pub fn write_opm<W: Write>(voices: &[(usize, FmVoice)], out: &mut W) -> io::Result<()> {
+    writeln!(out, "//MiOPMdrv sound bank Paramer Ver2002.04.22")?;
+    writeln!(out, "//LFO: LFRQ AMD PMD WF NFRQ")?;
+    writeln!(out, "//@:[Num] [Name]")?;
+    writeln!(out, "//CH: PAN\tFL CON AMS PMS SLOT NE")?;
+    writeln!(out, "//[OPname]:\tAR D1R D2R\tRR D1L\tTL\tKS MUL DT1 DT2 AMS-EN")?;
+    for (id, voice) in voices {
+        writeln!(out)?;
+        writeln!(out, "@:{} @x{}", id, id)?;
+        writeln!(out, "LFO:  0   0   0   0   0")?;
+        writeln!(
+            out,
+            "CH: 64 {:3} {:3} {:3} {:3} 120   0",
+            voice.fb, voice.alg, voice.ams, voice.fms
+        )?;
+        for (name, &i) in ["M1", "C1", "M2", "C2"].iter().zip(&OPM_ORDER) {
+            let op = &voice.ops[i];
+            writeln!(
+                out,
+                "{}: {:3} {:3} {:3} {:3} {:3} {:3} {:3} {:3} {:3}   0 {:3}",
+                name,
+                op.ar,
+                op.dr,
+                op.sr,
+                op.rr,
+                op.sl,
+                op.tl,
+                op.ks,
+                op.mul,
+                op.dt,
+                if op.am { 128 } else { 0 }
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Encode a voice as a DefleMask Genesis FM preset (version 11)
+pub fn dmp(voice: &FmVoice) -> Vec<u8> {
+    let mut data = vec![11, 0x02, 1, voice.fms, voice.fb, voice.alg, voice.ams];
+    for op in OPM_ORDER.map(|i| &voice.ops[i]) {
+        data.extend_from_slice(&[
+            op.mul,
+            op.tl,
+            op.ar,
+            op.dr,
+            op.sl,
+            op.rr,
+            op.am as u8,
+            op.ks,
+            (op.detune() + 3) as u8,
+            op.sr,
+            op.ssg_eg,
+        ]);
+    }
+    data
+}
+
+/// Encode a voice as a Furnace instrument
+pub fn fui(name: &str, voice: &FmVoice) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(b"FINS");
+    data.extend_from_slice(&143u16.to_le_bytes()); // format version
+    data.extend_from_slice(&1u16.to_le_bytes()); // instrument type: FM (OPN)
+
+    let mut feature = |code: &[u8; 2], body: &[u8]| {
+        data.extend_from_slice(code);
+        data.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        data.extend_from_slice(body);
+    };
+
+    let mut name_body = name.as_bytes().to_vec();
+    name_body.push(0);
+    feature(b"NA", &name_body);
+
+    // All four operators enabled, then ALG/FB, AMS/FMS and the (unused) OPL byte
+    let mut fm = vec![0xF4, (voice.alg << 4) | voice.fb, (voice.ams << 3) | voice.fms, 0];
+    for op in OPM_ORDER.map(|i| &voice.ops[i]) {
+        fm.extend_from_slice(&[
+            ((op.detune() + 3) as u8) << 4 | op.mul,
+            op.tl,
+            op.ks << 6 | op.ar,
+            (op.am as u8) << 7 | op.dr,
+            2 << 5 | op.sr, // KVS 2: volume scales carriers only
+            op.sl << 4 | op.rr,
+            op.ssg_eg,
+            0,
+        ]);
+    }
+    feature(b"FM", &fm);
+    feature(b"EN", &[]);
+    data
+}
+
+/// Write the FM voices of a parsed song next to `stem` in `dir`, returning the files written
+///
+/// VOPM gets one bank `<stem>.opm`; the other formats get one `<stem>.<n>.<ext>` per `@x<n>`.
+pub fn export(macro_env: &MacroEnvStorage, dir: &Path, stem: &str, format: InstrumentFormat) -> Result<Vec<PathBuf>> {
+    let voices = fm_voices(macro_env);
+    let mut written = Vec::new();
+    if voices.is_empty() {
+        return Ok(written);
+    }
+
+    match format {
+        InstrumentFormat::Opm => {
+            let path = dir.join(format!("{}.opm", stem));
+            let mut out = io::BufWriter::new(std::fs::File::create(&path)?);
+            write_opm(&voices, &mut out)?;
+            out.flush()?;
+            written.push(path);
+        }
+        InstrumentFormat::Dmp | InstrumentFormat::Fui => {
+            for (id, voice) in &voices {
+                let path = dir.join(format!("{}.{}.{}", stem, id, format.extension()));
+                let data = match format {
+                    InstrumentFormat::Dmp => dmp(voice),
+                    _ => fui(&format!("@x{}", id), voice),
+                };
+                std::fs::write(&path, data)?;
+                written.push(path);
+            }
+        }
+    }
+    Ok(written)
+}
@@ -0,0 +1,113 @@
+//! Channel MML lints
+//!
+//! Checks a channel's token stream for text that compiles but is probably
+//! a mistake: commands after `!`, loops and tuplets that are never closed
+//! and loops that do not repeat. Conditions are checked by the compiler
+//! before they are applied. Each finding has a stable diagnostic code and
+//! a byte range in the channel text, which the compiler turns into a
+//! channel, line and columns.
+
+use super::diagnostic::code;
+use super::token::{Span, Token, TokenKind};
+
+/// A finding in the channel text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// Stable diagnostic code
+    pub code: &'static str,
+    /// Byte range in the channel text
+    pub span: Span,
+    pub message: String,
+    /// How to fix it
+    pub suggestion: String,
+}
+
+impl Lint {
+    fn new(code: &'static str, span: Span, message: String, suggestion: &str) -> Self {
+        Self {
+            code,
+            span,
+            message,
+            suggestion: suggestion.to_string(),
+        }
+    }
+}
+
+/// The MML text of a token, for quoting in messages
+fn quote<'a>(text: &'a str, token: &Token) -> &'a str {
+    text.get(token.span.start..token.span.end).unwrap_or("").trim_end()
+}
+
+/// Lint the structure of the commands a channel plays
+///
+/// `tokens` are the channel's tokens after its conditions were applied and
+/// `text` the channel text their spans point into.
+pub fn check_structure(text: &str, tokens: &[Token]) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let mut loops: Vec<Span> = Vec::new();
+    let mut tuplets: Vec<Span> = Vec::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Stop => {
+                if let (Some(next), Some(last)) = (tokens.get(i + 1), tokens.last()) {
+                    lints.push(Lint::new(
+                        code::UNREACHABLE_AFTER_STOP,
+                        Span { start: next.span.start, end: last.span.end },
+                        format!("`{}` is after `!` and is never played", quote(text, next)),
+                        "remove the commands after `!`, or move `!` to the end of the channel",
+                    ));
+                }
+                // Nothing after the stop is compiled, so nothing there is left open
+                return lints;
+            }
+            TokenKind::LoopStart => loops.push(token.span),
+            TokenKind::LoopEnd(repeat) => {
+                if loops.pop().is_none() {
+                    lints.push(Lint::new(
+                        code::UNMATCHED_LOOP_END,
+                        token.span,
+                        format!("`{}` has no matching `[`", quote(text, token)),
+                        "add `[` where the repeated part starts, or remove the `]`",
+                    ));
+                } else if repeat <= 1 {
+                    lints.push(Lint::new(
+                        code::SINGLE_PASS_LOOP,
+                        token.span,
+                        format!("loop `{}` plays its body only once; write `]2` or more to repeat", quote(text, token)),
+                        "use `]2` or more, or remove the brackets",
+                    ));
+                }
+            }
+            TokenKind::TupletStart => tuplets.push(token.span),
+            TokenKind::TupletEnd if tuplets.pop().is_none() => {
+                lints.push(Lint::new(
+                    code::UNMATCHED_TUPLET_END,
+                    token.span,
+                    "`}` has no matching `{`".to_string(),
+                    "add `{` where the tuplet starts, or remove the `}`",
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    for span in loops {
+        lints.push(Lint::new(
+            code::UNTERMINATED_LOOP,
+            span,
+            "unterminated loop `[`, close it with `]N`".to_string(),
+            "add `]2` (or another repeat count) after the repeated part",
+        ));
+    }
+    for span in tuplets {
+        lints.push(Lint::new(
+            code::UNTERMINATED_TUPLET,
+            span,
+            "unterminated tuplet `{`, close it with `}`".to_string(),
+            "add `}` after the last note of the tuplet",
+        ));
+    }
+    lints.sort_by_key(|lint| lint.span.start);
+    lints
+}
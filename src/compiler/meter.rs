@@ -0,0 +1,114 @@
+//! Time signature (`#METER`) and measure:beat positions
+//!
+//! Diagnostics about channel text and `--dump-events` say where in the music
+//! something happens as `measure:beat`, both counted from 1, with the part of
+//! a beat after it in decimals: `3:2.5` is halfway through the second beat of
+//! the third measure. A channel's musical time is its running time in samples
+//! times its tempo, so a `t` change keeps the beats on the notes; the tempo
+//! changes of each channel are kept in a `TempoMap` to place its events.
+
+/// Musical time of a whole note: samples at 1 BPM (44100 * 60 * 4)
+pub const WHOLE_NOTE: i64 = 10584000;
+
+/// Beats per measure and the note value of a beat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Meter {
+    pub beats: u32,
+    /// 4 for quarter-note beats, 8 for eighths, ...
+    pub unit: u32,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self { beats: 4, unit: 4 }
+    }
+}
+
+impl Meter {
+    /// Parse `beats/unit`, e.g. `3/4` or `6/8`
+    pub fn parse(text: &str) -> Option<Self> {
+        let (beats, unit) = text.split_once('/')?;
+        let beats: u32 = beats.trim().parse().ok().filter(|&b| b > 0)?;
+        let unit: u32 = unit.trim().parse().ok().filter(|&u: &u32| u.is_power_of_two() && u <= 64)?;
+        Some(Self { beats, unit })
+    }
+
+    /// The `measure:beat` of a musical time
+    pub fn position(&self, time: i64) -> String {
+        let beat = WHOLE_NOTE / self.unit as i64;
+        // In hundredths of a beat, rounded so that note lengths cut to whole
+        // samples still land on their beat
+        let hundredths = (time.max(0) * 100 + beat / 2) / beat;
+        let measure = 100 * self.beats as i64;
+        let (number, within) = (hundredths / measure + 1, hundredths % measure);
+        let (beat, fraction) = (within / 100 + 1, within % 100);
+        match fraction {
+            0 => format!("{}:{}", number, beat),
+            f if f % 10 == 0 => format!("{}:{}.{}", number, beat, f / 10),
+            f => format!("{}:{}.{:02}", number, beat, f),
+        }
+    }
+}
+
+/// The tempo changes of a channel: from each time in samples on, the
+/// musical time there and the tempo
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    changes: Vec<(i64, i64, i64)>,
+}
+
+impl TempoMap {
+    pub fn new(tempo: i32) -> Self {
+        Self { changes: vec![(0, 0, tempo as i64)] }
+    }
+
+    /// Musical time at a time in samples
+    pub fn musical_time(&self, time: i64) -> i64 {
+        let index = self.changes.partition_point(|&(start, _, _)| start <= time).saturating_sub(1);
+        let (start, musical, tempo) = self.changes[index];
+        musical + (time - start) * tempo
+    }
+
+    /// Change the tempo from a time in samples on
+    pub fn set_tempo(&mut self, time: i64, tempo: i32) {
+        let musical = self.musical_time(time);
+        self.changes.retain(|&(start, _, _)| start < time);
+        self.changes.push((time, musical, tempo as i64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position() {
+        let meter = Meter::default();
+        let quarter = WHOLE_NOTE / 4;
+        assert_eq!(meter.position(0), "1:1");
+        assert_eq!(meter.position(quarter * 5), "2:2");
+        assert_eq!(meter.position(quarter * 5 + quarter / 2), "2:2.5");
+        assert_eq!(meter.position(quarter * 5 + quarter / 4), "2:2.25");
+        // A sample short of the beat at 150 BPM is on it
+        assert_eq!(meter.position(quarter * 5 - 150), "2:2");
+
+        let meter = Meter::parse("6/8").unwrap();
+        assert_eq!(meter.position(WHOLE_NOTE), "2:3");
+        assert_eq!(Meter::parse("3/5"), None);
+        assert_eq!(Meter::parse("0/4"), None);
+        assert_eq!(Meter::parse("4"), None);
+    }
+
+    #[test]
+    fn test_tempo_map() {
+        // Quarter notes take 22050 samples at 120 BPM and 11025 at 240
+        let mut map = TempoMap::new(120);
+        map.set_tempo(44100, 240);
+        let meter = Meter::default();
+        assert_eq!(meter.position(map.musical_time(22050)), "1:2");
+        assert_eq!(meter.position(map.musical_time(44100)), "1:3");
+        assert_eq!(meter.position(map.musical_time(44100 + 2 * 11025)), "2:1");
+        map.set_tempo(44100, 60);
+        assert_eq!(meter.position(map.musical_time(44100 + 44100)), "1:4");
+    }
+}
@@ -2,11 +2,32 @@
 //!
 //! This module closely follows the structure of the original vgmck.c
 
+pub mod ast;
+pub mod automation;
 pub mod channel;
+pub mod chord;
+pub mod conditional;
+pub mod diagnostic;
+pub mod directive;
 pub mod envelope;
 pub mod event;
+pub mod groove;
+pub mod heatmap;
+pub mod instlib;
+pub mod instrument;
+pub mod lint;
+pub mod meter;
 pub mod note;
+pub mod perf;
+pub mod remap;
+pub mod report;
+pub mod rng;
 pub mod sample;
+pub mod scaffold;
+pub mod session;
+pub mod text_macro;
+pub mod token;
+pub mod tuning;
 
 /// GD3 text field indices
 pub mod gd3 {
@@ -24,16 +45,33 @@ pub mod gd3 {
     pub const COUNT: usize = 11;
 }
 
-use crate::chips::{self, ChipInstance, ChipOptions, MacroCommand};
+use crate::chips::{self, ChipInstance, ChipOptions, MacroCommand, MacroResult, NoiseTarget, Sample, SampleMemory};
 use crate::error::{Error, Result};
-use envelope::{create_macro_env_storage, MacroEnvStorage, MacroType, MAX_MACRO_TYPES};
-use crate::vgm::VgmWriter;
-use channel::Channel;
-use event::{Event, EventData, EventQueue};
-use std::collections::HashMap;
+use envelope::{
+    create_macro_env_storage, ArpMode, MacroEnvStorage, MacroType, SampleLayer, VolumeMode,
+    MAX_MACRO_TYPES,
+};
+use crate::vgm::playback::PlaybackHints;
+use crate::vgm::{VgmDocument, VgmWriter};
+use channel::{Channel, ChannelDefaults, LineSegment};
+use conditional::Conditions;
+use diagnostic::{code, Diagnostic, DiagnosticFormat, Severity, SourceSpan};
+use event::{CompiledEvent, Event, EventData, EventQueue, EventRecord, EventSource};
+use groove::Groove;
+use heatmap::Heatmap;
+use meter::{Meter, TempoMap};
+use perf::PerfCounters;
+use report::{ChannelReport, CompileReport};
+use rng::Rng;
+use sample::{DacStream, PcmSample};
+use token::{Span, Token, TokenKind};
+use tuning::Quantization;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
 
 /// Number of available channels (A-Z = 26, a-z = 26)
 pub const MAX_CHANNELS: usize = 52;
@@ -41,12 +79,18 @@ pub const MAX_CHANNELS: usize = 52;
 /// Default frame rate (44100 / 60)
 pub const DEFAULT_FRAMERATE: i32 = 735;
 
+/// Default `#LOOP-LCM` cap on the loop length (ten minutes)
+pub const DEFAULT_LOOP_LCM_CAP: i64 = 600 * 44100;
+
 /// Main compiler state
 pub struct Compiler {
     /// Channel definitions
     pub channels: [Option<Channel>; MAX_CHANNELS],
     /// Chip instances by name
-    pub chips: HashMap<String, ChipInstance>,
+    pub chips: BTreeMap<String, ChipInstance>,
+    /// Chip names in the order their `#EX-` lines enabled them, which is the
+    /// order the chips are set up and written in
+    chip_order: Vec<String>,
     /// Event queue
     pub events: EventQueue,
     /// GD3 metadata text (indexed by gd3::* constants)
@@ -61,12 +105,16 @@ pub struct Compiler {
     pub framerate: i32,
     /// Base frequency for note calculation
     pub base_freq: f64,
-    /// Note frequencies for current scale
-    pub note_freq: [f64; 32],
+    /// Pitch of each step of the scale, as a ratio to the first
+    pub note_freq: Vec<f64>,
     /// Note letter mappings (a-j -> semitone offset)
     pub note_letter: [i32; 10],
     /// Calculated note values (set per-chip)
-    pub note_value: [i64; 32],
+    pub note_value: Vec<i64>,
+    /// Note values before rounding (set per-chip)
+    note_exact: Vec<f64>,
+    /// How note values are rounded (`#PITCH-QUANTIZE`)
+    pub quantization: Quantization,
     /// Notes per octave
     pub octave_count: i32,
     /// Volume modifier for VGM header
@@ -77,8 +125,10 @@ pub struct Compiler {
     pub loop_mod: u8,
     /// Recording rate for VGM header
     pub recording_rate: i32,
-    /// Text macros (*X definitions)
-    pub text_macros: [String; 128],
+    /// Text macros by name (`*X` and `*name =` definitions)
+    pub text_macros: HashMap<String, String>,
+    /// Named instruments (`@INST`): the macro commands `@"name"` plays
+    pub instruments: BTreeMap<String, Vec<(String, i64)>>,
     /// Macro envelopes
     pub macro_env: Box<MacroEnvStorage>,
     /// Currently active macro envelope indices per macro type
@@ -87,14 +137,146 @@ pub struct Compiler {
     pub fast_forward: i64,
     /// Portamento parameters
     pub portamento: [i64; 8],
+    /// Maximum length in frames of the slide between `&`-tied pitches (0 = instant change)
+    pub tie_slide: i64,
     /// Note off event mode
     pub note_off_event: i32,
     /// Sample list ID
     pub sample_list: i32,
+    /// Sample list accent level (selects the layer of tuple entries)
+    pub sample_accent: i32,
     /// Debug input lines flag
     pub debug_input_lines: bool,
+    /// Channels to duplicate onto the second PSG (channel index, detune, pan of the copy)
+    pub psg_widen: Vec<(usize, i64, i16)>,
+    /// Channels played again on the second chip with alternating pitch
+    /// offsets, and the offset in chip units (`#WIDE`)
+    pub wide: Vec<(usize, i64)>,
+    /// Automation lanes (`#AUTOMATE`)
+    pub automation: Vec<automation::Lane>,
+    /// Groove tables by number (`#GROOVE`)
+    pub grooves: BTreeMap<i64, Vec<i64>>,
+    /// `#WAV` samples by number, streamed to chip DACs with `@s`
+    pub wav_samples: BTreeMap<u8, PcmSample>,
+    /// Chips whose DAC stream plays `#WAV` samples, in stream number order
+    dac_chips: BTreeSet<String>,
+    /// Mono output: all panning is forced to center
+    pub mono: bool,
+    /// `#LOOP-LCM`: longest loop (in samples) the channels' loops may be
+    /// extended to so that each plays in full
+    pub loop_lcm: Option<i64>,
+    /// Whether repeats in the loop section are written out (`#LOOP-MODE`)
+    pub loop_mode: LoopMode,
+    /// Loop count and fade for players (`#LOOP-COUNT`, `#FADE-HINT`)
+    pub playback: PlaybackHints,
+    /// `@fade`: start and length in samples of the fade-out ending the song
+    pub fade: Option<(i64, i64)>,
+    /// How `v` and `@v` values map to chip volumes (`#VOLUME-MODE`)
+    pub volume_mode: VolumeMode,
+    /// Sound effects compiled to their own files instead of the song (`#SFX name = channels`)
+    pub sfx: Vec<(String, Vec<usize>)>,
+    /// Gzip the output (VGZ) even when its name does not end in `.vgz`
+    pub gzip: bool,
+    /// Drop redundant register writes and join waits (`--optimize`)
+    pub optimize: bool,
+    /// Whether vgmck's signature goes into the GD3 converter field
+    pub converter_signature: ConverterSignature,
+    /// Lyric events of all channels, as (time in samples, text)
+    pub lyrics: Vec<(i64, String)>,
+    /// Write lyrics to a side-car `.lrc` file instead of the GD3 notes (`#LYRICS LRC`)
+    pub lyrics_file: bool,
+    /// Channels with an `@echo`, with its depth and delay in samples
+    echoes: BTreeMap<usize, (i16, i64)>,
+    /// Song to compile from input with `#TRACK` sections (the first one if None)
+    pub track: Option<u32>,
+    /// Numbers of the `#TRACK` sections read, in input order
+    pub tracks: Vec<u32>,
+    /// The `#TRACK` section being read
+    current_track: Option<u32>,
+    /// Names defined for `#IFDEF`, by `define()` and `#DEFINE`
+    pub defines: BTreeSet<String>,
+    /// Names given to `define()`, for the #SFX and stem compilers
+    predefined: Vec<String>,
+    /// Channel letters read as other channels in the lines that follow (`#REMAP`)
+    pub channel_remap: HashMap<char, char>,
+    /// Channels that lines of a channel letter play on, splitting chords
+    /// between them (`#GROUP`)
+    channel_groups: HashMap<char, Vec<usize>>,
+    /// The `#REMAP` in force where the current file was included, which the
+    /// file's own `#REMAP` lines apply on top of
+    remap_base: HashMap<char, char>,
+    /// Channels each text macro was used on, for the octave check
+    pub text_macro_channels: HashMap<String, Vec<usize>>,
+    /// Shift each chip's octaves to standard pitch (`#NORMALIZE-OCTAVE`)
+    pub normalize_octave: bool,
+    /// Octaves added to the notes of each chip, by chip name
+    octave_shifts: HashMap<String, i32>,
+    /// The random number generator every random feature draws from
+    rng: Rng,
+    /// Fail instead of writing output that used random numbers without a seed
+    pub reproducible: bool,
+    /// Starting settings for every channel (`#DEFAULT`)
+    pub defaults: ChannelDefaults,
+    /// Per-channel starting settings (`#DEFAULT X`), overriding `defaults`
+    pub channel_defaults: [ChannelDefaults; MAX_CHANNELS],
+    /// Samples each channel's events are shifted by (`#LATENCY`)
+    pub latency: [i64; MAX_CHANNELS],
+    /// Semitones every channel's notes are transposed by (`#TRANSPOSE`)
+    pub transpose: i32,
+    /// Semitones each channel's notes are transposed by on top of
+    /// `transpose` (`#TRANSPOSE X`)
+    pub channel_transpose: [i32; MAX_CHANNELS],
+    /// Time signature that diagnostics and `--dump-events` count measures
+    /// and beats in (`#METER`)
+    pub meter: Meter,
+    /// Tempo changes of each compiled channel, to place its events in measures
+    tempo_maps: BTreeMap<usize, TempoMap>,
+    /// End and musical time of each token of the channel being compiled
+    /// when it is first reached, by offset in its text
+    beat_marks: BTreeMap<usize, (usize, i64)>,
+    /// Diagnostics of the channel being compiled, held back until the musical
+    /// time of its text is known, with the text offset of those that have one
+    channel_diagnostics: Option<Vec<(Diagnostic, Option<usize>)>>,
+    /// Channels and macros already warned about as unsupported by the chip
+    unsupported_macros: HashSet<(usize, MacroCommand)>,
+    /// Warnings collected during compilation (also printed to stderr)
+    pub warnings: Vec<String>,
+    /// The warnings with their codes and locations
+    pub diagnostics: Vec<Diagnostic>,
+    /// How warnings are printed to stderr
+    pub diagnostics_format: DiagnosticFormat,
+    /// Files read besides the main input (`#INCLUDE`, `#CHANNEL-FILE`), for rebuilding when they change
+    pub dependencies: Vec<PathBuf>,
+    /// Text of files given in memory, by the path `#INCLUDE` and
+    /// `#CHANNEL-FILE` resolve to; read instead of the file system
+    pub include_files: HashMap<PathBuf, String>,
+    /// Print the statistics report to stdout
+    pub report: bool,
+    /// Statistics of the last file built
+    compile_report: CompileReport,
+    /// Time spent in each phase of the last compilation
+    perf: PerfCounters,
+    /// Sample memory of each chip that uses samples, in declaration order
+    sample_memory: Vec<(&'static str, SampleMemory)>,
+    /// Directory to render one solo WAV file per channel into (`--render-stems`)
+    #[cfg(feature = "render")]
+    pub stems_dir: Option<PathBuf>,
+    /// The #SFX output this compiler writes (name and channels), instead of the song
+    sfx_output: Option<(String, Vec<usize>)>,
+    /// The channel a stem output plays; the events of all others are muted
+    solo: Option<usize>,
+    /// Collect warnings without printing them (used for #SFX outputs and the C interface)
+    pub(crate) silent: bool,
+    /// Turn the warnings in `code::STRICT` into errors (`--strict`)
+    strict: bool,
+    /// The first error, returned once compilation has gone on to find the rest
+    first_error: Option<Diagnostic>,
     /// Base path for resolving #INCLUDE paths
     base_path: Option<PathBuf>,
+    /// Line number of the input line being parsed
+    current_line: usize,
+    /// File being parsed (None for standard input)
+    current_file: Option<PathBuf>,
 
     // Envelope parsing state (static in original)
     env_mac: i32,
@@ -103,18 +285,19 @@ pub struct Compiler {
     env_rep: i32,
     env_brep: [i32; 32],
     env_bst: [i32; 32],
+
+    /// `@INST` block being read
+    instrument_block: Option<instlib::Block>,
+    /// Envelopes defined in `@INST` blocks, by type and number
+    instrument_envelopes: HashSet<(usize, usize)>,
+    /// Reading an `#INSTLIB` file, which holds only `@INST` blocks
+    reading_library: bool,
 }
 
 impl Compiler {
     pub fn new() -> Self {
-        let mut note_freq = [0.0; 32];
         // Initialize equal temperament (12-TET)
-        for i in 0..12 {
-            note_freq[i] = 2.0_f64.powf(i as f64 / 12.0);
-        }
-        for i in 12..32 {
-            note_freq[i] = 1.99999;
-        }
+        let note_freq = (0..12).map(|i| 2.0_f64.powf(i as f64 / 12.0)).collect();
 
         // Base frequency: C8 = 3520 * 2^(3/12) Hz
         let base_freq = 3520.0 * 2.0_f64.powf(3.0 / 12.0);
@@ -124,7 +307,8 @@ impl Compiler {
 
         Self {
             channels: std::array::from_fn(|_| None),
-            chips: HashMap::new(),
+            chips: BTreeMap::new(),
+            chip_order: Vec::new(),
             events: EventQueue::new(),
             gd3_text: std::array::from_fn(|_| String::new()),
             total_samples: 0,
@@ -134,82 +318,1091 @@ impl Compiler {
             base_freq,
             note_freq,
             note_letter,
-            note_value: [0; 32],
+            note_value: Vec::new(),
+            note_exact: Vec::new(),
+            quantization: Quantization::RoundDown,
             octave_count: 12,
             volume_mod: 0,
             loop_base: 0,
             loop_mod: 0,
             recording_rate: 0,
-            text_macros: std::array::from_fn(|_| String::new()),
+            text_macros: HashMap::new(),
+            instruments: BTreeMap::new(),
             macro_env: create_macro_env_storage(),
             macro_use: [-1; MAX_MACRO_TYPES],
             fast_forward: 0,
             portamento: [0; 8],
+            tie_slide: 0,
             note_off_event: 0,
             sample_list: -1,
+            sample_accent: 0,
             debug_input_lines: false,
+            psg_widen: Vec::new(),
+            wide: Vec::new(),
+            automation: Vec::new(),
+            grooves: BTreeMap::new(),
+            wav_samples: BTreeMap::new(),
+            dac_chips: BTreeSet::new(),
+            mono: false,
+            loop_lcm: None,
+            loop_mode: LoopMode::default(),
+            playback: PlaybackHints::default(),
+            fade: None,
+            volume_mode: VolumeMode::Absolute,
+            sfx: Vec::new(),
+            gzip: false,
+            optimize: false,
+            converter_signature: ConverterSignature::default(),
+            lyrics: Vec::new(),
+            lyrics_file: false,
+            echoes: BTreeMap::new(),
+            track: None,
+            tracks: Vec::new(),
+            current_track: None,
+            defines: BTreeSet::new(),
+            predefined: Vec::new(),
+            channel_remap: HashMap::new(),
+            channel_groups: HashMap::new(),
+            remap_base: HashMap::new(),
+            text_macro_channels: HashMap::new(),
+            normalize_octave: false,
+            octave_shifts: HashMap::new(),
+            rng: Rng::new(),
+            reproducible: false,
+            defaults: ChannelDefaults::default(),
+            channel_defaults: [ChannelDefaults::default(); MAX_CHANNELS],
+            latency: [0; MAX_CHANNELS],
+            transpose: 0,
+            channel_transpose: [0; MAX_CHANNELS],
+            meter: Meter::default(),
+            tempo_maps: BTreeMap::new(),
+            beat_marks: BTreeMap::new(),
+            channel_diagnostics: None,
+            unsupported_macros: HashSet::new(),
+            warnings: Vec::new(),
+            diagnostics: Vec::new(),
+            diagnostics_format: DiagnosticFormat::Text,
+            dependencies: Vec::new(),
+            report: true,
+            compile_report: CompileReport::default(),
+            perf: PerfCounters::default(),
+            sample_memory: Vec::new(),
+            include_files: HashMap::new(),
+            #[cfg(feature = "render")]
+            stems_dir: None,
+            sfx_output: None,
+            solo: None,
+            silent: false,
+            strict: false,
+            first_error: None,
             base_path: None,
+            current_line: 0,
+            current_file: None,
             env_mac: -1,
             env_id: 0,
             env_block: 0,
             env_rep: 1,
             env_brep: [0; 32],
             env_bst: [0; 32],
+            instrument_block: None,
+            instrument_envelopes: HashSet::new(),
+            reading_library: false,
         }
     }
 
     /// Compile MML input to VGM output
-    pub fn compile<R: Read>(&mut self, input: R, output: &Path) -> Result<()> {
-        // Parse input
+    pub fn compile<R: Read>(&mut self, mut input: R, output: &Path) -> Result<()> {
+        // Parse input, keeping the text for #SFX outputs
+        let start = Instant::now();
+        let mut text = String::new();
+        input.read_to_string(&mut text)?;
+        self.read_input(text.as_bytes())?;
+        self.perf.parse = start.elapsed();
+
+        self.compile_and_write(output)?;
+        self.write_sfx(output, |compiler, path| compiler.compile(text.as_bytes(), path))?;
+        #[cfg(feature = "render")]
+        self.write_stems(output, |compiler, vgm| compiler.compile_to_writer(text.as_bytes(), vgm))?;
+        Ok(())
+    }
+
+    /// Compile MML input and write the VGM file to any writer (a memory
+    /// buffer, a network stream, ...) instead of a path
+    ///
+    /// The file is gzipped if `gzip` is set. Outputs that need a path of
+    /// their own (#SFX files, stems, `.lrc` lyrics) are not written.
+    pub fn compile_to_writer<R: Read, W: Write>(&mut self, input: R, output: W) -> Result<()> {
+        let start = Instant::now();
         self.read_input(input)?;
+        self.perf.parse = start.elapsed();
+        self.compile_parsed()?;
 
-        // Compile each channel
-        for i in 0..MAX_CHANNELS {
-            if self.channels[i].is_some() {
-                self.compile_channel(i)?;
+        let start = Instant::now();
+        let mut writer = VgmWriter::from_writer(output);
+        writer.set_compressed(self.gzip);
+        self.write_output(&mut writer)?;
+        self.perf.write = start.elapsed();
+        Ok(())
+    }
+
+    /// Compile MML input into a VGM file split into header, command data
+    /// and GD3 tag, for embedders that store or stream them separately
+    ///
+    /// Like `compile_to_writer`, outputs that need a path of their own are
+    /// not written, and `gzip` does not apply.
+    pub fn compile_to_document<R: Read>(&mut self, input: R) -> Result<VgmDocument> {
+        let start = Instant::now();
+        self.read_input(input)?;
+        self.perf.parse = start.elapsed();
+        self.compile_parsed()?;
+
+        let start = Instant::now();
+        let mut writer = VgmWriter::from_writer(std::io::sink());
+        let document = self.finish_output(&mut writer)?;
+        self.perf.write = start.elapsed();
+        Ok(document)
+    }
+
+    /// Compile a song built with the `ast` types and write VGM output
+    ///
+    /// The song is compiled from its MML rendering, so it behaves like the
+    /// same song written as text; a song whose text fields would not render
+    /// as written (see `Song::validate`) is an error.
+    pub fn compile_song(&mut self, song: &ast::Song, output: &Path) -> Result<()> {
+        song.validate().map_err(Error::InvalidSong)?;
+        self.compile(song.to_string().as_bytes(), output)
+    }
+
+    /// Compile MML input into events without writing a VGM file, for
+    /// embedders that drive their own playback from `events()`
+    pub fn compile_events<R: Read>(&mut self, input: R) -> Result<()> {
+        let start = Instant::now();
+        self.read_input(input)?;
+        self.perf.parse = start.elapsed();
+        self.compile_parsed()
+    }
+
+    /// Compiled events in time order, with the channel and chip they play on
+    pub fn events(&self) -> impl Iterator<Item = CompiledEvent<'_>> {
+        self.events.iter().map(|event| {
+            let channel = usize::try_from(event.channel).ok().filter(|&idx| self.channels[idx].is_some());
+            CompiledEvent {
+                time: event.time,
+                channel: channel.and_then(index_to_channel),
+                chip: channel.and_then(|idx| self.channels[idx].as_ref()).map(|c| c.chip_name.as_str()),
+                source: event.source,
+                data: &event.data,
             }
-        }
+        })
+    }
 
-        // Write output
-        let mut writer = VgmWriter::new(output)?;
-        self.write_output(&mut writer)?;
+    /// The compiled events in time order with their frame, measure:beat,
+    /// channel, chip and meaning (`--dump-events`)
+    pub fn dump_events(&self) -> Vec<EventRecord> {
+        const NOTE_NAMES: [&str; 12] = ["c", "c+", "d", "d+", "e", "f", "f+", "g", "g+", "a", "a+", "b"];
+        self.events()
+            .map(|event| {
+                let description = match event.source {
+                    // Name the note when the scale has the usual twelve steps
+                    EventSource::NoteOn { key, duration } if self.octave_count == 12 && key >= 0 => format!(
+                        "note on o{} {} (key {}) for {} samples",
+                        key / 12,
+                        NOTE_NAMES[key as usize % 12],
+                        key,
+                        duration
+                    ),
+                    source => source.to_string(),
+                };
+                let index = event.channel.and_then(Self::channel_index);
+                let tempo_map = index.and_then(|index| self.tempo_maps.get(&index));
+                EventRecord {
+                    time: event.time,
+                    frame: event.time / self.framerate.max(1) as i64,
+                    position: tempo_map.map(|map| self.meter.position(map.musical_time(event.time))),
+                    channel: event.channel,
+                    chip: event.chip.map(str::to_string),
+                    source: event.source,
+                    description,
+                }
+            })
+            .collect()
+    }
 
-        Ok(())
+    /// Events of each channel over time (`--heatmap`), in columns of
+    /// `frames_per_column` frames or, for 0, fitted to `heatmap::WIDTH`
+    pub fn heatmap(&self, frames_per_column: i64) -> Heatmap {
+        let channels: Vec<char> = (0..MAX_CHANNELS)
+            .filter(|&idx| self.channels[idx].is_some() && self.is_output_channel(idx))
+            .filter_map(index_to_channel)
+            .collect();
+        let events = self.events().filter_map(|event| Some((event.channel?, event.time)));
+        Heatmap::new(events, &channels, self.total_samples, self.framerate as i64, frames_per_column)
     }
 
     /// Compile MML file to VGM output
     ///
     /// This method sets the base path for resolving #INCLUDE directives.
     pub fn compile_file(&mut self, input: &Path, output: &Path) -> Result<()> {
+        self.parse_file(input)?;
+        self.compile_and_write(output)?;
+        self.write_sfx(output, |compiler, path| compiler.compile_file(input, path))?;
+        #[cfg(feature = "render")]
+        self.write_stems(output, |compiler, vgm| compiler.compile_file_to_writer(input, vgm))?;
+        Ok(())
+    }
+
+    /// Compile an MML file and write the VGM file to any writer
+    ///
+    /// #INCLUDE paths are resolved as by `compile_file`; otherwise this is
+    /// `compile_to_writer`, so outputs that need a path of their own are not
+    /// written.
+    pub fn compile_file_to_writer<W: Write>(&mut self, input: &Path, output: W) -> Result<()> {
+        self.parse_file(input)?;
+        self.compile_parsed()?;
+
+        let start = Instant::now();
+        let mut writer = VgmWriter::from_writer(output);
+        writer.set_compressed(self.gzip);
+        self.write_output(&mut writer)?;
+        self.perf.write = start.elapsed();
+        Ok(())
+    }
+
+    /// Parse and compile an MML file (with its #SFX channels) without
+    /// writing anything, collecting the diagnostics
+    pub fn check_file(&mut self, input: &Path) -> Result<()> {
+        self.parse_file(input)?;
+        self.compile_parsed()?;
+        let check = |compiler: &mut Compiler, _: &Path| {
+            compiler.parse_file(input)?;
+            compiler.compile_parsed()
+        };
+        self.write_sfx(Path::new(""), check)
+    }
+
+    /// Parse an MML file without compiling it, for tools that only need its definitions
+    ///
+    /// This method sets the base path for resolving #INCLUDE directives.
+    pub fn parse_file(&mut self, input: &Path) -> Result<()> {
         // Set base path for includes
         self.base_path = input.parent().map(|p| p.to_path_buf());
 
         // Read and parse input file
+        let start = Instant::now();
         self.read_input_from_path(input)?;
+        self.perf.parse = start.elapsed();
+        Ok(())
+    }
 
-        // Compile each channel
-        for i in 0..MAX_CHANNELS {
-            if self.channels[i].is_some() {
-                self.compile_channel(i)?;
+    /// Read the `#TRACK` numbers of an MML file without compiling it or
+    /// printing its warnings, for compiling every song of it (`--all`)
+    pub fn scan_tracks(&mut self, input: &Path) -> Result<Vec<u32>> {
+        self.silent = true;
+        self.parse_file(input)?;
+        Ok(self.tracks.clone())
+    }
+
+    /// Path of the file a song of several is written to: `song.vgm` becomes
+    /// `song_01.vgm` for `#TRACK 1`
+    pub fn track_path(output: &Path, track: u32) -> PathBuf {
+        let stem = output.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let ext = output.extension().map(|s| s.to_string_lossy()).unwrap_or("vgm".into());
+        output.with_file_name(format!("{}_{:02}.{}", stem, track, ext))
+    }
+
+    /// Lyrics in time order, with times relative to the start of the output
+    fn output_lyrics(&self) -> Vec<(u64, String)> {
+        let mut lyrics: Vec<(u64, String)> = self
+            .lyrics
+            .iter()
+            .map(|(time, text)| ((time - self.fast_forward).max(0) as u64, text.clone()))
+            .collect();
+        lyrics.sort_by_key(|&(time, _)| time);
+        lyrics
+    }
+
+    /// Path of the file an #SFX is written to: `song.vgm` becomes `song.NAME.vgm`
+    /// (and `song.vgz` becomes `song.NAME.vgz`)
+    pub fn sfx_path(output: &Path, name: &str) -> PathBuf {
+        let stem = output.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let ext = output.extension().map(|s| s.to_string_lossy()).unwrap_or("vgm".into());
+        output.with_file_name(format!("{}.{}.{}", stem, name, ext))
+    }
+
+    /// Compile each #SFX into its own file, from the same input as the song
+    fn write_sfx<F>(&mut self, output: &Path, compile: F) -> Result<()>
+    where
+        F: Fn(&mut Compiler, &Path) -> Result<()>,
+    {
+        if self.sfx_output.is_some() || self.solo.is_some() {
+            return Ok(());
+        }
+        for (name, channels) in self.sfx.clone() {
+            let mut sfx = Compiler::new();
+            sfx.report = false;
+            sfx.silent = true;
+            sfx.gzip = self.gzip;
+            sfx.optimize = self.optimize;
+            sfx.converter_signature = self.converter_signature;
+            sfx.track = self.track;
+            sfx.reproducible = self.reproducible;
+            sfx.strict = self.strict;
+            sfx.include_files = self.include_files.clone();
+            self.predefined.iter().for_each(|name| sfx.define(name));
+            if self.rng.is_seeded() {
+                sfx.rng = self.rng.clone();
             }
+            sfx.sfx_output = Some((name.clone(), channels));
+            compile(&mut sfx, &Self::sfx_path(output, &name))?;
+
+            // Warnings about the shared definitions were already given for the song
+            for diagnostic in sfx.diagnostics {
+                if !self.warnings.contains(&diagnostic.to_string()) {
+                    let message = format!("#SFX {}: {}", name, diagnostic.message);
+                    self.emit(Diagnostic { message, ..diagnostic });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Path of a channel's stem: `song.vgm` becomes `DIR/song.A.wav`
+    #[cfg(feature = "render")]
+    pub fn stem_path(dir: &Path, output: &Path, channel: char) -> PathBuf {
+        let name = Self::sfx_path(output, &channel.to_string()).with_extension("wav");
+        dir.join(name.file_name().unwrap_or_default())
+    }
+
+    /// Compile each song channel on its own, with the events of every other
+    /// channel muted, and render it to a WAV file in `stems_dir`
+    ///
+    /// All channels are still compiled, so tempo, length and loop point are
+    /// the same in every stem.
+    #[cfg(feature = "render")]
+    fn write_stems<F>(&mut self, output: &Path, compile: F) -> Result<()>
+    where
+        F: Fn(&mut Compiler, &mut Vec<u8>) -> Result<()>,
+    {
+        use crate::vgm::render;
+
+        let Some(dir) = self.stems_dir.clone() else {
+            return Ok(());
+        };
+        std::fs::create_dir_all(&dir)?;
+        let channels: Vec<usize> = (0..MAX_CHANNELS)
+            .filter(|&i| self.channels[i].as_ref().is_some_and(|c| c.source.is_none()))
+            .filter(|&i| self.is_output_channel(i))
+            .collect();
+        let mut silent_chips = Vec::new();
+        for chan_idx in channels {
+            let mut stem = Compiler::new();
+            stem.report = false;
+            stem.silent = true;
+            stem.optimize = self.optimize;
+            stem.converter_signature = self.converter_signature;
+            stem.track = self.track;
+            stem.reproducible = self.reproducible;
+            stem.include_files = self.include_files.clone();
+            self.predefined.iter().for_each(|name| stem.define(name));
+            if self.rng.is_seeded() {
+                stem.rng = self.rng.clone();
+            }
+            stem.solo = Some(chan_idx);
+            let mut vgm = Vec::new();
+            compile(&mut stem, &mut vgm)?;
+            let rendered = render::render_bytes(&vgm)?;
+            let ch = index_to_channel(chan_idx).unwrap_or('?');
+            let wav = File::create(Self::stem_path(&dir, output, ch))?;
+            render::write_wav(&rendered.samples, std::io::BufWriter::new(wav))?;
+            silent_chips = rendered.silent_chips;
+        }
+        for chip in silent_chips {
+            self.warn(format!("no preview core for {}; it is silent in the stems", chip));
         }
+        Ok(())
+    }
+
+    /// Mute the events of every channel but one, as in its stem
+    pub fn set_solo(&mut self, channel: char) -> Result<()> {
+        self.solo = Some(channel_index(channel)?);
+        Ok(())
+    }
+
+    /// Whether the events of a channel are muted in this output (a stem of another channel)
+    fn is_muted(&self, chan_idx: usize) -> bool {
+        let chan_idx = self.channels[chan_idx].as_ref().and_then(|c| c.source).unwrap_or(chan_idx);
+        self.solo.is_some_and(|solo| solo != chan_idx)
+    }
+
+    /// Whether a channel belongs in this output: the song leaves out #SFX
+    /// channels, and an #SFX output has only its own
+    fn is_output_channel(&self, chan_idx: usize) -> bool {
+        let chan_idx = self.channels[chan_idx].as_ref().and_then(|c| c.source).unwrap_or(chan_idx);
+        match &self.sfx_output {
+            Some((_, channels)) => channels.contains(&chan_idx),
+            None => !self.sfx.iter().any(|(_, channels)| channels.contains(&chan_idx)),
+        }
+    }
+
+    /// Seed the random number generator, making random features reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::with_seed(seed);
+    }
+
+    /// Make unknown directives and commands, undefined envelopes and
+    /// out-of-range values errors instead of warnings
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Define a name for `#IFDEF` before compiling, like `#DEFINE` (`-D` on the command line)
+    pub fn define(&mut self, name: &str) {
+        self.defines.insert(name.to_string());
+        self.predefined.push(name.to_string());
+    }
+
+    /// The random number generator; features that vary their output must use it
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    /// Time spent in each phase of the last compilation
+    pub fn perf(&self) -> &PerfCounters {
+        &self.perf
+    }
+
+    /// Statistics of the last VGM file built: notes and length of each
+    /// channel, bytes by chip, register writes and the longest wait
+    pub fn compile_report(&self) -> &CompileReport {
+        &self.compile_report
+    }
+
+    /// Sample memory taken on each chip that uses samples, by chip name,
+    /// after the last compilation
+    pub fn sample_memory(&self) -> &[(&'static str, SampleMemory)] {
+        &self.sample_memory
+    }
+
+    /// Compile the parsed channels into the event queue
+    fn compile_parsed(&mut self) -> Result<()> {
+        if let Some(track) = self.track.filter(|track| !self.tracks.contains(track)) {
+            return Err(Error::UnknownTrack(track));
+        }
+        let start = Instant::now();
+        self.compile_channels()?;
+        if let Some(diagnostic) = self.first_error.take() {
+            return Err(diagnostic.into_error());
+        }
+        if self.reproducible && self.rng.was_used() && !self.rng.is_seeded() {
+            return Err(Error::NotReproducible(
+                "random features were used without a seed (set one with --seed)".to_string(),
+            ));
+        }
+        self.perf.compile = start.elapsed();
+        self.perf.channels = self.channels.iter().flatten().count();
+        self.perf.events = self.events.len();
+        Ok(())
+    }
+
+    /// Compile the parsed channels and write the VGM file
+    fn compile_and_write(&mut self, output: &Path) -> Result<()> {
+        if let Some((name, _)) = &self.sfx_output {
+            self.gd3_text[gd3::TITLE_EN] = name.clone();
+        }
+        if let Some(ch) = self.solo.and_then(index_to_channel) {
+            self.gd3_text[gd3::TITLE_EN] = format!("{} ({})", self.gd3_text[gd3::TITLE_EN], ch);
+        }
+        self.compile_parsed()?;
 
         // Write output
+        let start = Instant::now();
         let mut writer = VgmWriter::new(output)?;
+        if self.gzip {
+            writer.set_compressed(true);
+        }
         self.write_output(&mut writer)?;
+        if self.lyrics_file && !self.lyrics.is_empty() {
+            std::fs::write(output.with_extension("lrc"), crate::vgm::gd3::format_lrc(&self.output_lyrics()))?;
+        }
+        if !self.playback.is_empty() {
+            let file_name = output.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+            let title = match &self.gd3_text[gd3::TITLE_EN] {
+                title if title.is_empty() => output.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default(),
+                title => title.into(),
+            };
+            let playlist = crate::vgm::playback::m3u(&file_name, &title, self.compile_report.play_samples());
+            std::fs::write(output.with_extension("m3u"), playlist)?;
+        }
+        self.perf.write = start.elapsed();
+
+        Ok(())
+    }
+
+    /// Compile every declared channel into the event queue
+    fn compile_channels(&mut self) -> Result<()> {
+        if self.mono && !self.psg_widen.is_empty() {
+            self.warn("#PSG-WIDEN only detunes the copies with #MONO".to_string());
+        }
+        self.apply_psg_widen();
+        self.apply_wide();
+        self.check_octaves();
+
+        for i in 0..MAX_CHANNELS {
+            if self.channels[i].is_some() && self.is_output_channel(i) {
+                self.compile_channel(i)?;
+            }
+        }
+        self.apply_echoes()?;
+        self.apply_automation();
+        self.check_hardware_channels()?;
+
+        if let Some((start, length)) = self.fade {
+            self.apply_fade(start, length);
+            return Ok(());
+        }
+
+        if let Some(cap) = self.loop_lcm {
+            if self.apply_loop_lcm(cap) {
+                return Ok(());
+            }
+        }
+
+        // Only the last `L` compiled sets the song's loop point
+        let loops = self.channel_loops();
+        if loops.iter().any(|&(_, time)| time != self.loop_point) {
+            let list: Vec<String> = loops.iter().map(|(ch, time)| format!("{} at {}", ch, time)).collect();
+            self.warn(format!(
+                "channels loop at different points ({}); using {}",
+                list.join(", "),
+                self.loop_point
+            ));
+        }
+        Ok(())
+    }
+
+    /// Parse `#AUTOMATE channels parameter time=value ...`
+    fn parse_automation(&mut self, params: &str) {
+        match automation::parse(params) {
+            Ok(mut lane) => {
+                lane.channels = lane.channels.chars().map(|c| self.remap_channel(c)).collect();
+                self.automation.push(lane);
+            }
+            Err(message) => self.warn(format!("line {}: #AUTOMATE: {}", self.current_line, message)),
+        }
+    }
+
+    /// Parse `#GROOVE n = ticks ...`
+    fn parse_groove(&mut self, params: &str) {
+        match groove::parse(params) {
+            Ok((id, ticks)) => {
+                self.grooves.insert(id, ticks);
+            }
+            Err(message) => self.warn(format!("line {}: #GROOVE: {}", self.current_line, message)),
+        }
+    }
+
+    /// Add the events of the automation lanes to their channels
+    ///
+    /// Runs after every channel is compiled, so steps past the end of the
+    /// song are left out and a step wins over a channel's own command at
+    /// the same sample.
+    fn apply_automation(&mut self) {
+        for lane in self.automation.clone() {
+            for ch in lane.channels.chars() {
+                let Some(chan_idx) = Self::channel_index(ch) else {
+                    continue;
+                };
+                let Some(channel) = self.channels[chan_idx].clone() else {
+                    self.warn(format!("#AUTOMATE: channel {} is not declared", ch));
+                    continue;
+                };
+                if !self.is_output_channel(chan_idx) {
+                    continue;
+                }
+                let chip = &mut self.chips.get_mut(&channel.chip_name).unwrap().chip;
+                chip.start_channel(chan_idx);
+                chip.start_channel_with_info(channel.chip_sub, channel.chan_sub);
+                let max_volume = chip.max_volume();
+                for (time, value) in lane.steps(self.framerate as i64) {
+                    if time > self.total_samples {
+                        break;
+                    }
+                    let value = match lane.command {
+                        MacroCommand::Volume => self.volume_mode.scale(value, max_volume),
+                        MacroCommand::Panning => self.pan_value(&channel, value),
+                        _ => value,
+                    };
+                    let chip = &mut self.chips.get_mut(&channel.chip_name).unwrap().chip;
+                    match chip.set_macro(chan_idx, false, lane.command, value) {
+                        MacroResult::Event(chip_event) => {
+                            let source = EventSource::Macro { command: lane.command, value };
+                            self.events.insert(Event::chip(time, chan_idx as i8, source, chip_event));
+                        }
+                        MacroResult::NoEvent => {}
+                        MacroResult::Unsupported => {
+                            let command = format!("{:?}", lane.command).to_lowercase();
+                            self.warn(format!(
+                                "#AUTOMATE: chip {} does not support {} on channel {}, lane ignored",
+                                channel.chip_name, command, ch
+                            ));
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fade every channel out from the `@fade` point, ending the song there
+    ///
+    /// Each channel steps its volume down every frame from the volume it
+    /// last set, and its own volume changes during the fade are scaled down
+    /// the same way. Nothing plays after the fade and the song does not loop.
+    fn apply_fade(&mut self, start: i64, length: i64) {
+        let end = start + length;
+        let factor = |time: i64| if time >= end { 0.0 } else { (end - time) as f64 / length as f64 };
+        let frame = (self.framerate as usize).max(1);
+        for chan_idx in 0..MAX_CHANNELS {
+            let Some(channel) = self.channels[chan_idx].clone() else {
+                continue;
+            };
+            if !self.is_output_channel(chan_idx) {
+                continue;
+            }
+            let latency = self.events.latency(chan_idx);
+            let chip = &mut self.chips.get_mut(&channel.chip_name).unwrap().chip;
+
+            // The channel's own volume changes, scaled from the start
+            let mut volumes = Vec::new();
+            for event in self.events.iter_mut().filter(|e| e.channel == chan_idx as i8) {
+                let EventSource::Macro { command: MacroCommand::Volume, value } = event.source else {
+                    continue;
+                };
+                let time = event.time - latency;
+                volumes.push((time, value));
+                if time > start {
+                    let value = (value as f64 * factor(time)).round() as i16;
+                    if let Some(chip_event) = chip.set_macro(chan_idx, false, MacroCommand::Volume, value).event() {
+                        event.source = EventSource::Macro { command: MacroCommand::Volume, value };
+                        event.data = EventData::Chip(chip_event);
+                    }
+                }
+            }
+
+            // A step every frame, down from the volume last set
+            let mut current = chip.max_volume();
+            let mut next = 0;
+            let mut last = None;
+            for time in (start..end).step_by(frame).chain([end]) {
+                while next < volumes.len() && volumes[next].0 <= time {
+                    current = volumes[next].1;
+                    next += 1;
+                }
+                let value = (current as f64 * factor(time)).round() as i16;
+                if last == Some(value) {
+                    continue;
+                }
+                last = Some(value);
+                if let Some(chip_event) = chip.set_macro(chan_idx, false, MacroCommand::Volume, value).event() {
+                    let source = EventSource::Macro { command: MacroCommand::Volume, value };
+                    self.events.insert(Event::chip(time, chan_idx as i8, source, chip_event));
+                }
+            }
+        }
+
+        self.events.truncate(end);
+        self.total_samples = end;
+        self.loop_on = false;
+        self.loop_point = 0;
+    }
+
+    /// Extend the song loop to the least common multiple of the channels'
+    /// loop lengths (`#LOOP-LCM`), repeating each channel's loop to fill it
+    ///
+    /// The loop starts at the latest `L`. Returns false, leaving the song
+    /// alone, if the loops already line up or the length would pass `cap`.
+    fn apply_loop_lcm(&mut self, cap: i64) -> bool {
+        let loops: Vec<(usize, i64, i64)> = self
+            .channels
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| match c {
+                Some(c) if c.loop_point >= 0 && c.duration > c.loop_point && self.is_output_channel(i) => {
+                    Some((i, c.loop_point, c.duration - c.loop_point))
+                }
+                _ => None,
+            })
+            .collect();
+        let Some(start) = loops.iter().map(|&(_, point, _)| point).max() else {
+            return false;
+        };
+
+        let mut length = 1;
+        for &(_, _, channel_length) in &loops {
+            length = length / gcd(length, channel_length) * channel_length;
+            if length > cap {
+                let list: Vec<String> = loops
+                    .iter()
+                    .filter_map(|&(i, _, len)| Some(format!("{} {}", index_to_channel(i)?, len)))
+                    .collect();
+                self.warn(format!(
+                    "#LOOP-LCM: the loop lengths ({}) need a loop longer than {} samples; using the last `L`",
+                    list.join(", "),
+                    cap
+                ));
+                return false;
+            }
+        }
+        if loops.iter().all(|&(_, point, len)| point == start && len == length) {
+            return false;
+        }
+
+        for &(i, point, channel_length) in &loops {
+            let latency = self.events.latency(i);
+            let from = point + latency;
+            self.events.repeat(i as i8, from, from + channel_length, start + length + latency);
+        }
+        self.loop_point = start;
+        self.total_samples = (start + length).max(self.events.last_time().unwrap_or(0));
+        true
+    }
+
+    /// Loop point (in samples) of each channel that has an `L`
+    pub fn channel_loops(&self) -> Vec<(char, i64)> {
+        self.channels
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| match c {
+                Some(c) if c.loop_point >= 0 => Some((index_to_channel(i)?, c.loop_point)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Duplicate #PSG-WIDEN channels onto the second PSG
+    ///
+    /// The copy plays the same text on the matching channel of the second
+    /// chip, panned to one side with the original panned to the other.
+    fn apply_psg_widen(&mut self) {
+        for (src, detune, pan) in std::mem::take(&mut self.psg_widen) {
+            let source = match &self.channels[src] {
+                Some(c) if c.chip_name == "PSG" => c.clone(),
+                _ => {
+                    self.warn(format!(
+                        "#PSG-WIDEN channel {} is not a PSG channel",
+                        index_to_channel(src).unwrap_or('?')
+                    ));
+                    continue;
+                }
+            };
+
+            // Square channels 0-2 map to 3-5, noise channel 0 maps to 1
+            let chan_sub = match (source.chip_sub, source.chan_sub) {
+                (0, c @ 0..=2) => c + 3,
+                (1, 0) => 1,
+                _ => {
+                    self.warn(format!(
+                        "#PSG-WIDEN channel {} is already on the second chip",
+                        index_to_channel(src).unwrap_or('?')
+                    ));
+                    continue;
+                }
+            };
 
+            let taken = self.channels.iter().flatten().any(|c| {
+                c.chip_name == "PSG" && c.chip_sub == source.chip_sub && c.chan_sub == chan_sub
+            });
+            let slot = (0..MAX_CHANNELS).rev().find(|&i| self.channels[i].is_none());
+            let slot = match slot {
+                Some(slot) if !taken => slot,
+                _ => {
+                    self.warn(format!(
+                        "no free channel to widen {} onto",
+                        index_to_channel(src).unwrap_or('?')
+                    ));
+                    continue;
+                }
+            };
+
+            let mut copy = Channel::new(source.chip_name.clone(), source.chip_sub, chan_sub);
+            copy.text = source.text.clone();
+            copy.lines = source.lines.clone();
+            copy.source = Some(source.source.unwrap_or(src));
+            copy.pan_override = Some(pan);
+            copy.detune_offset = detune;
+            self.channels[slot] = Some(copy);
+
+            if let Some(ref mut ch) = self.channels[src] {
+                ch.pan_override = Some(-pan);
+            }
+        }
+    }
+
+    /// Parse `#WIDE chan=channels depth=n`
+    fn parse_wide(&mut self, params: &str) {
+        let mut channels = "";
+        let mut depth = 1;
+        for word in params.split_whitespace() {
+            match word.split_once('=') {
+                Some(("chan", value)) => channels = value,
+                Some(("depth", value)) if value.parse::<i64>().is_ok_and(|depth| depth > 0) => {
+                    depth = value.parse().unwrap();
+                }
+                _ => {
+                    self.warn(format!(
+                        "line {}: #WIDE: expected chan=channels and depth=n (above 0), got '{}'",
+                        self.current_line, word
+                    ));
+                    return;
+                }
+            }
+        }
+        let indices: Vec<usize> = channels.chars().filter_map(|c| self.input_channel_index(c)).collect();
+        if indices.is_empty() {
+            self.warn(format!("line {}: #WIDE: no channels given with chan=", self.current_line));
+        }
+        self.wide.extend(indices.into_iter().map(|idx| (idx, depth)));
+    }
+
+    /// Duplicate #WIDE channels onto the matching channel of the chip's
+    /// second chip, where the copy's pitch alternates either side of the
+    /// original's every frame
+    fn apply_wide(&mut self) {
+        for (src, depth) in std::mem::take(&mut self.wide) {
+            let name = index_to_channel(src).unwrap_or('?');
+            let Some(source) = self.channels[src].clone() else {
+                self.warn(format!("#WIDE channel {} is not declared", name));
+                continue;
+            };
+            let Some(chan_sub) = self.chips[&source.chip_name].chip.second_chip_channel(source.chip_sub, source.chan_sub)
+            else {
+                self.warn(format!(
+                    "#WIDE channel {}: {} has no second chip, or the channel is already on it",
+                    name, source.chip_name
+                ));
+                continue;
+            };
+            let taken = self.channels.iter().flatten().any(|c| {
+                c.chip_name == source.chip_name && c.chip_sub == source.chip_sub && c.chan_sub == chan_sub
+            });
+            let slot = (0..MAX_CHANNELS).rev().find(|&i| self.channels[i].is_none());
+            let slot = match slot {
+                Some(slot) if !taken => slot,
+                _ => {
+                    self.warn(format!("no free channel to widen {} onto", name));
+                    continue;
+                }
+            };
+
+            let mut copy = Channel::new(source.chip_name.clone(), source.chip_sub, chan_sub);
+            copy.text = source.text.clone();
+            copy.lines = source.lines.clone();
+            copy.source = Some(source.source.unwrap_or(src));
+            copy.pan_override = source.pan_override;
+            copy.detune_offset = source.detune_offset;
+            copy.wide = Some(depth);
+            self.channels[slot] = Some(copy);
+        }
+    }
+
+    /// Play each `@echo` channel again on a free channel of the same chip
+    /// group, delayed and with its volumes lowered by the echo's depth
+    ///
+    /// The echo of a looping song ends with the song rather than spilling
+    /// past the loop end, which would move the loop.
+    fn apply_echoes(&mut self) -> Result<()> {
+        for (src, (depth, delay)) in std::mem::take(&mut self.echoes) {
+            let Some(source) = self.channels[src].clone() else {
+                continue;
+            };
+            let name = index_to_channel(src).unwrap_or('?');
+            let group_size = self.chips[&source.chip_name].chip.group_sizes().get(source.chip_sub).copied();
+            let taken = |chan_sub: usize| {
+                self.channels.iter().flatten().any(|c| {
+                    c.chip_name == source.chip_name && c.chip_sub == source.chip_sub && c.chan_sub == chan_sub
+                })
+            };
+            let chan_sub = (0..group_size.unwrap_or(0)).find(|&s| !taken(s));
+            let slot = (0..MAX_CHANNELS).rev().find(|&i| self.channels[i].is_none());
+            let (Some(chan_sub), Some(slot)) = (chan_sub, slot) else {
+                self.warn(match group_size {
+                    None => format!("@echo on channel {}: {} has no free channels to echo on", name, source.chip_name),
+                    Some(_) => format!("@echo on channel {}: no free {} channel to echo on", name, source.chip_name),
+                });
+                continue;
+            };
+
+            let mut copy = Channel::new(source.chip_name.clone(), source.chip_sub, chan_sub);
+            copy.text = source.text.clone();
+            copy.lines = source.lines.clone();
+            copy.source = Some(source.source.unwrap_or(src));
+            copy.pan_override = source.pan_override;
+            copy.detune_offset = source.detune_offset;
+            copy.echo = Some((depth, delay));
+            self.channels[slot] = Some(copy);
+
+            let total_samples = self.total_samples;
+            self.compile_channel(slot)?;
+            if self.loop_on {
+                self.total_samples = total_samples;
+                self.events.retain(|e| e.channel != slot as i8 || e.time <= total_samples);
+            }
+
+            // Quieter by the depth
+            let chip = &mut self.chips.get_mut(&source.chip_name).unwrap().chip;
+            chip.start_channel(slot);
+            chip.start_channel_with_info(source.chip_sub, chan_sub);
+            for event in self.events.iter_mut().filter(|e| e.channel == slot as i8) {
+                let EventSource::Macro { command: MacroCommand::Volume, value } = event.source else {
+                    continue;
+                };
+                let value = (value - depth).max(0);
+                if let Some(chip_event) = chip.set_macro(slot, false, MacroCommand::Volume, value).event() {
+                    event.source = EventSource::Macro { command: MacroCommand::Volume, value };
+                    event.data = EventData::Chip(chip_event);
+                }
+            }
+        }
         Ok(())
     }
 
     /// Read input from a file path
     fn read_input_from_path(&mut self, path: &Path) -> Result<()> {
-        let file = File::open(path).map_err(|e| {
-            Error::Io(std::io::Error::new(
-                e.kind(),
-                format!("Failed to open '{}': {}", path.display(), e),
-            ))
-        })?;
-        self.read_input(file)
+        let file: Box<dyn Read> = match self.include_files.get(path) {
+            Some(text) => Box::new(std::io::Cursor::new(text.clone())),
+            None => Box::new(File::open(path).map_err(|e| {
+                Error::Io(std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to open '{}': {}", path.display(), e),
+                ))
+            })?),
+        };
+        // A #REMAP in the file ends with it
+        let outer = self.current_file.replace(path.to_path_buf());
+        let remap = self.channel_remap.clone();
+        let base = std::mem::replace(&mut self.remap_base, remap.clone());
+        let result = self.read_input(file);
+        self.current_file = outer;
+        self.channel_remap = remap;
+        self.remap_base = base;
+        result
+    }
+
+    /// Resolve a path given in the input relative to the input file's directory
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        match self.base_path {
+            Some(ref base) => base.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Resolve a path given in the input relative to the directory of the
+    /// file it is written in, which may be an included one
+    fn resolve_local_path(&self, path: &str) -> PathBuf {
+        match self.current_file.as_deref().and_then(Path::parent) {
+            Some(dir) => dir.join(path),
+            None => self.resolve_path(path),
+        }
+    }
+
+    /// Parse `#CHANNEL-FILE channels path`: append every line of the file to the channels
+    fn read_channel_file(&mut self, params: &str) -> Result<()> {
+        let (channels, path) = params.split_once(char::is_whitespace).unwrap_or((params, ""));
+        let path = path.trim();
+        let full_path = self.resolve_local_path(path);
+        let read = match self.include_files.get(&full_path) {
+            Some(text) => Ok(text.clone()),
+            None => std::fs::read_to_string(&full_path),
+        };
+        let text = match read {
+            Ok(text) => text,
+            Err(e) => {
+                self.warn(format!("Failed to read channel file '{}': {}", path, e));
+                return Ok(());
+            }
+        };
+        self.dependencies.push(full_path);
+
+        // Every line names the same channels, so one error is enough
+        let result = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .try_for_each(|line| self.parse_channel_line(&format!("{} {}", channels, line), None));
+        self.recover(result)
+    }
+
+    /// Load an FM patch file into an `@x` macro (`#LOADINST n path [voice]`)
+    fn load_instrument(&mut self, params: &str) {
+        let mut pos = 0;
+        let id = self.read_number(params, &mut pos);
+        let rest = params[pos..].trim();
+        let (path, voice) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        let voice = voice.trim().parse::<usize>().ok();
+        if !(0..256).contains(&id) || path.is_empty() {
+            self.warn(format!("#LOADINST: expected `n path`, got '{}'", params));
+            return;
+        }
+
+        let full_path = self.resolve_path(path);
+        match instrument::load(&full_path, voice) {
+            Ok(patch) => {
+                let env = &mut self.macro_env[MacroType::Option as usize][id as usize];
+                *env = Default::default();
+                for value in patch.to_opn2() {
+                    env.push(value);
+                }
+            }
+            Err(e) => self.warn(format!("Failed to load instrument '{}': {}", path, e)),
+        }
+        self.dependencies.push(full_path);
+    }
+
+    /// Parse `#WAV n path`: load a WAV file as sample n for `@s`
+    fn load_wav(&mut self, params: &str) {
+        let mut pos = 0;
+        let id = self.read_number(params, &mut pos);
+        let rest = params[pos..].trim();
+        let path = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split('"').next().unwrap_or(""),
+            None => rest,
+        };
+        if !(0..256).contains(&id) || path.is_empty() {
+            self.warn(format!("#WAV: expected `n path`, got '{}'", params));
+            return;
+        }
+
+        let full_path = self.resolve_path(path);
+        match sample::load_wav(&full_path) {
+            Ok(wav) => {
+                self.wav_samples.insert(id as u8, wav);
+            }
+            Err(e) => self.warn(format!("Failed to load #WAV {} '{}': {}", id, path, e)),
+        }
+        self.dependencies.push(full_path);
+    }
+
+    /// Read the sample files named by the `@S` macros, for chips with sample memory
+    fn load_samples(&mut self) -> Vec<Option<Sample>> {
+        let mut samples = vec![None; 256];
+        for (id, sample) in samples.iter_mut().enumerate() {
+            let env = &self.macro_env[MacroType::Sample as usize][id];
+            if env.text.is_empty() {
+                continue;
+            }
+            let name = env.text.clone();
+            let loop_start = env.data.first().and_then(|&l| usize::try_from(l).ok());
+            let full_path = self.resolve_path(&name);
+            match std::fs::read(&full_path) {
+                Ok(data) => *sample = Some(Sample { data, loop_start }),
+                Err(e) => self.warn(format!("Failed to load sample @S{} '{}': {}", id, name, e)),
+            }
+            self.dependencies.push(full_path);
+        }
+        samples
     }
 
     /// Add text to a GD3 field
@@ -222,6 +1415,139 @@ impl Compiler {
         }
     }
 
+    /// `#TEXTn`: add text to GD3 field `n`, warning when there is no such field
+    fn add_gd3_text(&mut self, number: &str, text: &str) {
+        match number.parse::<usize>() {
+            Ok(field) if field < gd3::COUNT => self.add_gd3(field, text),
+            _ => {
+                let message = match number {
+                    "" => "#TEXT needs a GD3 field number, text ignored".to_string(),
+                    _ => format!("#TEXT{}: no GD3 field {} (they are 0 to {}), text ignored", number, number, gd3::COUNT - 1),
+                };
+                self.emit(Diagnostic {
+                    code: code::UNKNOWN_GD3_FIELD,
+                    file: self.current_file.clone(),
+                    span: Some(SourceSpan { line: self.current_line, column: None, end_column: None }),
+                    suggestion: Some("use #NOTES for text with no field of its own".to_string()),
+                    ..Diagnostic::warning(message)
+                });
+            }
+        }
+    }
+
+    /// Record a warning and print it to stderr
+    fn warn(&mut self, message: String) {
+        self.emit(Diagnostic::warning(message));
+    }
+
+    /// Record a diagnostic and print it to stderr
+    ///
+    /// Errors do not stop compilation at once: the rest of the input is
+    /// still read and compiled so that every problem is reported, and then
+    /// compilation fails with the first error.
+    fn emit(&mut self, mut diagnostic: Diagnostic) {
+        if let Some(held) = self.channel_diagnostics.as_mut() {
+            held.push((diagnostic, None));
+            return;
+        }
+        if self.strict && code::STRICT.contains(&diagnostic.code) {
+            diagnostic.severity = Severity::Error;
+        }
+        let label = match diagnostic.severity {
+            Severity::Warning => "Warning",
+            Severity::Error => "Error",
+        };
+        if !self.silent {
+            match self.diagnostics_format {
+                DiagnosticFormat::Text => eprintln!("{}: {}", label, diagnostic.render()),
+                DiagnosticFormat::Json => eprintln!("{}", diagnostic.to_json()),
+            }
+        }
+        if diagnostic.severity == Severity::Error {
+            self.first_error.get_or_insert_with(|| diagnostic.clone());
+        } else {
+            self.warnings.push(diagnostic.to_string());
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Record a syntax error in the input and go on with the next line
+    fn recover(&mut self, result: Result<()>) -> Result<()> {
+        match result {
+            Err(error @ Error::MmlSyntax { .. }) => {
+                self.emit(Diagnostic::from_error(&error));
+                Ok(())
+            }
+            other => other,
+        }
+    }
+
+    /// Number of errors recorded so far (already printed unless silent)
+    pub fn error_count(&self) -> usize {
+        self.diagnostics.iter().filter(|d| d.severity == Severity::Error).count()
+    }
+
+    /// Record a warning with a code about a range of a channel's text
+    fn lint(&mut self, code: &'static str, chan_idx: usize, span: Span, message: String, suggestion: Option<String>) {
+        // An echo or #WIDE copy repeats the text of its channel, which was already checked
+        if self.channels[chan_idx].as_ref().is_some_and(Channel::is_replay) {
+            return;
+        }
+        let pos = span.start;
+        let segment = self.segment(chan_idx, pos).cloned();
+        let span = segment.as_ref().map(|s| {
+            let column = s.column.map(|c| c + span.start - s.offset);
+            SourceSpan {
+                line: s.line,
+                column,
+                end_column: column.map(|c| c + span.end.saturating_sub(span.start)),
+            }
+        });
+        let diagnostic = Diagnostic {
+            code,
+            channel: index_to_channel(chan_idx),
+            file: segment.as_ref().and_then(|s| s.file.clone()),
+            span,
+            suggestion,
+            snippet: segment.and_then(|s| s.source),
+            ..Diagnostic::warning(message)
+        };
+        match self.channel_diagnostics.as_mut() {
+            Some(held) => held.push((diagnostic, Some(pos))),
+            None => self.emit(diagnostic),
+        }
+    }
+
+    /// The measure:beat of an offset in the text of the channel being
+    /// compiled: where the token there was first reached, or for text that
+    /// is no token (or was never reached), the token after it
+    fn bar_at(&self, pos: usize) -> Option<String> {
+        let token = self.beat_marks.range(..=pos).next_back().filter(|(_, &(end, _))| pos < end);
+        let (_, &(_, time)) = token
+            .or_else(|| self.beat_marks.range(pos..).next())
+            .or_else(|| self.beat_marks.iter().next_back())?;
+        Some(self.meter.position(time))
+    }
+
+    /// The source segment a position in a channel's text came from
+    fn segment(&self, chan_idx: usize, pos: usize) -> Option<&LineSegment> {
+        self.channels[chan_idx].as_ref()?.lines.iter().rev().find(|s| s.offset <= pos)
+    }
+
+    /// Describe where a position in a channel's text came from
+    fn locate(&self, chan_idx: usize, pos: usize) -> String {
+        let ch = index_to_channel(chan_idx).unwrap_or('?');
+        let line = self.segment(chan_idx, pos).map(|s| s.line);
+        // Only tokens being compiled have a musical time yet
+        let bar = self.channel_diagnostics.as_ref().and_then(|_| self.bar_at(pos));
+        match (line, bar) {
+            (Some(line), Some(bar)) => format!("channel {} (line {}, bar {})", ch, line, bar),
+            (Some(line), None) => format!("channel {} (line {})", ch, line),
+            (None, Some(bar)) => format!("channel {} (bar {})", ch, bar),
+            (None, None) => format!("channel {}", ch),
+        }
+    }
+
     /// Convert channel character to index (A-Z = 0-25, a-z = 26-51)
     fn channel_index(ch: char) -> Option<usize> {
         match ch {
@@ -232,59 +1558,80 @@ impl Compiler {
     }
 
     /// Read a number from string, advancing the position
-    /// Supports decimal and hex ($XX) with optional sign
-    fn read_num(s: &str, pos: &mut usize) -> i64 {
+    ///
+    /// Numbers are decimal, hex (`$FF` or `0xFF`) or binary (`%1010`) with an
+    /// optional sign, and `_` may separate digits. A leading comma is
+    /// skipped. A prefix without digits, a digit outside the base or a value
+    /// that does not fit is an error, but the number is still read to its end.
+    pub(crate) fn parse_num(s: &str, pos: &mut usize) -> std::result::Result<i64, NumberError> {
         let bytes = s.as_bytes();
-        let mut base = 10i64;
+        let mut base = 10u32;
+        let mut prefixed = false;
         let mut sign = 1i64;
-        let mut value = 0i64;
 
         // Skip comma
         if *pos < bytes.len() && bytes[*pos] == b',' {
             *pos += 1;
         }
+        let start = *pos;
 
-        // Check for hex prefix or sign
+        // Check for base prefix or sign
         while *pos < bytes.len() {
             match bytes[*pos] {
-                b'$' => {
-                    base = 16;
-                    *pos += 1;
-                }
-                b'+' => {
-                    sign = 1;
-                    *pos += 1;
-                }
-                b'-' => {
-                    sign = -1;
-                    *pos += 1;
-                }
+                b'$' => base = 16,
+                b'%' => base = 2,
+                b'+' => sign = 1,
+                b'-' => sign = -1,
                 _ => break,
             }
+            prefixed |= matches!(bytes[*pos], b'$' | b'%');
+            *pos += 1;
+        }
+        let is_hex = |i: usize| bytes.get(i).is_some_and(u8::is_ascii_hexdigit);
+        if !prefixed && bytes.get(*pos) == Some(&b'0') && matches!(bytes.get(*pos + 1), Some(b'x' | b'X')) && is_hex(*pos + 2) {
+            base = 16;
+            prefixed = true;
+            *pos += 2;
         }
 
-        // Parse digits
+        // Parse digits; binary numbers take any decimal digit so that a bad
+        // one is reported rather than ending the number
+        let digits_start = *pos;
+        let digit = |i: usize| bytes.get(i).and_then(|&b| (b as char).to_digit(base.max(10)));
+        let mut value = Some(0i64);
+        let mut bad_digit = None;
         while *pos < bytes.len() {
-            let b = bytes[*pos];
-            let digit = if b >= b'0' && b <= b'9' {
-                Some((b - b'0') as i64)
-            } else if base == 16 && b >= b'A' && b <= b'F' {
-                Some((b - b'A' + 10) as i64)
-            } else if base == 16 && b >= b'a' && b <= b'f' {
-                Some((b - b'a' + 10) as i64)
-            } else {
-                None
-            };
-
-            if let Some(d) = digit {
-                value = value * base + d;
+            if bytes[*pos] == b'_' && *pos > digits_start && digit(*pos + 1).is_some() {
                 *pos += 1;
-            } else {
+                continue;
+            }
+            let Some(d) = digit(*pos) else {
                 break;
+            };
+            if d >= base {
+                bad_digit.get_or_insert(bytes[*pos] as char);
             }
+            value = value.and_then(|v| v.checked_mul(base as i64)?.checked_add(d as i64));
+            *pos += 1;
         }
 
-        sign * value
+        let text = &s[start..*pos];
+        let error = |value: i64, message: String| Err(NumberError { value, message });
+        match value {
+            _ if prefixed && *pos == digits_start => error(0, format!("`{}` has no digits", text)),
+            _ if bad_digit.is_some() => error(0, format!("`{}` is not a binary number", text)),
+            None => error(sign * i64::MAX, format!("`{}` is too large", text)),
+            Some(value) => Ok(sign * value),
+        }
+    }
+
+    /// Read a number from directive or definition text, warning with the
+    /// current line about malformed numbers
+    fn read_number(&mut self, s: &str, pos: &mut usize) -> i64 {
+        Self::parse_num(s, pos).unwrap_or_else(|e| {
+            self.warn(format!("line {}: {}", self.current_line, e.message));
+            e.value
+        })
     }
 
     /// Check if character is "graphic" (printable, > space)
@@ -297,15 +1644,17 @@ impl Compiler {
     fn read_input<R: Read>(&mut self, input: R) -> Result<()> {
         let reader = BufReader::new(input);
 
-        for line in reader.lines() {
+        let mut conditions = Conditions::default();
+        for (line_no, line) in reader.lines().enumerate() {
             let line = line?;
+            self.current_line = line_no + 1;
 
             // Strip trailing non-graphic characters
             let line = line.trim_end();
 
             // Strip UTF-8 BOM and leading whitespace
-            let line = line.trim_start_matches('\u{FEFF}');
-            let line = line.trim_start();
+            let source = line.trim_start_matches('\u{FEFF}');
+            let line = source.trim_start();
 
             if line.is_empty() {
                 continue;
@@ -315,6 +1664,40 @@ impl Compiler {
                 eprintln!("{}", line);
             }
 
+            // Conditional blocks, read even where lines are skipped
+            if let Some(cmd) = line.strip_prefix('#') {
+                let (command, param) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+                if let Some(result) = conditions.apply(command, param.trim(), self.current_line, &self.defines) {
+                    if let Err(message) = result {
+                        self.warn(format!("line {}: #{}: {}", self.current_line, command, message));
+                    }
+                    continue;
+                }
+            }
+            if !conditions.active() {
+                continue;
+            }
+
+            // Song sections: the lines of the songs not compiled are skipped
+            let track = line.strip_prefix("#TRACK");
+            if let Some(param) = track.filter(|p| p.is_empty() || p.starts_with(char::is_whitespace)) {
+                self.start_track(param.trim());
+                continue;
+            }
+            if self.current_track.is_some() && self.current_track != self.track {
+                continue;
+            }
+
+            // `@INST` blocks, which are all an #INSTLIB file may hold
+            if self.instrument_block.is_some() || line.starts_with("@INST") {
+                self.parse_instrument_line(line);
+                continue;
+            }
+            if self.reading_library && !line.starts_with(';') && !line.starts_with("#INSTLIB") {
+                self.warn(format!("line {}: #INSTLIB files hold only @INST blocks, ignored", self.current_line));
+                continue;
+            }
+
             let first_char = line.bytes().next().unwrap();
 
             match first_char {
@@ -330,19 +1713,17 @@ impl Compiler {
                 }
                 b'*' => {
                     // Text macro definition
-                    if line.len() >= 2 {
-                        let id = line.as_bytes()[1] as usize;
-                        if id < 128 {
-                            let text = if line.len() > 2 { &line[2..] } else { "" };
-                            self.text_macros[id] = text.to_string();
-                        }
+                    if let Some((name, body)) = text_macro::parse_definition(&line[1..]) {
+                        let body = remap::remap_text(&body, &self.channel_remap);
+                        self.text_macros.insert(name, body);
                     }
                 }
                 b'@' | b'-' | b'+' | b'$' | b'[' | b']' | b'{' | b',' | b'|' | b'0'..=b'9' => {
                     self.parse_envelope(line);
                 }
                 b'A'..=b'Z' | b'a'..=b'z' => {
-                    self.parse_channel_line(line)?;
+                    let result = self.parse_channel_line(line, Some(source));
+                    self.recover(result)?;
                 }
                 _ => {
                     // Ignore other lines
@@ -350,9 +1731,87 @@ impl Compiler {
             }
         }
 
+        for line in conditions.unclosed() {
+            self.warn(format!("line {}: #IFDEF without #ENDIF", line));
+        }
+        if let Some(block) = self.instrument_block.take() {
+            self.warn(format!("@INST \"{}\" without a closing `}}`", block.name));
+            self.instruments.insert(block.name, block.commands);
+        }
         Ok(())
     }
 
+    /// Read a line of an `@INST` block, or the line starting one
+    fn parse_instrument_line(&mut self, line: &str) {
+        let (mut block, body) = match self.instrument_block.take() {
+            Some(block) => (block, line),
+            None => match instlib::parse_start(line) {
+                Some(Ok(start)) => start,
+                Some(Err(message)) => return self.warn(format!("line {}: @INST: {}", self.current_line, message)),
+                None => return,
+            },
+        };
+        let in_envelope = block.in_envelope();
+        let (body, closed) = block.split_line(body);
+        if in_envelope {
+            self.parse_envelope(body);
+        } else if !body.is_empty() {
+            self.add_instrument_command(&mut block, body);
+        }
+        if closed {
+            self.instruments.insert(block.name, block.commands);
+        } else {
+            self.instrument_block = Some(block);
+        }
+    }
+
+    /// Add an envelope defined on a line of an `@INST` block, or the macro
+    /// commands written on it, to the instrument
+    fn add_instrument_command(&mut self, block: &mut instlib::Block, body: &str) {
+        if !(body.starts_with('@') && body.contains(['=', '{'])) {
+            for token in token::tokenize(body) {
+                match token.kind {
+                    TokenKind::Macro { name, value } => block.commands.push((name, value)),
+                    _ => {
+                        let text = &body[token.span.start..token.span.end];
+                        self.warn(format!("line {}: @INST \"{}\": `{}` is not a macro command, ignored", self.current_line, block.name, text));
+                    }
+                }
+            }
+            return;
+        }
+
+        // Envelopes without a number take the highest free one
+        let definition = match instlib::envelope_definition(body) {
+            Some((name, rest)) => {
+                let mac_type = MacroType::all().find(|t| !t.dyn_name().is_empty() && t.dyn_name() == name);
+                let free = mac_type.and_then(|t| {
+                    let envelopes = &self.macro_env[t as usize];
+                    (0..envelopes.len()).rev().find(|&id| envelopes[id].is_empty() && !self.instrument_envelopes.contains(&(t as usize, id)))
+                });
+                match (mac_type, free) {
+                    (Some(_), Some(id)) => format!("{}{} {}", name, id, rest),
+                    (Some(_), None) => {
+                        return self.warn(format!("line {}: @INST \"{}\": no free `{}` envelope left", self.current_line, block.name, name))
+                    }
+                    (None, _) => body.to_string(),
+                }
+            }
+            None => body.to_string(),
+        };
+        self.parse_envelope(&definition);
+        match MacroType::all().find(|&t| t as i32 == self.env_mac) {
+            Some(mac_type) => {
+                self.instrument_envelopes.insert((mac_type as usize, self.env_id));
+                block.commands.push((mac_type.dyn_name().to_string(), self.env_id as i64));
+            }
+            None => {
+                let name = body.split(|c: char| c.is_whitespace() || c == '=' || c == '{').next().unwrap_or(body);
+                self.warn(format!("line {}: @INST \"{}\": unknown envelope `{}`", self.current_line, block.name, name));
+            }
+        }
+    }
+
     /// Parse a global command (#COMMAND params)
     fn parse_global_command(&mut self, cmd: &str) -> Result<()> {
         // Split into command and parameter
@@ -360,103 +1819,139 @@ impl Compiler {
         let command = parts.next().unwrap_or("");
         let param = parts.next().unwrap_or("").trim();
 
-        match command {
-            "TITLE" => {
-                self.add_gd3(gd3::TITLE_EN, param);
-                self.add_gd3(gd3::TITLE_JP, param);
-            }
-            "TITLE-E" => self.add_gd3(gd3::TITLE_EN, param),
-            "TITLE-J" => self.add_gd3(gd3::TITLE_JP, param),
-            "GAME" => {
-                self.add_gd3(gd3::GAME_EN, param);
-                self.add_gd3(gd3::GAME_JP, param);
-            }
-            "GAME-E" => self.add_gd3(gd3::GAME_EN, param),
-            "GAME-J" => self.add_gd3(gd3::GAME_JP, param),
-            "SYSTEM" => {
-                self.add_gd3(gd3::SYSTEM_EN, param);
-                self.add_gd3(gd3::SYSTEM_JP, param);
-            }
-            "SYSTEM-E" => self.add_gd3(gd3::SYSTEM_EN, param),
-            "SYSTEM-J" => self.add_gd3(gd3::SYSTEM_JP, param),
-            "COMPOSER" => {
-                self.add_gd3(gd3::COMPOSER_EN, param);
-                self.add_gd3(gd3::COMPOSER_JP, param);
-            }
-            "COMPOSER-E" => self.add_gd3(gd3::COMPOSER_EN, param),
-            "COMPOSER-J" => self.add_gd3(gd3::COMPOSER_JP, param),
-            "PROGRAMER" | "PROGRAMMER" => self.add_gd3(gd3::CONVERTER, param),
-            "DATE" => self.add_gd3(gd3::DATE, param),
-            "NOTES" => self.add_gd3(gd3::NOTES, param),
-            "RATE" => {
-                let mut pos = 0;
-                let rate = Self::read_num(param, &mut pos) as i32;
-                if rate < 0 {
-                    self.framerate = 44100 / (-rate);
-                    self.recording_rate = 0;
-                } else if rate > 0 {
-                    self.framerate = 44100 / rate;
-                    self.recording_rate = rate;
-                }
-            }
-            "VOLUME" => {
-                let mut pos = 0;
-                self.volume_mod = Self::read_num(param, &mut pos) as i16;
-            }
-            "LOOP-BASE" => {
-                let mut pos = 0;
-                self.loop_base = Self::read_num(param, &mut pos) as i8;
-            }
-            "LOOP-MODIFIER" => {
-                let mut pos = 0;
-                self.loop_mod = Self::read_num(param, &mut pos) as u8;
-            }
-            "SCALE" => self.parse_scale(param),
-            "EQUAL-TEMPERAMENT" => self.make_equal_temperament(),
-            "JUST-INTONATION" => self.parse_just_intonation(param),
-            "PITCH-CHANGE" => {
-                let mut pos = 0;
-                self.base_freq = Self::read_num(param, &mut pos) as f64 * 10.0;
-            }
-            "INCLUDE" => {
-                // Resolve path relative to base_path
-                let include_path = if let Some(ref base) = self.base_path {
-                    base.join(param)
-                } else {
-                    PathBuf::from(param)
-                };
+        match directive::find(command) {
+            Some((directive, suffix)) => directive.apply(self, suffix, param),
+            None => {
+                self.emit(Diagnostic {
+                    code: code::UNKNOWN_DIRECTIVE,
+                    file: self.current_file.clone(),
+                    span: Some(SourceSpan { line: self.current_line, column: None, end_column: None }),
+                    ..Diagnostic::warning(format!("unknown directive #{}", command))
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Start the lines of the song `#TRACK n`; the first one read is the
+    /// song compiled unless `track` was set
+    fn start_track(&mut self, param: &str) {
+        let Ok(track) = param.parse::<u32>() else {
+            self.warn(format!("line {}: #TRACK: expected a song number, got '{}'", self.current_line, param));
+            return;
+        };
+        if !self.tracks.contains(&track) {
+            self.tracks.push(track);
+        }
+        self.track.get_or_insert(track);
+        self.current_track = Some(track);
+    }
+
+    /// Parse `#REMAP X->Y ...`, replacing the file's earlier `#REMAP`;
+    /// without pairs, channel letters are read as where the file was included
+    fn parse_remap(&mut self, params: &str) {
+        match remap::parse(params) {
+            Ok(pairs) => {
+                let mut map = self.remap_base.clone();
+                for (from, to) in pairs {
+                    map.insert(from, self.remap_base.get(&to).copied().unwrap_or(to));
+                }
+                self.channel_remap = map;
+            }
+            Err(message) => self.warn(format!("line {}: #REMAP: {}", self.current_line, message)),
+        }
+    }
+
+    /// A channel letter as written in the input, after `#REMAP`
+    pub(crate) fn remap_channel(&self, ch: char) -> char {
+        self.channel_remap.get(&ch).copied().unwrap_or(ch)
+    }
+
+    /// Index of a channel letter as written in the input, after `#REMAP`
+    pub(crate) fn input_channel_index(&self, ch: char) -> Option<usize> {
+        Self::channel_index(self.remap_channel(ch))
+    }
 
-                // Read the included file
-                if let Err(e) = self.read_input_from_path(&include_path) {
-                    eprintln!("Warning: Failed to include '{}': {}", param, e);
-                }
+    /// Parse `#GROUP X = channels`
+    fn parse_group(&mut self, params: &str) {
+        let group = chord::parse_group(params).and_then(|(name, channels)| {
+            let indices = channels
+                .iter()
+                .map(|&c| self.input_channel_index(c).ok_or_else(|| format!("'{}' is not a channel letter", c)))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            match self.input_channel_index(name) {
+                Some(_) => Ok((self.remap_channel(name), indices)),
+                None => Err(format!("'{}' is not a channel letter", name)),
             }
-            "DEBUG-INPUT-LINES" => {
-                let mut pos = 0;
-                self.debug_input_lines = Self::read_num(param, &mut pos) != 0;
+        });
+        match group {
+            Ok((name, indices)) => {
+                self.channel_groups.insert(name, indices);
             }
-            _ if command.starts_with("EX-") => {
-                let chip_name = &command[3..];
-                self.parse_chip_enable(chip_name, param)?;
+            Err(message) => self.warn(format!("line {}: #GROUP: {}", self.current_line, message)),
+        }
+    }
+
+    /// Parse `#SFX name = channels`
+    fn parse_sfx(&mut self, params: &str) {
+        let (name, channels) = match params.split_once('=') {
+            Some((name, channels)) => (name.trim(), channels),
+            None => ("", ""),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            self.warn(format!("#SFX: expected 'name = channels' with a plain name, got '{}'", params));
+            return;
+        }
+        let channels = channels.chars().filter_map(|c| self.input_channel_index(c)).collect();
+        self.sfx.push((name.to_string(), channels));
+    }
+
+    /// Parse #DEFAULT [channels] settings
+    fn parse_defaults(&mut self, params: &str) {
+        let params = params.trim();
+        let (channels, settings) = match params.split_once(char::is_whitespace) {
+            Some((first, rest)) if first.chars().all(|c| c.is_ascii_alphabetic()) => (Some(first), rest),
+            _ if params.chars().all(|c| c.is_ascii_alphabetic()) => (Some(params), ""),
+            _ => (None, params),
+        };
+
+        let mut defaults = ChannelDefaults::default();
+        let (tokens, errors) = token::tokenize_checked(settings);
+        for error in errors {
+            self.warn(format!("line {}: {}", self.current_line, error.message));
+        }
+        for token in tokens {
+            match token.kind {
+                TokenKind::Octave(octave) => defaults.octave = Some(octave as i32),
+                TokenKind::DefaultLength(length) => defaults.length = Some((length.value as i32, length.dots)),
+                TokenKind::Tempo(tempo) => defaults.tempo = Some(tempo as i32),
+                TokenKind::Quantize(frames, _) => defaults.quantize = Some(frames),
+                TokenKind::Macro { ref name, value } if name == "q" => defaults.quantize = Some(value),
+                _ => self.warn(format!(
+                    "#DEFAULT: unsupported setting '{}'",
+                    &settings[token.span.start..token.span.end]
+                )),
             }
-            _ if command.starts_with("TEXT") => {
-                // TEXTn commands - extract number and add to that GD3 field
-                if let Ok(n) = command[4..].parse::<usize>() {
-                    self.add_gd3(n, param);
+        }
+
+        match channels {
+            Some(channels) => {
+                for c in channels.chars() {
+                    if let Some(idx) = self.input_channel_index(c) {
+                        self.channel_defaults[idx] = defaults.or(self.channel_defaults[idx]);
+                    }
                 }
             }
-            _ => {
-                // Unknown command, ignore
-            }
+            None => self.defaults = defaults.or(self.defaults),
         }
-
-        Ok(())
     }
 
     /// Parse #EX-CHIP channel_list options
     fn parse_chip_enable(&mut self, chip_name: &str, params: &str) -> Result<()> {
-        // Create chip instance
-        let mut instance = chips::create_chip(chip_name)?;
+        // Create chip instance, keyed by its canonical name whatever alias enabled it
+        let info = chips::find_chip(chip_name).ok_or_else(|| Error::UnknownChip(chip_name.to_string()))?;
+        let chip_name = info.name;
+        let mut instance = info.create();
 
         // Parse parameters: "channels options"
         let mut parts = params.splitn(2, |c: char| c.is_whitespace());
@@ -477,7 +1972,7 @@ impl Compiler {
                     chan_sub += 1;
                 }
                 _ => {
-                    if let Some(idx) = Self::channel_index(c) {
+                    if let Some(idx) = self.input_channel_index(c) {
                         self.channels[idx] = Some(Channel::new(
                             chip_name.to_string(),
                             chip_sub,
@@ -491,6 +1986,7 @@ impl Compiler {
 
         // Parse options
         let mut options = ChipOptions::new();
+        options.base_path = self.base_path.clone();
         let mut pos = 0usize;
         let opt_bytes = options_str.as_bytes();
         let mut current_key = 0u8;
@@ -520,21 +2016,39 @@ impl Compiler {
                 }
                 b'=' => {
                     pos += 1;
-                    let value = Self::read_num(options_str, &mut pos);
-                    options.set(current_key as char, value as i32);
+                    let key = current_key as char;
+                    if opt_bytes.get(pos) == Some(&b'"') {
+                        // Quoted text, which may hold spaces
+                        let rest = &options_str[pos + 1..];
+                        let len = rest.find('"').unwrap_or_else(|| {
+                            self.warn(format!("line {}: option {} has no closing quote", self.current_line, key));
+                            rest.len()
+                        });
+                        options.set_str(key, &rest[..len]);
+                        pos += len + 2;
+                    } else {
+                        let rest = &options_str[pos..];
+                        let len = rest.find(|c: char| c.is_whitespace() || c == ',').unwrap_or(rest.len());
+                        if Self::is_numeric_option(&rest[..len]) {
+                            let value = self.read_number(options_str, &mut pos);
+                            options.set(key, value as i32);
+                        } else {
+                            options.set_str(key, &rest[..len]);
+                            pos += len;
+                        }
+                    }
                     current_key = 0;
                 }
                 b':' if current_key == b'o' => {
                     pos += 1;
-                    let value = Self::read_num(options_str, &mut pos);
-                    // Set basic octave on chip - this is handled in enable()
-                    options.set('o', value as i32);
+                    let value = self.read_number(options_str, &mut pos);
+                    options.basic_octave = Some(value as i32);
                     current_key = 0;
                 }
                 b':' if current_key == b'N' => {
                     pos += 1;
-                    let value = Self::read_num(options_str, &mut pos);
-                    options.set('N', value as i32);
+                    let value = self.read_number(options_str, &mut pos);
+                    options.noise = Some(value as i32);
                     current_key = 0;
                 }
                 _ => {
@@ -548,10 +2062,131 @@ impl Compiler {
         instance.chip.enable(&options);
         instance.options = options;
 
-        self.chips.insert(chip_name.to_string(), instance);
+        if self.chips.insert(chip_name.to_string(), instance).is_none() {
+            self.chip_order.push(chip_name.to_string());
+        }
         Ok(())
     }
 
+    /// Whether an option value is written as a number rather than as text
+    fn is_numeric_option(text: &str) -> bool {
+        text.starts_with(|c: char| c.is_ascii_digit() || matches!(c, '-' | '+' | '$' | '%'))
+    }
+
+    /// Parse `#OPTION chip name=value ...`, setting options of an enabled chip by long name
+    fn parse_chip_option(&mut self, params: &str) {
+        let mut words = params.split_whitespace();
+        let chip_name = words.next().unwrap_or("");
+        let wanted = chips::find_chip(chip_name).map_or(chip_name, |info| info.name);
+        let Some(key) = self.chips.keys().find(|k| k.eq_ignore_ascii_case(wanted)).cloned() else {
+            self.warn(format!("#OPTION: chip '{}' is not enabled by an earlier #EX- line", chip_name));
+            return;
+        };
+
+        let descriptions = self.chips[&key].chip.option_descriptions();
+        for word in words {
+            let (name, value) = word.split_once('=').unwrap_or((word, "on"));
+            let Some(letter) = descriptions.iter().find(|d| d.name.eq_ignore_ascii_case(name)).map(|d| d.letter) else {
+                let names: Vec<&str> = descriptions.iter().map(|d| d.name).collect();
+                self.warn(format!("#OPTION: {} has no option '{}' (options: {})", key, name, names.join(", ")));
+                continue;
+            };
+            let value = match value.to_ascii_lowercase().as_str() {
+                "on" | "yes" | "true" => 1,
+                "off" | "no" | "false" => 0,
+                _ if Self::is_numeric_option(value) => {
+                    let mut pos = 0;
+                    self.read_number(value, &mut pos)
+                }
+                _ => {
+                    let text = value.trim_matches('"');
+                    self.chips.get_mut(&key).unwrap().options.set_str(letter, text);
+                    continue;
+                }
+            };
+            self.chips.get_mut(&key).unwrap().options.set(letter, value as i32);
+        }
+
+        let instance = self.chips.get_mut(&key).unwrap();
+        instance.chip.enable(&instance.options);
+    }
+
+    /// Parse `#LATENCY channels=offset ...`, with the offset in samples or
+    /// with an `ms` suffix in milliseconds
+    fn parse_latency(&mut self, params: &str) {
+        for word in params.split_whitespace() {
+            let Some((channels, offset)) = word.split_once('=') else {
+                self.warn(format!("#LATENCY: expected channels=offset, got '{}'", word));
+                continue;
+            };
+            let samples = match offset.strip_suffix("ms") {
+                Some(ms) => match ms.parse::<f64>() {
+                    Ok(ms) => (ms * 44.1).round() as i64,
+                    Err(_) => {
+                        self.warn(format!("#LATENCY: invalid offset '{}'", offset));
+                        continue;
+                    }
+                },
+                None => {
+                    let mut pos = 0;
+                    self.read_number(offset, &mut pos)
+                }
+            };
+            for c in channels.chars() {
+                match self.input_channel_index(c) {
+                    Some(idx) => self.latency[idx] = samples,
+                    None => self.warn(format!("#LATENCY: '{}' is not a channel", c)),
+                }
+            }
+        }
+    }
+
+    /// Parse `#TRANSPOSE [channels] semitones`, the channels being letters
+    /// and ranges such as `A-D`
+    fn parse_transpose(&mut self, params: &str) {
+        let params = params.trim();
+        let (channels, amount) = match params.split_once(char::is_whitespace) {
+            Some((channels, amount)) => (Some(channels), amount.trim()),
+            None => (None, params),
+        };
+        let mut pos = 0;
+        let semitones = match Self::parse_num(amount, &mut pos) {
+            Ok(value) if pos == amount.len() => i32::try_from(value).ok(),
+            _ => None,
+        };
+        let Some(semitones) = semitones else {
+            self.warn(format!("#TRANSPOSE: expected a number of semitones, got '{}'", amount));
+            return;
+        };
+        let Some(channels) = channels else {
+            self.transpose = semitones;
+            return;
+        };
+
+        // Ranges run over the letters as written, which are then remapped
+        let mut letters = channels.chars().peekable();
+        while let Some(first) = letters.next() {
+            let (last, text) = match letters.next_if_eq(&'-') {
+                Some(_) => {
+                    let last = letters.next();
+                    (last, format!("{}-{}", first, last.map(String::from).unwrap_or_default()))
+                }
+                None => (Some(first), first.to_string()),
+            };
+            let range = match (Self::channel_index(first), last.and_then(Self::channel_index)) {
+                (Some(first), Some(last)) if first <= last => first..=last,
+                _ => {
+                    self.warn(format!("#TRANSPOSE: '{}' is not a channel or range of channels", text));
+                    continue;
+                }
+            };
+            let indices: Vec<usize> = range.filter_map(|idx| self.input_channel_index(index_to_channel(idx)?)).collect();
+            for idx in indices {
+                self.channel_transpose[idx] = semitones;
+            }
+        }
+    }
+
     /// Parse #SCALE definition
     fn parse_scale(&mut self, scale: &str) {
         let mut x = 0i32;
@@ -567,6 +2202,9 @@ impl Compiler {
             }
         }
         self.octave_count = x;
+        if self.note_freq.len() < x as usize {
+            self.note_freq.resize(x as usize, note::OCTAVE_TOP);
+        }
     }
 
     /// Initialize equal temperament
@@ -576,12 +2214,34 @@ impl Compiler {
         }
     }
 
+    /// Parse `#SCALE-CENTS`, the pitch of each step of the scale in cents
+    /// above the first
+    fn parse_scale_cents(&mut self, params: &str) {
+        let mut cents = Vec::new();
+        for word in params.split_whitespace() {
+            match word.parse::<f64>() {
+                Ok(value) if value.is_finite() => cents.push(value),
+                _ => {
+                    self.warn(format!("#SCALE-CENTS: '{}' is not a number of cents", word));
+                    return;
+                }
+            }
+        }
+        let steps = self.octave_count as usize;
+        if cents.len() != steps {
+            self.warn(format!("#SCALE-CENTS: {} pitches for the {} steps of #SCALE", cents.len(), steps));
+        }
+        for (ratio, cents) in self.note_freq[..steps].iter_mut().zip(cents) {
+            *ratio = 2.0_f64.powf(cents / 1200.0);
+        }
+    }
+
     /// Parse #JUST-INTONATION ratios
     fn parse_just_intonation(&mut self, params: &str) {
         let mut pos = 0;
         for i in 0..self.octave_count as usize {
-            let num = Self::read_num(params, &mut pos);
-            let denom = Self::read_num(params, &mut pos);
+            let num = self.read_number(params, &mut pos);
+            let denom = self.read_number(params, &mut pos);
             if denom != 0 {
                 self.note_freq[i] = num as f64 / denom as f64;
             }
@@ -624,13 +2284,16 @@ impl Compiler {
             }
 
             // Read envelope ID
-            self.env_id = (Self::read_num(line, &mut pos) & 255) as usize;
+            self.env_id = (self.read_number(line, &mut pos) & 255) as usize;
 
             // Reset envelope
             let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
             env.loop_start = -1;
             env.loop_end = 0;
             env.data.clear();
+            env.layers.clear();
+            env.arp_mode = ArpMode::Relative;
+            env.volume_mode = None;
         }
 
         if self.env_mac == -1 {
@@ -650,16 +2313,64 @@ impl Compiler {
 
             let b = bytes[pos];
 
-            if (b >= b'0' && b <= b'9') || b == b'-' || b == b'+' || b == b'$' {
+            if b.is_ascii_digit() || matches!(b, b'-' | b'+' | b'$' | b'%') {
                 // Number value
-                let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
-                if env.loop_end as usize >= envelope::MAX_ENVELOPE_DATA {
+                if self.macro_env[self.env_mac as usize][self.env_id].loop_end as usize >= envelope::MAX_ENVELOPE_DATA {
                     return;
                 }
-                let x = Self::read_num(line, &mut pos) as i16;
+                let x = self.read_number(line, &mut pos) as i16;
+                let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
+                for _ in 0..self.env_rep {
+                    env.push(x);
+                }
+            } else if b == b'(' && self.env_mac == MacroType::SampleList as i32 {
+                // Sample list tuple: (sample,volume,pitch/sample,volume,pitch...)
+                pos += 1;
+                let mut layers = Vec::new();
+                let mut layer = [0i16, -1, 0];
+                let mut field = 0;
+                while pos < bytes.len() && bytes[pos] != b')' {
+                    match bytes[pos] {
+                        b'/' => {
+                            layers.push(SampleLayer { sample: layer[0], volume: layer[1], pitch: layer[2] });
+                            layer = [0, -1, 0];
+                            field = 0;
+                            pos += 1;
+                        }
+                        b'0'..=b'9' | b'-' | b'+' | b'$' | b'%' | b',' => {
+                            let start = pos;
+                            let x = self.read_number(line, &mut pos) as i16;
+                            if field < 3 {
+                                layer[field] = x;
+                                field += 1;
+                            }
+                            if pos == start {
+                                pos += 1;
+                            }
+                        }
+                        _ => pos += 1,
+                    }
+                }
+                if pos < bytes.len() {
+                    pos += 1;
+                }
+                layers.push(SampleLayer { sample: layer[0], volume: layer[1], pitch: layer[2] });
+                let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
+                env.layers.push(layers);
+                let x = -(env.layers.len() as i16);
                 for _ in 0..self.env_rep {
                     env.push(x);
                 }
+            } else if (b == b'A' || b == b'F') && self.env_mac == MacroType::Arpeggio as i32 {
+                // Arpeggio mode prefix: absolute notes or fixed chip values
+                self.macro_env[self.env_mac as usize][self.env_id].arp_mode =
+                    if b == b'A' { ArpMode::Absolute } else { ArpMode::Fixed };
+                pos += 1;
+            } else if (b == b'A' || b == b'N') && self.env_mac == MacroType::Volume as i32 {
+                // Volume mode prefix: absolute chip units or normalized 0-15
+                self.macro_env[self.env_mac as usize][self.env_id].volume_mode =
+                    Some(if b == b'A' { VolumeMode::Absolute } else { VolumeMode::Normalized });
+                pos += 1;
             } else if b == b'|' {
                 // Loop point
                 let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
@@ -668,14 +2379,13 @@ impl Compiler {
             } else if b == b'\'' {
                 // Repeat count
                 pos += 1;
-                self.env_rep = Self::read_num(line, &mut pos) as i32;
+                self.env_rep = self.read_number(line, &mut pos) as i32;
             } else if b == b',' && pos + 1 < bytes.len() && bytes[pos + 1] >= b'a' && bytes[pos + 1] <= b'j' {
                 // Note-based repeat (e.g., ",c" means repeat to note C)
                 pos += 1;
                 let note_idx = (bytes[pos] - b'a') as usize;
                 pos += 1;
-                let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
-                let mut x = self.note_letter[note_idx] - env.loop_end;
+                let mut x = self.note_letter[note_idx] - self.macro_env[self.env_mac as usize][self.env_id].loop_end;
 
                 // Handle accidentals
                 while pos < bytes.len() {
@@ -690,8 +2400,9 @@ impl Compiler {
                     }
                 }
 
-                x += Self::read_num(line, &mut pos) as i32 * self.octave_count;
+                x += self.read_number(line, &mut pos) as i32 * self.octave_count;
 
+                let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
                 if let Some(last_val) = env.last() {
                     while x > 0 {
                         env.push(last_val);
@@ -710,7 +2421,7 @@ impl Compiler {
             } else if b == b']' && self.env_block > 0 {
                 // Block end with repeat
                 pos += 1;
-                let repeat_count = Self::read_num(line, &mut pos) as i32;
+                let repeat_count = self.read_number(line, &mut pos) as i32;
                 let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
                 let y = env.loop_end;
                 self.env_block -= 1;
@@ -744,7 +2455,7 @@ impl Compiler {
                     step_size += 1;
                     pos += 1;
                 }
-                let target = Self::read_num(line, &mut pos) as i16;
+                let target = self.read_number(line, &mut pos) as i16;
                 let env = &mut self.macro_env[self.env_mac as usize][self.env_id];
                 if let Some(mut current) = env.last() {
                     let dir = if target > current { step_size } else { -step_size };
@@ -765,16 +2476,173 @@ impl Compiler {
         }
     }
 
+    /// `@s n`: stream `#WAV` sample n to the DAC of the channel's chip (a
+    /// negative number stops the stream)
+    fn dac_sample(&mut self, chip_name: &str, chan_idx: usize, time: i64, value: i16, pos: usize) {
+        if self.chips[chip_name].chip.dac_target().is_none() {
+            let loc = self.locate(chan_idx, pos);
+            self.warn(format!("{}: {} has no DAC to stream #WAV samples to", loc, chip_name));
+            return;
+        }
+        let sample = match u8::try_from(value) {
+            Ok(n) if self.wav_samples.contains_key(&n) => Some(n),
+            _ if value < 0 => None,
+            _ => {
+                let message = format!("@s{}: no #WAV {} is loaded", value, value);
+                self.lint(code::UNDEFINED_MACRO, chan_idx, Span { start: pos, end: pos + 1 }, message, None);
+                return;
+            }
+        };
+        self.dac_chips.insert(chip_name.to_string());
+        let source = EventSource::DacSample(value);
+        self.events.insert(Event::new(time, chan_idx as i8, source, EventData::Dac(sample)));
+    }
+
+    /// Write the `#WAV` samples to the banks of the DACs that play them and
+    /// set up a stream for each of those chips
+    fn begin_dac_streams(&mut self, writer: &mut VgmWriter) -> Result<HashMap<String, DacStream>> {
+        let mut streams = HashMap::new();
+        let mut banks = Vec::new();
+        for (id, name) in self.dac_chips.iter().enumerate() {
+            let Some(instance) = self.chips.get_mut(name) else {
+                continue;
+            };
+            let Some(target) = instance.chip.dac_target() else {
+                continue;
+            };
+            if !banks.contains(&target.bank) {
+                sample::write_blocks(&self.wav_samples, target, writer)?;
+                banks.push(target.bank);
+            }
+            let stream = DacStream { id: id as u8, target };
+            stream.setup(writer)?;
+            instance.chip.dac_begin(writer);
+            streams.insert(name.clone(), stream);
+        }
+        Ok(streams)
+    }
+
+    /// Warn about `?(..)` blocks that are nested or never closed, and about
+    /// a `?X` left open at the end of the channel
+    fn check_conditions(&mut self, chan_idx: usize, tokens: &[Token]) {
+        let mut open: Option<Span> = None;
+        let mut single: Option<Span> = None;
+        for token in tokens {
+            let opens = match token.kind {
+                TokenKind::ConditionGroup(_) => true,
+                TokenKind::Condition(Some(cond)) => cond != b'.',
+                TokenKind::Condition(None) => false,
+                _ => continue,
+            };
+            if let (true, Some(outer)) = (opens, open) {
+                let outer = self.locate(chan_idx, outer.start);
+                let message = format!("conditionals cannot be nested; close the ?(..) from {} with `?` first", outer);
+                let suggestion = "add `?` before this conditional".to_string();
+                self.lint(code::NESTED_CONDITION, chan_idx, token.span, message, Some(suggestion));
+            }
+            open = match token.kind {
+                TokenKind::ConditionGroup(_) => Some(token.span),
+                _ if opens => open,
+                _ => None,
+            };
+            single = match token.kind {
+                TokenKind::Condition(Some(cond)) if cond != b'.' => Some(token.span),
+                _ => None,
+            };
+        }
+        if let Some(span) = open {
+            let message = "unterminated conditional ?(..), close it with `?`".to_string();
+            let suggestion = "add `?` where the channel-specific part ends".to_string();
+            self.lint(code::UNTERMINATED_CONDITION_GROUP, chan_idx, span, message, Some(suggestion));
+        }
+        if let Some(span) = single {
+            let message = "unterminated conditional ?X, close it with `?.`".to_string();
+            let suggestion = "add `?.` where the channel-specific part ends".to_string();
+            self.lint(code::UNTERMINATED_CONDITION, chan_idx, span, message, Some(suggestion));
+        }
+    }
+
+    /// Append channel text to `out`, expanding `*X` text macros (including
+    /// macros used inside macros and in arguments) and dropping `;`
+    /// comments. Every macro expanded is added to `used`.
+    fn expand_text_macros(&mut self, src: &str, out: &mut String, active: &mut Vec<String>, used: &mut Vec<String>) {
+        let bytes = src.as_bytes();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let b = bytes[pos];
+            if b == b';' {
+                // Comment - stop here
+                break;
+            } else if b == b'"' {
+                // Lyric text is kept as written
+                let end = src[pos + 1..].find('"').map_or(src.len(), |n| pos + n + 2);
+                out.push_str(&src[pos..end]);
+                pos = end;
+            } else if b == b'*' && pos + 1 < bytes.len() {
+                // Text macro expansion
+                let len = text_macro::name_len(&src[pos + 1..], |name| self.text_macros.contains_key(name));
+                let name = src[pos + 1..pos + 1 + len].to_string();
+                pos += 1 + len;
+                let mut body = self.text_macros.get(&name).cloned().unwrap_or_default();
+                let params = text_macro::param_count(&body);
+                if params > 0 {
+                    let (args, len) = text_macro::parse_args(&src[pos..]).unwrap_or_default();
+                    pos += len;
+                    if args.len() != params {
+                        self.warn(format!(
+                            "line {}: text macro *{} takes {} arguments, got {}",
+                            self.current_line,
+                            name,
+                            params,
+                            args.len()
+                        ));
+                    }
+                    // Arguments are expanded where the macro is used, so a
+                    // macro can be passed to itself
+                    let args: Vec<String> = args
+                        .iter()
+                        .map(|arg| {
+                            let mut expanded = String::new();
+                            self.expand_text_macros(arg, &mut expanded, active, used);
+                            expanded
+                        })
+                        .collect();
+                    body = text_macro::substitute(&body, &args);
+                }
+                if active.contains(&name) {
+                    self.warn(format!("line {}: text macro *{} uses itself", self.current_line, name));
+                } else {
+                    active.push(name.clone());
+                    used.push(name);
+                    self.expand_text_macros(&body, out, active, used);
+                    active.pop();
+                }
+            } else {
+                out.push(b as char);
+                pos += 1;
+            }
+        }
+    }
+
     /// Parse channel data line (e.g., "ABC cdefg")
-    fn parse_channel_line(&mut self, line: &str) -> Result<()> {
+    ///
+    /// `source` is the source line `line` was trimmed from, for diagnostic
+    /// columns and snippets (None if the line was not read verbatim).
+    fn parse_channel_line(&mut self, line: &str, source: Option<&str>) -> Result<()> {
         let bytes = line.as_bytes();
         let mut pos = 0;
 
-        // Collect channel names
+        // Collect channel names, with the chord voice each channel plays
         let mut channel_indices = Vec::new();
+        let mut voices = Vec::new();
         while pos < bytes.len() && bytes[pos] > b' ' {
-            if let Some(idx) = Self::channel_index(bytes[pos] as char) {
+            let ch = self.remap_channel(bytes[pos] as char);
+            if let Some(group) = self.channel_groups.get(&ch) {
+                channel_indices.extend(group);
+                voices.extend(0..group.len());
+            } else if let Some(idx) = Self::channel_index(ch) {
                 channel_indices.push(idx);
+                voices.push(0);
             } else {
                 break;
             }
@@ -787,35 +2655,44 @@ impl Compiler {
 
         // Process remaining text, expanding text macros
         let mut text = String::new();
-        while pos < bytes.len() {
-            let b = bytes[pos];
-            if b == b';' {
-                // Comment - stop here
-                break;
-            } else if b == b'*' && pos + 1 < bytes.len() {
-                // Text macro expansion
-                let macro_id = bytes[pos + 1] as usize;
-                if macro_id < 128 {
-                    text.push_str(&self.text_macros[macro_id]);
-                }
-                pos += 2;
-            } else {
-                text.push(b as char);
-                pos += 1;
-            }
+        let mut used = Vec::new();
+        let rest = remap::remap_text(&line[pos..], &self.channel_remap);
+        self.expand_text_macros(&rest, &mut text, &mut Vec::new(), &mut used);
+        let indent = source.map(|source| source.len() - line.len());
+        let column = indent.filter(|_| used.is_empty()).map(|indent| indent + pos + 1);
+        for name in used {
+            let channels = self.text_macro_channels.entry(name).or_default();
+            channels.extend(&channel_indices);
         }
 
+        // Split chords between the voices
+        let (texts, problems) = chord::split(&text, voices.iter().max().map_or(1, |&voice| voice + 1));
+        for problem in problems {
+            self.warn(format!("line {}: {}", self.current_line, problem));
+        }
+        let column = column.filter(|_| texts.iter().all(|voice| *voice == text));
+
         // Append to all specified channels
-        for &idx in &channel_indices {
+        for (&idx, &voice) in channel_indices.iter().zip(&voices) {
+            let text = &texts[voice];
             if let Some(ref mut channel) = self.channels[idx] {
-                channel.text.push_str(&text);
+                channel.lines.push(LineSegment {
+                    offset: channel.text.len(),
+                    line: self.current_line,
+                    file: self.current_file.clone(),
+                    column,
+                    source: column.and(source).map(str::to_string),
+                });
+                channel.text.push_str(text);
             } else {
-                let ch = if idx < 26 {
-                    (b'A' + idx as u8) as char
-                } else {
-                    (b'a' + (idx - 26) as u8) as char
-                };
-                return Err(Error::UndeclaredChannel(ch));
+                let ch = index_to_channel(idx).unwrap_or('?');
+                let offset = line.find(ch).unwrap_or(0);
+                return Err(Error::MmlSyntax {
+                    file: self.current_file.clone(),
+                    line: self.current_line,
+                    column: indent.map(|indent| indent + offset + 1),
+                    message: format!("channel {} is not declared; enable a chip on it with #EX first", ch),
+                });
             }
         }
 
@@ -823,7 +2700,7 @@ impl Compiler {
     }
 
     /// Calculate note values for a chip
-    fn figure_out_note_values(&mut self, clock_div: i32, note_bits: i32) {
+    pub(crate) fn figure_out_note_values(&mut self, clock_div: i32, note_bits: i32) {
         if clock_div == 0 {
             return;
         }
@@ -832,31 +2709,39 @@ impl Compiler {
         let bits = note_bits.abs();
         let mask = (!0u64) << bits;
 
-        let mut u = [0u64; 32];
-        let mut w = 0u64;
-
-        for i in 0..32 {
-            let freq = self.note_freq[i] * self.base_freq + 0.000001;
-            let v = if is_period {
+        let value = |ratio: f64| {
+            let freq = ratio * self.base_freq + 0.000001;
+            if is_period {
                 ((q as u64) << 24) / (freq as u64).max(1)
             } else {
                 (freq as u64) * ((q as u64) << 22)
-            };
-            u[i] = v;
-            w |= v;
-        }
+            }
+        };
+        let u: Vec<u64> = self.note_freq.iter().map(|&ratio| value(ratio)).collect();
+        let mut w = u.iter().fold(value(note::OCTAVE_TOP), |w, &v| w | v);
 
         // Normalize to fit in note_bits
+        let mut shift = 0;
         while (w & mask) != 0 {
             w >>= 1;
-            for v in &mut u {
-                *v >>= 1;
-            }
+            shift += 1;
         }
 
-        for i in 0..32 {
-            self.note_value[i] = u[i] as i64;
+        self.note_value = u.iter().map(|v| v.checked_shr(shift).unwrap_or(0) as i64).collect();
+        self.note_exact = u.iter().map(|&v| v as f64 / 2f64.powi(shift as i32)).collect();
+    }
+
+    /// Tone values a chip's registers hold, for chips whose note values are
+    /// shifted by octave (None for those given the octave as a block)
+    ///
+    /// Period chips cannot play a period of 0, and a value past the top
+    /// would lose its high bits and play a note of another octave.
+    fn note_value_range(clock_div: i32, note_bits: i32) -> Option<std::ops::RangeInclusive<i64>> {
+        if clock_div == 0 || note_bits <= 0 {
+            return None;
         }
+        let max = (1i64 << note_bits) - 1;
+        Some(if clock_div < 0 { 1..=max } else { 0..=max })
     }
 
     /// Calculate note length in samples
@@ -874,8 +2759,62 @@ impl Compiler {
         k / tempo as i64
     }
 
-    /// Compile a single channel's MML to events
+    /// Fail if two channels play on the same hardware channel, e.g. an OPL3
+    /// two-op channel on a four-op pair, as their register writes would mix
+    fn check_hardware_channels(&self) -> Result<()> {
+        // Later groups (four-op pairs, supplementary channels) claim their
+        // hardware first, so a conflict names the channel that took it away
+        let mut order: Vec<usize> = (0..MAX_CHANNELS).filter(|&i| self.is_output_channel(i)).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.channels[i].as_ref().map_or(0, |c| c.chip_sub)));
+
+        let mut used: HashMap<(&str, String), usize> = HashMap::new();
+        for i in order {
+            let Some(channel) = self.channels[i].as_ref() else {
+                continue;
+            };
+            let Some(instance) = self.chips.get(&channel.chip_name) else {
+                continue;
+            };
+            for hardware in instance.chip.hardware_channels(channel.chip_sub, channel.chan_sub) {
+                let describe = |idx: usize| {
+                    let c = self.channels[idx].as_ref().unwrap();
+                    format!(
+                        "channel {} ({} group {} channel {})",
+                        index_to_channel(idx).unwrap_or('?'),
+                        c.chip_name,
+                        c.chip_sub + 1,
+                        c.chan_sub + 1
+                    )
+                };
+                if let Some(&other) = used.get(&(channel.chip_name.as_str(), hardware.clone())) {
+                    return Err(Error::ChannelConflict(format!(
+                        "{} and {} both play {} {}",
+                        describe(other),
+                        describe(i),
+                        channel.chip_name,
+                        hardware
+                    )));
+                }
+                used.insert((channel.chip_name.as_str(), hardware), i);
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile a single channel's MML to events, then report its
+    /// diagnostics with the measure:beat each one is at
     fn compile_channel(&mut self, chan_idx: usize) -> Result<()> {
+        self.beat_marks.clear();
+        self.channel_diagnostics = Some(Vec::new());
+        let result = self.compile_channel_events(chan_idx);
+        for (mut diagnostic, pos) in self.channel_diagnostics.take().unwrap_or_default() {
+            diagnostic.position = pos.and_then(|pos| self.bar_at(pos));
+            self.emit(diagnostic);
+        }
+        result
+    }
+
+    fn compile_channel_events(&mut self, chan_idx: usize) -> Result<()> {
         let channel = match &self.channels[chan_idx] {
             Some(c) => c.clone(),
             None => return Ok(()),
@@ -883,390 +2822,541 @@ impl Compiler {
 
         let chip_name = channel.chip_name.clone();
 
-        // Get chip parameters first (immutable borrow)
-        let (clock_div, note_bits, basic_octave) = {
-            let chip_instance = match self.chips.get(&chip_name) {
+        // Start channel on chip, then get its parameters (which may depend on the channel group)
+        let (clock_div, note_bits, basic_octave, pitch_divider) = {
+            let chip_instance = match self.chips.get_mut(&chip_name) {
                 Some(c) => c,
                 None => {
-                    eprintln!("Warning: chip {} not found for channel", chip_name);
+                    self.warn(format!("chip {} not found for channel", chip_name));
                     return Ok(());
                 }
             };
-            (chip_instance.chip.clock_div(), chip_instance.chip.note_bits(), chip_instance.chip.basic_octave())
+            let chip = &mut chip_instance.chip;
+            chip.start_channel(chan_idx);
+            chip.start_channel_with_info(channel.chip_sub, channel.chan_sub);
+            // The basic octave can depend on the channel group, so read it after starting
+            let basic_octave = chip_instance.options.basic_octave.unwrap_or_else(|| chip.basic_octave());
+            (chip.clock_div(), chip.note_bits(), basic_octave, chip.pitch_divider())
         };
 
         // Calculate note values for this chip
         self.figure_out_note_values(clock_div, note_bits);
+        let octave_shift = self.octave_shifts.get(&chip_name).copied().unwrap_or(0);
 
         // Initialize channel compilation state
-        let mut state = ChannelCompileState::new(self.framerate);
+        let self_idx = channel.source.unwrap_or(chan_idx);
+        let defaults = self.channel_defaults[self_idx].or(self.defaults);
+        let mut state = ChannelCompileState::new(&defaults, self.framerate);
+        state.key = self.transpose + self.channel_transpose[self_idx];
+        state.transpose = state.key;
+        let mut tempo_map = TempoMap::new(state.tempo);
 
         // Reset macro usage
         self.macro_use = [-1; MAX_MACRO_TYPES];
         self.note_off_event = 0;
         self.sample_list = -1;
-
-        // Start channel on chip
-        if let Some(chip_instance) = self.chips.get_mut(&chip_name) {
-            chip_instance.chip.start_channel(chan_idx);
+        self.sample_accent = 0;
+
+        // Forced panning applies from the start of the channel
+        if let Some(pan) = channel.pan_override.map(|pan| self.pan_value(&channel, pan)) {
+            let chip = self.chips.get_mut(&chip_name).unwrap();
+            if let Some(chip_event) = chip.chip.set_macro(chan_idx, false, MacroCommand::Panning, pan).event() {
+                let source = EventSource::Macro { command: MacroCommand::Panning, value: pan };
+                self.events.insert(Event::chip(0, chan_idx as i8, source, chip_event));
+            }
         }
 
-        let text = channel.text.clone();
-        let bytes = text.as_bytes();
-        let mut pos = 0;
-
-        while pos < bytes.len() {
-            let b = bytes[pos];
+        let delay = channel.echo.map_or(0, |(_, delay)| delay);
+        self.events.set_latency(chan_idx, self.latency[self_idx] + delay);
 
-            if b >= b'a' && b <= b'j' {
-                // Note
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                let note_idx = (b - b'a') as usize;
-                state.current_note = state.octave * self.octave_count + self.note_letter[note_idx] + state.transpose;
-                state.current_len = state.default_len;
-                pos += 1;
-                self.read_note(&text, &mut pos, &mut state);
-            } else if b == b'r' {
-                // Rest
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                state.current_len = state.default_len;
-                pos += 1;
-                self.read_note(&text, &mut pos, &mut state);
-                state.current_note = -1;
-            } else if b == b'w' {
-                // Wait (no note off)
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                state.current_len = state.default_len;
-                pos += 1;
-                self.read_note(&text, &mut pos, &mut state);
-                state.current_note = -2;
-            } else if b == b'n' {
-                // Note by number
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 1;
-                state.current_note = Self::read_num(&text, &mut pos) as i32 + state.transpose;
-                state.current_len = state.default_len;
-                self.read_note(&text, &mut pos, &mut state);
-            } else if b == b'l' {
-                // Set default length
-                pos += 1;
-                state.default_len = self.read_len(&text, &mut pos, state.tempo);
-            } else if b == b'^' {
-                // Tie
-                pos += 1;
-                let mut tie_len = state.default_len;
-                let mut dummy_note = 0;
-                self.read_note_params(&text, &mut pos, &mut tie_len, &mut dummy_note, state.tempo);
-                state.current_len += tie_len;
-            } else if b == b'&' {
-                // Slur (no note off)
-                pos += 1;
-                state.kind |= 1;
-            } else if b == b'/' {
-                // Legato
-                pos += 1;
-                state.kind |= 2;
-            } else if b == b'o' {
-                // Set octave
-                pos += 1;
-                state.octave = Self::read_num(&text, &mut pos) as i32;
-            } else if b == b'>' {
-                // Octave up
-                pos += 1;
-                state.octave += 1;
-            } else if b == b'<' {
-                // Octave down
-                pos += 1;
-                state.octave -= 1;
-            } else if b == b't' {
-                // Set tempo
-                pos += 1;
-                state.tempo = Self::read_num(&text, &mut pos) as i32;
-            } else if b == b'D' {
-                // Detune
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 1;
-                state.detune = Self::read_num(&text, &mut pos);
-            } else if b == b'K' {
-                // Transpose
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 1;
-                state.transpose = Self::read_num(&text, &mut pos) as i32;
-            } else if b == b'!' {
-                // Stop parsing
-                break;
-            } else if b == b'L' {
-                // Loop point
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                if let Some(ref mut ch) = self.channels[chan_idx] {
-                    ch.loop_point = state.time;
+        let (tokens, errors) = token::tokenize_checked(&channel.text);
+        for error in errors {
+            self.lint(error.code, chan_idx, error.span, error.message, None);
+        }
+        self.check_conditions(chan_idx, &tokens);
+        let tokens = token::filter_conditions(tokens, self_idx);
+        let tokens = self.expand_instruments(tokens, chan_idx);
+        for lint in lint::check_structure(&channel.text, &tokens) {
+            self.lint(lint.code, chan_idx, lint.span, lint.message, Some(lint.suggestion));
+        }
+        let mut loops: Vec<LoopFrame> = Vec::new();
+        let mut i = 0;
+
+        while i < tokens.len() {
+            let token = &tokens[i];
+            i += 1;
+            // The token takes effect after the pending note
+            let musical_time = tempo_map.musical_time(state.time + state.current_len);
+            self.beat_marks.entry(token.span.start).or_insert((token.span.end, musical_time));
+
+            match token.kind {
+                TokenKind::Note { letter, shift, length } => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.current_note = (state.octave + octave_shift) * self.octave_count + self.note_letter[letter] + state.transpose;
+                    state.current_note += shift.steps + shift.octaves * self.octave_count;
+                    state.current_len = self.note_len(length, state.default_len, state.tempo);
+                    state.note_span = token.span;
                 }
-                self.loop_on = true;
-                self.loop_point = state.time;
-                pos += 1;
-            } else if b == b'@' && pos + 1 < bytes.len() && bytes[pos + 1] == b'q' {
-                // Quantize
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 2;
-                state.quantize = Self::read_num(&text, &mut pos) * self.framerate as i64;
-                state.quantize -= Self::read_num(&text, &mut pos);
-            } else if b == b'[' && state.loop_depth < 127 {
-                // Loop start
-                state.loop_depth += 1;
-                pos += 1;
-                state.loop_start[state.loop_depth as usize] = pos;
-                state.loop_end[state.loop_depth as usize] = 0;
-                state.loop_count[state.loop_depth as usize] = 0;
-            } else if b == b']' && state.loop_depth >= 0 {
-                // Loop end
-                let depth = state.loop_depth as usize;
-                state.loop_end[depth] = pos;
-                pos += 1;
-                let repeat = Self::read_num(&text, &mut pos) as i32;
-                state.loop_count[depth] += 1;
-                if state.loop_count[depth] < repeat {
-                    pos = state.loop_start[depth];
-                } else {
-                    state.loop_depth -= 1;
+                TokenKind::NoteNumber { number, shift, length } => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.current_note = number as i32 + state.transpose;
+                    state.current_note += shift.steps + shift.octaves * self.octave_count;
+                    state.current_len = self.note_len(length, state.default_len, state.tempo);
+                    state.note_span = token.span;
                 }
-            } else if b == b'\\' && state.loop_depth >= 0 {
-                // Loop break
-                let depth = state.loop_depth as usize;
-                if state.loop_end[depth] != 0 {
-                    pos = state.loop_end[depth];
-                } else {
-                    pos += 1;
+                TokenKind::Frequency { millihertz, length } => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    let freq = millihertz as f64 / 1000.0;
+                    if clock_div != 0 && pitch_divider > 0.0 && freq > 0.0 {
+                        state.current_note = self.nearest_note(freq, clock_div, note_bits, basic_octave, pitch_divider);
+                        state.current_freq = Some(freq);
+                    } else {
+                        let loc = self.locate(chan_idx, token.span.start);
+                        self.warn(format!("{}: chip {} cannot play exact frequencies, f= treated as a rest", loc, chip_name));
+                        state.current_note = -1;
+                    }
+                    state.current_len = self.note_len(length, state.default_len, state.tempo);
+                    state.note_span = token.span;
+                }
+                TokenKind::Rest(length) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.current_len = self.note_len(length, state.default_len, state.tempo);
+                    state.current_note = -1;
+                }
+                TokenKind::Wait(length) => {
+                    // Wait (no note off)
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.current_len = self.note_len(length, state.default_len, state.tempo);
+                    state.current_note = -2;
+                }
+                TokenKind::DefaultLength(length) => {
+                    state.default_len = Self::calc_note_len(state.tempo, length.value as i32, length.dots);
+                }
+                TokenKind::Tie(length) => {
+                    state.current_len += self.note_len(length, state.default_len, state.tempo);
+                }
+                TokenKind::Slur => {
+                    // Slur (no note off)
+                    state.kind |= 1;
+                }
+                TokenKind::Legato => {
+                    state.kind |= 2;
+                }
+                TokenKind::Octave(octave) => state.octave = octave as i32,
+                TokenKind::OctaveUp => state.octave += 1,
+                TokenKind::OctaveDown => state.octave -= 1,
+                TokenKind::Tempo(tempo) => {
+                    state.tempo = tempo as i32;
+                    tempo_map.set_tempo(state.time + state.current_len, state.tempo);
+                }
+                TokenKind::FrameRate(rate) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    // A frame is at least a sample long
+                    let hz = rate.unsigned_abs();
+                    if hz > 44100 {
+                        let message = format!("frame rate `R{}` is above 44100 Hz; clamped to 44100", rate);
+                        self.lint(code::OUT_OF_RANGE, chan_idx, token.span, message, None);
+                    }
+                    state.framerate = match hz {
+                        0 => self.framerate,
+                        hz => (44100 / hz.min(44100)) as i32,
+                    };
+                }
+                TokenKind::Detune(detune) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.detune = detune;
+                }
+                TokenKind::Jitter(jitter) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.jitter = jitter;
+                }
+                TokenKind::Transpose(transpose) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.transpose = state.key + transpose as i32;
+                }
+                TokenKind::Bend(cents) => state.current_bend = cents,
+                TokenKind::Stop => break,
+                TokenKind::LoopPoint => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    if let Some(ref mut ch) = self.channels[chan_idx] {
+                        ch.loop_point = state.time;
+                    }
+                    self.loop_on = true;
+                    self.loop_point = state.time;
+                }
+                TokenKind::Quantize(frames, samples) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.quantize = frames * state.framerate as i64 - samples;
+                }
+                TokenKind::LoopStart => {
+                    loops.push(LoopFrame { start: i, end: None, count: 0 });
+                }
+                TokenKind::LoopEnd(repeat) => {
+                    let end = i - 1;
+                    match loops.last_mut() {
+                        Some(frame) => {
+                            frame.end = Some(end);
+                            frame.count += 1;
+                            if (frame.count as i64) < repeat {
+                                i = frame.start;
+                            } else {
+                                loops.pop();
+                            }
+                        }
+                        None => {
+                            // Stray `]` outside any loop
+                            self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                        }
+                    }
+                }
+                TokenKind::LoopBreak => {
+                    // Jumps to the loop end once it is known (from the second pass on)
+                    match loops.last() {
+                        Some(frame) => {
+                            if let Some(end) = frame.end {
+                                i = end;
+                            }
+                        }
+                        None => {
+                            self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                        }
+                    }
+                }
+                TokenKind::Lyric(ref text) => {
+                    // Sung from the start of the next note
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    if !channel.is_replay() {
+                        self.lyrics.push((state.time, text.clone()));
+                    }
+                }
+                // Already applied by `filter_conditions`
+                TokenKind::Condition(_) | TokenKind::ConditionGroup(_) => {}
+                TokenKind::ArpeggioOff => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    self.macro_use[MacroType::Arpeggio as usize] = -1;
+                }
+                TokenKind::PitchEnvelopeOff => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    self.macro_use[MacroType::PitchEnv as usize] = -1;
+                }
+                TokenKind::VibratoOff => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    self.macro_use[MacroType::Vibrato as usize] = -1;
+                }
+                TokenKind::Arpeggio(id) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    self.select_macro(MacroType::Arpeggio, id as i32, chan_idx, token.span.start);
+                    state.macro_indices[MacroType::Arpeggio as usize] = 0;
+                }
+                // Replaced by the instrument's commands before compiling
+                TokenKind::Instrument(_) => {}
+                TokenKind::Direct(addr, value) => {
+                    // Direct register write
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    let chip = self.chips.get_mut(&chip_name).unwrap();
+                    if let Some(chip_event) = chip.chip.direct(chan_idx, addr as u16, value as u8) {
+                        let source = EventSource::Direct { address: addr as u16, value: value as u8 };
+                        self.events.insert(Event::chip(state.time, chan_idx as i8, source, chip_event));
+                    }
+                }
+                TokenKind::RawByte(value) => {
+                    // Raw VGM byte
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    self.events.insert(Event::raw(state.time, value as u8));
+                }
+                TokenKind::TupletStart => {
+                    // Tuplet start (2/3 length)
+                    state.default_len = state.default_len * 2 / 3;
+                }
+                TokenKind::TupletEnd => {
+                    // Tuplet end (3/2 length)
+                    state.default_len = state.default_len * 3 / 2;
+                }
+                TokenKind::NoteOffMode(mode) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    self.note_off_event = mode as i32;
                 }
-            } else if b == b'?' {
-                // Conditional (channel-specific)
-                pos += 1;
-                if pos < bytes.len() {
-                    let cond_ch = bytes[pos];
-                    pos += 1;
-                    let cond_idx = Self::channel_index(cond_ch as char);
-                    if cond_ch != b'.' && cond_idx != Some(chan_idx) {
-                        // Skip until next ?
-                        while pos < bytes.len() && bytes[pos] != b'?' {
-                            pos += 1;
+                TokenKind::MacroRetrigger(mode) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.macro_retrigger = mode != 0;
+                }
+                TokenKind::PhaseSync(ref group) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.phase = 0;
+                    state.phase_count = 0;
+                    for &member in group {
+                        if member == Some(self_idx) {
+                            state.phase = state.phase_count;
                         }
+                        state.phase_count += 1;
+                    }
+                    if state.phase_count > 0 {
+                        state.phase_count += 1;
                     }
                 }
-            } else if b == b'E' && pos + 3 < bytes.len()
-                && bytes[pos + 1] == b'N' && bytes[pos + 2] == b'O' && bytes[pos + 3] == b'F' {
-                // Arpeggio off
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 4;
-                self.macro_use[MacroType::Arpeggio as usize] = -1;
-            } else if b == b'E' && pos + 1 < bytes.len() && bytes[pos + 1] == b'N' {
-                // Arpeggio on
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 2;
-                self.macro_use[MacroType::Arpeggio as usize] = Self::read_num(&text, &mut pos) as i32;
-            } else if b == b'x' {
-                // Direct register write
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 1;
-                let addr = Self::read_num(&text, &mut pos) as u16;
-                let value = Self::read_num(&text, &mut pos) as u8;
-
-                let chip = self.chips.get_mut(&chip_name).unwrap();
-                if let Some(chip_event) = chip.chip.direct(chan_idx, addr, value) {
-                    self.events.insert(Event::new(
-                        state.time,
-                        chan_idx as i8,
-                        EventData::Chip(chip_event),
-                    ));
+                TokenKind::FastForward(seconds) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    self.fast_forward = state.time - seconds * state.framerate as i64;
                 }
-            } else if b == b'y' {
-                // Raw VGM byte
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 1;
-                let value = Self::read_num(&text, &mut pos) as u8;
-                self.events.insert(Event::raw(state.time, value));
-            } else if b == b'{' {
-                // Tuplet start (2/3 length)
-                pos += 1;
-                state.default_len = state.default_len * 2 / 3;
-            } else if b == b'}' {
-                // Tuplet end (3/2 length)
-                pos += 1;
-                state.default_len = state.default_len * 3 / 2;
-            } else if b == b'N' && pos + 2 < bytes.len()
-                && bytes[pos + 1] == b'O' && bytes[pos + 2] == b'E' {
-                // Note off event mode
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 3;
-                self.note_off_event = Self::read_num(&text, &mut pos) as i32;
-            } else if b == b'@' && pos + 1 < bytes.len() && bytes[pos + 1] == b'[' {
-                // Phase sync
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 2;
-                state.phase = 0;
-                state.phase_count = 0;
-                while pos < bytes.len() && bytes[pos] != b']' {
-                    if Self::channel_index(bytes[pos] as char) == Some(chan_idx) {
-                        state.phase = state.phase_count;
-                    }
-                    state.phase_count += 1;
-                    pos += 1;
+                TokenKind::WaitFrames(frames, shift) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    state.time += (frames * state.framerate as i64) >> shift;
                 }
-                if state.phase_count > 0 {
-                    state.phase_count += 1;
+                TokenKind::VolumeSlide(target, frames) => {
+                    // Runs alongside the notes, one step per frame from the last volume
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    self.cut_volume_slide(&mut state, chan_idx);
+                    self.macro_use[MacroType::Volume as usize] = -1;
+                    let chip = self.chips.get_mut(&chip_name).unwrap();
+                    let max_volume = chip.chip.max_volume();
+                    let to = self.volume_mode.scale(target as i16, max_volume);
+                    let from = state.volume.unwrap_or(max_volume);
+                    let frames = frames.max(0);
+                    let mut last = state.volume;
+                    for i in 0..=frames {
+                        let value = match frames {
+                            0 => to,
+                            _ => from + ((to - from) as f64 * i as f64 / frames as f64).round() as i16,
+                        };
+                        if last == Some(value) {
+                            continue;
+                        }
+                        last = Some(value);
+                        if let Some(chip_event) = chip.chip.set_macro(chan_idx, false, MacroCommand::Volume, value).event() {
+                            let source = EventSource::Macro { command: MacroCommand::Volume, value };
+                            let time = state.time + i * state.framerate as i64;
+                            self.events.insert(Event::chip(time, chan_idx as i8, source, chip_event));
+                        }
+                    }
+                    state.volume = Some(to);
+                    state.volume_slide = Some(state.time + frames * state.framerate as i64);
                 }
-                if pos < bytes.len() && bytes[pos] == b']' {
-                    pos += 1;
+                TokenKind::Fade(frames) => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    // The earliest fade in any channel wins
+                    let fade = (state.time, frames.max(0) * state.framerate as i64);
+                    match self.fade {
+                        Some((start, _)) if start <= fade.0 => {}
+                        _ => self.fade = Some(fade),
+                    }
                 }
-            } else if b == b'@' && pos + 1 < bytes.len() && bytes[pos + 1] == b'!' {
-                // Fast forward
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 2;
-                self.fast_forward = state.time - Self::read_num(&text, &mut pos) * self.framerate as i64;
-            } else if b == b'@' && pos + 1 < bytes.len() && bytes[pos + 1] == b'w' {
-                // Wait frames
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-                pos += 2;
-                let x = Self::read_num(&text, &mut pos);
-                let y = Self::read_num(&text, &mut pos);
-                state.time += (x * self.framerate as i64) >> y;
-            } else if b == b'@' && pos + 1 < bytes.len() && bytes[pos + 1] == b'/' {
-                // Portamento parameters
-                pos += 2;
-                for i in 0..8 {
-                    self.portamento[i] = Self::read_num(&text, &mut pos);
-                }
-            } else if b >= b'@' {
-                // Macro command
-                self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
-
-                // Extract command name
-                let mut name = String::new();
-                while pos < bytes.len() && bytes[pos] >= b'@' {
-                    name.push(bytes[pos] as char);
-                    pos += 1;
-                    if name.len() >= 7 {
-                        break;
+                TokenKind::Echo(depth, delay) => {
+                    if channel.echo.is_some() {
+                        // The echo's own copy of the command
+                    } else if delay <= 0 {
+                        let message = "`@echo` needs a delay of 1 frame or more".to_string();
+                        self.lint(code::OUT_OF_RANGE, chan_idx, token.span, message, None);
+                    } else {
+                        let depth = depth.clamp(0, i16::MAX as i64) as i16;
+                        self.echoes.insert(chan_idx, (depth, delay * state.framerate as i64));
                     }
                 }
+                TokenKind::Portamento(params) => {
+                    self.portamento = params;
+                }
+                TokenKind::Macro { ref name, value: raw } => {
+                    self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+                    let mut value = raw as i16;
+                    if i16::try_from(raw).is_err() {
+                        let message = format!("`{}{}` is out of range", name, raw);
+                        self.lint(code::OUT_OF_RANGE, chan_idx, token.span, message, None);
+                    }
 
-                let value = Self::read_num(&text, &mut pos) as i16;
-
-                // Try to match static command
-                if let Some(mac_type) = MacroType::from_stat_name(&name) {
-                    self.macro_use[mac_type as usize] = -1;
-                    let chip = self.chips.get_mut(&chip_name).unwrap();
-                    let mac_cmd = match mac_type {
-                        MacroType::Volume => MacroCommand::Volume,
-                        MacroType::Panning => MacroCommand::Panning,
-                        MacroType::Tone => MacroCommand::Tone,
-                        MacroType::Global => MacroCommand::Global,
-                        MacroType::Multiply => MacroCommand::Multiply,
-                        MacroType::Waveform => MacroCommand::Waveform,
-                        MacroType::ModWaveform => MacroCommand::Waveform,
-                        MacroType::VolumeEnv => MacroCommand::Volume,
-                        MacroType::Sample => MacroCommand::Sample,
-                        MacroType::SampleList => MacroCommand::SampleList,
-                        _ => MacroCommand::Volume,
-                    };
-                    if let Some(chip_event) = chip.chip.set_macro(chan_idx, false, mac_cmd, value) {
-                        self.events.insert(Event::new(
-                            state.time,
-                            chan_idx as i8,
-                            EventData::Chip(chip_event),
-                        ));
+                    // Try to match static command
+                    if name == "@SL" {
+                        // Select sample list
+                        self.sample_list = if value < 0 { -1 } else { (value & 255) as i32 };
+                    } else if name == "@SA" {
+                        // Sample list accent level
+                        self.sample_accent = (value as i32).max(0);
+                    } else if name == "@s" {
+                        self.dac_sample(&chip_name, chan_idx, state.time, value, token.span.start);
+                    } else if name == "@g" {
+                        // Groove, with steps of the default length (negative turns it off)
+                        state.groove = None;
+                        if raw >= 0 {
+                            match self.grooves.get(&raw) {
+                                Some(ticks) => state.groove = Some(Groove::new(ticks.clone(), state.default_len)),
+                                None => {
+                                    let message = format!("groove {} is not defined, @g ignored", raw);
+                                    let suggestion = format!("define it with `#GROOVE {} = ...`", raw);
+                                    self.lint(code::UNDEFINED_MACRO, chan_idx, token.span, message, Some(suggestion));
+                                }
+                            }
+                        }
+                    } else if let Some(mac_type) = MacroType::from_stat_name(name) {
+                        if mac_type == MacroType::Panning {
+                            value = self.pan_value(&channel, value);
+                        } else if mac_type == MacroType::Volume {
+                            let max_volume = self.chips[&chip_name].chip.max_volume();
+                            value = self.volume_mode.scale(value, max_volume);
+                            if !(0..=max_volume).contains(&value) {
+                                let message =
+                                    format!("volume `{}{}` is outside {}'s range 0-{}", name, raw, chip_name, max_volume);
+                                self.lint(code::OUT_OF_RANGE, chan_idx, token.span, message, None);
+                            }
+                            state.volume = Some(value);
+                            self.cut_volume_slide(&mut state, chan_idx);
+                        } else if mac_type == MacroType::Global && self.mono && chip_name == "HuC6280" {
+                            let loc = self.locate(chan_idx, token.span.start);
+                            self.warn(format!("{}: HuC6280 global balance is a stereo effect, ignored with #MONO", loc));
+                            continue;
+                        }
+                        self.macro_use[mac_type as usize] = -1;
+                        let chip = self.chips.get_mut(&chip_name).unwrap();
+                        let mac_cmd = match mac_type {
+                            MacroType::Volume => MacroCommand::Volume,
+                            MacroType::Panning => MacroCommand::Panning,
+                            MacroType::Tone => MacroCommand::Tone,
+                            MacroType::Global => MacroCommand::Global,
+                            MacroType::Multiply => MacroCommand::Multiply,
+                            MacroType::Waveform => MacroCommand::Waveform,
+                            MacroType::ModWaveform => MacroCommand::Waveform,
+                            MacroType::VolumeEnv => MacroCommand::Volume,
+                            MacroType::Sample => MacroCommand::Sample,
+                            MacroType::SampleList => MacroCommand::SampleList,
+                            _ => MacroCommand::Volume,
+                        };
+                        match chip.chip.set_macro(chan_idx, false, mac_cmd, value) {
+                            MacroResult::Event(chip_event) => {
+                                let source = EventSource::Macro { command: mac_cmd, value };
+                                self.events.insert(Event::chip(state.time, chan_idx as i8, source, chip_event));
+                            }
+                            MacroResult::NoEvent => {}
+                            MacroResult::Unsupported => {
+                                if self.unsupported_macros.insert((chan_idx, mac_cmd)) {
+                                    let message = format!("chip {} does not support `{}`, ignored on this channel", chip_name, name);
+                                    self.lint(code::UNSUPPORTED_MACRO, chan_idx, token.span, message, None);
+                                }
+                            }
+                        }
+                    } else if let Some(mac_type) = MacroType::from_dyn_name(name) {
+                        if !(0..=255).contains(&raw) {
+                            let message = format!("envelope number `{}{}` is outside 0-255", name, raw);
+                            self.lint(code::OUT_OF_RANGE, chan_idx, token.span, message, None);
+                        }
+                        if matches!(mac_type, MacroType::Volume | MacroType::VolumeEnv) {
+                            self.cut_volume_slide(&mut state, chan_idx);
+                        }
+                        self.select_macro(mac_type, (value & 255) as i32, chan_idx, token.span.start);
+                        state.macro_indices[mac_type as usize] = 0;
+                    } else {
+                        let message = format!("unknown command `{}`", name);
+                        self.lint(code::UNKNOWN_COMMAND, chan_idx, token.span, message, None);
                     }
-                } else if let Some(mac_type) = MacroType::from_dyn_name(&name) {
-                    self.macro_use[mac_type as usize] = (value & 255) as i32;
                 }
-            } else {
-                // Skip unknown characters
-                pos += 1;
             }
         }
 
         // Send final note
         self.send_note_if_pending(&mut state, chan_idx, clock_div, note_bits, basic_octave);
+        self.cut_volume_slide(&mut state, chan_idx);
 
         // Update channel duration
         if let Some(ref mut ch) = self.channels[chan_idx] {
             ch.duration = state.time;
         }
-
-        if self.total_samples < state.time {
-            self.total_samples = state.time;
+        // Text after the last token played is reached at the end
+        let end = channel.text.len();
+        self.beat_marks.entry(end).or_insert((end, tempo_map.musical_time(state.time)));
+        self.tempo_maps.insert(chan_idx, tempo_map);
+
+        // Shifted events can end after the channel does
+        let end = (state.time + self.events.latency(chan_idx)).max(state.time);
+        if self.total_samples < end {
+            self.total_samples = end;
         }
 
-        // Print channel info
-        let ch_char = if chan_idx < 26 {
-            (b'A' + chan_idx as u8) as char
-        } else {
-            (b'a' + (chan_idx - 26) as u8) as char
-        };
-        println!("|  {}  |  {:8}  |  {:8}  |", ch_char, state.time, self.loop_point);
-
         Ok(())
     }
 
-    /// Read note length value
-    fn read_len(&self, text: &str, pos: &mut usize, tempo: i32) -> i64 {
-        let x = Self::read_num(text, pos) as i32;
-        let mut dots = 0;
-        let bytes = text.as_bytes();
-        while *pos < bytes.len() && bytes[*pos] == b'.' {
-            dots += 1;
-            *pos += 1;
-        }
-        Self::calc_note_len(tempo, x, dots)
+    /// Drop the steps of a channel's running `@vs` slide from now on, where
+    /// a volume command or the end of the channel stops it
+    fn cut_volume_slide(&mut self, state: &mut ChannelCompileState, chan_idx: usize) {
+        let Some(end) = state.volume_slide.take() else {
+            return;
+        };
+        let latency = self.events.latency(chan_idx);
+        let steps = (state.time + latency).max(0)..=end + latency;
+        self.events.retain(|event| {
+            let slide = matches!(event.source, EventSource::Macro { command: MacroCommand::Volume, .. });
+            !(slide && event.channel == chan_idx as i8 && steps.contains(&event.time))
+        });
     }
 
-    /// Read note modifiers (accidentals, length, dots)
-    fn read_note(&self, text: &str, pos: &mut usize, state: &mut ChannelCompileState) {
-        self.read_note_params(text, pos, &mut state.current_len, &mut state.current_note, state.tempo);
+    /// Select a dynamic macro envelope, disabling it if the envelope is not defined
+    fn select_macro(&mut self, mac_type: MacroType, id: i32, chan_idx: usize, pos: usize) {
+        let defined = (0..256).contains(&id) && !self.macro_env[mac_type as usize][id as usize].is_empty();
+        if defined {
+            self.macro_use[mac_type as usize] = id;
+        } else {
+            let message = format!("envelope {}{} is not defined, macro disabled", mac_type.dyn_name(), id);
+            let suggestion = format!("define it with `{}{} = {{ ... }}`", mac_type.dyn_name(), id);
+            self.lint(code::UNDEFINED_MACRO, chan_idx, Span { start: pos, end: pos + 1 }, message, Some(suggestion));
+            self.macro_use[mac_type as usize] = -1;
+        }
     }
 
-    /// Read note parameters
-    fn read_note_params(&self, text: &str, pos: &mut usize, len: &mut i64, note: &mut i32, tempo: i32) {
-        let bytes = text.as_bytes();
-        let len2 = *len;
-
-        // Parse accidentals (if note >= 0)
-        if *note >= 0 {
-            while *pos < bytes.len() {
-                match bytes[*pos] {
-                    b'+' => {
-                        *note += 1;
-                        *pos += 1;
-                    }
-                    b'-' => {
-                        *note -= 1;
-                        *pos += 1;
-                    }
-                    b'\'' => {
-                        *note += self.octave_count;
-                        *pos += 1;
-                    }
-                    _ => break,
+    /// Replace each `@"name"` with the macro commands of the instrument
+    fn expand_instruments(&mut self, tokens: Vec<Token>, chan_idx: usize) -> Vec<Token> {
+        let mut out = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            let TokenKind::Instrument(ref name) = token.kind else {
+                out.push(token);
+                continue;
+            };
+            match self.instruments.get(name) {
+                Some(commands) => out.extend(commands.iter().map(|(name, value)| Token {
+                    kind: TokenKind::Macro { name: name.clone(), value: *value },
+                    span: token.span,
+                })),
+                None => {
+                    let message = format!("instrument \"{}\" is not defined", name);
+                    let suggestion = format!("define it with `@INST \"{}\" {{ ... }}` or read a library with `#INSTLIB`", name);
+                    self.lint(code::UNDEFINED_MACRO, chan_idx, token.span, message, Some(suggestion));
                 }
             }
         }
+        out
+    }
 
-        // Parse length
-        let x = Self::read_num(text, pos) as i32;
-        let mut dots = 0;
-        while *pos < bytes.len() && bytes[*pos] == b'.' {
-            dots += 1;
-            *pos += 1;
+    /// `@NMAP` value of a note, taken by degree so that a table as long as
+    /// the scale maps every octave alike
+    fn noise_map_value(&self, note: i32) -> Option<i16> {
+        let id = self.macro_use[MacroType::NoiseMap as usize];
+        let data = &self.macro_env[MacroType::NoiseMap as usize].get(usize::try_from(id).ok()?)?.data;
+        (!data.is_empty()).then(|| data[note.rem_euclid(data.len() as i32) as usize])
+    }
+
+    /// `@NMAP` value of a note on a chip that plays it in place of the note
+    fn noise_value(&self, chip_name: &str, note: i32) -> Option<i64> {
+        match self.chips[chip_name].chip.noise_target() {
+            NoiseTarget::Note => self.noise_map_value(note).map(i64::from),
+            NoiseTarget::Macro(_) => None,
+        }
+    }
+
+    /// `@NMAP` macro command and value of a note on a chip that takes it
+    /// through a macro
+    fn noise_macro(&self, chip_name: &str, note: i32) -> Option<(MacroCommand, i16)> {
+        match self.chips[chip_name].chip.noise_target() {
+            NoiseTarget::Note => None,
+            NoiseTarget::Macro(command) => self.noise_map_value(note).map(|value| (command, value)),
         }
+    }
 
-        if x != 0 {
-            *len = Self::calc_note_len(tempo, x, dots);
-        } else {
-            // Just dots - extend current length
-            let mut j = len2;
-            for _ in 0..dots {
-                j /= 2;
-                *len += j;
-            }
+    /// Length of a note in samples; an omitted value extends `base` by the dots
+    fn note_len(&self, length: token::Length, base: i64, tempo: i32) -> i64 {
+        if length.value != 0 {
+            return Self::calc_note_len(tempo, length.value as i32, length.dots);
+        }
+        let mut len = base;
+        let mut j = base;
+        for _ in 0..length.dots {
+            j /= 2;
+            len += j;
         }
+        len
     }
 
     /// Send pending note/rest and advance time
@@ -1278,6 +3368,12 @@ impl Compiler {
         note_bits: i32,
         basic_octave: i32,
     ) {
+        let freq = state.current_freq.take();
+        let bend = std::mem::take(&mut state.current_bend);
+        if let Some(groove) = state.groove.as_mut() {
+            state.current_len = groove.advance(state.current_len);
+        }
+
         // Phase check
         if state.current_len > 0 {
             state.phase_counter = (state.phase_counter + 1) % state.phase_count.max(1);
@@ -1302,7 +3398,7 @@ impl Compiler {
 
         let note = state.current_note;
         let dur = state.current_len;
-        let detune = state.detune;
+        let detune = state.detune + channel.detune_offset + self.rng.jitter(state.jitter);
         let mut quantize = state.quantize;
         let kind = state.kind;
 
@@ -1315,41 +3411,129 @@ impl Compiler {
             // Rest
             let chip = self.chips.get_mut(chip_name).unwrap();
             if let Some(chip_event) = chip.chip.rest(chan_idx, dur as i32) {
-                self.events.insert(Event::new(
-                    state.time,
-                    chan_idx as i8,
-                    EventData::Chip(chip_event),
-                ));
+                self.events.insert(Event::chip(state.time, chan_idx as i8, EventSource::Rest, chip_event));
             }
         } else if note >= 0 {
-            // Note
-            let o1 = note / self.octave_count;
-            let o = if note_bits < 0 {
+            // Sample list lookup (may shift the pitch of the note)
+            let layer = if self.sample_list != -1 {
+                let env = &self.macro_env[MacroType::SampleList as usize][self.sample_list as usize];
+                Some(env.sample_layer(note as usize, self.sample_accent as usize))
+            } else {
+                None
+            };
+            let note = note + layer.map_or(0, |l| l.pitch as i32);
+
+            // Note (or exact frequency)
+            let max_volume = self.chips[chip_name].chip.max_volume();
+            let (v, o1) = match freq {
+                Some(freq) => {
+                    let pitch_divider = self.chips[chip_name].chip.pitch_divider();
+                    let (value, block) = Self::freq_chip_value(freq, clock_div, note_bits, pitch_divider);
+                    (value - detune, block.unwrap_or(note / self.octave_count))
+                }
+                None => match self.noise_value(chip_name, note) {
+                    Some(value) => (value, note / self.octave_count),
+                    None => {
+                        let value = self.note_chip_value(note, clock_div, note_bits, basic_octave);
+                        let bent = Self::bend_value(value, bend, clock_div);
+                        if bend != 0 && bent == value && clock_div != 0 {
+                            let message = format!("`~{:+}` is below {}'s pitch resolution at key {} (o{}), which plays unbent", bend, chip_name, note, note / self.octave_count);
+                            let suggestion = "bend by more cents, or play the note an octave lower".to_string();
+                            self.lint(code::BEND_TOO_FINE, chan_idx, state.note_span, message, Some(suggestion));
+                        }
+                        let value = if clock_div != 0 { bent - detune } else { value };
+                        (value, note / self.octave_count)
+                    }
+                },
+            };
+            let v = match Self::note_value_range(clock_div, note_bits) {
+                Some(range) if !range.contains(&v) && self.noise_value(chip_name, note).is_none() => {
+                    let clamped = v.clamp(*range.start(), *range.end());
+                    let too_low = (clock_div < 0) == (v > *range.end());
+                    let message = format!(
+                        "key {} (o{}) needs a {} {} of {}, outside its {}-bit range {}-{}; clamped to {}",
+                        note,
+                        note / self.octave_count,
+                        chip_name,
+                        if clock_div < 0 { "period" } else { "frequency number" },
+                        v,
+                        note_bits,
+                        range.start(),
+                        range.end(),
+                        clamped
+                    );
+                    let suggestion = if too_low { "play it an octave higher" } else { "play it an octave lower" };
+                    self.lint(code::OUT_OF_RANGE, chan_idx, state.note_span, message, Some(suggestion.to_string()));
+                    clamped
+                }
+                _ => v,
+            };
+            let d = (dur - quantize).max(0);
+            if let Some((command, value)) = self.noise_macro(chip_name, note) {
+                let chip = self.chips.get_mut(chip_name).unwrap();
+                if let Some(event) = chip.chip.set_macro(chan_idx, true, command, value).event() {
+                    let source = EventSource::Macro { command, value };
+                    self.events.insert(Event::chip(state.time, chan_idx as i8, source, event));
+                }
+            }
+
+            // Slide from the previous pitch: over #TIE-SLIDE (or the `@/`
+            // time) when tied with `&`, and with the `@/` settings after `/`
+            let slide_frames = if clock_div == 0 || note == state.old_note || self.noise_value(chip_name, note).is_some() {
                 0
-            } else if clock_div < 0 {
-                o1 - basic_octave
+            } else if kind & 4 != 0 {
+                if self.tie_slide > 0 {
+                    self.tie_slide
+                } else {
+                    self.portamento[1]
+                }
+            } else if kind & 8 != 0 {
+                match self.portamento {
+                    [_, time, ..] if time > 0 => time,
+                    // A step without a time slides for the whole note
+                    [_, _, step, ..] if step != 0 => i64::MAX,
+                    _ => 0,
+                }
             } else {
-                basic_octave - o1
+                0
             };
-            let n = (note % self.octave_count) as usize;
-            let v = if clock_div != 0 {
-                (self.note_value[n] >> o) - detune
+            let slide = if slide_frames > 0 {
+                let frames = slide_frames.min(d / state.framerate as i64).max(1);
+                self.calc_portamento(
+                    clock_div,
+                    note_bits,
+                    basic_octave,
+                    state.old_note,
+                    note,
+                    frames as usize,
+                )
             } else {
-                n as i64
+                Vec::new()
             };
-            let d = (dur - quantize).max(0);
+            for (i, &value) in slide.iter().enumerate() {
+                let value = value - detune;
+                let chip = self.chips.get_mut(chip_name).unwrap();
+                if let Some(event) = chip.chip.note_change(chan_idx, value as i32, o1) {
+                    let t = state.time + i as i64 * state.framerate as i64;
+                    let source = EventSource::NoteChange { value: value as i32, octave: o1 };
+                    self.events.insert(Event::chip(t, chan_idx as i8, source, event));
+                }
+            }
+            let slide_time = slide.len() as i64 * state.framerate as i64;
 
             // Sample list handling
-            if self.sample_list != -1 {
-                let sample_id = self.macro_env[MacroType::SampleList as usize][self.sample_list as usize]
-                    .data.get(note as usize).copied().unwrap_or(0);
+            if let Some(layer) = layer {
                 let chip = self.chips.get_mut(chip_name).unwrap();
-                if let Some(chip_event) = chip.chip.set_macro(chan_idx, true, MacroCommand::Sample, sample_id) {
-                    self.events.insert(Event::new(
-                        state.time,
-                        chan_idx as i8,
-                        EventData::Chip(chip_event),
-                    ));
+                if let Some(chip_event) = chip.chip.set_macro(chan_idx, true, MacroCommand::Sample, layer.sample).event() {
+                    let source = EventSource::Macro { command: MacroCommand::Sample, value: layer.sample };
+                    self.events.insert(Event::chip(state.time, chan_idx as i8, source, chip_event));
+                }
+                if layer.volume >= 0 {
+                    let volume = self.volume_mode.scale(layer.volume, max_volume);
+                    if let Some(chip_event) = chip.chip.set_macro(chan_idx, false, MacroCommand::Volume, volume).event() {
+                        let source = EventSource::Macro { command: MacroCommand::Volume, value: volume };
+                        self.events.insert(Event::chip(state.time, chan_idx as i8, source, chip_event));
+                    }
                 }
             }
 
@@ -1357,33 +3541,35 @@ impl Compiler {
             if self.note_off_event == 1 && (kind & 12) == 0 {
                 let chip = self.chips.get_mut(chip_name).unwrap();
                 if let Some(chip_event) = chip.chip.note_off(chan_idx, v as i32, o1) {
-                    self.events.insert(Event::new(
-                        state.time,
-                        chan_idx as i8,
-                        EventData::Chip(chip_event),
-                    ));
+                    self.events.insert(Event::chip(state.time, chan_idx as i8, EventSource::NoteOff, chip_event));
                 }
             }
 
             // Note on or change
-            let chip_event = {
+            let (chip_event, source) = {
                 let chip = self.chips.get_mut(chip_name).unwrap();
                 if kind & 12 != 0 {
-                    chip.chip.note_change(chan_idx, v as i32, o1)
+                    let source = EventSource::NoteChange { value: v as i32, octave: o1 };
+                    (chip.chip.note_change(chan_idx, v as i32, o1), source)
                 } else {
-                    chip.chip.note_on(chan_idx, v as i32, o1, d as i32)
+                    let source = EventSource::NoteOn { key: note, duration: d };
+                    (chip.chip.note_on(chan_idx, v as i32, o1, d as i32), source)
                 }
             };
             if let Some(event) = chip_event {
-                self.events.insert(Event::new(
-                    state.time,
-                    chan_idx as i8,
-                    EventData::Chip(event),
-                ));
+                self.events.insert(Event::chip(state.time + slide_time, chan_idx as i8, source, event));
             }
 
             // Process macro envelopes during note
-            let mut macro_indices = [0i32; MAX_MACRO_TYPES];
+            if state.macro_retrigger {
+                state.macro_indices = [0; MAX_MACRO_TYPES];
+            }
+            let macro_indices = &mut state.macro_indices;
+            // Note value the pitch envelope offsets, following the arpeggio
+            let mut pitch = (v, o1);
+            let mut pitch_offset = 0;
+            // Envelopes the chip has no use for, reported at the note
+            let mut unsupported = Vec::new();
             let mut t = state.time;
             while t < state.time + d {
                 for mac_type_idx in 0..MAX_MACRO_TYPES {
@@ -1395,32 +3581,61 @@ impl Compiler {
                         if idx < env.data.len() {
                             if mac_type_idx == MacroType::Arpeggio as usize {
                                 // Arpeggio modifies note pitch
-                                let arp_offset = env.data[idx];
-                                if arp_offset != 0 {
-                                    let arp_note = note + arp_offset as i32;
-                                    let arp_o1 = arp_note / self.octave_count;
-                                    let arp_o = if note_bits < 0 {
-                                        0
-                                    } else if clock_div < 0 {
-                                        arp_o1 - basic_octave
-                                    } else {
-                                        basic_octave - arp_o1
-                                    };
-                                    let arp_n = (arp_note % self.octave_count) as usize;
-                                    let arp_v = if clock_div != 0 {
-                                        (self.note_value[arp_n] >> arp_o) - detune
-                                    } else {
-                                        arp_n as i64
-                                    };
+                                let arp_value = env.data[idx] as i32;
+                                let arp_note = match env.arp_mode {
+                                    ArpMode::Relative if arp_value != 0 => Some(note + arp_value),
+                                    ArpMode::Absolute => Some(arp_value),
+                                    _ => None,
+                                };
+                                if let Some((command, value)) = arp_note.and_then(|n| self.noise_macro(chip_name, n)) {
+                                    let chip = self.chips.get_mut(chip_name).unwrap();
+                                    if let Some(event) = chip.chip.set_macro(chan_idx, true, command, value).event() {
+                                        let source = EventSource::Macro { command, value };
+                                        self.events.insert(Event::chip(t, chan_idx as i8, source, event));
+                                    }
+                                }
+                                let noise = arp_note.and_then(|arp_note| self.noise_value(chip_name, arp_note));
+                                let target = match arp_note {
+                                    Some(arp_note) if noise.is_some() => noise.map(|value| (value, arp_note / self.octave_count)),
+                                    Some(arp_note) => {
+                                        let arp_o1 = arp_note / self.octave_count;
+                                        let arp_v = self.note_chip_value(arp_note, clock_div, note_bits, basic_octave);
+                                        let arp_v = Self::bend_value(arp_v, bend, clock_div);
+                                        let arp_v = if clock_div != 0 { arp_v - detune } else { arp_v };
+                                        Some((arp_v, arp_o1))
+                                    }
+                                    None if env.arp_mode == ArpMode::Fixed => Some((arp_value as i64, o1)),
+                                    None => None,
+                                };
+                                if let Some((arp_v, arp_o1)) = target {
+                                    pitch = (arp_v, arp_o1);
                                     let chip = self.chips.get_mut(chip_name).unwrap();
                                     if let Some(event) = chip.chip.note_change(chan_idx, arp_v as i32, arp_o1) {
-                                        self.events.insert(Event::new(t, chan_idx as i8, EventData::Chip(event)));
+                                        let source = EventSource::NoteChange { value: arp_v as i32, octave: arp_o1 };
+                                        self.events.insert(Event::chip(t, chan_idx as i8, source, event));
                                     }
                                 }
+                            } else if mac_type_idx == MacroType::PitchEnv as usize {
+                                // Pitch envelope offsets the chip note value directly
+                                let (pitch_v, pitch_o1) = pitch;
+                                pitch_offset = env.data[idx] as i64;
+                                let value = pitch_v + pitch_offset;
+                                let chip = self.chips.get_mut(chip_name).unwrap();
+                                if let Some(event) = chip.chip.note_change(chan_idx, value as i32, pitch_o1) {
+                                    let source = EventSource::NoteChange { value: value as i32, octave: pitch_o1 };
+                                    self.events.insert(Event::chip(t, chan_idx as i8, source, event));
+                                }
                             } else {
                                 // Other macros
-                                let value = env.data[idx];
-                                let mac_cmd = match MacroType::all().nth(mac_type_idx).unwrap() {
+                                let value = if mac_type_idx == MacroType::Panning as usize {
+                                    self.pan_value(&channel, env.data[idx])
+                                } else if mac_type_idx == MacroType::Volume as usize {
+                                    env.volume_mode.unwrap_or(self.volume_mode).scale(env.data[idx], max_volume)
+                                } else {
+                                    env.data[idx]
+                                };
+                                let mac_type = MacroType::all().nth(mac_type_idx).unwrap();
+                                let mac_cmd = match mac_type {
                                     MacroType::Volume => MacroCommand::Volume,
                                     MacroType::Panning => MacroCommand::Panning,
                                     MacroType::Tone => MacroCommand::Tone,
@@ -1431,8 +3646,17 @@ impl Compiler {
                                     _ => continue,
                                 };
                                 let chip = self.chips.get_mut(chip_name).unwrap();
-                                if let Some(event) = chip.chip.set_macro(chan_idx, true, mac_cmd, value) {
-                                    self.events.insert(Event::new(t, chan_idx as i8, EventData::Chip(event)));
+                                match chip.chip.set_macro(chan_idx, true, mac_cmd, value) {
+                                    MacroResult::Event(event) => {
+                                        let source = EventSource::Macro { command: mac_cmd, value };
+                                        self.events.insert(Event::chip(t, chan_idx as i8, source, event));
+                                    }
+                                    MacroResult::NoEvent => {}
+                                    MacroResult::Unsupported => {
+                                        if self.unsupported_macros.insert((chan_idx, mac_cmd)) {
+                                            unsupported.push(mac_type);
+                                        }
+                                    }
                                 }
                             }
 
@@ -1445,18 +3669,37 @@ impl Compiler {
                         }
                     }
                 }
-                t += self.framerate as i64;
+
+                // Vibrato swings the pitch around the note and pitch envelope,
+                // and a #WIDE copy alternates either side of it every frame
+                let vibrato = self.macro_use[MacroType::Vibrato as usize];
+                let frame = (t - state.time) / state.framerate as i64;
+                let vibrato_offset = match vibrato {
+                    -1 => None,
+                    _ => envelope::vibrato_offset(&self.macro_env[MacroType::Vibrato as usize][vibrato as usize].data, frame),
+                };
+                let wide_offset = channel.wide.map(|depth| if frame % 2 == 0 { depth } else { -depth });
+                if clock_div != 0 && (vibrato_offset.is_some() || wide_offset.is_some()) {
+                    let (pitch_v, pitch_o1) = pitch;
+                    let value = pitch_v + pitch_offset + vibrato_offset.unwrap_or(0) + wide_offset.unwrap_or(0);
+                    let chip = self.chips.get_mut(chip_name).unwrap();
+                    if let Some(event) = chip.chip.note_change(chan_idx, value as i32, pitch_o1) {
+                        let source = EventSource::NoteChange { value: value as i32, octave: pitch_o1 };
+                        self.events.insert(Event::chip(t, chan_idx as i8, source, event));
+                    }
+                }
+                t += state.framerate as i64;
+            }
+            for mac_type in unsupported {
+                let message = format!("chip {} does not support `{}` envelopes, ignored on this channel", chip_name, mac_type.dyn_name());
+                self.lint(code::UNSUPPORTED_MACRO, chan_idx, state.note_span, message, None);
             }
 
             // Note off after note (if mode 0)
             if self.note_off_event == 0 && (kind & 3) == 0 {
                 let chip = self.chips.get_mut(chip_name).unwrap();
                 if let Some(chip_event) = chip.chip.note_off(chan_idx, v as i32, o1) {
-                    self.events.insert(Event::new(
-                        state.time + d,
-                        chan_idx as i8,
-                        EventData::Chip(chip_event),
-                    ));
+                    self.events.insert(Event::chip(state.time + d, chan_idx as i8, EventSource::NoteOff, chip_event));
                 }
             }
 
@@ -1468,21 +3711,284 @@ impl Compiler {
         state.kind <<= 2;
     }
 
+    /// Panning value after #MONO and forced channel panning are applied
+    fn pan_value(&self, channel: &Channel, value: i16) -> i16 {
+        if self.mono {
+            0
+        } else {
+            channel.pan_override.unwrap_or(value)
+        }
+    }
+
+    /// Chip note value of a note, before detune
+    fn note_chip_value(&self, note: i32, clock_div: i32, note_bits: i32, basic_octave: i32) -> i64 {
+        let o1 = note / self.octave_count;
+        let o = if note_bits < 0 {
+            0
+        } else if clock_div < 0 {
+            o1 - basic_octave
+        } else {
+            basic_octave - o1
+        };
+        let n = (note % self.octave_count) as usize;
+        if clock_div == 0 {
+            return n as i64;
+        }
+        match self.quantization {
+            // Notes outside the chip's octaves keep doubling or halving
+            // rather than failing; the chip decides what it can play
+            Quantization::RoundDown => match o {
+                0.. => self.note_value[n].checked_shr(o as u32).unwrap_or(0),
+                _ => self.note_value[n].checked_shl(o.unsigned_abs()).unwrap_or(i64::MAX),
+            },
+            Quantization::RoundNearest => self.exact_chip_value(note, clock_div, note_bits, basic_octave).round() as i64,
+            Quantization::ErrorDiffusion => tuning::diffuse(
+                (note.min(0)..=note).map(|k| self.exact_chip_value(k, clock_div, note_bits, basic_octave)),
+            ),
+        }
+    }
+
+    /// Chip note value of a note before rounding
+    fn exact_chip_value(&self, note: i32, clock_div: i32, note_bits: i32, basic_octave: i32) -> f64 {
+        let o1 = note.div_euclid(self.octave_count);
+        let o = if note_bits < 0 {
+            0
+        } else if clock_div < 0 {
+            o1 - basic_octave
+        } else {
+            basic_octave - o1
+        };
+        self.note_exact[note.rem_euclid(self.octave_count) as usize] / 2f64.powi(o)
+    }
+
+    /// Chip note value of an exact frequency in Hz, before detune. Chips that
+    /// take an octave (negative note bits) get the lowest one that fits the
+    /// value in their note bits, returned alongside.
+    fn freq_chip_value(freq: f64, clock_div: i32, note_bits: i32, pitch_divider: f64) -> (i64, Option<i32>) {
+        let q = clock_div.unsigned_abs() as f64;
+        if clock_div < 0 {
+            return ((q / (freq * pitch_divider)).round() as i64, None);
+        }
+        let value = freq * pitch_divider / q;
+        if note_bits >= 0 {
+            return (value.round() as i64, None);
+        }
+        let limit = (1u64 << -note_bits) as f64 - 0.5;
+        let block = (0..7).find(|&b| value / f64::from(1 << b) < limit).unwrap_or(7);
+        ((value / f64::from(1 << block)).round() as i64, Some(block))
+    }
+
+    /// A note's chip value bent by cents: frequency numbers rise with the
+    /// pitch and periods fall
+    fn bend_value(value: i64, cents: i64, clock_div: i32) -> i64 {
+        if cents == 0 || clock_div == 0 {
+            return value;
+        }
+        let ratio = 2f64.powf(cents as f64 / 1200.0);
+        if clock_div < 0 {
+            (value as f64 / ratio).round() as i64
+        } else {
+            (value as f64 * ratio).round() as i64
+        }
+    }
+
+    /// Pitch in Hz of a scale note on the current chip
+    fn note_pitch(&self, note: i32, clock_div: i32, note_bits: i32, basic_octave: i32, pitch_divider: f64) -> f64 {
+        let q = clock_div.unsigned_abs() as f64;
+        let o1 = note / self.octave_count;
+        let value = self.note_chip_value(note, clock_div, note_bits, basic_octave).max(1) as f64;
+        if clock_div < 0 {
+            q / (value * pitch_divider)
+        } else if note_bits >= 0 {
+            value * q / pitch_divider
+        } else {
+            value * q * f64::from(1 << o1) / pitch_divider
+        }
+    }
+
+    /// Octaves (0-7) whose note values can be shifted into range on a chip
+    fn playable_octaves(clock_div: i32, note_bits: i32, basic_octave: i32) -> impl Iterator<Item = i32> {
+        (0..8).filter(move |&o1| note_bits < 0 || (if clock_div < 0 { o1 - basic_octave } else { basic_octave - o1 }) >= 0)
+    }
+
+    /// Scale note whose pitch on the current chip is closest to a frequency
+    pub(crate) fn nearest_note(&self, freq: f64, clock_div: i32, note_bits: i32, basic_octave: i32, pitch_divider: f64) -> i32 {
+        let distance = |note: i32| {
+            let pitch = self.note_pitch(note, clock_div, note_bits, basic_octave, pitch_divider);
+            (pitch / freq).log2().abs()
+        };
+        Self::playable_octaves(clock_div, note_bits, basic_octave)
+            .flat_map(|o1| o1 * self.octave_count..(o1 + 1) * self.octave_count)
+            .min_by(|&a, &b| distance(a).total_cmp(&distance(b)))
+            .unwrap_or(0)
+    }
+
+    /// Octaves between a chip's `o4 c` and standard pitch (`#PITCH-CHANGE`
+    /// sets `o8 c`), or None if the chip's pitch is not known
+    fn chip_octave_offset(&mut self, chip_name: &str) -> Option<i32> {
+        let instance = self.chips.get(chip_name)?;
+        let chip = &instance.chip;
+        let (clock_div, note_bits, basic_octave, pitch_divider) =
+            (chip.clock_div(), chip.note_bits(), instance.basic_octave(), chip.pitch_divider());
+        if clock_div == 0 || pitch_divider <= 0.0 {
+            return None;
+        }
+        self.figure_out_note_values(clock_div, note_bits);
+        // The playable octave nearest to 4, compared with where it should sound
+        let octave = Self::playable_octaves(clock_div, note_bits, basic_octave).min_by_key(|o1| (o1 - 4).abs())?;
+        let pitch = self.note_pitch(octave * self.octave_count, clock_div, note_bits, basic_octave, pitch_divider);
+        let standard = self.note_freq[0] * self.base_freq * f64::from(1 << octave) / 256.0;
+        Some((pitch / standard).log2().round() as i32)
+    }
+
+    /// Warn about text macros played on chips that sound octaves apart, or
+    /// line every chip up with standard pitch under `#NORMALIZE-OCTAVE`
+    fn check_octaves(&mut self) {
+        let chip_names: Vec<String> = self.chips.keys().cloned().collect();
+        let offsets: HashMap<String, i32> = chip_names
+            .into_iter()
+            .filter_map(|name| Some((name.clone(), self.chip_octave_offset(&name)?)))
+            .collect();
+
+        if self.normalize_octave {
+            self.octave_shifts = offsets.into_iter().map(|(name, offset)| (name, -offset)).collect();
+            return;
+        }
+
+        // Only macros with notes written by letter depend on the octave
+        let mut names: Vec<String> = self
+            .text_macro_channels
+            .keys()
+            .filter(|&name| {
+                token::tokenize(self.text_macros.get(name).map_or("", String::as_str))
+                    .iter()
+                    .any(|t| matches!(t.kind, TokenKind::Note { .. }))
+            })
+            .cloned()
+            .collect();
+        names.sort();
+        for name in names {
+            let mut chips: Vec<(&str, i32)> = self.text_macro_channels[&name]
+                .iter()
+                .filter_map(|&idx| self.channels[idx].as_ref())
+                .filter_map(|channel| Some((channel.chip_name.as_str(), *offsets.get(&channel.chip_name)?)))
+                .collect();
+            chips.sort();
+            chips.dedup();
+            let lowest = chips.iter().map(|&(_, offset)| offset).min();
+            if lowest == chips.iter().map(|&(_, offset)| offset).max() {
+                continue;
+            }
+            let list: Vec<String> = chips.iter().map(|(name, offset)| format!("{} {:+}", name, offset)).collect();
+            self.warn(format!(
+                "text macro *{} sounds in different octaves on its chips (octaves from standard pitch: {}); \
+                 use #NORMALIZE-OCTAVE or adjust `o` per chip",
+                name,
+                list.join(", ")
+            ));
+        }
+    }
+
+    /// Intermediate chip note values (one per frame, at most `count`) of a
+    /// portamento from `old_note` to `new_note`, following the `@/` settings.
+    /// Neither end point is included.
+    fn calc_portamento(
+        &self,
+        clock_div: i32,
+        note_bits: i32,
+        basic_octave: i32,
+        old_note: i32,
+        new_note: i32,
+        count: usize,
+    ) -> Vec<i64> {
+        let value = |note: i32| self.note_chip_value(note, clock_div, note_bits, basic_octave);
+        let step = self.portamento[2];
+        let mut out = Vec::new();
+
+        if self.portamento[0] == 1 {
+            // Glissando: move through the notes of the scale
+            let dir = (new_note - old_note).signum();
+            if step != 0 {
+                let mut x = old_note + dir * step as i32;
+                while out.len() < count && (new_note - x) * dir > 0 {
+                    out.push(value(x));
+                    x += dir * step as i32;
+                }
+            } else {
+                let c = count as i32 + 1;
+                for i in 1..c {
+                    out.push(value(((new_note - old_note) * i + c / 2) / c + old_note));
+                }
+            }
+        } else {
+            // Amiga: move the chip note value linearly
+            let (old_v, new_v) = (value(old_note), value(new_note));
+            let dir = (new_v - old_v).signum();
+            if step != 0 {
+                let mut x = old_v + dir * step;
+                while out.len() < count && (new_v - x) * dir > 0 {
+                    out.push(x);
+                    x += dir * step;
+                }
+            } else {
+                let c = count as i64 + 1;
+                for i in 1..c {
+                    out.push(((new_v - old_v) * i + c / 2) / c + old_v);
+                }
+            }
+        }
+        out
+    }
+
     /// Write output to VGM file
     fn write_output(&mut self, writer: &mut VgmWriter) -> Result<()> {
-        // Write header placeholder
-        writer.write_header()?;
+        let document = self.finish_output(writer)?;
+        writer.write_document(&document)
+    }
 
-        // Begin file for all chips
-        for (_, instance) in &mut self.chips {
-            instance.chip.file_begin(writer);
+    /// Total and loop lengths for the VGM header, which holds them in 32 bits
+    ///
+    /// A song too long for the header is an error rather than a wrapped
+    /// length; a loop that `@!` fast-forwards past has no length to play.
+    fn header_samples(&mut self) -> Result<(u32, u32)> {
+        let total = self.total_samples - self.fast_forward;
+        let total = match u32::try_from(total) {
+            Ok(total) => total,
+            Err(_) if total > 0 => return Err(Error::SongTooLong(total)),
+            Err(_) => {
+                self.warn(format!("song length after fast-forward (`@!`) is {} samples; writing 0", total));
+                0
+            }
+        };
+        let looped = total as i64 - self.loop_point;
+        if self.loop_on && looped < 0 {
+            self.warn(format!(
+                "loop length is {} samples, as fast-forward (`@!`) skips past the loop point at {}; writing 0",
+                looped, self.loop_point
+            ));
         }
+        Ok((total, looped.clamp(0, u32::MAX as i64) as u32))
+    }
 
-        // Output events
-        let mut current_time = 0i64;
-        let events: Vec<Event> = self.events.iter().cloned().collect();
+    /// Chip instances in the order their `#EX-` lines enabled them
+    fn chips_in_order(&mut self) -> Vec<&mut ChipInstance> {
+        let mut chips: Vec<_> = self.chips.iter_mut().collect();
+        chips.sort_by_key(|(name, _)| self.chip_order.iter().position(|n| n == *name));
+        chips.into_iter().map(|(_, instance)| instance).collect()
+    }
 
-        for event in &events {
+    /// Write the events in time order, with the waits between them and the
+    /// loop point, returning the time of the last one
+    fn write_events(
+        &mut self,
+        events: &EventQueue,
+        streams: &HashMap<String, DacStream>,
+        writer: &mut VgmWriter,
+    ) -> Result<i64> {
+        let mut current_time = 0i64;
+        // The values last written by chip-global events, by chip and event type
+        let mut globals: HashMap<(String, u16), (i32, i32)> = HashMap::new();
+        for event in events.iter() {
             // Handle loop point
             if self.loop_on && self.loop_point >= current_time && self.loop_point <= event.time {
                 let delay = (self.loop_point - current_time) as u64;
@@ -1493,9 +3999,11 @@ impl Compiler {
                 current_time = self.loop_point;
 
                 // Notify chips of loop start
-                for (_, instance) in &mut self.chips {
+                for instance in self.chips_in_order() {
                     instance.chip.loop_start(writer);
                 }
+                // The loop sets them again, for the state at its end may differ
+                globals.clear();
                 self.loop_on = false;
             }
 
@@ -1506,15 +4014,35 @@ impl Compiler {
             }
             current_time = event.time;
 
-            // Write event
+            // Write event; chip-global events are the whole chip's, so they
+            // are kept for muted channels and written once per value
+            if event.channel >= 0 && self.is_muted(event.channel as usize) && !event.is_global() {
+                continue;
+            }
             match &event.data {
                 EventData::Raw(byte) => {
                     writer.write_byte(*byte)?;
                 }
+                EventData::Dac(sample) => {
+                    let chip_name = self.channels[event.channel as usize].as_ref().map(|c| &c.chip_name);
+                    if let Some(stream) = chip_name.and_then(|name| streams.get(name)) {
+                        let block = sample.and_then(|n| self.wav_samples.keys().position(|&k| k == n));
+                        match (block, sample.and_then(|n| self.wav_samples.get(&n))) {
+                            (Some(block), Some(wav)) => stream.play(block as u16, wav.rate, writer)?,
+                            _ => stream.stop(writer)?,
+                        }
+                    }
+                }
                 EventData::Chip(chip_event) => {
                     let chan_idx = event.channel as usize;
                     if let Some(channel) = &self.channels[chan_idx] {
                         let chip_name = &channel.chip_name;
+                        if chip_event.global {
+                            let value = (chip_event.value1, chip_event.value2);
+                            if globals.insert((chip_name.clone(), chip_event.event_type), value) == Some(value) {
+                                continue;
+                            }
+                        }
                         if let Some(instance) = self.chips.get_mut(chip_name) {
                             instance.chip.send_with_macro_env(
                                 chip_event,
@@ -1529,25 +4057,89 @@ impl Compiler {
                 }
             }
         }
+        Ok(current_time)
+    }
+
+    /// Build the VGM file, returning it in parts
+    fn finish_output(&mut self, writer: &mut VgmWriter) -> Result<VgmDocument> {
+        let (total_samples, loop_samples) = self.header_samples()?;
+        let looped = self.loop_on;
+
+        // Write header placeholder
+        writer.write_header()?;
+
+        // Hand the samples to chips that store them in their own memory
+        if self.chips.values().any(|instance| instance.chip.uses_samples()) {
+            let samples = self.load_samples();
+            for instance in self.chips.values_mut() {
+                instance.chip.set_samples(&samples);
+            }
+        }
+        self.sample_memory = self
+            .chips_in_order()
+            .into_iter()
+            .filter_map(|instance| Some((instance.chip.name(), instance.chip.sample_memory()?)))
+            .collect();
+        if let Some((name, memory)) = self.sample_memory.iter().find(|(_, memory)| !memory.fits()) {
+            let mut breakdown: Vec<String> = memory.samples.iter().map(|(number, bytes)| format!("@S{} {}", number, bytes)).collect();
+            let padding = memory.used - memory.samples.iter().map(|(_, bytes)| bytes).sum::<usize>();
+            if padding > 0 {
+                breakdown.push(format!("bank padding {}", padding));
+            }
+            return Err(Error::Sample(format!(
+                "{} sample memory holds {} bytes but the samples take {} ({})",
+                name,
+                memory.capacity,
+                memory.used,
+                breakdown.join(", ")
+            )));
+        }
+
+        // Begin file for all chips
+        for instance in self.chips_in_order() {
+            instance.chip.file_begin(writer);
+        }
+        let streams = self.begin_dac_streams(writer)?;
+
+        // Output events, with the queue set aside so the chips can be
+        // borrowed while it is read
+        let events = std::mem::take(&mut self.events);
+        let written = self.write_events(&events, &streams, writer);
+        self.events = events;
+        let current_time = written?;
 
         // Write final delay
-        let final_delay = (self.total_samples - current_time) as u64;
+        let final_delay = (self.total_samples - current_time).max(0) as u64;
         if final_delay > 0 {
             writer.write_delay(final_delay)?;
         }
 
         // End file for all chips
-        for (_, instance) in &mut self.chips {
+        for instance in self.chips_in_order() {
             instance.chip.file_end(writer);
         }
 
         // Set header values
-        writer.set_total_samples((self.total_samples - self.fast_forward) as u32);
-        writer.set_loop_samples((self.total_samples - self.fast_forward - self.loop_point) as u32);
+        writer.set_total_samples(total_samples);
+        writer.set_loop_samples(loop_samples);
         writer.set_rate(self.recording_rate as u32);
         writer.set_volume_modifier(if self.volume_mod == -64 { -63 } else { self.volume_mod as i8 });
-        writer.set_loop_base(self.loop_base);
-        writer.set_loop_modifier(self.loop_mod);
+        match self.playback.loop_count {
+            Some(count) => {
+                if self.loop_base != 0 || self.loop_mod != 0 {
+                    self.warn("#LOOP-COUNT replaces #LOOP-BASE and #LOOP-MODIFIER".to_string());
+                }
+                if !looped {
+                    self.warn("#LOOP-COUNT: the song has no loop (`L`)".to_string());
+                }
+                writer.set_loop_base(0);
+                writer.set_loop_modifier(count.loop_modifier());
+            }
+            None => {
+                writer.set_loop_base(self.loop_base);
+                writer.set_loop_modifier(self.loop_mod);
+            }
+        }
 
         // Generate GD3 metadata
         let metadata = crate::compiler::Gd3Metadata {
@@ -1560,13 +4152,51 @@ impl Compiler {
             composer_en: self.gd3_text[gd3::COMPOSER_EN].clone(),
             composer_jp: self.gd3_text[gd3::COMPOSER_JP].clone(),
             date: self.gd3_text[gd3::DATE].clone(),
-            converter: self.gd3_text[gd3::CONVERTER].clone(),
+            converter: self.converter_signature.apply(&self.gd3_text[gd3::CONVERTER]),
             notes: self.gd3_text[gd3::NOTES].clone(),
+            lyrics: if self.lyrics_file { Vec::new() } else { self.output_lyrics() },
         };
 
-        writer.finalize(&metadata)?;
+        let mut document = writer.finish(&metadata)?;
+        if self.loop_mode == LoopMode::Compact {
+            match crate::vgm::compact::compact_document(&document)? {
+                Some((compacted, _)) => document = compacted,
+                None if looped => self.warn("#LOOP-MODE compact: the loop section is not made of repeats, written as is".to_string()),
+                None => self.warn("#LOOP-MODE compact: the song has no loop (`L`), written as is".to_string()),
+            }
+        }
+        if self.optimize {
+            document = crate::vgm::optimizer::optimize_document(&document)?;
+        }
+        self.compile_report = CompileReport::new(self.channel_reports(), &document.to_bytes())?;
+        self.compile_report.playback = self.playback;
+        if self.report {
+            print!("{}", self.compile_report);
+        }
+        Ok(document)
+    }
 
-        Ok(())
+    /// Notes, length and loop point of each compiled channel
+    fn channel_reports(&self) -> Vec<ChannelReport> {
+        let mut notes = [0u64; MAX_CHANNELS];
+        for event in self.events.iter() {
+            if let (Ok(idx), EventSource::NoteOn { .. }) = (usize::try_from(event.channel), event.source) {
+                notes[idx] += 1;
+            }
+        }
+        (0..MAX_CHANNELS)
+            .filter(|&idx| self.is_output_channel(idx))
+            .filter_map(|idx| {
+                let channel = self.channels[idx].as_ref()?;
+                Some(ChannelReport {
+                    channel: index_to_channel(idx)?,
+                    chip: channel.chip_name.clone(),
+                    notes: notes[idx],
+                    samples: channel.duration.max(0) as u64,
+                    loop_point: u64::try_from(channel.loop_point).ok(),
+                })
+            })
+            .collect()
     }
 }
 
@@ -1578,52 +4208,95 @@ impl Default for Compiler {
 
 /// Channel compile state (local to parse_music)
 struct ChannelCompileState {
+    /// Samples per frame, for macros and frame counts (`R` changes it)
+    framerate: i32,
     octave: i32,
     tempo: i32,
     default_len: i64,
     time: i64,
+    /// Semitones from `#TRANSPOSE`, which `K` transposes on top of
+    key: i32,
     transpose: i32,
     detune: i64,
+    /// Largest random detune of each note, from `@jitter`
+    jitter: i64,
     quantize: i64,
     current_note: i32,
+    /// Exact frequency of the pending note, from `f=`
+    current_freq: Option<f64>,
+    /// Cents the pending note is bent by, from `~`
+    current_bend: i64,
     current_len: i64,
     kind: u8,
     old_note: i32,
-    loop_depth: i32,
-    loop_start: [usize; 128],
-    loop_end: [usize; 128],
-    loop_count: [i32; 128],
     phase: i32,
     phase_count: i32,
     phase_counter: i32,
+    /// Restart dynamic macros on every note (false continues across notes)
+    macro_retrigger: bool,
+    /// Current position in each dynamic macro envelope
+    macro_indices: [i32; MAX_MACRO_TYPES],
+    /// Chip volume last set by `v` or `@vs`, where `@vs` slides from
+    volume: Option<i16>,
+    /// Time of the last step of the `@vs` slide that may still be running
+    volume_slide: Option<i64>,
+    /// Groove applied with `@g`
+    groove: Option<Groove>,
+    /// Where the pending note is written, for warnings about it
+    note_span: Span,
 }
 
 impl ChannelCompileState {
-    fn new(framerate: i32) -> Self {
-        let _ = framerate;
+    fn new(defaults: &ChannelDefaults, framerate: i32) -> Self {
+        let tempo = defaults.tempo.unwrap_or(120);
+        let (len, dots) = defaults.length.unwrap_or((4, 0));
         Self {
-            octave: 0,
-            tempo: 120,
-            default_len: Compiler::calc_note_len(120, 4, 0),
+            framerate,
+            octave: defaults.octave.unwrap_or(0),
+            tempo,
+            default_len: Compiler::calc_note_len(tempo, len, dots),
             time: 0,
+            key: 0,
             transpose: 0,
             detune: 0,
-            quantize: 0,
+            jitter: 0,
+            quantize: defaults.quantize.unwrap_or(0) * framerate as i64,
             current_note: -1,
+            current_freq: None,
+            current_bend: 0,
             current_len: 0,
             kind: 0,
             old_note: 0,
-            loop_depth: -1,
-            loop_start: [0; 128],
-            loop_end: [0; 128],
-            loop_count: [0; 128],
             phase: 0,
             phase_count: 1,
             phase_counter: 0,
+            macro_retrigger: true,
+            macro_indices: [0; MAX_MACRO_TYPES],
+            volume: None,
+            volume_slide: None,
+            groove: None,
+            note_span: Span { start: 0, end: 0 },
         }
     }
 }
 
+/// An open `[ ]` loop while compiling a channel
+struct LoopFrame {
+    /// Token index just after `[`
+    start: usize,
+    /// Token index of the `]`, once reached
+    end: Option<usize>,
+    /// Passes completed so far
+    count: i32,
+}
+
+/// A malformed number: what is wrong with it and the value to use anyway
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NumberError {
+    pub value: i64,
+    pub message: String,
+}
+
 /// GD3 metadata
 #[derive(Debug, Default)]
 pub struct Gd3Metadata {
@@ -1638,6 +4311,85 @@ pub struct Gd3Metadata {
     pub date: String,
     pub converter: String,
     pub notes: String,
+    /// Timed lyrics (time in samples, text), appended to the notes as LRC lines
+    pub lyrics: Vec<(u64, String)>,
+}
+
+/// What to do with vgmck's signature (`vgmck <version>`) in the GD3
+/// converter field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConverterSignature {
+    /// Add it as a line after the `#PROGRAMER` credits
+    Append,
+    /// Write only the signature, dropping the credits
+    Replace,
+    /// Leave the field as the MML wrote it
+    #[default]
+    Off,
+}
+
+impl ConverterSignature {
+    /// The signature of this version
+    pub fn signature() -> String {
+        format!("vgmck {}", env!("CARGO_PKG_VERSION"))
+    }
+
+    /// The converter field for the credits given in the MML
+    ///
+    /// A signature already in the credits (of any version, as in MML made
+    /// from a vgmck-built VGM) is dropped first, so it is never doubled.
+    pub fn apply(self, credits: &str) -> String {
+        let lines = || credits.lines().filter(|line| !line.starts_with("vgmck "));
+        match self {
+            Self::Off => credits.to_string(),
+            Self::Replace => Self::signature(),
+            Self::Append => lines().chain([Self::signature().as_str()]).collect::<Vec<_>>().join("\n"),
+        }
+    }
+}
+
+impl FromStr for ConverterSignature {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "append" => Ok(Self::Append),
+            "replace" => Ok(Self::Replace),
+            "off" => Ok(Self::Off),
+            _ => Err(format!("unknown converter signature policy '{}' (expected append, replace or off)", s)),
+        }
+    }
+}
+
+/// How a loop section that repeats itself is written (`#LOOP-MODE`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoopMode {
+    /// Every repeat, as compiled
+    #[default]
+    Unroll,
+    /// One copy, with the loop modifier multiplied by the number of copies
+    Compact,
+}
+
+impl FromStr for LoopMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "unroll" => Ok(Self::Unroll),
+            "compact" => Ok(Self::Compact),
+            _ => Err(format!("unknown loop mode '{}' (expected unroll or compact)", s)),
+        }
+    }
+}
+
+/// Greatest common divisor
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
 }
 
 /// Convert channel character to index
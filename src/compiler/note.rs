@@ -1,15 +1,20 @@
 //! Note and frequency calculations
 
+/// Pitch of the steps of a scale left without one, as a ratio to its first
+/// step: just below the next octave, which the note values have to fit too
+pub const OCTAVE_TOP: f64 = 1.99999;
+
 /// Calculated note values for a chip
 #[derive(Debug, Clone)]
 pub struct NoteTable {
-    /// Note values (frequency or period depending on chip)
-    pub values: [i64; 32],
+    /// Note values (frequency or period depending on chip), one per step
+    /// of the scale
+    pub values: Vec<i64>,
 }
 
 impl NoteTable {
     pub fn new() -> Self {
-        Self { values: [0; 32] }
+        Self { values: Vec::new() }
     }
 
     /// Calculate note values for a chip
@@ -22,7 +27,7 @@ impl NoteTable {
     pub fn calculate(
         clock_div: i32,
         note_bits: i32,
-        note_freq: &[f64; 32],
+        note_freq: &[f64],
         base_freq: f64,
     ) -> Self {
         let mut table = Self::new();
@@ -36,19 +41,16 @@ impl NoteTable {
         let q = clock_div.abs() as u64;
         let mask = (!0u64) << bits;
 
-        let mut u = [0u64; 32];
-        let mut w = 0u64;
-
-        for i in 0..32 {
-            let freq = note_freq[i] * base_freq + 0.000001;
-            let v = if is_period {
+        let value = |ratio: f64| {
+            let freq = ratio * base_freq + 0.000001;
+            if is_period {
                 ((q as u64) << 24) / (freq as u64)
             } else {
                 (freq as u64) * ((q as u64) << 22)
-            };
-            u[i] = v;
-            w |= v;
-        }
+            }
+        };
+        let mut u: Vec<u64> = note_freq.iter().map(|&ratio| value(ratio)).collect();
+        let mut w = u.iter().fold(value(OCTAVE_TOP), |w, &v| w | v);
 
         // Normalize to fit in note_bits
         while (w & mask) != 0 {
@@ -58,16 +60,14 @@ impl NoteTable {
             }
         }
 
-        for i in 0..32 {
-            table.values[i] = u[i] as i64;
-        }
+        table.values = u.into_iter().map(|v| v as i64).collect();
 
         table
     }
 
     /// Get note value for a given note and octave
     pub fn get(&self, note: i32, octave: i32, basic_octave: i32, clock_div: i32, note_bits: i32) -> i64 {
-        if note < 0 || note >= 32 {
+        if note < 0 || note as usize >= self.values.len() {
             return 0;
         }
 
@@ -0,0 +1,25 @@
+//! Per-phase timing of a compilation
+
+use std::time::Duration;
+
+/// Time spent in each compiler phase, filled in by `Compiler::compile`
+#[derive(Debug, Clone, Default)]
+pub struct PerfCounters {
+    /// Reading and parsing the MML input (including #INCLUDE files)
+    pub parse: Duration,
+    /// Compiling channel text into events
+    pub compile: Duration,
+    /// Writing the VGM file
+    pub write: Duration,
+    /// Number of channels compiled
+    pub channels: usize,
+    /// Number of events in the queue after compiling
+    pub events: usize,
+}
+
+impl PerfCounters {
+    /// Time spent in all phases
+    pub fn total(&self) -> Duration {
+        self.parse + self.compile + self.write
+    }
+}
@@ -0,0 +1,94 @@
+//! Channel letter remapping (`#REMAP`)
+//!
+//! `#REMAP A->Q B->R` reads the channel letters A and B as Q and R in the
+//! lines that follow: in channel lines, in text macro definitions and in
+//! the directives that name channels. Files written for different channel
+//! layouts can then be included side by side without editing them.
+
+use super::Compiler;
+use std::collections::HashMap;
+
+/// Parse the `X->Y` pairs of a `#REMAP` line
+pub fn parse(params: &str) -> Result<HashMap<char, char>, String> {
+    let mut map = HashMap::new();
+    for pair in params.split_whitespace() {
+        let letters = pair.split_once("->").and_then(|(from, to)| {
+            let mut from = from.chars();
+            let mut to = to.chars();
+            match (from.next(), from.next(), to.next(), to.next()) {
+                (Some(from), None, Some(to), None) => Some((from, to)),
+                _ => None,
+            }
+        });
+        match letters {
+            Some((from, to)) if Compiler::channel_index(from).is_some() && Compiler::channel_index(to).is_some() => {
+                map.insert(from, to);
+            }
+            _ => return Err(format!("expected channel pairs such as A->Q, got '{}'", pair)),
+        }
+    }
+    Ok(map)
+}
+
+/// Rename the channels channel text refers to: `?X` and `?(..)`
+/// conditions and `@[..]` phase groups
+pub fn remap_text(text: &str, map: &HashMap<char, char>) -> String {
+    let remap = |c: char| map.get(&c).copied().unwrap_or(c);
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        match c {
+            // Comments and lyrics are kept as written
+            ';' => {
+                out.extend(chars.by_ref());
+            }
+            '"' => {
+                for c in chars.by_ref() {
+                    out.push(c);
+                    if c == '"' {
+                        break;
+                    }
+                }
+            }
+            // The character after `*` names a text macro
+            '*' => out.extend(chars.next()),
+            '?' | '@' => {
+                let close = match chars.clone().next() {
+                    Some('(') if c == '?' => ')',
+                    Some('[') if c == '@' => ']',
+                    Some(next) if c == '?' => {
+                        chars.next();
+                        out.push(remap(next));
+                        continue;
+                    }
+                    _ => continue,
+                };
+                for c in chars.by_ref() {
+                    out.push(remap(c));
+                    if c == close {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remap() {
+        let map = parse("A->Q B->A").unwrap();
+        assert_eq!(map, HashMap::from([('A', 'Q'), ('B', 'A')]));
+        assert!(parse("A->").is_err());
+        assert!(parse("A=Q").is_err());
+
+        let text = remap_text("?A c ?. *A ?(AB) @[BA] \"?A\" e ; ?B", &map);
+        assert_eq!(text, "?Q c ?. *A ?(QA) @[AQ] \"?A\" e ; ?B");
+    }
+}
@@ -0,0 +1,151 @@
+//! Statistics printed after compiling
+//!
+//! For each channel, the notes it plays and how long it runs; for the
+//! song, its length and loop, the bytes each chip's commands take, the
+//! number of register writes and the longest wait between two commands.
+//! The file figures are read back from the VGM data as written, so they
+//! count what `--optimize` left.
+
+use crate::error::Result;
+use crate::vgm::analyze::format_time;
+use crate::vgm::playback::{self, PlaybackHints};
+use crate::vgm::reglog::opcode_chip_name;
+use crate::vgm::{VgmCommand, VgmReader};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Statistics of one channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelReport {
+    /// Channel letter
+    pub channel: char,
+    /// Name of the chip the channel plays on
+    pub chip: String,
+    /// Number of notes played
+    pub notes: u64,
+    /// Length of the channel in samples
+    pub samples: u64,
+    /// Loop point in samples, if the channel has one
+    pub loop_point: Option<u64>,
+}
+
+/// Statistics of a compiled song (`Compiler::compile_report`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileReport {
+    /// The channels in letter order
+    pub channels: Vec<ChannelReport>,
+    /// Length of the song in samples
+    pub total_samples: u64,
+    /// Length of the loop in samples, if the song loops
+    pub loop_samples: Option<u64>,
+    /// Times players play the loop, by the header's loop base and modifier
+    pub loops_played: u32,
+    /// Loop count and fade the song asks for
+    pub playback: PlaybackHints,
+    /// Bytes of command data by the chip written to, with data blocks and
+    /// DAC stream commands counted apart
+    pub chip_bytes: BTreeMap<&'static str, u64>,
+    /// Number of register writes
+    pub register_writes: u64,
+    /// Longest time between two commands in samples
+    pub longest_wait: u64,
+}
+
+impl CompileReport {
+    /// Report on the channels and on an uncompressed VGM file
+    pub fn new(channels: Vec<ChannelReport>, file: &[u8]) -> Result<Self> {
+        let mut reader = VgmReader::new(file);
+        let header = reader.parse_header()?;
+        let positions = reader.parse_positions(&header)?;
+        let mut report = Self {
+            channels,
+            total_samples: header.total_samples as u64,
+            loop_samples: (header.loop_offset != 0).then_some(header.loop_samples as u64),
+            loops_played: playback::loops_played(header.loop_base, header.loop_modifier),
+            ..Self::default()
+        };
+
+        let ends = positions.iter().skip(1).map(|&(pos, _, _)| pos);
+        let mut wait = 0u64;
+        for (&(pos, opcode, ref command), end) in positions.iter().zip(ends) {
+            let chip = match opcode {
+                0x67 => Some("data blocks"),
+                0x90..=0x95 => Some("DAC streams"),
+                _ => opcode_chip_name(opcode),
+            };
+            if let Some(chip) = chip {
+                *report.chip_bytes.entry(chip).or_default() += (end - pos) as u64;
+            }
+            if opcode_chip_name(opcode).is_some() {
+                report.register_writes += 1;
+            }
+            match *command {
+                VgmCommand::Wait { samples } => wait += samples as u64,
+                _ => {
+                    report.longest_wait = report.longest_wait.max(wait);
+                    wait = match *command {
+                        VgmCommand::Ym2612Dac { wait, .. } => wait as u64,
+                        _ => 0,
+                    };
+                }
+            }
+        }
+        // A wait before the end is a gap too
+        report.longest_wait = report.longest_wait.max(wait);
+        Ok(report)
+    }
+
+    /// How long players play the song with its loops and fade, in samples
+    /// (None if it loops forever)
+    pub fn play_samples(&self) -> Option<u64> {
+        self.playback.play_samples(self.total_samples, self.loop_samples, self.loops_played)
+    }
+
+    /// Bytes of command data, of all chips
+    pub fn total_bytes(&self) -> u64 {
+        self.chip_bytes.values().sum()
+    }
+}
+
+impl fmt::Display for CompileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Channel  Chip        Notes     Length       Loop")?;
+        for channel in &self.channels {
+            let loop_point = channel.loop_point.map_or("-".to_string(), format_time);
+            writeln!(
+                f,
+                "   {}     {:<10} {:>6} {:>10} {:>10}",
+                channel.channel,
+                channel.chip,
+                channel.notes,
+                format_time(channel.samples),
+                loop_point
+            )?;
+        }
+        match self.loop_samples {
+            Some(samples) => writeln!(f, "Length {}, loop {}", format_time(self.total_samples), format_time(samples))?,
+            None => writeln!(f, "Length {}, no loop", format_time(self.total_samples))?,
+        }
+        match (self.play_samples(), self.loop_samples) {
+            _ if self.playback.is_empty() => {}
+            (None, _) => writeln!(f, "Plays until stopped (loops forever)")?,
+            (Some(samples), Some(_)) => {
+                let fade = self.playback.fade.map_or(String::new(), |fade| format!(", fade {}", format_time(fade)));
+                writeln!(f, "Plays {} (loop {} times{})", format_time(samples), self.loops_played, fade)?
+            }
+            (Some(samples), None) => writeln!(f, "Plays {}", format_time(samples))?,
+        }
+        writeln!(
+            f,
+            "{} register writes, longest wait {} ({} samples)",
+            self.register_writes,
+            format_time(self.longest_wait),
+            self.longest_wait
+        )?;
+        writeln!(f, "Bytes by chip ({} in all):", self.total_bytes())?;
+        for (chip, bytes) in &self.chip_bytes {
+            writeln!(f, "  {:<12} {:>8}", chip, bytes)?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,15 @@
 //! Sample loading and processing
+//!
+//! Besides the raw sample loader, this holds the `#WAV` samples streamed to
+//! chip DACs: WAV files are read as unsigned 8-bit mono PCM, written to the
+//! VGM file as data blocks, and played with the DAC stream commands
+//! (0x90-0x95).
 
+use crate::chips::DacTarget;
 use crate::error::{Error, Result};
+use crate::vgm::commands::opcode;
+use crate::vgm::VgmWriter;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
@@ -148,3 +157,117 @@ pub fn generate_sine(length: usize, amplitudes: &[(f64, f64)], signed: bool) ->
 
     out
 }
+
+/// PCM sample of a `#WAV` file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PcmSample {
+    /// Playback rate in Hz
+    pub rate: u32,
+    /// Unsigned 8-bit mono samples
+    pub data: Vec<u8>,
+}
+
+/// Read a WAV file as unsigned 8-bit mono PCM
+pub fn load_wav(path: &Path) -> Result<PcmSample> {
+    parse_wav(&std::fs::read(path)?)
+}
+
+/// Decode an 8- or 16-bit PCM WAV file, mixing the channels down to mono
+pub fn parse_wav(bytes: &[u8]) -> Result<PcmSample> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(Error::Sample("not a RIFF WAVE file".to_string()));
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let size = u32::from_le_bytes([bytes[pos + 4], bytes[pos + 5], bytes[pos + 6], bytes[pos + 7]]) as usize;
+        let body = &bytes[pos + 8..(pos + 8).saturating_add(size).min(bytes.len())];
+        match &bytes[pos..pos + 4] {
+            b"fmt " if body.len() >= 16 => {
+                let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+                let rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                format = Some((u16_at(0), u16_at(2) as usize, rate, u16_at(14)));
+            }
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        pos = pos.saturating_add(8 + size + (size & 1));
+    }
+
+    let (tag, channels, rate, bits) = format.ok_or_else(|| Error::Sample("missing fmt chunk".to_string()))?;
+    let data = data.ok_or_else(|| Error::Sample("missing data chunk".to_string()))?;
+    // 1 = PCM, 0xFFFE = extensible (PCM in practice for 8 and 16 bits)
+    if !matches!(tag, 1 | 0xFFFE) || !matches!(bits, 8 | 16) || channels == 0 {
+        return Err(Error::Sample(format!(
+            "unsupported WAV format (type {}, {} bits, {} channels); use 8- or 16-bit PCM",
+            tag, bits, channels
+        )));
+    }
+
+    let width = bits as usize / 8;
+    let data = data
+        .chunks_exact(width * channels)
+        .map(|frame| {
+            let sum: i32 = frame
+                .chunks_exact(width)
+                .map(|s| match *s {
+                    [b] => b as i32 - 128,
+                    [lo, hi] => i16::from_le_bytes([lo, hi]) as i32 >> 8,
+                    _ => 0,
+                })
+                .sum();
+            (sum / channels as i32 + 128) as u8
+        })
+        .collect();
+    Ok(PcmSample { rate, data })
+}
+
+/// Write every `#WAV` sample, in number order, as a data block of a DAC's
+/// bank, reduced to the bits the DAC takes
+///
+/// The block index of a sample is its position in `samples`.
+pub fn write_blocks(samples: &BTreeMap<u8, PcmSample>, target: DacTarget, writer: &mut VgmWriter) -> Result<()> {
+    let shift = 8 - target.bits.min(8);
+    for sample in samples.values() {
+        writer.write_data(&[opcode::DATA_BLOCK, opcode::END, target.bank])?;
+        writer.write_data(&(sample.data.len() as u32).to_le_bytes())?;
+        let data: Vec<u8> = sample.data.iter().map(|&b| b >> shift).collect();
+        writer.write_data(&data)?;
+    }
+    Ok(())
+}
+
+/// A VGM DAC stream feeding a chip register from its data bank
+#[derive(Debug, Clone, Copy)]
+pub struct DacStream {
+    /// Stream number
+    pub id: u8,
+    /// Where the stream writes
+    pub target: DacTarget,
+}
+
+impl DacStream {
+    /// Point the stream at its chip register and data bank
+    pub fn setup(&self, writer: &mut VgmWriter) -> Result<()> {
+        let t = self.target;
+        writer.write_data(&[opcode::DAC_STREAM_SETUP, self.id, t.chip_type, t.port, t.register])?;
+        // Step size 1, step base 0: every byte of the block is played
+        writer.write_data(&[opcode::DAC_STREAM_DATA, self.id, t.bank, 1, 0])
+    }
+
+    /// Play a data block of the bank from its start at a rate in Hz
+    pub fn play(&self, block: u16, rate: u32, writer: &mut VgmWriter) -> Result<()> {
+        writer.write_data(&[opcode::DAC_STREAM_FREQ, self.id])?;
+        writer.write_data(&rate.to_le_bytes())?;
+        let [lo, hi] = block.to_le_bytes();
+        writer.write_data(&[opcode::DAC_STREAM_FAST, self.id, lo, hi, 0])
+    }
+
+    /// Stop the stream
+    pub fn stop(&self, writer: &mut VgmWriter) -> Result<()> {
+        writer.write_data(&[opcode::DAC_STREAM_STOP, self.id])
+    }
+}
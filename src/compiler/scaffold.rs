@@ -0,0 +1,130 @@
+//! Starter projects (`vgmck new`)
+//!
+//! `vgmck new mysong --chip OPN2` makes a directory `mysong` holding a
+//! song that compiles as it is: `mysong.mml` enables the chip, includes
+//! `instruments.mml` and plays three looping channels, and a `Makefile`
+//! builds `mysong.vgm` from both. The files are written from templates
+//! kept in the binary, so nothing else needs to be installed.
+
+use crate::chips::{self, ChipInfo};
+use crate::error::{Error, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Chips whose tone `@0` is the FM voice `@x0`
+const FM_CHIPS: &[&str] = &["OPN", "OPN2"];
+
+/// FM voice for `FM_CHIPS`: a soft bell, all operators carriers
+const FM_VOICE: &str = "\
+; FM voice: per operator DT/MUL, TL, RS/AR, AM/D1R, D2R, SL/RR, SSG-EG,
+; then the algorithm and the panning byte
+@x0 = 1 20 31 8 6 42 0
+      2 25 31 10 8 58 0
+      1 30 28 12 10 74 0
+      1 15 31 6 4 26 0
+      7 $C0
+";
+
+/// The song file
+fn song(name: &str, chip: &ChipInfo) -> String {
+    let tone = if FM_CHIPS.contains(&chip.name) { " @0" } else { "" };
+    format!(
+        "\
+; {name}
+;
+; Build with `make`, or `vgmck -i {name}.mml {name}.vgm`
+
+#TITLE {name}
+#EX-{chip} ABC
+#INCLUDE instruments.mml
+
+; Melody, harmony and bass; everything after L repeats
+A t120 o5 l4{tone} @v0 L
+A c e g e f a g2
+
+B t120 o4 l2{tone} @v0 L
+B e g f e
+
+C t120 o3 l2{tone} @v0 L
+C c e f c
+",
+        chip = chip.name,
+    )
+}
+
+/// The instruments file
+fn instruments(name: &str, chip: &ChipInfo) -> String {
+    let mut text = format!(
+        "\
+; Instruments for {name}.mml
+
+; Volume envelope in steps of 0-15, scaled to the chip: full at the
+; attack, settling to a lower level that holds
+@v0 = {{ N 15 14 13 12 | 11 }}
+"
+    );
+    if FM_CHIPS.contains(&chip.name) {
+        text.push('\n');
+        text.push_str(FM_VOICE);
+    }
+    text
+}
+
+/// The Makefile (`make VGMCK=path/to/vgmck` for a vgmck not on the path)
+fn makefile(name: &str) -> String {
+    format!("VGMCK ?= vgmck\n\n{name}.vgm: {name}.mml instruments.mml\n\t$(VGMCK) -i {name}.mml {name}.vgm\n")
+}
+
+/// Create a starter project in `dir` for a chip, returning the files
+/// written
+///
+/// The song is named after the directory. Files already there are left
+/// alone: if any of the project's files exists, nothing is written.
+pub fn create(dir: &Path, chip_name: &str) -> Result<Vec<PathBuf>> {
+    let chip = chips::find_chip(chip_name).ok_or_else(|| Error::UnknownChip(chip_name.to_string()))?;
+    let name = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no song name in '{}'", dir.display())))?;
+
+    let files = [
+        (dir.join(format!("{}.mml", name)), song(&name, chip)),
+        (dir.join("instruments.mml"), instruments(&name, chip)),
+        (dir.join("Makefile"), makefile(&name)),
+    ];
+    if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())).into());
+    }
+    fs::create_dir_all(dir)?;
+    for (path, text) in &files {
+        fs::write(path, text)?;
+    }
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compiler;
+
+    #[test]
+    fn test_create() {
+        let dir = tempfile::tempdir().unwrap();
+        for info in chips::list_chips() {
+            let project = dir.path().join(format!("song_{}", info.name));
+            let files = create(&project, info.name).unwrap();
+            assert_eq!(files.len(), 3);
+
+            let mut compiler = Compiler::new();
+            compiler.report = false;
+            compiler.compile_file(&files[0], &project.join("song.vgm")).unwrap();
+            assert!(compiler.warnings.is_empty(), "{}: {:?}", info.name, compiler.warnings);
+            assert!(compiler.total_samples > 0);
+        }
+
+        let project = dir.path().join("song_PSG");
+        assert!(create(&project, "PSG").is_err(), "existing files are not overwritten");
+        assert!(create(&dir.path().join("other"), "SID").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Interactive compile session
+//!
+//! Keeps the definitions entered so far (directives, envelopes and text
+//! macros) and compiles channel snippets against them. Every snippet is
+//! compiled on a fresh compiler, so it plays with the current instruments
+//! but without the snippets entered before it. Used by `vgmck repl`.
+
+use super::Compiler;
+use crate::error::Result;
+use std::path::{Path, PathBuf};
+
+/// What an entered line turned out to be
+pub enum Entry {
+    /// A definition, kept for later snippets, with the warnings it caused
+    Definition(Vec<String>),
+    /// A channel snippet, compiled
+    Snippet(Box<Compiler>),
+}
+
+/// Definitions shared by the snippets of an interactive session
+#[derive(Debug, Default)]
+pub struct Session {
+    /// Non-channel MML lines, in the order entered
+    definitions: Vec<String>,
+    /// Base path for resolving #INCLUDE paths (the directory of a loaded file)
+    base_path: Option<PathBuf>,
+    /// Seed for random features, so a snippet sounds the same every time
+    seed: Option<u64>,
+    /// Warnings the definitions cause, left out of the snippet warnings
+    known_warnings: Vec<String>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a line is a channel snippet rather than a definition
+    pub fn is_snippet(line: &str) -> bool {
+        line.trim_start().starts_with(|c: char| c.is_ascii_alphabetic())
+    }
+
+    /// Definitions entered so far
+    pub fn definitions(&self) -> &[String] {
+        &self.definitions
+    }
+
+    /// Forget all definitions
+    pub fn clear(&mut self) {
+        self.definitions.clear();
+        self.known_warnings.clear();
+    }
+
+    /// Seed random features of every snippet
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    /// Add the definitions of an MML file, skipping its channel lines
+    ///
+    /// Returns the number of lines added. Later `#INCLUDE` paths are resolved
+    /// from the file's directory.
+    pub fn load(&mut self, path: &Path) -> Result<usize> {
+        let text = std::fs::read_to_string(path)?;
+        let before = self.definitions.len();
+        self.definitions.extend(
+            text.lines()
+                .map(|line| line.trim_start_matches('\u{FEFF}').trim())
+                .take_while(|&line| line != "#EOF")
+                .filter(|line| !line.is_empty() && !Self::is_snippet(line))
+                .map(str::to_string),
+        );
+        self.base_path = path.parent().map(Path::to_path_buf);
+        self.known_warnings = self.prepare("")?.warnings;
+        Ok(self.definitions.len() - before)
+    }
+
+    /// Add a definition, returning the warnings it caused
+    pub fn define(&mut self, line: &str) -> Result<Vec<String>> {
+        self.definitions.push(line.trim().to_string());
+        let warnings = match self.prepare("") {
+            Ok(compiler) => compiler.warnings,
+            Err(e) => {
+                self.definitions.pop();
+                return Err(e);
+            }
+        };
+        let new = warnings.iter().filter(|w| !self.known_warnings.contains(w)).cloned().collect();
+        self.known_warnings = warnings;
+        Ok(new)
+    }
+
+    /// Compile a channel snippet into events
+    pub fn compile(&self, snippet: &str) -> Result<Box<Compiler>> {
+        let mut compiler = self.prepare(snippet)?;
+        compiler.compile_parsed()?;
+        compiler.warnings.retain(|w| !self.known_warnings.contains(w));
+        Ok(compiler)
+    }
+
+    /// Compile a channel snippet and write it as a VGM file
+    pub fn write(&self, snippet: &str, output: &Path) -> Result<Box<Compiler>> {
+        let mut compiler = self.prepare(snippet)?;
+        compiler.compile_and_write(output)?;
+        compiler.warnings.retain(|w| !self.known_warnings.contains(w));
+        Ok(compiler)
+    }
+
+    /// Enter a line: definitions are kept, snippets are compiled (and
+    /// written to `output` if given)
+    pub fn enter(&mut self, line: &str, output: Option<&Path>) -> Result<Entry> {
+        if !Self::is_snippet(line) {
+            return self.define(line).map(Entry::Definition);
+        }
+        let compiler = match output {
+            Some(output) => self.write(line, output)?,
+            None => self.compile(line)?,
+        };
+        Ok(Entry::Snippet(compiler))
+    }
+
+    /// A fresh compiler that has read the definitions followed by `extra`
+    ///
+    /// Boxed, as a compiler is too large to pass around on the stack freely.
+    fn prepare(&self, extra: &str) -> Result<Box<Compiler>> {
+        let mut compiler = Box::new(Compiler::new());
+        compiler.report = false;
+        compiler.silent = true;
+        compiler.base_path = self.base_path.clone();
+        if let Some(seed) = self.seed {
+            compiler.set_seed(seed);
+        }
+        let mut text = self.definitions.join("\n");
+        text.push('\n');
+        text.push_str(extra);
+        compiler.read_input(text.as_bytes())?;
+        Ok(compiler)
+    }
+}
@@ -0,0 +1,118 @@
+//! Text macro definitions and arguments
+//!
+//! `*A o4 l8` defines a macro named by a single character, written right
+//! after the `*`. `*kick = o2 c16` names one with a word. A body that uses
+//! `%1` to `%9` takes arguments: `*riff{o4,c}` puts `o4` for `%1` and `c`
+//! for `%2` before the body is expanded.
+
+/// Highest argument number (`%9`)
+const MAX_PARAMS: usize = 9;
+
+/// Whether a character can be part of a macro name given with `=`
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Parse the part of a `*` line after the `*` into a name and a body, or
+/// None if there is no name
+pub fn parse_definition(def: &str) -> Option<(String, String)> {
+    let name_len = def.find(|c: char| !is_name_char(c)).unwrap_or(def.len());
+    if name_len > 0 {
+        if let Some(body) = def[name_len..].trim_start().strip_prefix('=') {
+            return Some((def[..name_len].to_string(), body.trim_start().to_string()));
+        }
+    }
+    let first = def.chars().next().filter(char::is_ascii)?;
+    Some((first.to_string(), def[1..].to_string()))
+}
+
+/// Length of the name of a macro used at the start of `text`: the longest
+/// defined name of two or more characters, or else the first character
+pub fn name_len(text: &str, is_defined: impl Fn(&str) -> bool) -> usize {
+    let word = text.find(|c: char| !is_name_char(c)).unwrap_or(text.len());
+    (2..=word)
+        .rev()
+        .find(|&len| is_defined(&text[..len]))
+        .unwrap_or_else(|| text.chars().next().map_or(0, char::len_utf8))
+}
+
+/// Number of arguments a macro body takes: its highest `%n`
+pub fn param_count(body: &str) -> usize {
+    let bytes = body.as_bytes();
+    bytes
+        .windows(2)
+        .filter(|w| w[0] == b'%' && (b'1'..=b'0' + MAX_PARAMS as u8).contains(&w[1]))
+        .map(|w| (w[1] - b'0') as usize)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Read `{arg,arg,...}` at the start of `text`, returning the arguments and
+/// the length read, or None if `text` does not start with a closed list.
+/// Commas inside nested braces do not separate arguments.
+pub fn parse_args(text: &str) -> Option<(Vec<String>, usize)> {
+    if !text.starts_with('{') {
+        return None;
+    }
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 1;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            '}' => {
+                args.push(text[start..i].trim().to_string());
+                return Some((args, i + 1));
+            }
+            ',' if depth == 0 => {
+                args.push(text[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Put the arguments in place of `%1` to `%9` in a body (missing ones are empty)
+pub fn substitute(body: &str, args: &[String]) -> String {
+    let mut out = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        let param = chars.peek().and_then(|d| d.to_digit(10)).filter(|&n| (1..=MAX_PARAMS as u32).contains(&n));
+        match param {
+            Some(n) if c == '%' => {
+                chars.next();
+                out.push_str(args.get(n as usize - 1).map_or("", String::as_str));
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definitions_and_arguments() {
+        assert_eq!(parse_definition("A o4 l8"), Some(("A".to_string(), " o4 l8".to_string())));
+        assert_eq!(parse_definition("Ao4"), Some(("A".to_string(), "o4".to_string())));
+        assert_eq!(parse_definition("kick = o2 c16"), Some(("kick".to_string(), "o2 c16".to_string())));
+        assert_eq!(parse_definition(""), None);
+
+        let defined = |name: &str| name == "kick" || name == "ki";
+        assert_eq!(name_len("kick c", defined), 4);
+        assert_eq!(name_len("kic", defined), 2);
+        assert_eq!(name_len("Acde", defined), 1);
+
+        assert_eq!(param_count("%1 l8 %2 %2"), 2);
+        assert_eq!(param_count("v%0101"), 0);
+        let (args, len) = parse_args("{o4, {cde}, @q1,2} c").unwrap();
+        assert_eq!((args.as_slice(), len), (["o4", "{cde}", "@q1", "2"].map(String::from).as_slice(), 18));
+        assert_eq!(parse_args("{o4"), None);
+        assert_eq!(substitute("%1 l8 %2%3", &["o4".to_string(), "c".to_string()]), "o4 l8 c");
+    }
+}
@@ -0,0 +1,461 @@
+//! Channel MML tokenizer
+//!
+//! Splits the accumulated text of a channel into commands with their byte
+//! spans, so the compiler can walk (and jump around) a token stream instead
+//! of raw byte offsets.
+
+use super::diagnostic::code;
+use super::Compiler;
+use std::cell::RefCell;
+
+/// Channel text the tokenizer could not read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// Stable diagnostic code
+    pub code: &'static str,
+    pub span: Span,
+    pub message: String,
+}
+
+type SyntaxErrors = RefCell<Vec<SyntaxError>>;
+
+/// Byte range of a token in the channel text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Length written after a note, rest, tie or `l`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Length {
+    /// Note value (4 = quarter); 0 if omitted
+    pub value: i64,
+    /// Number of dots
+    pub dots: i32,
+}
+
+/// Pitch change written after a note with `+`, `-` and `'`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Shift {
+    /// Scale steps
+    pub steps: i32,
+    /// Octaves
+    pub octaves: i32,
+}
+
+/// A channel command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Note letter `a`-`j` (index into the scale letters)
+    Note { letter: usize, shift: Shift, length: Length },
+    /// `f=440.0`: exact frequency in millihertz (use comma before length)
+    Frequency { millihertz: i64, length: Length },
+    /// `n`: note by number
+    NoteNumber { number: i64, shift: Shift, length: Length },
+    /// `r`
+    Rest(Length),
+    /// `w`: wait without note off
+    Wait(Length),
+    /// `l`
+    DefaultLength(Length),
+    /// `^`
+    Tie(Length),
+    /// `&`
+    Slur,
+    /// `/`
+    Legato,
+    /// `o`
+    Octave(i64),
+    /// `>`
+    OctaveUp,
+    /// `<`
+    OctaveDown,
+    /// `t`
+    Tempo(i64),
+    /// `R`: frame rate in Hz (0 = song rate)
+    FrameRate(i64),
+    /// `D`
+    Detune(i64),
+    /// `K`
+    Transpose(i64),
+    /// `~+15` after a note: bend it by cents
+    Bend(i64),
+    /// `!`: stop parsing
+    Stop,
+    /// `L`
+    LoopPoint,
+    /// `@q frames,samples`
+    Quantize(i64, i64),
+    /// `@vs target,frames`: slide the volume to `target` over `frames`
+    VolumeSlide(i64, i64),
+    /// `@fade frames`: fade every channel out, ending the song
+    Fade(i64),
+    /// `@echo depth,delay`: replay the channel `delay` frames later, `depth` quieter
+    Echo(i64, i64),
+    /// `@jitter n`: detune each note by a random amount up to `n`
+    Jitter(i64),
+    /// `[`
+    LoopStart,
+    /// `]N`
+    LoopEnd(i64),
+    /// `\`
+    LoopBreak,
+    /// `?X` or `?.` (None for a bare `?` not followed by a channel letter)
+    Condition(Option<u8>),
+    /// `?(AB)`: named condition, channel indices of the group
+    ConditionGroup(Vec<usize>),
+    /// `ENOF`
+    ArpeggioOff,
+    /// `EN`
+    Arpeggio(i64),
+    /// `EPOF`
+    PitchEnvelopeOff,
+    /// `MPOF`
+    VibratoOff,
+    /// `@"name"`: the commands of a named instrument (`@INST`)
+    Instrument(String),
+    /// `x addr,value`
+    Direct(i64, i64),
+    /// `y`
+    RawByte(i64),
+    /// `{`
+    TupletStart,
+    /// `}`
+    TupletEnd,
+    /// `NOE`
+    NoteOffMode(i64),
+    /// `MRE`
+    MacroRetrigger(i64),
+    /// `@[ ]`: channel letters of the phase group, in order
+    PhaseSync(Vec<Option<usize>>),
+    /// `@!`
+    FastForward(i64),
+    /// `@w frames,shift`
+    WaitFrames(i64, i64),
+    /// `@/`
+    Portamento([i64; 8]),
+    /// `"text"`: lyric sung from the next note
+    Lyric(String),
+    /// Named macro command (`v`, `@v`, `@SL`, ...) with its value
+    Macro { name: String, value: i64 },
+}
+
+/// A command and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Drop the commands a channel does not play because of `?X` / `?(..)`
+/// conditions, along with the condition markers themselves
+///
+/// Runs before loops are interpreted, so a loop sees only the commands of
+/// its own channel. `chan` is the channel matched against the conditions.
+pub fn filter_conditions(tokens: Vec<Token>, chan: usize) -> Vec<Token> {
+    let mut active = true;
+    tokens
+        .into_iter()
+        .filter(|token| match token.kind {
+            TokenKind::Condition(Some(cond)) => {
+                active = cond == b'.' || Compiler::channel_index(cond as char) == Some(chan);
+                false
+            }
+            TokenKind::Condition(None) => {
+                active = true;
+                false
+            }
+            TokenKind::ConditionGroup(ref group) => {
+                active = group.contains(&chan);
+                false
+            }
+            _ => active,
+        })
+        .collect()
+}
+
+/// Split channel text into tokens; unknown characters are skipped
+pub fn tokenize(text: &str) -> Vec<Token> {
+    tokenize_checked(text).0
+}
+
+/// Split channel text into tokens, also returning what could not be read:
+/// malformed numbers, unknown characters and `x` without a value
+pub fn tokenize_checked(text: &str) -> (Vec<Token>, Vec<SyntaxError>) {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    let errors = SyntaxErrors::default();
+
+    while pos < bytes.len() {
+        let start = pos;
+        let b = bytes[pos];
+        let next = |n: usize| bytes.get(pos + n).copied();
+        let num = |pos: &mut usize| read_num(text, pos, &errors);
+
+        let kind = if b == b'f' && next(1) == Some(b'=') {
+            pos += 2;
+            let millihertz = read_millihertz(text, &mut pos, &errors);
+            let length = read_length(text, &mut pos, &errors);
+            TokenKind::Frequency { millihertz, length }
+        } else if (b'a'..=b'j').contains(&b) {
+            pos += 1;
+            let shift = read_shift(bytes, &mut pos);
+            let length = read_length(text, &mut pos, &errors);
+            TokenKind::Note { letter: (b - b'a') as usize, shift, length }
+        } else if b == b'r' || b == b'w' || b == b'^' {
+            pos += 1;
+            read_shift(bytes, &mut pos);
+            let length = read_length(text, &mut pos, &errors);
+            match b {
+                b'r' => TokenKind::Rest(length),
+                b'w' => TokenKind::Wait(length),
+                _ => TokenKind::Tie(length),
+            }
+        } else if b == b'n' {
+            pos += 1;
+            let number = num(&mut pos);
+            let shift = read_shift(bytes, &mut pos);
+            let length = read_length(text, &mut pos, &errors);
+            TokenKind::NoteNumber { number, shift, length }
+        } else if b == b'l' {
+            pos += 1;
+            TokenKind::DefaultLength(read_length(text, &mut pos, &errors))
+        } else if let Some(kind) = match b {
+            b'&' => Some(TokenKind::Slur),
+            b'/' => Some(TokenKind::Legato),
+            b'>' => Some(TokenKind::OctaveUp),
+            b'<' => Some(TokenKind::OctaveDown),
+            b'!' => Some(TokenKind::Stop),
+            b'L' => Some(TokenKind::LoopPoint),
+            b'[' => Some(TokenKind::LoopStart),
+            b'\\' => Some(TokenKind::LoopBreak),
+            b'{' => Some(TokenKind::TupletStart),
+            b'}' => Some(TokenKind::TupletEnd),
+            _ => None,
+        } {
+            pos += 1;
+            kind
+        } else if let Some(make) = match b {
+            b'o' => Some(TokenKind::Octave as fn(i64) -> TokenKind),
+            b't' => Some(TokenKind::Tempo as fn(i64) -> TokenKind),
+            b'R' => Some(TokenKind::FrameRate as fn(i64) -> TokenKind),
+            b'D' => Some(TokenKind::Detune as fn(i64) -> TokenKind),
+            b'K' => Some(TokenKind::Transpose as fn(i64) -> TokenKind),
+            b'~' => Some(TokenKind::Bend as fn(i64) -> TokenKind),
+            b']' => Some(TokenKind::LoopEnd as fn(i64) -> TokenKind),
+            b'y' => Some(TokenKind::RawByte as fn(i64) -> TokenKind),
+            _ => None,
+        } {
+            pos += 1;
+            make(num(&mut pos))
+        } else if b == b'"' {
+            pos += 1;
+            let end = text[pos..].find('"').map_or(text.len(), |n| pos + n);
+            let lyric = text[pos..end].to_string();
+            pos = (end + 1).min(text.len());
+            TokenKind::Lyric(lyric)
+        } else if b == b'?' && next(1) == Some(b'(') {
+            pos += 2;
+            let mut group = Vec::new();
+            while pos < bytes.len() && bytes[pos] != b')' {
+                group.extend(Compiler::channel_index(bytes[pos] as char));
+                pos += 1;
+            }
+            if pos < bytes.len() {
+                pos += 1;
+            }
+            TokenKind::ConditionGroup(group)
+        } else if b == b'?' {
+            pos += 1;
+            let cond = bytes.get(pos).copied().filter(|&c| c == b'.' || c.is_ascii_alphabetic());
+            if cond.is_some() {
+                pos += 1;
+            }
+            TokenKind::Condition(cond)
+        } else if b == b'x' {
+            pos += 1;
+            let addr = num(&mut pos);
+            if bytes.get(pos) != Some(&b',') {
+                errors.borrow_mut().push(SyntaxError {
+                    code: code::MISSING_ARGUMENT,
+                    span: Span { start, end: pos },
+                    message: format!("`{}` needs a value: write `x address,value`", &text[start..pos]),
+                });
+            }
+            TokenKind::Direct(addr, num(&mut pos))
+        } else if bytes[pos..].starts_with(b"ENOF") {
+            pos += 4;
+            TokenKind::ArpeggioOff
+        } else if bytes[pos..].starts_with(b"EPOF") {
+            pos += 4;
+            TokenKind::PitchEnvelopeOff
+        } else if bytes[pos..].starts_with(b"MPOF") {
+            pos += 4;
+            TokenKind::VibratoOff
+        } else if b == b'E' && next(1) == Some(b'N') {
+            pos += 2;
+            TokenKind::Arpeggio(num(&mut pos))
+        } else if bytes[pos..].starts_with(b"NOE") {
+            pos += 3;
+            TokenKind::NoteOffMode(num(&mut pos))
+        } else if bytes[pos..].starts_with(b"MRE") {
+            pos += 3;
+            TokenKind::MacroRetrigger(num(&mut pos))
+        } else if bytes[pos..].starts_with(b"@vs") {
+            pos += 3;
+            let target = num(&mut pos);
+            TokenKind::VolumeSlide(target, num(&mut pos))
+        } else if bytes[pos..].starts_with(b"@echo") {
+            pos += 5;
+            let depth = num(&mut pos);
+            TokenKind::Echo(depth, num(&mut pos))
+        } else if bytes[pos..].starts_with(b"@jitter") {
+            pos += 7;
+            TokenKind::Jitter(num(&mut pos))
+        } else if bytes[pos..].starts_with(b"@fade") {
+            pos += 5;
+            TokenKind::Fade(num(&mut pos))
+        } else if b == b'@' && next(1) == Some(b'q') {
+            pos += 2;
+            let frames = num(&mut pos);
+            TokenKind::Quantize(frames, num(&mut pos))
+        } else if b == b'@' && next(1) == Some(b'[') {
+            pos += 2;
+            let mut group = Vec::new();
+            while pos < bytes.len() && bytes[pos] != b']' {
+                group.push(Compiler::channel_index(bytes[pos] as char));
+                pos += 1;
+            }
+            if pos < bytes.len() {
+                pos += 1;
+            }
+            TokenKind::PhaseSync(group)
+        } else if b == b'@' && next(1) == Some(b'!') {
+            pos += 2;
+            TokenKind::FastForward(num(&mut pos))
+        } else if b == b'@' && next(1) == Some(b'w') {
+            pos += 2;
+            let frames = num(&mut pos);
+            TokenKind::WaitFrames(frames, num(&mut pos))
+        } else if b == b'@' && next(1) == Some(b'"') {
+            pos += 2;
+            let end = text[pos..].find('"').map_or(text.len(), |n| pos + n);
+            let name = text[pos..end].to_string();
+            pos = (end + 1).min(text.len());
+            TokenKind::Instrument(name)
+        } else if b == b'@' && next(1) == Some(b'/') {
+            pos += 2;
+            let mut params = [0; 8];
+            for p in params.iter_mut() {
+                *p = num(&mut pos);
+            }
+            TokenKind::Portamento(params)
+        } else if b >= b'@' {
+            // Command names are up to 7 characters from `@` upwards
+            let mut name = String::new();
+            while pos < bytes.len() && bytes[pos] >= b'@' && name.len() < 7 {
+                name.push(bytes[pos] as char);
+                pos += 1;
+            }
+            TokenKind::Macro { name, value: num(&mut pos) }
+        } else {
+            // Skip unknown characters, noting all but spacing
+            let len = text[pos..].chars().next().map_or(1, char::len_utf8);
+            pos += len;
+            if !b.is_ascii_whitespace() {
+                let mut errors = errors.borrow_mut();
+                // A run of unknown characters is one unknown command
+                let span = match errors.last() {
+                    Some(last) if last.code == code::UNKNOWN_COMMAND && last.span.end == start => {
+                        let span = last.span;
+                        errors.pop();
+                        Span { start: span.start, end: pos }
+                    }
+                    _ => Span { start, end: pos },
+                };
+                errors.push(SyntaxError {
+                    code: code::UNKNOWN_COMMAND,
+                    span,
+                    message: format!("unknown command `{}`", &text[span.start..span.end]),
+                });
+            }
+            continue;
+        };
+
+        tokens.push(Token { kind, span: Span { start, end: pos } });
+    }
+
+    (tokens, errors.into_inner())
+}
+
+/// Read a number, noting it in `errors` if malformed
+fn read_num(text: &str, pos: &mut usize, errors: &SyntaxErrors) -> i64 {
+    let start = *pos;
+    Compiler::parse_num(text, pos).unwrap_or_else(|e| {
+        errors.borrow_mut().push(SyntaxError {
+            code: code::MALFORMED_NUMBER,
+            span: Span { start, end: (*pos).max(start + 1) },
+            message: e.message,
+        });
+        e.value
+    })
+}
+
+/// Read `+`, `-` and `'` after a note
+fn read_shift(bytes: &[u8], pos: &mut usize) -> Shift {
+    let mut shift = Shift::default();
+    while let Some(&b) = bytes.get(*pos) {
+        match b {
+            b'+' => shift.steps += 1,
+            b'-' => shift.steps -= 1,
+            b'\'' => shift.octaves += 1,
+            _ => break,
+        }
+        *pos += 1;
+    }
+    shift
+}
+
+/// Read a decimal frequency such as `440` or `261.63` as millihertz
+fn read_millihertz(text: &str, pos: &mut usize, errors: &SyntaxErrors) -> i64 {
+    let bytes = text.as_bytes();
+    let start = *pos;
+    let mut hz = Some(0i64);
+    let mut fraction = 0i64;
+    let mut scale = None;
+    while let Some(&b) = bytes.get(*pos) {
+        match (b, scale) {
+            (b'0'..=b'9', None) => hz = hz.and_then(|hz| hz.checked_mul(10)?.checked_add((b - b'0') as i64)),
+            (b'0'..=b'9', Some(s)) => {
+                // Digits past millihertz precision are ignored
+                let s = s / 10;
+                fraction += (b - b'0') as i64 * s;
+                scale = Some(s);
+            }
+            (b'.', None) => scale = Some(1000),
+            _ => break,
+        }
+        *pos += 1;
+    }
+    hz.and_then(|hz| hz.checked_mul(1000)?.checked_add(fraction)).unwrap_or_else(|| {
+        errors.borrow_mut().push(SyntaxError {
+            code: code::MALFORMED_NUMBER,
+            span: Span { start, end: *pos },
+            message: format!("`{}` is too large", &text[start..*pos]),
+        });
+        i64::MAX
+    })
+}
+
+fn read_length(text: &str, pos: &mut usize, errors: &SyntaxErrors) -> Length {
+    let value = read_num(text, pos, errors);
+    let bytes = text.as_bytes();
+    let mut dots = 0;
+    while *pos < bytes.len() && bytes[*pos] == b'.' {
+        dots += 1;
+        *pos += 1;
+    }
+    Length { value, dots }
+}
@@ -0,0 +1,43 @@
+//! Quantization of pitch table values
+//!
+//! Chips take integer periods or frequency numbers, so the exact value of
+//! each note has to be rounded. The original vgmck rounds down, which
+//! leaves high notes on period chips noticeably flat; rounding to the
+//! nearest value or diffusing the error keeps them closer to pitch.
+
+/// How exact note values are rounded to the integers a chip takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quantization {
+    /// Round down, like the original vgmck (default)
+    #[default]
+    RoundDown,
+    /// Round to the nearest value
+    RoundNearest,
+    /// Round to the nearest value after adding the rounding error left
+    /// by the notes below, from the lowest note up across the octaves
+    ErrorDiffusion,
+}
+
+impl Quantization {
+    /// Parse a `#PITCH-QUANTIZE` mode name
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "DOWN" => Some(Quantization::RoundDown),
+            "NEAREST" => Some(Quantization::RoundNearest),
+            "DIFFUSE" => Some(Quantization::ErrorDiffusion),
+            _ => None,
+        }
+    }
+}
+
+/// The error-diffused value of the last of a run of exact values, given
+/// lowest note first
+pub fn diffuse(exact: impl IntoIterator<Item = f64>) -> i64 {
+    let mut carry = 0.0;
+    let mut value = 0.0;
+    for x in exact {
+        value = (x + carry).round();
+        carry += x - value;
+    }
+    value as i64
+}
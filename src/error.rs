@@ -1,9 +1,15 @@
 use std::io;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("Parse error at line {line}: {message}")]
-    Parse { line: usize, message: String },
+    #[error("{}: {message}", source_location(.file, *.line, *.column))]
+    MmlSyntax {
+        file: Option<PathBuf>,
+        line: usize,
+        column: Option<usize>,
+        message: String,
+    },
 
     #[error("VGM parse error: {0}")]
     VgmParse(String),
@@ -11,9 +17,6 @@ pub enum Error {
     #[error("Unknown chip: {0}")]
     UnknownChip(String),
 
-    #[error("Channel '{0}' not declared before use")]
-    UndeclaredChannel(char),
-
     #[error("Invalid channel: '{0}'")]
     InvalidChannel(char),
 
@@ -23,8 +26,42 @@ pub enum Error {
     #[error("Sample error: {0}")]
     Sample(String),
 
+    #[error("Instrument error: {0}")]
+    Instrument(String),
+
+    #[error("Channel conflict: {0}")]
+    ChannelConflict(String),
+
+    #[error("No #TRACK {0} in the input")]
+    UnknownTrack(u32),
+
+    #[error("Song is {0} samples long, more than a VGM header holds ({max}, about 27 hours)", max = u32::MAX)]
+    SongTooLong(i64),
+
+    #[error("NSF export error: {0}")]
+    Nsf(String),
+
+    #[error("Device error: {0}")]
+    Device(String),
+
+    #[error("Invalid song: {0}")]
+    InvalidSong(String),
+
+    #[error("Output is not reproducible: {0}")]
+    NotReproducible(String),
+
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// `file:line:column`, or `line 3, column 5` without a file
+fn source_location(file: &Option<PathBuf>, line: usize, column: Option<usize>) -> String {
+    match (file.as_deref().map(Path::display), column) {
+        (Some(file), Some(column)) => format!("{}:{}:{}", file, line, column),
+        (Some(file), None) => format!("{}:{}", file, line),
+        (None, Some(column)) => format!("line {}, column {}", line, column),
+        (None, None) => format!("line {}", line),
+    }
+}
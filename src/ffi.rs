@@ -0,0 +1,221 @@
+//! C interface (cargo feature `ffi`)
+//!
+//! Compiles MML to VGM for programs that link the library instead of
+//! running `vgmck`: C and C++ build tools, or Python through `ctypes`. The
+//! declarations are in `include/vgmck.h`. Output buffers and message
+//! strings are allocated here and must be given back to `vgmck_free_bytes`
+//! and `vgmck_free_string`, not to `free()`.
+//!
+//! Diagnostics are not printed and no report is written to stdout; they
+//! come back as text, one `Warning: ...` or `Error: ...` line each.
+
+use crate::compiler::diagnostic::Severity;
+use crate::Compiler;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+/// Return codes
+pub const VGMCK_OK: c_int = 0;
+pub const VGMCK_ERROR: c_int = 1;
+/// A required pointer was null or the input was not UTF-8
+pub const VGMCK_INVALID_ARGUMENT: c_int = 2;
+
+/// Version of the library, for `vgmck_version`
+const VERSION: &CStr = match CStr::from_bytes_with_nul(concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes()) {
+    Ok(version) => version,
+    Err(_) => panic!("the package version has a NUL byte"),
+};
+
+/// The diagnostics of a compilation as `Warning: ...`/`Error: ...` lines,
+/// with an error that was not recorded as a diagnostic (I/O, writer) last
+fn messages(compiler: &Compiler, error: Option<&crate::Error>) -> String {
+    let mut lines: Vec<String> = compiler
+        .diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let label = match diagnostic.severity {
+                Severity::Warning => "Warning",
+                Severity::Error => "Error",
+            };
+            format!("{}: {}", label, diagnostic.render())
+        })
+        .collect();
+    if let Some(error) = error.filter(|_| compiler.error_count() == 0) {
+        lines.push(format!("Error: {}", error));
+    }
+    lines.join("\n")
+}
+
+/// Hand a string to the caller, or null for an empty one
+fn into_c_string(text: String) -> *mut c_char {
+    if text.is_empty() {
+        return ptr::null_mut();
+    }
+    // Diagnostics quote the input, which cannot hold NULs once read as a C string
+    CString::new(text.replace('\0', " ")).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Compile with a fresh compiler and hand the file and messages to the caller
+///
+/// # Safety
+///
+/// `out` and `len` must be valid for writes; `errors` must be null or valid
+/// for writes.
+unsafe fn run(
+    compile: impl FnOnce(&mut Compiler, &mut Vec<u8>) -> crate::error::Result<()>,
+    out: *mut *mut u8,
+    len: *mut usize,
+    errors: *mut *mut c_char,
+) -> c_int {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut compiler = Compiler::new();
+        compiler.report = false;
+        compiler.silent = true;
+        let mut vgm = Vec::new();
+        let result = compile(&mut compiler, &mut vgm);
+        (result.is_ok().then_some(vgm), messages(&compiler, result.as_ref().err()))
+    }));
+    let (vgm, text) = result.unwrap_or_else(|_| (None, "Error: internal compiler error".to_string()));
+
+    *out = ptr::null_mut();
+    *len = 0;
+    if !errors.is_null() {
+        *errors = into_c_string(text);
+    }
+    match vgm {
+        Some(vgm) => {
+            *len = vgm.len();
+            *out = Box::into_raw(vgm.into_boxed_slice()) as *mut u8;
+            VGMCK_OK
+        }
+        None => VGMCK_ERROR,
+    }
+}
+
+/// Compile MML text to a VGM file in memory
+///
+/// On success returns `VGMCK_OK` and stores the file in `*out` and its
+/// size in `*len`. On failure returns `VGMCK_ERROR` with `*out` null. If
+/// `errors` is not null, `*errors` gets the warnings and errors, or null if
+/// there were none. `#INCLUDE` paths are relative to the working directory.
+///
+/// # Safety
+///
+/// `mml` must be a NUL-terminated string; `out` and `len` must be valid
+/// for writes; `errors` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn vgmck_compile(
+    mml: *const c_char,
+    out: *mut *mut u8,
+    len: *mut usize,
+    errors: *mut *mut c_char,
+) -> c_int {
+    if mml.is_null() || out.is_null() || len.is_null() {
+        return VGMCK_INVALID_ARGUMENT;
+    }
+    let Ok(mml) = CStr::from_ptr(mml).to_str() else {
+        return VGMCK_INVALID_ARGUMENT;
+    };
+    run(|compiler, vgm| compiler.compile_to_writer(Cursor::new(mml), vgm), out, len, errors)
+}
+
+/// Compile an MML file to a VGM file in memory, resolving `#INCLUDE` paths
+/// relative to it
+///
+/// Returns and stores the same as `vgmck_compile`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string; `out` and `len` must be valid
+/// for writes; `errors` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn vgmck_compile_file(
+    path: *const c_char,
+    out: *mut *mut u8,
+    len: *mut usize,
+    errors: *mut *mut c_char,
+) -> c_int {
+    if path.is_null() || out.is_null() || len.is_null() {
+        return VGMCK_INVALID_ARGUMENT;
+    }
+    let Ok(path) = CStr::from_ptr(path).to_str() else {
+        return VGMCK_INVALID_ARGUMENT;
+    };
+    run(|compiler, vgm| compiler.compile_file_to_writer(Path::new(path), vgm), out, len, errors)
+}
+
+/// Free a VGM file returned by `vgmck_compile` or `vgmck_compile_file`
+///
+/// # Safety
+///
+/// `data` and `len` must be a pair returned by this library and not freed
+/// yet, or `data` null.
+#[no_mangle]
+pub unsafe extern "C" fn vgmck_free_bytes(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// Free a message string returned through `errors`
+///
+/// # Safety
+///
+/// `text` must be a string returned by this library and not freed yet, or
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn vgmck_free_string(text: *mut c_char) {
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
+
+/// The library version, as a static string not to be freed
+#[no_mangle]
+pub extern "C" fn vgmck_version() -> *const c_char {
+    VERSION.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compile through the C interface, returning the code, file and messages
+    fn compile(mml: &CStr) -> (c_int, Vec<u8>, Option<String>) {
+        let (mut out, mut len, mut errors) = (ptr::null_mut(), 0, ptr::null_mut());
+        unsafe {
+            let code = vgmck_compile(mml.as_ptr(), &mut out, &mut len, &mut errors);
+            let vgm = if out.is_null() { Vec::new() } else { std::slice::from_raw_parts(out, len).to_vec() };
+            let text = (!errors.is_null()).then(|| CStr::from_ptr(errors).to_string_lossy().into_owned());
+            vgmck_free_bytes(out, len);
+            vgmck_free_string(errors);
+            (code, vgm, text)
+        }
+    }
+
+    #[test]
+    fn test_compile() {
+        let (code, vgm, errors) = compile(c"#EX-PSG A\nA o4 c4 e4 g4\n");
+        assert_eq!(code, VGMCK_OK);
+        assert_eq!(&vgm[..4], b"Vgm ");
+        assert_eq!(errors, None);
+
+        let (code, vgm, errors) = compile(c"#EX-PSG A\n#BOGUS 1\nA o4 c4\n");
+        assert_eq!(code, VGMCK_OK);
+        assert!(!vgm.is_empty());
+        assert!(errors.unwrap().starts_with("Warning: "));
+
+        let (code, vgm, errors) = compile(c"#EX-NOSUCHCHIP A\nA c\n");
+        assert_eq!(code, VGMCK_ERROR);
+        assert!(vgm.is_empty());
+        assert!(errors.unwrap().contains("Error: "));
+
+        let mut len = 0;
+        let code = unsafe { vgmck_compile(ptr::null(), ptr::null_mut(), &mut len, ptr::null_mut()) };
+        assert_eq!(code, VGMCK_INVALID_ARGUMENT);
+        assert_eq!(unsafe { CStr::from_ptr(vgmck_version()) }.to_str(), Ok(env!("CARGO_PKG_VERSION")));
+    }
+}
@@ -1,6 +1,10 @@
 pub mod chips;
 pub mod compiler;
 pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+mod python;
 pub mod vgm;
 
 pub use compiler::Compiler;
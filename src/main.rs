@@ -1,46 +1,696 @@
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use vgmck::compiler::diagnostic::{Diagnostic, DiagnosticFormat};
+use vgmck::compiler::instlib;
+use vgmck::compiler::instrument::{self, InstrumentFormat};
+use vgmck::compiler::ConverterSignature;
+use vgmck::compiler::session::{Entry, Session};
+use vgmck::vgm::play::{Protocol, RegisterSink, Reload, Spfm, VgmStream};
+use vgmck::vgm::OutputFormat;
 
 #[derive(Parser, Debug)]
 #[command(name = "vgmck")]
 #[command(version = "0.1.0")]
 #[command(about = "MML to VGM compiler", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Output VGM file
-    #[arg(required_unless_present = "list_chips")]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Output file
+    #[arg(required_unless_present_any = ["list_chips", "help_directives", "dry_run"])]
     output: Option<PathBuf>,
 
     /// Input MML file (reads from stdin if not specified)
     #[arg(short, long)]
     input: Option<PathBuf>,
 
-    /// List available sound chips
+    /// List available sound chips with their `#OPTION` names
     #[arg(short = 'L', long)]
     list_chips: bool,
+
+    /// Output file format: vgm, or nsf (2A03 songs only)
+    #[arg(long, value_name = "FORMAT", default_value = "vgm", conflicts_with_all = ["gzip", "reglog", "dry_run"])]
+    format: OutputFormat,
+
+    /// Gzip the output (VGZ); implied when the output name ends in .vgz
+    #[arg(short = 'z', long)]
+    gzip: bool,
+
+    /// Drop register writes that repeat a register's value and join the
+    /// waits between them, for a smaller file
+    #[arg(long)]
+    optimize: bool,
+
+    /// Also write a register write log (CSV if the name ends in .csv, binary otherwise)
+    #[arg(long, value_name = "PATH")]
+    reglog: Option<PathBuf>,
+
+    /// Also render one WAV per channel, with the other channels muted, into this directory
+    #[cfg(feature = "render")]
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["format", "dry_run"])]
+    render_stems: Option<PathBuf>,
+
+    /// Also render the song to a WAV file, through the built-in chip cores
+    #[cfg(feature = "render")]
+    #[arg(long, value_name = "WAV", conflicts_with = "dry_run")]
+    render: Option<PathBuf>,
+
+    /// Seed for random features, making the output reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Put vgmck's signature in the GD3 converter field: append (after the
+    /// `#PROGRAMER` credits), replace (instead of them) or off
+    #[arg(long, value_name = "POLICY", default_value = "off")]
+    converter_signature: ConverterSignature,
+
+    /// Fail if random features are used without --seed
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Song to compile from an input with `#TRACK` sections (default the first)
+    #[arg(short = 't', long, value_name = "N")]
+    track: Option<u32>,
+
+    /// Compile every `#TRACK` of the input, to `<output>_01.vgm`, `<output>_02.vgm`, ...
+    #[arg(long, requires = "input", conflicts_with_all = ["track", "dry_run", "reglog"])]
+    all: bool,
+
+    /// Define a name for `#IFDEF` blocks, as `#DEFINE NAME` would (repeatable)
+    #[arg(short = 'D', long = "define", value_name = "NAME")]
+    define: Vec<String>,
+
+    /// Print every compiled event with its time, frame, channel, chip and meaning
+    #[arg(long)]
+    dump_events: bool,
+
+    /// Print a per-channel timeline of event density, one column per FRAMES
+    /// frames (by default the song is fitted into 64 columns)
+    #[arg(long, value_name = "FRAMES", num_args = 0..=1, default_missing_value = "0")]
+    heatmap: Option<i64>,
+
+    /// Fail on unknown directives and commands, undefined envelopes and
+    /// out-of-range values instead of warning about them
+    #[arg(long)]
+    strict: bool,
+
+    /// Compile and build the VGM file in memory only, printing its size and
+    /// statistics; no file is written
+    #[arg(long, conflicts_with = "reglog")]
+    dry_run: bool,
+
+    /// List supported global `#` directives
+    #[arg(long)]
+    help_directives: bool,
+
+    /// How warnings and errors are printed: text, or json (one object per line)
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    diagnostics_format: DiagnosticFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Reconstruct MML from a VGM or VGZ file (lossy; PSG and AY8910 tone channels)
+    Decompile {
+        /// Input VGM, VGZ, GYM or DRO file
+        input: PathBuf,
+
+        /// Output MML file
+        output: PathBuf,
+    },
+
+    /// Print command statistics of a VGM or VGZ file: opcode and wait
+    /// histograms, writes per second and the largest write bursts
+    Analyze {
+        /// Input VGM, VGZ, GYM or DRO file
+        input: PathBuf,
+    },
+
+    /// Report registers that hold other values at the end of a VGM or VGZ
+    /// file's loop than at its start, which make the loop sound different
+    /// after the first time; exits with 2 if there are any
+    LoopCheck {
+        /// Input VGM, VGZ, GYM or DRO file
+        input: PathBuf,
+    },
+
+    /// Print a VGM or VGZ file as JSON, for diffing and scripting edits
+    Json {
+        /// Input VGM, VGZ, GYM or DRO file
+        input: PathBuf,
+    },
+
+    /// Turn JSON printed by `vgmck json` back into a VGM or VGZ file
+    Unjson {
+        /// Input JSON file
+        input: PathBuf,
+
+        /// Output VGM file
+        output: PathBuf,
+    },
+
+    /// Parse and compile an MML file without writing output, reporting its
+    /// diagnostics; exits with 1 on errors and 2 on warnings only
+    Check {
+        /// Input MML file
+        input: PathBuf,
+
+        /// Treat unknown names and out-of-range values as errors
+        #[arg(long)]
+        strict: bool,
+
+        /// Define a name for `#IFDEF` blocks (repeatable)
+        #[arg(short = 'D', long = "define", value_name = "NAME")]
+        define: Vec<String>,
+
+        /// How warnings and errors are printed: text, or json (one object per line)
+        #[arg(long, value_name = "FORMAT", default_value = "text")]
+        diagnostics_format: DiagnosticFormat,
+    },
+
+    /// Export the FM instruments (OPN2 `@x` macros) of an MML file
+    ExportInst {
+        /// Input MML file
+        input: PathBuf,
+
+        /// Instrument format: opm (VOPM bank), dmp (DefleMask) or fui (Furnace)
+        #[arg(short, long, default_value = "opm")]
+        format: InstrumentFormat,
+
+        /// Output directory (defaults to the input file's directory)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Create a starter project: a song, an instruments include and a Makefile
+    New {
+        /// Directory to create; the song is named after it
+        dir: PathBuf,
+
+        /// Chip the song plays on, as in `#EX-` (see --list-chips)
+        #[arg(short, long, default_value = "PSG")]
+        chip: String,
+    },
+
+    /// Play a song in real time on a register-writer board
+    Play {
+        /// MML file to compile, or a VGM, VGZ, GYM or DRO file to play as it is
+        input: PathBuf,
+
+        /// Device the board is on, such as a serial port (set its speed beforehand, e.g. with stty)
+        #[arg(long, value_name = "PATH")]
+        device: PathBuf,
+
+        /// Wire format: vgm (each command in its VGM encoding) or spfm (SPFM Light register writes)
+        #[arg(long, value_name = "PROTOCOL", default_value = "vgm")]
+        protocol: Protocol,
+
+        /// Chips in the SPFM slots in slot order, by VGM name such as ym2612
+        /// (default: the song's chips in name order)
+        #[arg(long, value_name = "CHIPS", value_delimiter = ',')]
+        slots: Vec<String>,
+
+        /// Define a name for `#IFDEF` blocks (repeatable)
+        #[arg(short = 'D', long = "define", value_name = "NAME")]
+        define: Vec<String>,
+
+        /// Seed for random features
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Keep playing and recompile the MML file whenever it is saved: a
+        /// change to envelopes or `@INST` blocks only is heard at once, any
+        /// other change plays the song from the start
+        #[arg(long)]
+        watch: bool,
+    },
+
+    /// Enter definitions and channel snippets interactively, compiling each snippet
+    Repl {
+        /// MML file whose definitions (directives, envelopes, text macros) are loaded first
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+
+        /// VGM file each snippet is written to, for auditioning in a player
+        #[arg(short, long, default_value = "repl.vgm")]
+        output: PathBuf,
+
+        /// Seed for random features
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+}
+
+const REPL_HELP: &str = "\
+Lines starting with a channel letter are compiled on their own and written
+to the output file; any other line (#directive, @envelope, *macro) is kept
+as a definition for later snippets.
+
+  :defs         List the definitions
+  :clear        Forget all definitions
+  :load PATH    Load the definitions of an MML file
+  :help         Show this help
+  :quit         Leave (also Ctrl-D)";
+
+/// Read lines from stdin until `:quit` or end of input
+fn run_repl(session: &mut Session, output: &Path) -> Result<(), vgmck::Error> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let Some(line) = lines.next().transpose()? else {
+            println!();
+            return Ok(());
+        };
+        let line = line.trim();
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "" => {}
+            ":quit" | ":q" => return Ok(()),
+            ":help" => println!("{}", REPL_HELP),
+            ":defs" => session.definitions().iter().for_each(|d| println!("{}", d)),
+            ":clear" => session.clear(),
+            ":load" => match session.load(Path::new(arg.trim())) {
+                Ok(count) => println!("{} definitions loaded", count),
+                Err(e) => eprintln!("Error: {}", e),
+            },
+            _ if command.starts_with(':') => eprintln!("Unknown command {} (see :help)", command),
+            _ => match session.enter(line, Some(output)) {
+                Ok(Entry::Definition(warnings)) => warnings.iter().for_each(|w| eprintln!("Warning: {}", w)),
+                Ok(Entry::Snippet(compiler)) => {
+                    compiler.warnings.iter().for_each(|w| eprintln!("Warning: {}", w));
+                    for event in compiler.events() {
+                        println!("{:>8}  {}", event.time, event.describe());
+                    }
+                    println!("{} samples written to {}", compiler.total_samples, output.display());
+                }
+                Err(e) => eprintln!("Error: {}", e),
+            },
+        }
+    }
 }
 
 fn main() -> Result<(), vgmck::Error> {
     let args = Args::parse();
 
+    if let Some(Command::Decompile { input, output }) = &args.command {
+        return vgmck::vgm::decompile::decompile_file(input, output);
+    }
+
+    if let Some(Command::Analyze { input }) = &args.command {
+        print!("{}", vgmck::vgm::analyze::analyze_file(input)?);
+        return Ok(());
+    }
+
+    if let Some(Command::LoopCheck { input }) = &args.command {
+        let Some(report) = vgmck::vgm::seam::check_file(input)? else {
+            println!("{} does not loop", input.display());
+            return Ok(());
+        };
+        print!("{}", report);
+        if !report.changes.is_empty() {
+            std::process::exit(2);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Json { input }) = &args.command {
+        println!("{}", vgmck::vgm::json::vgm_to_json(input)?);
+        return Ok(());
+    }
+
+    if let Some(Command::Unjson { input, output }) = &args.command {
+        return vgmck::vgm::json::json_to_vgm(input, output);
+    }
+
+    if let Some(Command::Check { input, strict, define, diagnostics_format }) = &args.command {
+        let mut compiler = vgmck::Compiler::new();
+        compiler.report = false;
+        compiler.diagnostics_format = *diagnostics_format;
+        compiler.set_strict(*strict);
+        define.iter().for_each(|name| compiler.define(name));
+        if let Err(e) = compiler.check_file(input) {
+            fail(&compiler, &e, *diagnostics_format);
+        }
+        if compiler.diagnostics.is_empty() {
+            return Ok(());
+        }
+        if *diagnostics_format == DiagnosticFormat::Text {
+            let count = compiler.diagnostics.len();
+            eprintln!("{}: {} warning{}", input.display(), count, if count == 1 { "" } else { "s" });
+        }
+        std::process::exit(2);
+    }
+
+    if let Some(Command::ExportInst { input, format, output }) = &args.command {
+        let mut compiler = vgmck::Compiler::new();
+        compiler.parse_file(input)?;
+        let dir = output
+            .clone()
+            .or_else(|| input.parent().map(|p| p.to_path_buf()))
+            .unwrap_or_default();
+        let stem = input.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let written = instrument::export(&compiler.macro_env, &dir, &stem, *format)?;
+        if written.is_empty() {
+            eprintln!("No FM instruments (@x macros) found in {}", input.display());
+        }
+        for path in written {
+            println!("{}", path.display());
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::New { dir, chip }) = &args.command {
+        for path in vgmck::compiler::scaffold::create(dir, chip)? {
+            println!("{}", path.display());
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Play { input, device, protocol, slots, define, seed, watch }) = &args.command {
+        return play(input, device, *protocol, slots, define, *seed, *watch);
+    }
+
+    if let Some(Command::Repl { input, output, seed }) = &args.command {
+        let mut session = Session::new();
+        if let Some(seed) = seed {
+            session.set_seed(*seed);
+        }
+        if let Some(input) = input {
+            session.load(input)?;
+        }
+        println!("vgmck repl - type :help for commands");
+        return run_repl(&mut session, output);
+    }
+
     if args.list_chips {
-        for name in vgmck::chips::list_chips() {
-            println!("{}", name);
+        for info in vgmck::chips::list_chips() {
+            if info.aliases.is_empty() {
+                println!("{}", info.name);
+            } else {
+                println!("{} (also {})", info.name, info.aliases.join(", "));
+            }
+            for option in info.create().chip.option_descriptions() {
+                println!("    {:<16} {}  {}", option.name, option.letter, option.description);
+            }
+        }
+        return Ok(());
+    }
+
+    if args.help_directives {
+        for directive in vgmck::compiler::directive::directives() {
+            let name = if directive.prefix {
+                format!("#{}*", directive.name)
+            } else {
+                format!("#{}", directive.name)
+            };
+            println!("{:<20} {:<20} {}", name, directive.args, directive.description);
+        }
+        return Ok(());
+    }
+
+    if args.all {
+        return compile_all(&args);
+    }
+
+    let mut compiler = new_compiler(&args);
+    if args.dry_run {
+        let vgz = |path: &PathBuf| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vgz"));
+        compiler.gzip |= args.output.as_ref().is_some_and(vgz);
+        if let Err(e) = dry_run(&mut compiler, &args) {
+            fail(&compiler, &e, args.diagnostics_format);
         }
         return Ok(());
     }
 
-    let output = args.output.expect("output is required when not listing chips");
+    let output = args.output.clone().expect("output is required when not listing chips");
+    if let Err(e) = compile(&mut compiler, &args, &output) {
+        fail(&compiler, &e, args.diagnostics_format);
+    }
+    Ok(())
+}
 
+/// A compiler set up as the command line asks
+fn new_compiler(args: &Args) -> vgmck::Compiler {
     let mut compiler = vgmck::Compiler::new();
+    compiler.gzip = args.gzip;
+    compiler.optimize = args.optimize;
+    compiler.converter_signature = args.converter_signature;
+    compiler.reproducible = args.reproducible;
+    compiler.diagnostics_format = args.diagnostics_format;
+    #[cfg(feature = "render")]
+    compiler.stems_dir.clone_from(&args.render_stems);
+    compiler.track = args.track;
+    compiler.set_strict(args.strict);
+    args.define.iter().for_each(|name| compiler.define(name));
+    if let Some(seed) = args.seed {
+        compiler.set_seed(seed);
+    }
+    compiler
+}
+
+/// Compile each `#TRACK` of the input to its own file (`--all`)
+fn compile_all(args: &Args) -> Result<(), vgmck::Error> {
+    let input = args.input.as_deref().expect("--all requires an input file");
+    let output = args.output.clone().expect("output is required when not listing chips");
+    let mut scan = new_compiler(args);
+    let tracks = match scan.scan_tracks(input) {
+        Ok(tracks) => tracks,
+        Err(e) => fail(&scan, &e, args.diagnostics_format),
+    };
+
+    let outputs: Vec<(Option<u32>, PathBuf)> = if tracks.is_empty() {
+        eprintln!("No #TRACK sections in {}; compiling it as one song", input.display());
+        vec![(None, output)]
+    } else {
+        tracks.iter().map(|&track| (Some(track), vgmck::Compiler::track_path(&output, track))).collect()
+    };
+    for (track, path) in outputs {
+        let mut compiler = new_compiler(args);
+        compiler.track = track;
+        if let Err(e) = compile(&mut compiler, args, &path) {
+            fail(&compiler, &e, args.diagnostics_format);
+        }
+    }
+    Ok(())
+}
+
+/// Play an MML or VGM file on a device in real time (`vgmck play`)
+fn play(
+    input: &Path,
+    device: &Path,
+    protocol: Protocol,
+    slots: &[String],
+    define: &[String],
+    seed: Option<u64>,
+    watch: bool,
+) -> Result<(), vgmck::Error> {
+    let is_vgm = input.extension().is_some_and(|ext| ["vgm", "vgz", "gym", "dro"].iter().any(|e| ext.eq_ignore_ascii_case(e)));
+    let compile = |compiler: &mut vgmck::Compiler| {
+        compiler.report = false;
+        define.iter().for_each(|name| compiler.define(name));
+        if let Some(seed) = seed {
+            compiler.set_seed(seed);
+        }
+        let mut data = Vec::new();
+        compiler.compile_file_to_writer(input, &mut data).map(|_| data)
+    };
+    let data = if is_vgm {
+        if watch {
+            eprintln!("Warning: --watch recompiles MML files; {} plays once", input.display());
+        }
+        vgmck::vgm::read_file(input)?
+    } else {
+        let mut compiler = vgmck::Compiler::new();
+        match compile(&mut compiler) {
+            Ok(data) => data,
+            Err(e) => fail(&compiler, &e, DiagnosticFormat::Text),
+        }
+    };
+    let mut reader = vgmck::vgm::VgmReader::new(&data);
+    let header = reader.parse_header()?;
+    let commands = reader.parse_commands(&header)?;
+
+    let port = std::fs::OpenOptions::new().write(true).open(device).map_err(|e| {
+        io::Error::new(e.kind(), format!("Failed to open device '{}': {}", device.display(), e))
+    })?;
+    let output = io::BufWriter::new(port);
+    let mut sink: Box<dyn RegisterSink> = match protocol {
+        Protocol::Vgm => Box::new(VgmStream::new(output)),
+        Protocol::Spfm if slots.is_empty() => {
+            let mut chips: Vec<String> = header.chips.keys().cloned().collect();
+            chips.sort();
+            Box::new(Spfm::new(output, chips))
+        }
+        Protocol::Spfm => Box::new(Spfm::new(output, slots.to_vec())),
+    };
+    eprintln!(
+        "Playing {} ({}) on {}",
+        input.display(),
+        vgmck::vgm::analyze::format_time(header.total_samples as u64),
+        device.display()
+    );
+    if !watch || is_vgm {
+        return vgmck::vgm::play::play(&commands, sink.as_mut());
+    }
+
+    // Look at the file's modification time a few times a second, and keep
+    // the last version that compiled
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified());
+    let mut last_modified = modified(input).ok();
+    let mut last_source = std::fs::read_to_string(input).unwrap_or_default();
+    let mut checked = std::time::Instant::now();
+    let reload = || {
+        if checked.elapsed() < std::time::Duration::from_millis(250) {
+            return None;
+        }
+        checked = std::time::Instant::now();
+        let Ok(time) = modified(input) else {
+            eprintln!("{} is gone; stopping", input.display());
+            return Some(Reload::Stop);
+        };
+        if last_modified == Some(time) {
+            return None;
+        }
+        last_modified = Some(time);
+        let source = std::fs::read_to_string(input).ok()?;
+        let mut compiler = vgmck::Compiler::new();
+        let data = match compile(&mut compiler) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error: {}; still playing the last version", e);
+                return None;
+            }
+        };
+        let mut reader = vgmck::vgm::VgmReader::new(&data);
+        let commands = match reader.parse_header().and_then(|header| reader.parse_commands(&header)) {
+            Ok(commands) => commands,
+            Err(e) => {
+                eprintln!("Error: {}; still playing the last version", e);
+                return None;
+            }
+        };
+        let instruments_only = instlib::without_instruments(&source) == instlib::without_instruments(&last_source);
+        last_source = source;
+        if instruments_only {
+            eprintln!("Reloaded {}: instruments changed", input.display());
+            Some(Reload::Patch(commands))
+        } else {
+            eprintln!("Reloaded {}: playing from the start", input.display());
+            Some(Reload::Restart(commands))
+        }
+    };
+    eprintln!("Watching {} for changes; press Ctrl-C to stop", input.display());
+    vgmck::vgm::play::play_watched(commands, sink.as_mut(), reload)
+}
 
+/// Compile the input into memory and print what would be written
+fn dry_run(compiler: &mut vgmck::Compiler, args: &Args) -> Result<(), vgmck::Error> {
+    let mut output = Vec::new();
     match &args.input {
-        Some(path) => {
+        Some(path) => compiler.compile_file_to_writer(path, &mut output)?,
+        None => compiler.compile_to_writer(std::io::stdin(), &mut output)?,
+    }
+
+    if args.dump_events {
+        println!("{:>9} {:>6} {:>8}  event", "sample", "frame", "bar");
+        for record in compiler.dump_events() {
+            println!("{}", record);
+        }
+    }
+    if let Some(frames) = args.heatmap {
+        print!("{}", compiler.heatmap(frames));
+    }
+
+    let size = output.len();
+    let data = vgmck::vgm::decompress(output)?;
+    let name = args.output.as_ref().map_or("output".into(), |path| path.display().to_string());
+    if compiler.gzip {
+        println!("{}: {} bytes ({} uncompressed), not written", name, size, data.len());
+    } else {
+        println!("{}: {} bytes, not written", name, size);
+    }
+    for (chip, memory) in compiler.sample_memory() {
+        println!(
+            "{} sample memory: {} of {} bytes ({} sample{})",
+            chip,
+            memory.used,
+            memory.capacity,
+            memory.samples.len(),
+            if memory.samples.len() == 1 { "" } else { "s" }
+        );
+    }
+    print!("{}", vgmck::vgm::analyze::analyze_bytes(&data)?);
+    Ok(())
+}
+
+/// Report the error compilation failed with and exit; the errors in the
+/// MML were already printed as they were found, so they are only counted
+fn fail(compiler: &vgmck::Compiler, error: &vgmck::Error, format: DiagnosticFormat) -> ! {
+    let errors = compiler.error_count();
+    let reported = errors > 0 && matches!(error, vgmck::Error::MmlSyntax { .. });
+    match format {
+        DiagnosticFormat::Text if reported => {
+            eprintln!("{} error{}, no output written", errors, if errors == 1 { "" } else { "s" });
+        }
+        DiagnosticFormat::Text => eprintln!("Error: {}", error),
+        DiagnosticFormat::Json if reported => {}
+        DiagnosticFormat::Json => eprintln!("{}", Diagnostic::from_error(error).to_json()),
+    }
+    std::process::exit(1);
+}
+
+/// Compile the input to the output file (and the register log)
+fn compile(compiler: &mut vgmck::Compiler, args: &Args, output: &Path) -> Result<(), vgmck::Error> {
+    let mut vgm = Vec::new();
+    match (&args.input, args.format) {
+        (Some(path), OutputFormat::Vgm) => {
             // Use compile_file to properly resolve #INCLUDE paths
-            compiler.compile_file(path, &output)?;
+            compiler.compile_file(path, output)?;
         }
-        None => {
+        (None, OutputFormat::Vgm) => {
             // Read from stdin (no base path for includes)
-            compiler.compile(std::io::stdin(), &output)?;
+            compiler.compile(std::io::stdin(), output)?;
+        }
+        (input, OutputFormat::Nsf) => {
+            // The VGM data is converted, never written
+            compiler.gzip = false;
+            match input {
+                Some(path) => compiler.compile_file_to_writer(path, &mut vgm)?,
+                None => compiler.compile_to_writer(std::io::stdin(), &mut vgm)?,
+            }
+            std::fs::write(output, vgmck::vgm::nsf::from_vgm(&vgm)?)?;
+        }
+    }
+
+    if args.dump_events {
+        println!("{:>9} {:>6} {:>8}  event", "sample", "frame", "bar");
+        for record in compiler.dump_events() {
+            println!("{}", record);
+        }
+    }
+    if let Some(frames) = args.heatmap {
+        print!("{}", compiler.heatmap(frames));
+    }
+
+    if let Some(path) = &args.reglog {
+        vgmck::vgm::reglog::export(output, path)?;
+    }
+
+    #[cfg(feature = "render")]
+    if let Some(wav) = &args.render {
+        use vgmck::vgm::render;
+        // The VGM file as written, or as converted to NSF
+        if vgm.is_empty() {
+            vgm = vgmck::vgm::read_file(output)?;
+        }
+        let rendered = render::render_bytes(&vgm)?;
+        render::write_wav(&rendered.samples, io::BufWriter::new(std::fs::File::create(wav)?))?;
+        for chip in rendered.silent_chips {
+            eprintln!("Warning: no preview core for {}; it is silent in {}", chip, wav.display());
         }
     }
 
@@ -0,0 +1,183 @@
+//! Python bindings (cargo feature `python`)
+//!
+//! `vgmck.compile(mml, includes)` compiles MML text to VGM bytes, taking
+//! the files `#INCLUDE` names from a dict of path to text, so songs can be
+//! built from notebooks and scripts without touching the disk. A
+//! `vgmck.Compiler` holds seed, strict mode and defines across compiles,
+//! and keeps the diagnostics and statistics of the last one. Failures
+//! raise `vgmck.CompileError`, whose `diagnostics` attribute lists them.
+
+use crate::compiler::diagnostic::{Diagnostic, Severity};
+use crate::compiler::report::CompileReport;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+create_exception!(vgmck, CompileError, PyException, "MML that failed to compile; `diagnostics` lists the errors and warnings");
+
+/// Diagnostics as dicts, with the keys of `--diagnostics-format json`
+fn diagnostic_list<'py>(py: Python<'py>, diagnostics: &[Diagnostic]) -> PyResult<Bound<'py, PyList>> {
+    let json = py.import("json")?;
+    let items = diagnostics.iter().map(|d| json.call_method1("loads", (d.to_json(),))).collect::<PyResult<Vec<_>>>()?;
+    PyList::new(py, items)
+}
+
+/// A compile report as a dict
+fn report_dict<'py>(py: Python<'py>, report: &CompileReport) -> PyResult<Bound<'py, PyDict>> {
+    let channels = PyList::empty(py);
+    for channel in &report.channels {
+        let item = PyDict::new(py);
+        item.set_item("channel", channel.channel)?;
+        item.set_item("chip", &channel.chip)?;
+        item.set_item("notes", channel.notes)?;
+        item.set_item("samples", channel.samples)?;
+        item.set_item("loop_point", channel.loop_point)?;
+        channels.append(item)?;
+    }
+    let stats = PyDict::new(py);
+    stats.set_item("channels", channels)?;
+    stats.set_item("total_samples", report.total_samples)?;
+    stats.set_item("loop_samples", report.loop_samples)?;
+    stats.set_item("chip_bytes", report.chip_bytes.clone())?;
+    stats.set_item("total_bytes", report.total_bytes())?;
+    stats.set_item("register_writes", report.register_writes)?;
+    stats.set_item("longest_wait", report.longest_wait)?;
+    Ok(stats)
+}
+
+/// Compiler settings, and the diagnostics and statistics of the last compile
+#[pyclass(name = "Compiler", module = "vgmck")]
+#[derive(Default)]
+struct PyCompiler {
+    /// Seed for random features (`--seed`)
+    #[pyo3(get, set)]
+    seed: Option<u64>,
+    /// Turn lint warnings into errors (`--strict`)
+    #[pyo3(get, set)]
+    strict: bool,
+    /// Names for `#IFDEF` blocks (`-D`)
+    #[pyo3(get, set)]
+    defines: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    report: Option<CompileReport>,
+}
+
+#[pymethods]
+impl PyCompiler {
+    #[new]
+    #[pyo3(signature = (*, seed = None, strict = false, defines = Vec::new()))]
+    fn new(seed: Option<u64>, strict: bool, defines: Vec<String>) -> Self {
+        Self { seed, strict, defines, ..Self::default() }
+    }
+
+    /// Compile MML text to VGM bytes, with `#INCLUDE` files from a dict of
+    /// path to text (paths not in it are read from the disk)
+    #[pyo3(signature = (mml, includes = None))]
+    fn compile<'py>(
+        &mut self,
+        py: Python<'py>,
+        mml: &str,
+        includes: Option<HashMap<String, String>>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let mut compiler = crate::Compiler::new();
+        compiler.report = false;
+        compiler.silent = true;
+        compiler.set_strict(self.strict);
+        self.defines.iter().for_each(|name| compiler.define(name));
+        if let Some(seed) = self.seed {
+            compiler.set_seed(seed);
+        }
+        compiler.include_files = includes.unwrap_or_default().into_iter().map(|(path, text)| (PathBuf::from(path), text)).collect();
+
+        let mut vgm = Vec::new();
+        let result = compiler.compile_to_writer(Cursor::new(mml), &mut vgm);
+        self.diagnostics = compiler.diagnostics.clone();
+        self.report = result.is_ok().then(|| compiler.compile_report().clone());
+        match result {
+            Ok(()) => Ok(PyBytes::new(py, &vgm)),
+            Err(error) => {
+                let error = CompileError::new_err(error.to_string());
+                error.value(py).setattr("diagnostics", diagnostic_list(py, &self.diagnostics)?)?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Warnings and errors of the last compile, as dicts
+    #[getter]
+    fn diagnostics<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        diagnostic_list(py, &self.diagnostics)
+    }
+
+    /// Warning messages of the last compile
+    #[getter]
+    fn warnings(&self) -> Vec<String> {
+        self.diagnostics.iter().filter(|d| d.severity == Severity::Warning).map(Diagnostic::to_string).collect()
+    }
+
+    /// Statistics of the last successful compile (the compile report), or None
+    #[getter]
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+        self.report.as_ref().map(|report| report_dict(py, report)).transpose()
+    }
+}
+
+/// Compile MML text to VGM bytes, with `#INCLUDE` files from a dict of path
+/// to text
+#[pyfunction]
+#[pyo3(signature = (mml, includes = None))]
+fn compile<'py>(py: Python<'py>, mml: &str, includes: Option<HashMap<String, String>>) -> PyResult<Bound<'py, PyBytes>> {
+    PyCompiler::default().compile(py, mml, includes)
+}
+
+#[pymodule]
+fn vgmck(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add("CompileError", m.py().get_type::<CompileError>())?;
+    m.add_class::<PyCompiler>()?;
+    m.add_function(wrap_pyfunction!(compile, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module() {
+        Python::initialize();
+        Python::attach(|py| {
+            let module = PyModule::new(py, "vgmck").unwrap();
+            vgmck(&module).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("vgmck", module).unwrap();
+            py.run(
+                cr##"
+vgm = vgmck.compile("#INCLUDE chips.mml\nA o4 c4 e4\n", {"chips.mml": "#EX-PSG A\n"})
+assert vgm[:4] == b"Vgm ", vgm[:4]
+
+compiler = vgmck.Compiler(strict=False)
+compiler.compile("#EX-PSG A\n#BOGUS 1\nA o4 c4\n")
+assert compiler.warnings == ["line 2: unknown directive #BOGUS"], compiler.warnings
+assert compiler.diagnostics[0]["code"] == "W0001", compiler.diagnostics
+stats = compiler.stats
+assert stats["channels"][0]["notes"] == 1 and stats["total_samples"] == 22050, stats
+
+try:
+    vgmck.compile("#EX-NOSUCHCHIP A\nA c\n")
+    raise AssertionError("no error")
+except vgmck.CompileError as error:
+    assert "NOSUCHCHIP" in str(error), error
+    assert error.diagnostics == []
+"##,
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+}
@@ -0,0 +1,238 @@
+//! VGM command stream statistics
+//!
+//! Counts what a VGM file asks of its player: how often each opcode is
+//! used, how long the waits are, how many register writes fall in each
+//! second and the largest bursts of writes without a wait between them.
+//! Large bursts and busy seconds are what make low-end hardware players
+//! stutter, and the counts show what the optimizer saved.
+
+use super::{VgmCommand, VgmReader};
+use crate::error::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// Samples per second in VGM timing
+const SAMPLE_RATE: u64 = 44100;
+
+/// Number of bursts kept
+const MAX_BURSTS: usize = 10;
+
+/// Width of the longest histogram bar
+const BAR_WIDTH: u64 = 40;
+
+/// Writes at the same sample, with no wait between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Burst {
+    /// Time in samples
+    pub time: u64,
+    pub writes: u64,
+}
+
+/// Statistics of a VGM command stream
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Analysis {
+    /// Number of commands by opcode
+    pub opcodes: BTreeMap<u8, u64>,
+    /// Number of waits by length bucket: bucket `n` holds waits of
+    /// `2^n` to `2^(n+1) - 1` samples
+    pub waits: BTreeMap<u32, u64>,
+    /// Register writes in each second of the song
+    pub writes_per_second: Vec<u64>,
+    /// The largest bursts, largest first (earliest first among equals)
+    pub bursts: Vec<Burst>,
+    /// Length of the song in samples
+    pub total_samples: u64,
+}
+
+/// Whether an opcode writes a chip register
+fn is_write(opcode: u8) -> bool {
+    matches!(opcode, 0x30..=0x3F | 0x4F..=0x5F | 0x80..=0x8F | 0xA0..=0xBF | 0xC0..=0xC8 | 0xD0..=0xD6)
+}
+
+/// Short description of an opcode
+fn opcode_name(opcode: u8) -> String {
+    let name = match opcode {
+        0x30..=0x3F => "second SN76489",
+        0x61 => "wait n samples",
+        0x62 => "wait 1/60 s",
+        0x63 => "wait 1/50 s",
+        0x66 => "end",
+        0x67 => "data block",
+        0x68 => "PCM RAM write",
+        0x70..=0x7F => "short wait",
+        0x80..=0x8F => "YM2612 DAC + wait",
+        0x90..=0x95 => "DAC stream control",
+        0xA0 => "AY8910",
+        0xA1..=0xAF => "second chip",
+        0xE0 => "PCM seek",
+        _ => match super::reglog::opcode_chip(opcode) {
+            Some((chip, _, port)) if matches!(opcode, 0x52 | 0x53 | 0x56..=0x59 | 0x5E | 0x5F) => {
+                return format!("{} port {}", chip, port);
+            }
+            Some((chip, _, _)) => chip,
+            None => "",
+        },
+    };
+    name.to_string()
+}
+
+/// Samples a command waits for
+fn wait_samples(command: &VgmCommand) -> u64 {
+    match *command {
+        VgmCommand::Wait { samples } => samples as u64,
+        VgmCommand::Ym2612Dac { wait, .. } => wait as u64,
+        _ => 0,
+    }
+}
+
+/// Collect the statistics of a list of commands with their opcodes
+pub fn analyze(commands: &[(u8, VgmCommand)]) -> Analysis {
+    let mut analysis = Analysis::default();
+    let mut bursts = Vec::new();
+    let mut time = 0u64;
+    let mut burst = Burst { time: 0, writes: 0 };
+
+    for (opcode, command) in commands {
+        *analysis.opcodes.entry(*opcode).or_default() += 1;
+        if is_write(*opcode) {
+            let second = (time / SAMPLE_RATE) as usize;
+            if analysis.writes_per_second.len() <= second {
+                analysis.writes_per_second.resize(second + 1, 0);
+            }
+            analysis.writes_per_second[second] += 1;
+            burst.writes += 1;
+        }
+        let wait = wait_samples(command);
+        if wait > 0 {
+            *analysis.waits.entry(wait.ilog2()).or_default() += 1;
+            if burst.writes > 0 {
+                bursts.push(burst);
+            }
+            time += wait;
+            burst = Burst { time, writes: 0 };
+        }
+    }
+    if burst.writes > 0 {
+        bursts.push(burst);
+    }
+
+    bursts.sort_by(|a, b| b.writes.cmp(&a.writes).then(a.time.cmp(&b.time)));
+    bursts.truncate(MAX_BURSTS);
+    analysis.bursts = bursts;
+    analysis.total_samples = time;
+    analysis
+}
+
+/// Analyze a VGM or VGZ file
+pub fn analyze_file(path: &Path) -> Result<Analysis> {
+    analyze_bytes(&super::read_file(path)?)
+}
+
+/// Analyze the contents of an uncompressed VGM file
+pub fn analyze_bytes(data: &[u8]) -> Result<Analysis> {
+    let mut reader = VgmReader::new(data);
+    let header = reader.parse_header()?;
+    Ok(analyze(&reader.parse_opcodes(&header)?))
+}
+
+/// A bar of `#` for a count, scaled to the largest count
+fn bar(count: u64, max: u64) -> String {
+    let width = (count * BAR_WIDTH).div_ceil(max.max(1));
+    "#".repeat(width as usize)
+}
+
+/// Time in samples as `m:ss.mmm`
+pub fn format_time(samples: u64) -> String {
+    let ms = samples * 1000 / SAMPLE_RATE;
+    format!("{}:{:02}.{:03}", ms / 60_000, ms / 1000 % 60, ms % 1000)
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let writes: u64 = self.writes_per_second.iter().sum();
+        writeln!(
+            f,
+            "{} commands, {} register writes, {} long",
+            self.opcodes.values().sum::<u64>(),
+            writes,
+            format_time(self.total_samples)
+        )?;
+
+        writeln!(f, "\nOpcodes:")?;
+        let max = self.opcodes.values().copied().max().unwrap_or(0);
+        for (&opcode, &count) in &self.opcodes {
+            writeln!(f, "  0x{:02X} {:<20} {:>8}  {}", opcode, opcode_name(opcode), count, bar(count, max))?;
+        }
+
+        writeln!(f, "\nWaits (samples):")?;
+        let max = self.waits.values().copied().max().unwrap_or(0);
+        for (&bucket, &count) in &self.waits {
+            let range = match bucket {
+                0 => "1".to_string(),
+                _ => format!("{}-{}", 1u64 << bucket, (1u64 << (bucket + 1)) - 1),
+            };
+            writeln!(f, "  {:<13} {:>8}  {}", range, count, bar(count, max))?;
+        }
+
+        writeln!(f, "\nWrites per second:")?;
+        let max = self.writes_per_second.iter().copied().max().unwrap_or(0);
+        for (second, &count) in self.writes_per_second.iter().enumerate() {
+            writeln!(f, "  {:>5}s {:>8}  {}", second, count, bar(count, max))?;
+        }
+
+        writeln!(f, "\nLargest bursts (writes at one sample):")?;
+        for burst in &self.bursts {
+            writeln!(f, "  {:>10} {:>8}", format_time(burst.time), burst.writes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_commands() -> Vec<(u8, VgmCommand)> {
+        vec![
+            (0x50, VgmCommand::Sn76489Write { data: 0x9F }),
+            (0x50, VgmCommand::Sn76489Write { data: 0xBF }),
+            (0x62, VgmCommand::Wait { samples: 735 }),
+            (0x50, VgmCommand::Sn76489Write { data: 0xDF }),
+            (0x61, VgmCommand::Wait { samples: 44100 }),
+            (0x52, VgmCommand::Ym2612Write { port: 0, reg: 0x28, data: 0 }),
+            (0x52, VgmCommand::Ym2612Write { port: 0, reg: 0x28, data: 1 }),
+            (0x52, VgmCommand::Ym2612Write { port: 0, reg: 0x28, data: 2 }),
+            (0x70, VgmCommand::Wait { samples: 1 }),
+            (0x66, VgmCommand::End),
+        ]
+    }
+
+    #[test]
+    fn test_analyze() {
+        let analysis = analyze(&sample_commands());
+        assert_eq!(analysis.opcodes[&0x50], 3);
+        assert_eq!(analysis.opcodes[&0x52], 3);
+        assert_eq!(analysis.waits, BTreeMap::from([(0, 1), (9, 1), (15, 1)]));
+        assert_eq!(analysis.writes_per_second, vec![3, 3]);
+        assert_eq!(
+            analysis.bursts,
+            vec![
+                Burst { time: 44835, writes: 3 },
+                Burst { time: 0, writes: 2 },
+                Burst { time: 735, writes: 1 },
+            ]
+        );
+        assert_eq!(analysis.total_samples, 44836);
+    }
+
+    #[test]
+    fn test_report() {
+        let report = analyze(&sample_commands()).to_string();
+        assert!(report.starts_with("10 commands, 6 register writes, 0:01.016 long\n"), "{}", report);
+        assert!(report.contains("  0x52 YM2612 port 0               3  ####"), "{}", report);
+        assert!(report.contains("  512-1023             1  "), "{}", report);
+        assert!(report.contains("\n  1                    1  "), "{}", report);
+        assert!(report.contains("    0:01.016        3"), "{}", report);
+    }
+}
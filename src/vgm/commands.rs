@@ -1,6 +1,6 @@
 //! VGM command definitions and parsing
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 /// VGM command opcodes
 pub mod opcode {
@@ -38,7 +38,7 @@ pub mod opcode {
 }
 
 /// A parsed VGM command
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "cmd", rename_all = "snake_case")]
 pub enum VgmCommand {
     /// Game Gear PSG stereo control
@@ -78,6 +78,9 @@ pub enum VgmCommand {
         block_type: u8,
         #[serde(skip_serializing_if = "Option::is_none")]
         size: Option<u32>,
+        /// Block contents
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        data: Vec<u8>,
     },
     /// PCM RAM write
     PcmRamWrite {
@@ -181,16 +184,135 @@ pub enum VgmCommand {
     Unknown { opcode: u8, bytes: Vec<u8> },
 }
 
+impl VgmCommand {
+    /// Encode the command as VGM bytes
+    ///
+    /// This is the inverse of the reader. Waits are re-encoded in their
+    /// shortest form, and commands the reader decodes lossily (the 0xC1-0xC7
+    /// memory writes and 0xD2/0xD6) come back in the form it keeps.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        use VgmCommand::*;
+        let reg_data = |op: u8, reg: u8, data: u8| vec![op, reg, data];
+        let wide_reg = |op: u8, reg: u16, data: u8| {
+            let [lo, hi] = reg.to_le_bytes();
+            vec![op, lo, hi, data]
+        };
+        match *self {
+            GgStereo { data } => vec![opcode::GG_STEREO, data],
+            Sn76489Write { data } => vec![opcode::SN76489, data],
+            Ym2413Write { reg, data } => reg_data(opcode::YM2413, reg, data),
+            Ym2612Write { port, reg, data } => reg_data(opcode::YM2612_PORT0 + (port & 1), reg, data),
+            Ym2151Write { reg, data } => reg_data(opcode::YM2151, reg, data),
+            Ym2203Write { reg, data } => reg_data(opcode::YM2203, reg, data),
+            Ym2608Write { port, reg, data } => reg_data(opcode::YM2608_PORT0 + (port & 1), reg, data),
+            Ym2610Write { port, reg, data } => reg_data(opcode::YM2610_PORT0 + (port & 1), reg, data),
+            Ym3812Write { reg, data } => reg_data(opcode::YM3812, reg, data),
+            Ym3526Write { reg, data } => reg_data(opcode::YM3526, reg, data),
+            Y8950Write { reg, data } => reg_data(opcode::Y8950, reg, data),
+            Ymz280bWrite { reg, data } => reg_data(opcode::YMZ280B, reg, data),
+            Ymf262Write { port, reg, data } => reg_data(opcode::YMF262_PORT0 + (port & 1), reg, data),
+            Ay8910Write { reg, data } => reg_data(opcode::AY8910, reg, data),
+            Wait { samples } => super::delay::generate_delay(samples as u64),
+            End => vec![opcode::END],
+            DataBlock { block_type, size, ref data } => {
+                let size = (size.unwrap_or(0) & 0x8000_0000) | data.len() as u32;
+                let mut bytes = vec![opcode::DATA_BLOCK, opcode::END, block_type];
+                bytes.extend_from_slice(&size.to_le_bytes());
+                bytes.extend_from_slice(data);
+                bytes
+            }
+            PcmRamWrite { chip_type, read_offset, write_offset, size } => {
+                let mut bytes = vec![opcode::PCM_RAM_WRITE, opcode::END, chip_type];
+                for value in [read_offset, write_offset, size] {
+                    bytes.extend_from_slice(&value.to_le_bytes()[..3]);
+                }
+                bytes
+            }
+            Ym2612Dac { wait, .. } => vec![0x80 | (wait & 0x0F)],
+            DacStreamSetup { stream_id, chip_type, port, reg } => {
+                vec![opcode::DAC_STREAM_SETUP, stream_id, chip_type, port, reg]
+            }
+            DacStreamData { stream_id, bank_id, step_base, step_size } => {
+                vec![opcode::DAC_STREAM_DATA, stream_id, bank_id, step_base, step_size]
+            }
+            DacStreamFreq { stream_id, frequency } => {
+                let mut bytes = vec![opcode::DAC_STREAM_FREQ, stream_id];
+                bytes.extend_from_slice(&frequency.to_le_bytes());
+                bytes
+            }
+            DacStreamStart { stream_id, data_start, length_mode, data_length } => {
+                let mut bytes = vec![opcode::DAC_STREAM_START, stream_id];
+                bytes.extend_from_slice(&data_start.to_le_bytes());
+                bytes.push(length_mode);
+                bytes.extend_from_slice(&data_length.to_le_bytes());
+                bytes
+            }
+            DacStreamStop { stream_id } => vec![opcode::DAC_STREAM_STOP, stream_id],
+            DacStreamFast { stream_id, block_id, flags } => {
+                let [lo, hi] = block_id.to_le_bytes();
+                vec![opcode::DAC_STREAM_FAST, stream_id, lo, hi, flags]
+            }
+            SeekPcm { offset } => {
+                let mut bytes = vec![opcode::SEEK_PCM];
+                bytes.extend_from_slice(&offset.to_le_bytes());
+                bytes
+            }
+            Rf5c68Write { reg, data } => reg_data(0xB0, reg, data),
+            Rf5c164Write { reg, data } => reg_data(0xB1, reg, data),
+            PwmWrite { reg, data } => reg_data(0xB2, (reg & 0x0F) | ((data >> 4) as u8 & 0xF0), data as u8),
+            GbDmgWrite { reg, data } => reg_data(0xB3, reg, data),
+            NesApuWrite { reg, data } => reg_data(0xB4, reg, data),
+            MultiPcmWrite { reg, data } => reg_data(0xB5, reg, data),
+            Upd7759Write { reg, data } => reg_data(0xB6, reg, data),
+            Okim6258Write { reg, data } => reg_data(0xB7, reg, data),
+            Okim6295Write { reg, data } => reg_data(0xB8, reg, data),
+            Huc6280Write { reg, data } => reg_data(0xB9, reg, data),
+            K053260Write { reg, data } => reg_data(0xBA, reg, data),
+            PokeyWrite { reg, data } => reg_data(0xBB, reg, data),
+            WonderSwanWrite { reg, data } => reg_data(0xBC, reg, data),
+            Saa1099Write { reg, data } => reg_data(0xBD, reg, data),
+            Es5503Write { reg, data } => reg_data(0xBE, reg, data),
+            Ga20Write { reg, data } => reg_data(0xBF, reg, data),
+            QsoundWrite { reg, data } => {
+                let [lo, hi] = data.to_le_bytes();
+                vec![0xC4, reg, hi, lo]
+            }
+            ScspWrite { reg, data } => wide_reg(0xC5, reg, data),
+            VsuWrite { reg, data } => vec![0xC7, reg, data, 0],
+            X1010Write { reg, data } => wide_reg(0xC8, reg, data),
+            Ymf278Write { port, reg, data } => vec![0xD0, port, reg, data],
+            Ymf271Write { port, reg, data } => vec![0xD1, port, reg, data],
+            K051649Write { reg, data } => vec![0xD2, reg, data, 0],
+            K054539Write { reg, data } => wide_reg(0xD3, reg, data),
+            C140Write { reg, data } => wide_reg(0xD4, reg, data),
+            Es5506Write { reg, data } => {
+                let [lo, hi] = data.to_le_bytes();
+                vec![0xD5, reg, lo, hi]
+            }
+            C352Write { reg, data } => {
+                let ([reg_lo, reg_hi], [lo, hi]) = (reg.to_le_bytes(), data.to_le_bytes());
+                vec![0xE1, reg_lo, reg_hi, lo, hi]
+            }
+            MikeyWrite { reg, data } => reg_data(0x40, reg, data),
+            Unknown { opcode, ref bytes } => {
+                let mut encoded = vec![opcode];
+                encoded.extend_from_slice(bytes);
+                encoded
+            }
+        }
+    }
+}
+
 /// Get the number of bytes to read after the opcode for a command
 pub fn command_size(opcode: u8) -> usize {
     match opcode {
         // 0 bytes after opcode
         0x62 | 0x63 | 0x66 => 0,
-        // 1 byte after opcode
-        0x4F | 0x50 => 1,
+        // 1 byte after opcode (0x30-0x3F are second-chip PSG writes)
+        0x30..=0x3F | 0x4F | 0x50 => 1,
         // 2 bytes after opcode
         0x51 | 0x52 | 0x53 | 0x54 | 0x55 | 0x56 | 0x57 | 0x58 | 0x59 | 0x5A | 0x5B | 0x5C
-        | 0x5D | 0x5E | 0x5F | 0x61 | 0xA0 | 0xB0..=0xBF => 2,
+        | 0x5D | 0x5E | 0x5F | 0x61 | 0xA0..=0xAF | 0xB0..=0xBF => 2,
         // 3 bytes after opcode
         0xC0..=0xC8 => 3,
         // 4 bytes after opcode
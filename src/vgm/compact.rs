@@ -0,0 +1,240 @@
+//! Loop section compaction (`#LOOP-MODE compact`)
+//!
+//! `[...]n` loops are unrolled when compiled, so a loop section that repeats
+//! a phrase holds every repeat of it. `compact_document` finds a loop
+//! section made of copies of the same commands and keeps one, multiplying
+//! the loop modifier by the number of copies so that players still play the
+//! loop for as long.
+//!
+//! Copies are compared byte for byte. Drivers write registers anew at the
+//! loop point, so the first copy may differ from the others, or follow
+//! setup writes that take no time: it is kept in front of the loop if it
+//! plays the same as the others would from the chip state at the end of the
+//! loop, which is where the original plays it again. Writes at the loop
+//! point before the first copy, such as the note-off of the note before the
+//! loop, are left out of that comparison when they change nothing there:
+//! they repeat the last writes of the loop, or store values the copy
+//! overwrites before any time passes. The loop modifier then plays the kept
+//! copy once less.
+//! Loop sections with `0x8n` PCM writes, which read from a moving data bank
+//! position, are left alone.
+
+use super::header::offset;
+use super::optimizer::{register_write, Registers};
+use super::playback::{loops_played, DEFAULT_LOOPS};
+use super::{VgmCommand, VgmDocument, VgmReader};
+use crate::error::Result;
+
+/// A command and its bytes as written
+type Command<'a> = (VgmCommand, &'a [u8]);
+
+/// A step of what commands do to the chips: writes that change something,
+/// and the waits between them
+#[derive(Debug, PartialEq, Eq)]
+enum Step<'a> {
+    Write(&'a [u8]),
+    Wait(u64),
+}
+
+/// Samples a command waits
+fn samples(command: &VgmCommand) -> u64 {
+    match *command {
+        VgmCommand::Wait { samples } => samples as u64,
+        _ => 0,
+    }
+}
+
+/// What commands do when played from a chip state, leaving out writes of
+/// values the registers already hold
+fn steps<'a>(commands: &[Command<'a>], registers: &Registers) -> Vec<Step<'a>> {
+    let mut registers = registers.clone();
+    let mut out = Vec::new();
+    for (command, bytes) in commands {
+        match (command, out.last_mut()) {
+            (VgmCommand::Wait { samples }, Some(Step::Wait(pending))) => *pending += *samples as u64,
+            (VgmCommand::Wait { samples }, _) => out.push(Step::Wait(*samples as u64)),
+            (command, _) => {
+                if registers.apply(command) {
+                    out.push(Step::Write(bytes));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Whether writes at the loop point, before the first copy, change
+/// nothing when the loop is played again: each repeats one of the last
+/// writes of the loop, after which only time passes, or stores a value in
+/// a register the copy writes again before any time passes
+fn seam_is_redundant(seam: &[Command], section: &[Command], copy: &[Command]) -> bool {
+    let is_wait = |(command, _): &Command| matches!(command, VgmCommand::Wait { .. });
+    let writes_end = section.iter().rposition(|c| !is_wait(c)).map_or(0, |i| i + 1);
+    let writes_start = section[..writes_end].iter().rposition(is_wait).map_or(0, |i| i + 1);
+    let last_writes = &section[writes_start..writes_end];
+    let first_writes = &copy[..copy.iter().position(is_wait).unwrap_or(copy.len())];
+    let rewritten = |register| first_writes.iter().any(|(command, _)| register_write(command).is_some_and(|(r, _)| r == register));
+    seam.iter().all(|(command, bytes)| {
+        last_writes.iter().any(|&(_, last)| last == *bytes) || register_write(command).is_some_and(|(register, _)| rewritten(register))
+    })
+}
+
+/// For each period `p`, how many items the list ends with that repeat the
+/// `p` items before them (the Z-function of the reversed list)
+fn repeated_tails(items: &[&[u8]]) -> Vec<usize> {
+    let n = items.len();
+    let at = |i: usize| items[n - 1 - i];
+    let mut z = vec![0; n];
+    let (mut left, mut right) = (0, 0);
+    for i in 1..n {
+        let mut length = if i < right { (right - i).min(z[i - left]) } else { 0 };
+        while i + length < n && at(length) == at(i + length) {
+            length += 1;
+        }
+        if i + length > right {
+            (left, right) = (i, i + length);
+        }
+        z[i] = length;
+    }
+    z
+}
+
+/// Keep one copy of a loop section made of copies, returning the document
+/// with the header's lengths, offsets and loop modifier updated and the
+/// number of copies the loop held (None if there is nothing to compact)
+pub fn compact_document(document: &VgmDocument) -> Result<Option<(VgmDocument, u32)>> {
+    let file = [&document.header[..], &document.data].concat();
+    let mut reader = VgmReader::new(&file);
+    let header = reader.parse_header()?;
+    if header.loop_offset == 0 || document.header.len() <= offset::LOOP_MODIFIER {
+        return Ok(None);
+    }
+    let positions = reader.parse_positions(&header)?;
+    let ends = positions.iter().skip(1).map(|&(pos, _, _)| pos).chain(std::iter::once(file.len()));
+    let commands: Vec<Command> =
+        positions.iter().zip(ends).map(|((pos, _, command), end)| (command.clone(), &file[*pos..end])).collect();
+
+    let loop_position = header.loop_offset as usize + offset::LOOP_OFFSET;
+    let Some(start) = positions.iter().position(|&(pos, _, _)| pos == loop_position) else {
+        return Ok(None);
+    };
+    let end = commands.iter().position(|(command, _)| matches!(command, VgmCommand::End)).unwrap_or(commands.len());
+    let section = &commands[start..end];
+    if section.iter().any(|(command, _)| matches!(command, VgmCommand::Ym2612Dac { .. })) {
+        return Ok(None);
+    }
+
+    // Chip state at the end of the loop, the first time and the second
+    let mut end_state = Registers::default();
+    for (command, _) in &commands[..end] {
+        end_state.apply(command);
+    }
+    let mut second_end_state = end_state.clone();
+    for (command, _) in section {
+        second_end_state.apply(command);
+    }
+
+    let mut elapsed = vec![0u64];
+    for (command, _) in section {
+        elapsed.push(elapsed.last().unwrap() + samples(command));
+    }
+    let bytes: Vec<&[u8]> = section.iter().map(|&(_, bytes)| bytes).collect();
+    let tails = repeated_tails(&bytes);
+    let loops = loops_played(header.loop_base, header.loop_modifier);
+
+    // The period keeping the fewest bytes: bytes removed, end of the copy
+    // kept in front of the loop (if any), period and copies in all
+    let n = section.len();
+    let mut best: Option<(usize, usize, usize, usize, u8)> = None;
+    for period in 1..=n / 2 {
+        let copies = (period + tails[period]) / period;
+        let first = n - copies * period;
+        let length = elapsed[n] - elapsed[n - period];
+        if copies < 2 || length == 0 {
+            continue;
+        }
+        // The copies may follow one that differs, such as the first after
+        // the loop point where drivers write registers anew, or setup
+        // writes at the loop point taken with the first copy
+        let (front, total) = match elapsed[first] {
+            _ if first == 0 => (0, copies),
+            samples if samples == length => (first, copies + 1),
+            0 => (first + period, copies),
+            _ => continue,
+        };
+        // which has to play the same as the others from the state it is
+        // played again in, leaving out writes at the loop point that change
+        // nothing there
+        let seam = match front {
+            _ if front == first + period && seam_is_redundant(&section[..first], section, &section[first..front]) => first,
+            _ => 0,
+        };
+        if front > 0
+            && (second_end_state != end_state || steps(&section[seam..front], &end_state) != steps(&section[n - period..], &end_state))
+        {
+            continue;
+        }
+        // The loop plays as many copies as before, less the one in front
+        let played = (loops * total as u32) as i64 - (front > 0) as i64;
+        let modifier = (played + header.loop_base as i64) * 16 / DEFAULT_LOOPS as i64;
+        let removed: usize = bytes[front + period..].iter().map(|b| b.len()).sum();
+        if removed == 0 || !(1..=u8::MAX as i64).contains(&modifier) {
+            continue;
+        }
+        if best.is_none_or(|(best_removed, ..)| removed > best_removed) {
+            best = Some((removed, front, period, total, modifier as u8));
+        }
+    }
+    let Some((_, front, period, copies, modifier)) = best else {
+        return Ok(None);
+    };
+    let length = elapsed[n] - elapsed[n - period];
+
+    // Everything up to the end of the first copy in the loop, then the end
+    let header_size = document.header.len();
+    let loop_start = loop_position + section[..front].iter().map(|(_, b)| b.len()).sum::<usize>();
+    let kept = commands[..start + front + period].iter().chain(&commands[end..]);
+    let mut data = file[header_size..positions[0].0].to_vec();
+    data.extend(kept.flat_map(|(_, b)| b.iter().copied()));
+
+    let mut out = document.header.clone();
+    let mut put = |at: usize, value: u64| out[at..at + 4].copy_from_slice(&(value as u32).to_le_bytes());
+    let gd3_start = header_size + data.len();
+    put(offset::TOTAL_SAMPLES, header.total_samples as u64 - (elapsed[n] - elapsed[front + period]));
+    put(offset::LOOP_OFFSET, (loop_start - offset::LOOP_OFFSET) as u64);
+    put(offset::LOOP_SAMPLES, length);
+    if header.gd3_offset != 0 {
+        put(offset::GD3_OFFSET, (gd3_start - offset::GD3_OFFSET) as u64);
+    }
+    put(offset::EOF_OFFSET, (gd3_start + document.gd3.len() - offset::EOF_OFFSET) as u64);
+    out[offset::LOOP_MODIFIER] = modifier;
+    let document = VgmDocument { header: out, data, gd3: document.gd3.clone() };
+    Ok(Some((document, copies as u32)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_tails() {
+        let items: Vec<&[u8]> = vec![b"x", b"a", b"b", b"a", b"b", b"a", b"b"];
+        let tails = repeated_tails(&items);
+        // Period 2 repeats for 4 items (3 copies), period 1 for none
+        assert_eq!(tails[2], 4);
+        assert_eq!(tails[1], 0);
+        assert_eq!(tails[4], 2);
+    }
+
+    #[test]
+    fn test_steps() {
+        let write = VgmCommand::Ym3812Write { reg: 0xA0, data: 0x40 };
+        let key_on = VgmCommand::Ym3812Write { reg: 0xB0, data: 0x31 };
+        let wait = VgmCommand::Wait { samples: 100 };
+        let (w, t, k): (&[u8], &[u8], &[u8]) = (b"w", b"t", b"k");
+        let commands = vec![(write.clone(), w), (wait.clone(), t), (write, w), (wait.clone(), t), (key_on, k), (wait, t)];
+        // The second write stores the value already held
+        let played = steps(&commands, &Registers::default());
+        assert_eq!(played, [Step::Write(w), Step::Wait(200), Step::Write(k), Step::Wait(100)]);
+    }
+}
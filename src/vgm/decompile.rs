@@ -0,0 +1,319 @@
+//! VGM to MML decompiler
+//!
+//! A lossy reconstruction of channel MML from the register writes of a VGM
+//! file, for learning from existing rips. The tone channels of the first
+//! SN76489 PSG and AY-3-8910 are supported; noise is left out.
+//!
+//! Times are rounded to frames of the file's rate (60 Hz unless it says 50),
+//! and the tempo is chosen so that a 96th note lasts one frame. A note keeps
+//! the pitch and volume it starts with: volume envelopes, vibrato and other
+//! changes within a note are not reconstructed.
+
+use super::{Gd3Info, VgmCommand, VgmHeader, VgmReader};
+use crate::chips::{self, ChipOptions};
+use crate::error::Result;
+use crate::Compiler;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Note names by semitone
+const NOTE_NAMES: [&str; 12] = ["c", "c+", "d", "d+", "e", "f", "f+", "g", "g+", "a", "a+", "b"];
+
+/// Note values by length in frames (a whole note is 96 frames)
+const LENGTHS: [(u64, &str); 8] = [(96, "1"), (48, "2"), (24, "4"), (12, "8"), (6, "16"), (3, "32"), (2, "48"), (1, "96")];
+
+/// What a tone channel is playing
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Voice {
+    /// Frequency in Hz (0 = silent)
+    freq: f64,
+    /// Volume (0-15)
+    volume: u8,
+}
+
+impl Voice {
+    const SILENT: Voice = Voice { freq: 0.0, volume: 0 };
+
+    fn audible(&self) -> bool {
+        self.freq > 0.0 && self.volume > 0
+    }
+}
+
+/// A supported chip: its `#EX-` name, VGM header name and default clock
+struct ChipKind {
+    mml_name: &'static str,
+    header_name: &'static str,
+    default_clock: u32,
+}
+
+const PSG: ChipKind = ChipKind { mml_name: "PSG", header_name: "sn76489", default_clock: 3579545 };
+const AY: ChipKind = ChipKind { mml_name: "AY8910", header_name: "ay8910", default_clock: 1789750 };
+
+/// Timeline of one tone channel: (time in samples, voice) at every change
+type Timeline = Vec<(u64, Voice)>;
+
+/// Record a voice change
+fn record(timeline: &mut Timeline, time: u64, voice: Voice) {
+    let voice = if voice.audible() { voice } else { Voice::SILENT };
+    if timeline.last().map(|&(_, v)| v) != Some(voice) {
+        timeline.push((time, voice));
+    }
+}
+
+/// Timelines of the three PSG tone channels
+fn psg_timelines(commands: &[VgmCommand], clock: u32) -> Vec<Timeline> {
+    let mut period = [0u16; 3];
+    let mut atten = [15u8; 3];
+    let mut latched = 0usize;
+    let mut timelines = vec![Timeline::new(); 3];
+    let mut time = 0u64;
+
+    for command in commands {
+        match *command {
+            VgmCommand::Wait { samples } => time += samples as u64,
+            VgmCommand::Sn76489Write { data } => {
+                if data & 0x80 != 0 {
+                    latched = ((data >> 5) & 3) as usize;
+                    if latched == 3 {
+                        continue;
+                    }
+                    if data & 0x10 != 0 {
+                        atten[latched] = data & 15;
+                    } else {
+                        period[latched] = (period[latched] & 0x3F0) | (data & 15) as u16;
+                    }
+                } else if latched < 3 {
+                    period[latched] = (period[latched] & 15) | (((data & 0x3F) as u16) << 4);
+                } else {
+                    continue;
+                }
+                let ch = latched;
+                let freq = if period[ch] > 0 { clock as f64 / (32.0 * period[ch] as f64) } else { 0.0 };
+                record(&mut timelines[ch], time, Voice { freq, volume: 15 - atten[ch] });
+            }
+            _ => {}
+        }
+    }
+    timelines
+}
+
+/// Timelines of the three AY-3-8910 tone channels
+fn ay_timelines(commands: &[VgmCommand], clock: u32) -> Vec<Timeline> {
+    let mut regs = [0u8; 16];
+    let mut timelines = vec![Timeline::new(); 3];
+    let mut time = 0u64;
+
+    for command in commands {
+        match *command {
+            VgmCommand::Wait { samples } => time += samples as u64,
+            VgmCommand::Ay8910Write { reg, data } if reg < 16 => {
+                regs[reg as usize] = data;
+                for (ch, timeline) in timelines.iter_mut().enumerate() {
+                    let period = (regs[ch * 2] as u16) | (((regs[ch * 2 + 1] & 15) as u16) << 8);
+                    let tone_on = regs[7] & (1 << ch) == 0;
+                    let level = regs[8 + ch];
+                    // Envelope-controlled channels are taken as full volume
+                    let volume = if level & 0x10 != 0 { 15 } else { level & 15 };
+                    let freq = if tone_on && period > 0 { clock as f64 / (16.0 * period as f64) } else { 0.0 };
+                    record(timeline, time, Voice { freq, volume });
+                }
+            }
+            _ => {}
+        }
+    }
+    timelines
+}
+
+/// MML length of a number of frames, tying note values together
+fn length_text(mut frames: u64) -> String {
+    let mut parts = Vec::new();
+    for &(value, text) in &LENGTHS {
+        while frames >= value {
+            parts.push(text);
+            frames -= value;
+        }
+    }
+    parts.join("^")
+}
+
+/// Converts chip frequencies to the compiler's note numbers
+struct NoteMapper {
+    compiler: Compiler,
+    clock_div: i32,
+    note_bits: i32,
+    basic_octave: i32,
+    pitch_divider: f64,
+}
+
+impl NoteMapper {
+    fn new(kind: &ChipKind, clock: u32) -> Result<Self> {
+        let mut instance = chips::create_chip(kind.mml_name)?;
+        let mut options = ChipOptions::new();
+        options.set('H', clock as i32);
+        instance.chip.enable(&options);
+        let chip = &instance.chip;
+        let mut compiler = Compiler::new();
+        compiler.figure_out_note_values(chip.clock_div(), chip.note_bits());
+        Ok(Self {
+            clock_div: chip.clock_div(),
+            note_bits: chip.note_bits(),
+            basic_octave: chip.basic_octave(),
+            pitch_divider: chip.pitch_divider(),
+            compiler,
+        })
+    }
+
+    fn note(&self, freq: f64) -> i32 {
+        self.compiler
+            .nearest_note(freq, self.clock_div, self.note_bits, self.basic_octave, self.pitch_divider)
+    }
+}
+
+/// Frame grid of the output
+#[derive(Clone, Copy)]
+struct Timing {
+    /// Samples per frame
+    frame: u64,
+    /// Song length in frames
+    end: u64,
+    /// Loop point in frames
+    loop_frame: Option<u64>,
+    /// Tempo at which a 96th note is one frame
+    tempo: u64,
+}
+
+/// Write the MML of one channel
+fn channel_mml(out: &mut String, letter: char, timeline: &Timeline, mapper: &NoteMapper, timing: &Timing) {
+    let Timing { frame, end, loop_frame, tempo } = *timing;
+    // Notes by starting frame; a later change within the same frame wins.
+    // A rising volume on the same pitch starts a new note, other volume
+    // changes within a note are dropped.
+    let mut segments: Vec<(u64, Option<(i32, u8)>)> = Vec::new();
+    let mut last_volume = 0;
+    for &(time, voice) in timeline {
+        let start = (time + frame / 2) / frame;
+        let note = voice.audible().then(|| (mapper.note(voice.freq), voice.volume));
+        if segments.last().is_some_and(|&(s, _)| s == start) {
+            segments.pop();
+        }
+        let same = match (segments.last(), note) {
+            (Some(&(_, Some((prev, _)))), Some((n, v))) => prev == n && v <= last_volume,
+            (Some(&(_, None)), None) | (None, None) => true,
+            _ => false,
+        };
+        if !same {
+            segments.push((start, note));
+        }
+        last_volume = voice.volume;
+    }
+    if segments.iter().all(|&(_, note)| note.is_none()) {
+        return;
+    }
+    if segments.first().is_none_or(|&(start, _)| start > 0) {
+        segments.insert(0, (0, None));
+    }
+    if let Some(lp) = loop_frame {
+        if let Some(i) = segments.iter().rposition(|&(start, _)| start <= lp) {
+            if segments[i].0 != lp {
+                segments.insert(i + 1, (lp, segments[i].1));
+            }
+        }
+    }
+
+    let mut items = vec![format!("t{}", tempo)];
+    let (mut octave, mut volume) = (None, None);
+    for (i, &(start, note)) in segments.iter().enumerate() {
+        let stop = segments.get(i + 1).map_or(end, |&(s, _)| s);
+        if Some(start) == loop_frame {
+            items.push("L".to_string());
+        }
+        if stop <= start {
+            continue;
+        }
+        let length = length_text(stop - start);
+        match note {
+            Some((n, v)) => {
+                let mut item = String::new();
+                if volume != Some(v) {
+                    write!(item, "v{} ", v).unwrap();
+                    volume = Some(v);
+                }
+                if octave != Some(n / 12) {
+                    write!(item, "o{} ", n / 12).unwrap();
+                    octave = Some(n / 12);
+                }
+                write!(item, "{}{}", NOTE_NAMES[(n % 12) as usize], length).unwrap();
+                items.push(item);
+            }
+            None => items.push(format!("r{}", length)),
+        }
+    }
+
+    writeln!(out).unwrap();
+    for line in items.chunks(16) {
+        writeln!(out, "{} {}", letter, line.join(" ")).unwrap();
+    }
+}
+
+/// Decompile VGM data to MML
+pub fn decompile(data: &[u8]) -> Result<String> {
+    let mut reader = VgmReader::new(data);
+    let header: VgmHeader = reader.parse_header()?;
+    let gd3: Option<Gd3Info> = reader.parse_gd3(&header)?;
+    let commands = reader.parse_commands(&header)?;
+
+    let rate: u64 = if header.rate == 50 { 50 } else { 60 };
+    let frame = 44100 / rate;
+    let to_frames = |samples: u32| (samples as u64 + frame / 2) / frame;
+    let timing = Timing {
+        frame,
+        end: to_frames(header.total_samples),
+        loop_frame: (header.loop_samples > 0)
+            .then(|| to_frames(header.total_samples.saturating_sub(header.loop_samples))),
+        tempo: rate * 5 / 2,
+    };
+
+    let mut out = String::new();
+    writeln!(out, "; Decompiled by vgmck (lossy: notes, lengths and volumes only)").unwrap();
+    if let Some(gd3) = &gd3 {
+        for (directive, text) in [("TITLE", &gd3.title), ("GAME", &gd3.game), ("COMPOSER", &gd3.composer)] {
+            if !text.is_empty() {
+                writeln!(out, "#{} {}", directive, text).unwrap();
+            }
+        }
+    }
+    writeln!(out, "#RATE {}", rate).unwrap();
+
+    let mut letters = 'A'..='Z';
+    let mut body = String::new();
+    for kind in [PSG, AY] {
+        let Some(info) = header.chips.get(kind.header_name) else {
+            continue;
+        };
+        let timelines = match kind.header_name {
+            "sn76489" => psg_timelines(&commands, info.clock),
+            _ => ay_timelines(&commands, info.clock),
+        };
+        let mapper = NoteMapper::new(&kind, info.clock)?;
+
+        let channels: String = letters.by_ref().take(timelines.len()).collect();
+        write!(out, "#EX-{} {}", kind.mml_name, channels).unwrap();
+        if info.clock != kind.default_clock {
+            write!(out, " H={}", info.clock).unwrap();
+        }
+        writeln!(out).unwrap();
+
+        for (letter, timeline) in channels.chars().zip(&timelines) {
+            channel_mml(&mut body, letter, timeline, &mapper, &timing);
+        }
+    }
+    out.push_str(&body);
+    Ok(out)
+}
+
+/// Decompile a VGM or VGZ file to an MML file
+pub fn decompile_file(vgm_path: &Path, out_path: &Path) -> Result<()> {
+    let data = super::read_file(vgm_path)?;
+    std::fs::write(out_path, decompile(&data)?)?;
+    Ok(())
+}
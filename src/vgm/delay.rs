@@ -14,51 +14,46 @@ pub mod cmd {
     pub const WAIT_N_BASE: u8 = 0x70;
 }
 
+/// Longest wait of one 0x61 command
+const MAX_WAIT_NNNN: u64 = 0xFFFF;
+
+/// The one-byte waits, longest first: samples and command
+fn short_waits() -> impl Iterator<Item = (u64, u8)> + Clone {
+    let frames = [(882, cmd::WAIT_50TH), (735, cmd::WAIT_60TH)];
+    frames.into_iter().chain((1..=16).rev().map(|n| (n, cmd::WAIT_N_BASE + n as u8 - 1)))
+}
+
 /// Generate optimal delay commands for a given duration
 ///
+/// A delay is written as 0x61 waits (three bytes, up to 65535 samples
+/// each) followed by at most two one-byte waits (0x62, 0x63 and
+/// 0x70-0x7F), in whichever combination takes the fewest bytes: 1470
+/// samples is 0x62 0x62, and 66270 is a 0x61 wait and 0x63. Three
+/// one-byte waits are never shorter than one more 0x61.
+///
 /// Returns a vector of bytes representing the VGM commands
-pub fn generate_delay(mut duration: u64) -> Vec<u8> {
-    let mut commands = Vec::new();
+pub fn generate_delay(duration: u64) -> Vec<u8> {
+    // One-byte waits to end with, by the samples they add up to
+    let singles = short_waits().map(|(samples, command)| (samples, [Some(command), None]));
+    let pairs = short_waits().flat_map(|a| {
+        short_waits().filter(move |b| b.0 <= a.0).map(move |b| (a.0 + b.0, [Some(a.1), Some(b.1)]))
+    });
+    let tails = std::iter::once((0, [None, None])).chain(singles).chain(pairs);
 
-    while duration > 0 {
-        if (735..=751).contains(&duration)
-            || duration == 1470
-            || duration == 1617
-            || (65536..=67152).contains(&duration)
-        {
-            // Use 1/60 second wait (735 samples)
-            commands.push(cmd::WAIT_60TH);
-            duration -= 735;
-        } else if (882..=898).contains(&duration)
-            || duration == 1764
-            || (67153..=67299).contains(&duration)
-        {
-            // Use 1/50 second wait (882 samples)
-            commands.push(cmd::WAIT_50TH);
-            duration -= 882;
-        } else if duration <= 16 {
-            // Use short wait (1-16 samples)
-            commands.push(cmd::WAIT_N_BASE + (duration as u8) - 1);
-            break;
-        } else if duration <= 32 {
-            // Use max short wait (16 samples)
-            commands.push(cmd::WAIT_N_BASE + 15);
-            duration -= 16;
-        } else if duration <= 65535 {
-            // Use 16-bit wait
-            commands.push(cmd::WAIT_NNNN);
-            commands.push((duration & 0xFF) as u8);
-            commands.push(((duration >> 8) & 0xFF) as u8);
-            break;
-        } else {
-            // Use max 16-bit wait
-            commands.push(cmd::WAIT_NNNN);
-            commands.push(0xFF);
-            commands.push(0xFF);
-            duration -= 65535;
-        }
-    }
+    let (mut rest, tail) = tails
+        .filter(|(samples, _)| *samples <= duration)
+        .map(|(samples, tail)| (duration - samples, tail))
+        .min_by_key(|(rest, tail)| 3 * rest.div_ceil(MAX_WAIT_NNNN) + tail.iter().flatten().count() as u64)
+        .unwrap_or_default();
 
+    let mut commands = Vec::new();
+    while rest > 0 {
+        let wait = rest.min(MAX_WAIT_NNNN);
+        commands.push(cmd::WAIT_NNNN);
+        commands.extend_from_slice(&(wait as u16).to_le_bytes());
+        rest -= wait;
+    }
+    commands.extend(tail.into_iter().flatten());
     commands
 }
 
@@ -89,4 +84,17 @@ mod tests {
         let cmds = generate_delay(1000);
         assert_eq!(cmds, vec![0x61, 0xE8, 0x03]); // 1000 = 0x03E8
     }
+
+    #[test]
+    fn test_frame_wait_combinations() {
+        assert!(generate_delay(0).is_empty());
+        assert_eq!(generate_delay(20), vec![0x7F, 0x73]);
+        assert_eq!(generate_delay(1470), vec![0x62, 0x62]);
+        assert_eq!(generate_delay(1617), vec![0x63, 0x62]);
+        assert_eq!(generate_delay(897), vec![0x63, 0x7E]);
+        // A frame wait saves a second 0x61 after a full one
+        assert_eq!(generate_delay(65535 + 735), vec![0x61, 0x6C, 0xFF, 0x63]);
+        assert_eq!(generate_delay(65535 * 3), vec![0x61, 0xFF, 0xFF, 0x61, 0xFF, 0xFF, 0x61, 0xFF, 0xFF]);
+        assert_eq!(generate_delay(2 * 65535 + 1764).len(), 8);
+    }
 }
@@ -0,0 +1,212 @@
+//! DRO (DOSBox raw OPL capture) import
+//!
+//! DOSBox's `captures` write the OPL register writes of a game as DRO
+//! files, with waits in milliseconds. Version 0.1 files use byte codes for
+//! the waits and chip switches; version 2.0 files hold register/value pairs,
+//! with the waits as reserved registers and the registers coded through a
+//! table. `parse` turns either into the commands VGM would hold for it, so
+//! the VGM tools read DRO files as if they were VGM.
+
+use super::header::VGM_VERSION;
+use super::reader::{ChipInfo, VgmHeader};
+use super::{VgmCommand, VgmJson};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+
+/// Start of a DRO file
+pub const MAGIC: &[u8] = b"DBRAWOPL";
+
+/// Clocks of the OPL2 and OPL3 of a PC sound card
+const YM3812_CLOCK: u32 = 3579545;
+const YMF262_CLOCK: u32 = 14318180;
+
+/// VGM opcode of a write to the second YM3812
+const YM3812_SECOND: u8 = 0xAA;
+
+/// The chips a capture was made from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hardware {
+    Opl2,
+    DualOpl2,
+    Opl3,
+}
+
+/// Turns DRO events into commands, with the waits in samples
+struct Converter {
+    hardware: Hardware,
+    commands: Vec<VgmCommand>,
+    /// Milliseconds and samples waited so far, to round each wait without drift
+    ms: u64,
+    samples: u64,
+}
+
+impl Converter {
+    fn new(hardware: Hardware) -> Self {
+        Self { hardware, commands: Vec::new(), ms: 0, samples: 0 }
+    }
+
+    fn wait(&mut self, ms: u64) {
+        self.ms += ms;
+        let samples = self.ms * 44100 / 1000 - self.samples;
+        self.samples += samples;
+        if let Some(VgmCommand::Wait { samples: last }) = self.commands.last_mut() {
+            *last += samples as u32;
+        } else {
+            self.commands.push(VgmCommand::Wait { samples: samples as u32 });
+        }
+    }
+
+    /// A write to the first chip (or OPL3 bank), or the second when `high`
+    fn write(&mut self, high: bool, reg: u8, data: u8) {
+        self.commands.push(match (self.hardware, high) {
+            (Hardware::Opl3, _) => VgmCommand::Ymf262Write { port: high as u8, reg, data },
+            (Hardware::DualOpl2, true) => VgmCommand::Unknown { opcode: YM3812_SECOND, bytes: vec![reg, data] },
+            // A lone OPL2 has no second chip; DOSBox logs its writes anyway
+            (_, _) => VgmCommand::Ym3812Write { reg, data },
+        });
+    }
+
+    fn finish(mut self) -> VgmJson {
+        self.commands.push(VgmCommand::End);
+        let (name, clock) = match self.hardware {
+            Hardware::Opl3 => ("ymf262", YMF262_CLOCK),
+            _ => ("ym3812", YM3812_CLOCK),
+        };
+        let mut header = VgmHeader { version: VGM_VERSION, total_samples: self.samples as u32, ..VgmHeader::default() };
+        let chip = ChipInfo { clock, dual: self.hardware == Hardware::DualOpl2, extra: HashMap::new() };
+        header.chips.insert(name.to_string(), chip);
+        VgmJson::new(&header, None, self.commands)
+    }
+}
+
+fn truncated() -> Error {
+    Error::VgmParse("DRO: unexpected end of data".into())
+}
+
+/// Parse a DRO file of version 0.1 or 2.0
+pub fn parse(data: &[u8]) -> Result<VgmJson> {
+    if !data.starts_with(MAGIC) || data.len() < 12 {
+        return Err(Error::VgmParse("DRO: not a DOSBox raw OPL file".into()));
+    }
+    let u16_at = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]])).ok_or_else(truncated);
+    let u32_at = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).ok_or_else(truncated);
+    match (u16_at(8)?, u16_at(10)?) {
+        (0, 1) => {
+            let length = u32_at(16)? as usize;
+            let hardware = match *data.get(20).ok_or_else(truncated)? {
+                0 => Hardware::Opl2,
+                1 => Hardware::Opl3,
+                2 => Hardware::DualOpl2,
+                other => return Err(Error::VgmParse(format!("DRO: unknown hardware type {}", other))),
+            };
+            // Early captures stored the hardware type in one byte, later ones in four
+            let start = if data.get(21..24) == Some(&[0, 0, 0]) { 24 } else { 21 };
+            let body = data.get(start..start + length).ok_or_else(truncated)?;
+            parse_v1(body, hardware)
+        }
+        (2, 0) => {
+            let pairs = u32_at(12)? as usize;
+            let hardware = match *data.get(20).ok_or_else(truncated)? {
+                0 => Hardware::Opl2,
+                1 => Hardware::DualOpl2,
+                2 => Hardware::Opl3,
+                other => return Err(Error::VgmParse(format!("DRO: unknown hardware type {}", other))),
+            };
+            let fields = data.get(21..26).ok_or_else(truncated)?;
+            let (format, compression, short_delay, long_delay, codemap_size) =
+                (fields[0], fields[1], fields[2], fields[3], fields[4] as usize);
+            if format != 0 || compression != 0 {
+                return Err(Error::VgmParse("DRO: only uncompressed, interleaved data is supported".into()));
+            }
+            let codemap = data.get(26..26 + codemap_size).ok_or_else(truncated)?;
+            let start = 26 + codemap_size;
+            let body = data.get(start..start + pairs * 2).ok_or_else(truncated)?;
+
+            let mut converter = Converter::new(hardware);
+            for pair in body.chunks(2) {
+                let (code, value) = (pair[0], pair[1]);
+                if code == short_delay {
+                    converter.wait(value as u64 + 1);
+                } else if code == long_delay {
+                    converter.wait((value as u64 + 1) * 256);
+                } else {
+                    let reg = *codemap
+                        .get((code & 0x7F) as usize)
+                        .ok_or_else(|| Error::VgmParse(format!("DRO: register code {:#04x} not in the table", code)))?;
+                    converter.write(code & 0x80 != 0, reg, value);
+                }
+            }
+            Ok(converter.finish())
+        }
+        (major, minor) => Err(Error::VgmParse(format!("DRO: unsupported version {}.{}", major, minor))),
+    }
+}
+
+/// Parse the data of a version 0.1 file
+fn parse_v1(body: &[u8], hardware: Hardware) -> Result<VgmJson> {
+    let mut converter = Converter::new(hardware);
+    let mut high = false;
+    let mut pos = 0;
+    let operands = |pos: usize, count: usize| body.get(pos + 1..pos + 1 + count).ok_or_else(truncated);
+    while let Some(&code) = body.get(pos) {
+        match code {
+            0x00 => {
+                converter.wait(operands(pos, 1)?[0] as u64 + 1);
+                pos += 1;
+            }
+            0x01 => {
+                let operands = operands(pos, 2)?;
+                converter.wait(u16::from_le_bytes([operands[0], operands[1]]) as u64 + 1);
+                pos += 2;
+            }
+            0x02 | 0x03 => high = code == 0x03,
+            // The escape writes registers 0x00-0x04, whose numbers are codes
+            0x04 => {
+                let operands = operands(pos, 2)?;
+                converter.write(high, operands[0], operands[1]);
+                pos += 2;
+            }
+            reg => {
+                converter.write(high, reg, operands(pos, 1)?[0]);
+                pos += 1;
+            }
+        }
+        pos += 1;
+    }
+    Ok(converter.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        // Version 2.0, OPL3: a write to each bank, 10 ms, then 512 ms
+        let mut dro = MAGIC.to_vec();
+        dro.extend_from_slice(&[2, 0, 0, 0, 4, 0, 0, 0, 0x0A, 0x02, 0, 0, 2, 0, 0, 0x3A, 0x3B, 2, 0xB0, 0x20]);
+        dro.extend_from_slice(&[0x00, 0x21, 0x81, 0x11, 0x3A, 9, 0x3B, 1]);
+        let json = parse(&dro).unwrap();
+        assert!(json.header.chips.contains_key("ymf262"));
+        assert!(matches!(json.commands[0], VgmCommand::Ymf262Write { port: 0, reg: 0xB0, data: 0x21 }));
+        assert!(matches!(json.commands[1], VgmCommand::Ymf262Write { port: 1, reg: 0x20, data: 0x11 }));
+        assert!(matches!(json.commands[2], VgmCommand::Wait { samples: 23020 }));
+        assert!(matches!(json.commands[3], VgmCommand::End));
+        assert_eq!(json.header.total_samples, 23020);
+
+        // Version 0.1, dual OPL2 with a one-byte hardware type
+        let mut dro = MAGIC.to_vec();
+        dro.extend_from_slice(&[0, 0, 1, 0, 1, 0, 0, 0, 11, 0, 0, 0, 2]);
+        dro.extend_from_slice(&[0xB0, 0x21, 0x00, 0x00, 0x03, 0x04, 0x01, 0x02, 0x02, 0x20, 0x01]);
+        let json = parse(&dro).unwrap();
+        assert!(json.header.chips["ym3812"].dual);
+        assert!(matches!(json.commands[0], VgmCommand::Ym3812Write { reg: 0xB0, data: 0x21 }));
+        assert!(matches!(json.commands[1], VgmCommand::Wait { samples: 44 }));
+        assert!(matches!(json.commands[2], VgmCommand::Unknown { opcode: YM3812_SECOND, ref bytes } if bytes == &[0x01, 0x02]));
+        assert!(matches!(json.commands[3], VgmCommand::Ym3812Write { reg: 0x20, data: 0x01 }));
+        assert_eq!(json.commands.len(), 5);
+
+        assert!(parse(b"DBRAWOPL\x03\x00\x00\x00").is_err());
+        assert!(parse(b"not a capture").is_err());
+    }
+}
@@ -47,7 +47,16 @@ pub fn generate_gd3(metadata: &Gd3Metadata) -> Vec<u8> {
     write_utf16_string(&mut data, &metadata.composer_jp);
     write_utf16_string(&mut data, &metadata.date);
     write_utf16_string(&mut data, &metadata.converter);
-    write_utf16_string(&mut data, &metadata.notes);
+    if metadata.lyrics.is_empty() {
+        write_utf16_string(&mut data, &metadata.notes);
+    } else {
+        let mut notes = metadata.notes.clone();
+        if !notes.is_empty() {
+            notes.push('\n');
+        }
+        notes.push_str(&format_lrc(&metadata.lyrics));
+        write_utf16_string(&mut data, &notes);
+    }
 
     // Fill in size
     let strings_size = (data.len() - strings_start) as u32;
@@ -56,6 +65,16 @@ pub fn generate_gd3(metadata: &Gd3Metadata) -> Vec<u8> {
     data
 }
 
+/// Format timed lyrics (time in samples at 44100 Hz) as LRC lines: `[mm:ss.xx]text`
+pub fn format_lrc(lyrics: &[(u64, String)]) -> String {
+    let mut lrc = String::new();
+    for (time, text) in lyrics {
+        let centis = time * 100 / 44100;
+        lrc.push_str(&format!("[{:02}:{:02}.{:02}]{}\n", centis / 6000, centis / 100 % 60, centis % 100, text));
+    }
+    lrc
+}
+
 /// Write a UTF-16LE null-terminated string
 fn write_utf16_string(data: &mut Vec<u8>, s: &str) {
     for c in s.chars() {
@@ -98,4 +117,10 @@ mod tests {
         // U+3042 = hiragana A
         assert_eq!(data, vec![0x42, 0x30, 0x00, 0x00]);
     }
+
+    #[test]
+    fn test_format_lrc() {
+        let lyrics = vec![(0, "la".to_string()), (44100 * 61 + 22050, "li".to_string())];
+        assert_eq!(format_lrc(&lyrics), "[00:00.00]la\n[01:01.50]li\n");
+    }
 }
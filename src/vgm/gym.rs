@@ -0,0 +1,164 @@
+//! GYM (Genesis YM2612 log) import
+//!
+//! A GYM file is a log of the YM2612 and PSG writes a Mega Drive game made,
+//! one byte code per event and one wait per 1/60 s frame. Files may start
+//! with a GYMX header holding the song's tags and loop frame, and its data
+//! may be zlib-compressed. `parse` turns a file into the commands VGM would
+//! hold for it, so the VGM tools read GYM files as if they were VGM.
+
+use super::header::VGM_VERSION;
+use super::reader::{ChipInfo, Gd3Info, VgmHeader};
+use super::{VgmCommand, VgmJson};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::io::Read;
+
+/// Start of a file with a GYMX header
+pub const MAGIC: &[u8] = b"GYMX";
+
+/// Size of the GYMX header
+const HEADER_SIZE: usize = 428;
+
+/// Samples in a 1/60 s frame
+const FRAME_SAMPLES: u32 = 735;
+
+/// Clocks of an NTSC Mega Drive
+const YM2612_CLOCK: u32 = 7670453;
+const SN76489_CLOCK: u32 = 3579545;
+
+/// Noise feedback and shift register width of the Mega Drive's PSG
+const SN76489_FEEDBACK: u32 = 0x0009;
+const SN76489_SHIFT_WIDTH: u32 = 16;
+
+/// A tag of the GYMX header: Latin-1 text padded with NULs
+fn tag(field: &[u8]) -> String {
+    field.iter().take_while(|&&b| b != 0).map(|&b| b as char).collect::<String>().trim().to_string()
+}
+
+/// Append a wait, adding it to the last command if that is a wait the loop
+/// does not start at
+fn push_wait(commands: &mut Vec<VgmCommand>, samples: u32, loop_command: Option<usize>) {
+    if loop_command != Some(commands.len()) {
+        if let Some(VgmCommand::Wait { samples: last }) = commands.last_mut() {
+            *last += samples;
+            return;
+        }
+    }
+    commands.push(VgmCommand::Wait { samples });
+}
+
+/// Parse a GYM file, with or without a GYMX header
+pub fn parse(data: &[u8]) -> Result<VgmJson> {
+    let mut gd3 = None;
+    let mut loop_frame = 0;
+    let mut unpacked = Vec::new();
+    let mut body = data;
+    if data.starts_with(MAGIC) {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::VgmParse("GYM: truncated GYMX header".into()));
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        gd3 = Some(Gd3Info {
+            title: tag(&data[4..36]),
+            game: tag(&data[36..68]),
+            system: "Sega Mega Drive / Genesis".to_string(),
+            converter: tag(&data[132..164]),
+            notes: tag(&data[164..420]),
+            ..Gd3Info::default()
+        });
+        loop_frame = u32_at(420);
+        body = &data[HEADER_SIZE..];
+        if u32_at(424) != 0 {
+            flate2::read::ZlibDecoder::new(body)
+                .read_to_end(&mut unpacked)
+                .map_err(|e| Error::VgmParse(format!("GYM: bad compressed data: {}", e)))?;
+            body = &unpacked;
+        }
+    }
+
+    let mut commands = Vec::new();
+    // The loop frame counts from 1, for the start of the song
+    let mut loop_command = (loop_frame == 1).then_some(0);
+    let mut frames = 0;
+    let mut pos = 0;
+    let operands = |pos: usize, count: usize| {
+        body.get(pos + 1..pos + 1 + count).ok_or_else(|| Error::VgmParse(format!("GYM: truncated command at {:#x}", pos)))
+    };
+    while let Some(&code) = body.get(pos) {
+        match code {
+            0x00 => {
+                push_wait(&mut commands, FRAME_SAMPLES, loop_command);
+                frames += 1;
+                if loop_frame > 1 && frames == loop_frame - 1 {
+                    loop_command = Some(commands.len());
+                }
+            }
+            0x01 | 0x02 => {
+                let operands = operands(pos, 2)?;
+                commands.push(VgmCommand::Ym2612Write { port: code - 1, reg: operands[0], data: operands[1] });
+                pos += 2;
+            }
+            0x03 => {
+                commands.push(VgmCommand::Sn76489Write { data: operands(pos, 1)?[0] });
+                pos += 1;
+            }
+            _ => return Err(Error::VgmParse(format!("GYM: unknown command {:#04x} at {:#x}", code, pos))),
+        }
+        pos += 1;
+    }
+    commands.push(VgmCommand::End);
+
+    let total_samples = frames * FRAME_SAMPLES;
+    let loop_command = loop_command.filter(|_| loop_frame <= frames);
+    let mut header = VgmHeader {
+        version: VGM_VERSION,
+        total_samples,
+        loop_samples: if loop_command.is_some() { total_samples - (loop_frame - 1) * FRAME_SAMPLES } else { 0 },
+        rate: 60,
+        ..VgmHeader::default()
+    };
+    header.chips.insert("ym2612".to_string(), ChipInfo { clock: YM2612_CLOCK, dual: false, extra: HashMap::new() });
+    let extra = HashMap::from([
+        ("feedback".to_string(), SN76489_FEEDBACK),
+        ("shift_width".to_string(), SN76489_SHIFT_WIDTH),
+    ]);
+    header.chips.insert("sn76489".to_string(), ChipInfo { clock: SN76489_CLOCK, dual: false, extra });
+
+    let mut json = VgmJson::new(&header, gd3.as_ref(), commands);
+    json.header.loop_command = loop_command;
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let song = [0x01, 0x28, 0xF0, 0x00, 0x00, 0x03, 0x9F, 0x02, 0xB4, 0xC0, 0x00];
+        let json = parse(&song).unwrap();
+        assert_eq!(json.header.total_samples, 3 * 735);
+        assert_eq!(json.header.loop_command, None);
+        assert!(matches!(json.commands[0], VgmCommand::Ym2612Write { port: 0, reg: 0x28, data: 0xF0 }));
+        assert!(matches!(json.commands[1], VgmCommand::Wait { samples: 1470 }));
+        assert!(matches!(json.commands[2], VgmCommand::Sn76489Write { data: 0x9F }));
+        assert!(matches!(json.commands[3], VgmCommand::Ym2612Write { port: 1, reg: 0xB4, data: 0xC0 }));
+        assert!(matches!(json.commands[5], VgmCommand::End));
+        assert_eq!(json.header.chips["sn76489"].extra["feedback"], 9);
+
+        let mut gymx = vec![0; HEADER_SIZE];
+        gymx[..4].copy_from_slice(MAGIC);
+        gymx[4..9].copy_from_slice(b"Stage");
+        gymx[420] = 2;
+        gymx.extend_from_slice(&song);
+        let json = parse(&gymx).unwrap();
+        assert_eq!(json.gd3.unwrap().title, "Stage");
+        // Frame 2 starts after the first wait, which is not merged into the second
+        assert_eq!(json.header.loop_command, Some(2));
+        assert!(matches!(json.commands[1], VgmCommand::Wait { samples: 735 }));
+        assert_eq!(json.header.loop_samples, Some(2 * 735));
+
+        assert!(parse(&[0x01, 0x28]).is_err());
+        assert!(parse(&[0x07]).is_err());
+    }
+}
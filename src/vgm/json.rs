@@ -1,12 +1,17 @@
 //! JSON serialization types for VGM data
 
 use super::commands::VgmCommand;
-use super::reader::{ChipInfo, Gd3Info, VgmHeader};
-use serde::Serialize;
-use std::collections::HashMap;
+use super::header::offset;
+use super::reader::{ChipInfo, Gd3Info, VgmHeader, VgmReader};
+use super::writer::VgmWriter;
+use crate::compiler::Gd3Metadata;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
 
 /// Top-level JSON structure for a VGM file
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VgmJson {
     /// VGM version as a string (e.g., "1.61")
     pub version: String,
@@ -20,7 +25,7 @@ pub struct VgmJson {
 }
 
 /// JSON representation of VGM header
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VgmHeaderJson {
     /// Total samples in the file
     pub total_samples: u32,
@@ -42,21 +47,26 @@ pub struct VgmHeaderJson {
     /// Loop modifier
     #[serde(skip_serializing_if = "Option::is_none")]
     pub loop_modifier: Option<u8>,
+    /// Index in `commands` of the command the loop starts at; this, not
+    /// `loop_offset`, places the loop when the JSON is turned back into VGM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loop_command: Option<usize>,
     /// Sound chips used in this file
-    pub chips: HashMap<String, ChipJson>,
+    #[serde(default)]
+    pub chips: BTreeMap<String, ChipJson>,
 }
 
 /// JSON representation of chip information
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChipJson {
     /// Clock frequency in Hz
     pub clock: u32,
     /// Whether this is a dual-chip configuration
-    #[serde(skip_serializing_if = "is_false")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub dual: bool,
     /// Extra chip-specific parameters
-    #[serde(flatten, skip_serializing_if = "HashMap::is_empty")]
-    pub extra: HashMap<String, u32>,
+    #[serde(flatten, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, u32>,
 }
 
 fn is_false(b: &bool) -> bool {
@@ -64,7 +74,8 @@ fn is_false(b: &bool) -> bool {
 }
 
 /// JSON representation of GD3 metadata
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Gd3Json {
     /// Track title (English)
     #[serde(skip_serializing_if = "String::is_empty")]
@@ -111,6 +122,108 @@ impl VgmJson {
             commands,
         }
     }
+
+    /// Parse VGM data, recording which command the loop starts at
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = VgmReader::new(data);
+        let header = reader.parse_header()?;
+        let gd3 = reader.parse_gd3(&header)?;
+        let positions = reader.parse_positions(&header)?;
+
+        let loop_position = header.loop_offset as usize + offset::LOOP_OFFSET;
+        let loop_command = match header.loop_offset {
+            0 => None,
+            _ => positions.iter().position(|(pos, _, _)| *pos == loop_position),
+        };
+        let commands = positions.into_iter().map(|(_, _, cmd)| cmd).collect();
+        let mut json = Self::new(&header, gd3.as_ref(), commands);
+        json.header.loop_command = loop_command;
+        Ok(json)
+    }
+
+    /// Write the file back out as VGM
+    ///
+    /// Header fields other than those in the JSON (such as AY8910 flags)
+    /// are left at their defaults, and waits are re-encoded, so the bytes
+    /// can differ from the original file while playing the same.
+    pub fn write(&self, writer: &mut VgmWriter) -> Result<()> {
+        let header = &self.header;
+        writer.header_mut().write_u32(offset::VERSION, parse_version(&self.version)?);
+        for (name, chip) in &header.chips {
+            let clock_offset =
+                chip_clock_offset(name).ok_or_else(|| Error::VgmParse(format!("unknown chip '{}'", name)))?;
+            let dual = if chip.dual { 0x4000_0000 } else { 0 };
+            writer.set_chip_clock(clock_offset, chip.clock | dual);
+        }
+        if let Some(sn76489) = header.chips.get("sn76489") {
+            let extra = |name: &str| sn76489.extra.get(name).copied();
+            let out = writer.header_mut();
+            if let Some(feedback) = extra("feedback") {
+                out.write_u16(offset::SN76489_FEEDBACK, feedback as u16);
+            }
+            if let Some(width) = extra("shift_width") {
+                out.write_u8(offset::SN76489_SHIFT_WIDTH, width as u8);
+            }
+            if let Some(flags) = extra("flags") {
+                out.write_u8(offset::SN76489_FLAGS, flags as u8);
+            }
+        }
+        writer.set_total_samples(header.total_samples);
+        writer.set_loop_samples(header.loop_samples.unwrap_or(0));
+        writer.set_rate(header.rate.unwrap_or(0));
+        writer.set_volume_modifier(header.volume_modifier.unwrap_or(0));
+        writer.set_loop_base(header.loop_base.unwrap_or(0));
+        writer.set_loop_modifier(header.loop_modifier.unwrap_or(0));
+        writer.write_header()?;
+
+        for (i, command) in self.commands.iter().enumerate() {
+            if header.loop_command == Some(i) {
+                writer.mark_loop_start();
+            }
+            if matches!(command, VgmCommand::End) {
+                break;
+            }
+            writer.write_data(&command.to_bytes())?;
+        }
+
+        let gd3 = self.gd3.clone().unwrap_or_default();
+        writer.finalize(&Gd3Metadata {
+            title_en: gd3.title,
+            title_jp: gd3.title_jp,
+            game_en: gd3.game,
+            game_jp: gd3.game_jp,
+            system_en: gd3.system,
+            system_jp: gd3.system_jp,
+            composer_en: gd3.composer,
+            composer_jp: gd3.composer_jp,
+            date: gd3.date,
+            converter: gd3.converter,
+            notes: gd3.notes,
+            lyrics: Vec::new(),
+        })
+    }
+
+    /// Write the file out as VGM in memory
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut vgm = Vec::new();
+        self.write(&mut VgmWriter::from_writer(&mut vgm))?;
+        Ok(vgm)
+    }
+}
+
+/// Convert a VGM or VGZ file to pretty-printed JSON
+pub fn vgm_to_json(path: &Path) -> Result<String> {
+    let json = VgmJson::parse(&super::read_file(path)?)?;
+    serde_json::to_string_pretty(&json).map_err(|e| Error::VgmParse(e.to_string()))
+}
+
+/// Convert a JSON file made by `vgm_to_json` back to VGM (VGZ if the
+/// output name ends in .vgz)
+pub fn json_to_vgm(input: &Path, output: &Path) -> Result<()> {
+    let text = std::fs::read_to_string(input)?;
+    let json: VgmJson =
+        serde_json::from_str(&text).map_err(|e| Error::VgmParse(format!("{}: {}", input.display(), e)))?;
+    json.write(&mut VgmWriter::new(output)?)
 }
 
 impl From<&VgmHeader> for VgmHeaderJson {
@@ -153,6 +266,7 @@ impl From<&VgmHeader> for VgmHeaderJson {
             } else {
                 None
             },
+            loop_command: None,
             chips,
         }
     }
@@ -163,7 +277,7 @@ impl From<&ChipInfo> for ChipJson {
         Self {
             clock: info.clock,
             dual: info.dual,
-            extra: info.extra.clone(),
+            extra: info.extra.iter().map(|(name, &value)| (name.clone(), value)).collect(),
         }
     }
 }
@@ -186,6 +300,51 @@ impl From<&Gd3Info> for Gd3Json {
     }
 }
 
+/// Header offset of a chip's clock, by the name the reader gives the chip
+fn chip_clock_offset(name: &str) -> Option<usize> {
+    Some(match name {
+        "sn76489" => offset::SN76489_CLOCK,
+        "ym2413" => offset::YM2413_CLOCK,
+        "ym2612" => offset::YM2612_CLOCK,
+        "ym2151" => offset::YM2151_CLOCK,
+        "sega_pcm" => offset::SEGA_PCM_CLOCK,
+        "ym2203" => offset::YM2203_CLOCK,
+        "ym2608" => offset::YM2608_CLOCK,
+        "ym2610" => offset::YM2610_CLOCK,
+        "ym3812" => offset::YM3812_CLOCK,
+        "ym3526" => offset::YM3526_CLOCK,
+        "y8950" => offset::Y8950_CLOCK,
+        "ymf262" => offset::YMF262_CLOCK,
+        "ymf278b" => offset::YMF278B_CLOCK,
+        "ymf271" => offset::YMF271_CLOCK,
+        "ymz280b" => offset::YMZ280B_CLOCK,
+        "rf5c164" => offset::RF5C164_CLOCK,
+        "pwm" => offset::PWM_CLOCK,
+        "ay8910" => offset::AY8910_CLOCK,
+        "gb_dmg" => offset::GB_DMG_CLOCK,
+        "nes_apu" => offset::NES_APU_CLOCK,
+        "multi_pcm" => offset::MULTI_PCM_CLOCK,
+        "upd7759" => offset::UPD7759_CLOCK,
+        "okim6258" => offset::OKIM6258_CLOCK,
+        "k051649" => offset::K051649_CLOCK,
+        "k054539" => offset::K054539_CLOCK,
+        "huc6280" => offset::HUC6280_CLOCK,
+        "c140" => offset::C140_CLOCK,
+        "k053260" => offset::K053260_CLOCK,
+        "pokey" => offset::POKEY_CLOCK,
+        "qsound" => offset::QSOUND_CLOCK,
+        _ => return None,
+    })
+}
+
+/// Parse a version string such as "1.61" into BCD
+fn parse_version(version: &str) -> Result<u32> {
+    let parsed = version.split_once('.').and_then(|(major, minor)| {
+        Some((u32::from_str_radix(major, 16).ok()? << 8) | u32::from_str_radix(minor, 16).ok()?)
+    });
+    parsed.ok_or_else(|| Error::VgmParse(format!("invalid version '{}'", version)))
+}
+
 /// Format a BCD version number as a string
 fn format_version(version: u32) -> String {
     let major = (version >> 8) & 0xFF;
@@ -204,4 +363,11 @@ mod tests {
         assert_eq!(format_version(0x100), "1.00");
         assert_eq!(format_version(0x171), "1.71");
     }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("1.61").unwrap(), 0x161);
+        assert_eq!(parse_version(&format_version(0x150)).unwrap(), 0x150);
+        assert!(parse_version("161").is_err());
+    }
 }
@@ -1,12 +1,76 @@
+pub mod analyze;
 pub mod commands;
+pub mod compact;
+pub mod decompile;
 pub mod delay;
+pub mod dro;
 pub mod gd3;
+pub mod gym;
 pub mod header;
 pub mod json;
+pub mod nsf;
+pub mod optimizer;
+pub mod play;
+pub mod playback;
 pub mod reader;
+pub mod reglog;
+pub mod seam;
+#[cfg(feature = "render")]
+pub mod render;
 pub mod writer;
 
 pub use commands::VgmCommand;
 pub use json::VgmJson;
 pub use reader::{ChipInfo, Gd3Info, VgmHeader, VgmReader};
-pub use writer::VgmWriter;
+pub use writer::{VgmDocument, VgmWriter};
+
+/// File format written by the compiler (`--format`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Vgm,
+    /// NSF with a player for the compiled 2A03 stream (`nsf::from_vgm`)
+    Nsf,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vgm" => Ok(Self::Vgm),
+            "nsf" => Ok(Self::Nsf),
+            _ => Err(format!("unknown output format '{}' (expected vgm or nsf)", s)),
+        }
+    }
+}
+
+/// Read a VGM file, decompressing it if it is gzipped (VGZ)
+///
+/// GYM and DRO files (told apart by their magic, or a `.gym` extension for
+/// GYM files without a GYMX header) are converted to VGM, so every tool that
+/// reads VGM reads them too.
+pub fn read_file(path: &std::path::Path) -> crate::error::Result<Vec<u8>> {
+    let data = decompress(std::fs::read(path)?)?;
+    let gym = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gym"));
+    let song = if data.starts_with(dro::MAGIC) {
+        dro::parse(&data)?
+    } else if data.starts_with(gym::MAGIC) || gym {
+        gym::parse(&data)?
+    } else {
+        return Ok(data);
+    };
+    song.to_bytes()
+}
+
+/// Decompress the contents of a VGM file if they are gzipped (VGZ)
+pub fn decompress(data: Vec<u8>) -> crate::error::Result<Vec<u8>> {
+    use std::io::Read;
+
+    if !data.starts_with(&[0x1F, 0x8B]) {
+        return Ok(data);
+    }
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
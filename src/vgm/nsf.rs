@@ -0,0 +1,265 @@
+//! NSF export of 2A03 songs (`--format nsf`)
+//!
+//! The register writes of a VGM file for the NES APU alone are packed
+//! into a frame-timed stream, and a small 6502 player replays it from the
+//! NSF play routine, once per frame. The stream is bankswitched through a
+//! 4 KB window, so songs of any length fit, and loops the way the VGM
+//! does. DPCM samples, a second 2A03 and the FDS are not supported.
+//!
+//! Stream bytes, read by the player at the start of each frame until it
+//! meets a wait or a jump:
+//!
+//! | Bytes | Meaning |
+//! |-------|---------|
+//! | `$00-$17 v` | Write `v` to `$4000` + the first byte |
+//! | `$80-$FE` | Wait `n - $7F` frames (1-127) |
+//! | `$FF bank lo hi` | Continue from the stream at another bank and address |
+
+use super::header::offset;
+use super::{VgmCommand, VgmReader};
+use crate::error::{Error, Result};
+
+/// Size of an NSF bank
+const BANK_SIZE: usize = 0x1000;
+
+/// Where the player and its banks are loaded
+const LOAD_ADDRESS: u16 = 0x8000;
+/// The player's init and play routines
+const INIT_ADDRESS: u16 = 0x8000;
+const PLAY_ADDRESS: u16 = 0x8012;
+
+/// Where the stream's banks are switched in ($9000-$9FFF, bank slot 1)
+const WINDOW: u16 = 0x9000;
+
+/// Play routine period in microseconds at the NTSC and PAL frame rates
+const NTSC_SPEED: u16 = 16639;
+const PAL_SPEED: u16 = 19997;
+
+/// Stream bytes
+const WAIT: u8 = 0x7F;
+const MAX_WAIT: u32 = 0x7F;
+const JUMP: u8 = 0xFF;
+
+/// Registers of one 2A03, `$4000-$4017`
+const REGISTERS: u8 = 0x18;
+
+/// The player, assembled for `INIT_ADDRESS`
+///
+/// Zero page: `$00-$01` stream pointer, `$02` stream bank, `$03` frames
+/// left to wait.
+#[rustfmt::skip]
+const PLAYER: [u8; 0x6A] = [
+    // init: pointer to the start of bank 1, no wait
+    0xA9, 0x00,             // 8000  LDA #$00
+    0x85, 0x00,             // 8002  STA $00
+    0x85, 0x03,             // 8004  STA $03
+    0xA9, 0x90,             // 8006  LDA #$90
+    0x85, 0x01,             // 8008  STA $01
+    0xA9, 0x01,             // 800A  LDA #$01
+    0x85, 0x02,             // 800C  STA $02
+    0x8D, 0xF9, 0x5F,       // 800E  STA $5FF9
+    0x60,                   // 8011  RTS
+    // play: count down a wait, or read the stream
+    0xA5, 0x03,             // 8012  LDA $03
+    0xF0, 0x03,             // 8014  BEQ read
+    0xC6, 0x03,             // 8016  DEC $03
+    0x60,                   // 8018  RTS
+    // read: register writes until a wait or a jump
+    0x20, 0x4C, 0x80,       // 8019  JSR get
+    0xC9, 0x80,             // 801C  CMP #$80
+    0xB0, 0x0A,             // 801E  BCS special
+    0xAA,                   // 8020  TAX
+    0x20, 0x4C, 0x80,       // 8021  JSR get
+    0x9D, 0x00, 0x40,       // 8024  STA $4000,X
+    0x4C, 0x19, 0x80,       // 8027  JMP read
+    // special: $FF jumps, anything else waits
+    0xC9, 0xFF,             // 802A  CMP #$FF
+    0xF0, 0x05,             // 802C  BEQ jump
+    0x29, 0x7F,             // 802E  AND #$7F
+    0x85, 0x03,             // 8030  STA $03
+    0x60,                   // 8032  RTS
+    // jump: bank, address low, address high
+    0x20, 0x4C, 0x80,       // 8033  JSR get
+    0x48,                   // 8036  PHA
+    0x20, 0x4C, 0x80,       // 8037  JSR get
+    0x48,                   // 803A  PHA
+    0x20, 0x4C, 0x80,       // 803B  JSR get
+    0x85, 0x01,             // 803E  STA $01
+    0x68,                   // 8040  PLA
+    0x85, 0x00,             // 8041  STA $00
+    0x68,                   // 8043  PLA
+    0x85, 0x02,             // 8044  STA $02
+    0x8D, 0xF9, 0x5F,       // 8046  STA $5FF9
+    0x4C, 0x19, 0x80,       // 8049  JMP read
+    // get: the next stream byte, switching in the next bank at $A000
+    0xA0, 0x00,             // 804C  LDY #$00
+    0xB1, 0x00,             // 804E  LDA ($00),Y
+    0xE6, 0x00,             // 8050  INC $00
+    0xD0, 0x15,             // 8052  BNE done
+    0xE6, 0x01,             // 8054  INC $01
+    0x48,                   // 8056  PHA
+    0xA5, 0x01,             // 8057  LDA $01
+    0xC9, 0xA0,             // 8059  CMP #$A0
+    0xD0, 0x0B,             // 805B  BNE restore
+    0xA9, 0x90,             // 805D  LDA #$90
+    0x85, 0x01,             // 805F  STA $01
+    0xE6, 0x02,             // 8061  INC $02
+    0xA5, 0x02,             // 8063  LDA $02
+    0x8D, 0xF9, 0x5F,       // 8065  STA $5FF9
+    0x68,                   // 8068  restore: PLA
+    0x60,                   // 8069  done: RTS
+];
+
+/// Register writes and frame waits on their way to stream bytes
+#[derive(Debug, Default)]
+struct Stream {
+    bytes: Vec<u8>,
+    /// Frame the bytes written so far end at
+    frame: u64,
+}
+
+impl Stream {
+    /// Wait until a frame
+    fn wait_until(&mut self, frame: u64) {
+        while self.frame < frame {
+            let frames = (frame - self.frame).min(MAX_WAIT as u64);
+            self.bytes.push(WAIT + frames as u8);
+            self.frame += frames;
+        }
+    }
+
+    /// Continue from a stream offset
+    fn jump(&mut self, to: usize) {
+        let address = WINDOW + (to % BANK_SIZE) as u16;
+        self.bytes.push(JUMP);
+        self.bytes.push((1 + to / BANK_SIZE) as u8);
+        self.bytes.extend_from_slice(&address.to_le_bytes());
+    }
+}
+
+/// A string of an NSF header field: ASCII, cut to 31 bytes and padded with NULs
+fn header_text(text: &str) -> [u8; 32] {
+    let mut field = [0u8; 32];
+    let ascii = text.lines().next().unwrap_or("").chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' });
+    for (byte, c) in field.iter_mut().zip(ascii.take(31)) {
+        *byte = c;
+    }
+    field
+}
+
+/// Convert an uncompressed VGM file of a song for one 2A03 into an NSF
+pub fn from_vgm(vgm: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = VgmReader::new(vgm);
+    let header = reader.parse_header()?;
+    let mut others: Vec<&str> = header.chips.keys().map(String::as_str).filter(|&chip| chip != "nes_apu").collect();
+    others.sort_unstable();
+    if !others.is_empty() || !header.chips.contains_key("nes_apu") {
+        return Err(Error::Nsf(format!(
+            "the song must play on the 2A03 alone (it uses {})",
+            if others.is_empty() { "no 2A03".to_string() } else { others.join(", ") }
+        )));
+    }
+    let gd3 = reader.parse_gd3(&header)?.unwrap_or_default();
+    let positions = reader.parse_positions(&header)?;
+
+    // Frames at the song's rate, and the play routine period to match
+    let rate = match header.rate {
+        0 => 60,
+        rate => rate as u64,
+    };
+    let frame_of = |time: u64| (time * rate + 22050) / 44100;
+    let loop_position = (header.loop_offset != 0).then(|| header.loop_offset as usize + offset::LOOP_OFFSET);
+
+    let mut stream = Stream::default();
+    let mut loop_start = None;
+    let mut time = 0u64;
+    for (position, _, command) in positions {
+        if Some(position) == loop_position {
+            stream.wait_until(frame_of(time));
+            loop_start = Some((stream.bytes.len(), stream.frame));
+        }
+        match command {
+            VgmCommand::Wait { samples } => time += samples as u64,
+            VgmCommand::NesApuWrite { reg, data } if reg < REGISTERS => {
+                stream.wait_until(frame_of(time));
+                stream.bytes.extend_from_slice(&[reg, data]);
+            }
+            VgmCommand::NesApuWrite { reg, .. } if reg & 0x80 != 0 => {
+                return Err(Error::Nsf("a second 2A03 is not supported".to_string()));
+            }
+            VgmCommand::NesApuWrite { .. } => return Err(Error::Nsf("FDS registers are not supported".to_string())),
+            VgmCommand::End => break,
+            // DAC streams of `#WAV` samples, for one
+            other => return Err(Error::Nsf(format!("unsupported VGM command {:?}", other))),
+        }
+    }
+    stream.wait_until(frame_of(header.total_samples as u64));
+    match loop_start {
+        Some((start, frame)) => {
+            // A loop with no wait would keep the play routine from returning
+            if stream.frame == frame {
+                stream.wait_until(frame + 1);
+            }
+            stream.jump(start);
+        }
+        None => {
+            // Idle after the end
+            let end = stream.bytes.len();
+            stream.wait_until(stream.frame + MAX_WAIT as u64);
+            stream.jump(end);
+        }
+    }
+    // Bank numbers are bytes, and bank 0 is the player's
+    let max_bytes = 255 * BANK_SIZE;
+    if stream.bytes.len() > max_bytes {
+        return Err(Error::Nsf(format!("the song takes {} bytes, more than the {} that fit", stream.bytes.len(), max_bytes)));
+    }
+
+    // 50 Hz songs play at the PAL rate; others as NTSC, at their own rate
+    let (ntsc_speed, pal) = match rate {
+        50 => (NTSC_SPEED, true),
+        60 => (NTSC_SPEED, false),
+        rate => ((1_000_000 / rate).min(u16::MAX as u64) as u16, false),
+    };
+    let mut nsf = Vec::with_capacity(0x80 + BANK_SIZE + stream.bytes.len());
+    nsf.extend_from_slice(b"NESM\x1A");
+    nsf.push(1); // version
+    nsf.push(1); // songs
+    nsf.push(1); // first song
+    nsf.extend_from_slice(&LOAD_ADDRESS.to_le_bytes());
+    nsf.extend_from_slice(&INIT_ADDRESS.to_le_bytes());
+    nsf.extend_from_slice(&PLAY_ADDRESS.to_le_bytes());
+    let title = if gd3.title.is_empty() { &gd3.game } else { &gd3.title };
+    nsf.extend_from_slice(&header_text(title));
+    nsf.extend_from_slice(&header_text(&gd3.composer));
+    nsf.extend_from_slice(&header_text(&gd3.date));
+    nsf.extend_from_slice(&ntsc_speed.to_le_bytes());
+    // Bank 0 (the player) everywhere but the stream window
+    nsf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    nsf.extend_from_slice(&PAL_SPEED.to_le_bytes());
+    nsf.push(pal as u8);
+    nsf.extend_from_slice(&[0; 5]); // no expansion chips, reserved
+
+    nsf.extend_from_slice(&PLAYER);
+    nsf.resize(0x80 + BANK_SIZE, 0);
+    nsf.extend_from_slice(&stream.bytes);
+    Ok(nsf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream() {
+        let mut stream = Stream::default();
+        stream.bytes.extend_from_slice(&[0x15, 0x0F]);
+        stream.wait_until(200);
+        assert_eq!(stream.bytes, [0x15, 0x0F, 0xFE, 0x7F + 73]);
+        assert_eq!(stream.frame, 200);
+
+        stream.jump(BANK_SIZE + 0x123);
+        assert_eq!(&stream.bytes[4..], [0xFF, 2, 0x23, 0x91]);
+        assert_eq!(&header_text("Song\nsecond line")[..5], b"Song\0");
+    }
+}
@@ -0,0 +1,238 @@
+//! VGM command stream size optimizer (`--optimize`)
+//!
+//! Drops register writes that store the value the register already holds,
+//! whichever channel wrote it last, and joins the waits that end up next
+//! to each other into one, written in the fewest bytes. Writes that do
+//! more than store a value (key-on, envelope restarts, frequency latches,
+//! timers) are always kept, and so are all writes to chips whose registers
+//! are banked or streamed, such as the SN76489, DMG and HuC6280.
+
+use super::commands::opcode;
+use super::header::offset;
+use super::{VgmCommand, VgmDocument, VgmReader};
+use crate::error::Result;
+use std::collections::HashMap;
+
+/// A register: the opcode of its chip and port, and its number
+type Register = (u8, u16);
+
+/// The register a command stores a value in, and the value, for writes
+/// that do nothing else (None for other writes and commands)
+pub(super) fn register_write(command: &VgmCommand) -> Option<(Register, u16)> {
+    use VgmCommand::*;
+    let (register, data) = match *command {
+        GgStereo { data } => ((opcode::GG_STEREO, 0), data),
+        // Timers, key-on, DAC data and the shared frequency latch
+        Ym2612Write { port, reg, data } if !matches!((port, reg), (0, 0x24..=0x2A) | (_, 0xA0..=0xAF)) => {
+            ((opcode::YM2612_PORT0 + (port & 1), reg as u16), data)
+        }
+        // The same, plus the SSG envelope shape and the prescaler
+        Ym2203Write { reg, data } if !matches!(reg, 0x0D | 0x24..=0x28 | 0x2D..=0x2F | 0xA0..=0xAF) => {
+            ((opcode::YM2203, reg as u16), data)
+        }
+        Ym2413Write { reg, data } => ((opcode::YM2413, reg as u16), data),
+        // Timers
+        Ym3812Write { reg, data } if !(0x02..=0x04).contains(&reg) => ((opcode::YM3812, reg as u16), data),
+        Ym3526Write { reg, data } if !(0x02..=0x04).contains(&reg) => ((opcode::YM3526, reg as u16), data),
+        Ymf262Write { port, reg, data } if !(port == 0 && (0x02..=0x04).contains(&reg)) => {
+            ((opcode::YMF262_PORT0 + (port & 1), reg as u16), data)
+        }
+        // The envelope shape restarts the envelope (and banks the AY8930)
+        Ay8910Write { reg, data } if reg & 0x7F != 0x0D => ((opcode::AY8910, reg as u16), data),
+        // Only the registers that restart nothing when written
+        NesApuWrite { reg, data } if matches!(reg & 0x7F, 0x00 | 0x02 | 0x04 | 0x06 | 0x08 | 0x0A | 0x0C | 0x0E | 0x10 | 0x12 | 0x13) => {
+            ((0xB4, reg as u16), data)
+        }
+        _ => return None,
+    };
+    Some((register, data as u16))
+}
+
+/// The values plain writes have stored in the chips' registers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Registers {
+    values: HashMap<Register, u16>,
+}
+
+impl Registers {
+    /// Play a command other than a wait, returning false if it is a write
+    /// of the value the register already holds
+    pub fn apply(&mut self, command: &VgmCommand) -> bool {
+        match *command {
+            // A new envelope shape may switch the AY8930's register bank
+            VgmCommand::Ay8910Write { reg, .. } if reg & 0x7F == 0x0D => {
+                self.values.retain(|&(chip, number), _| chip != opcode::AY8910 || number & 0x80 != reg as u16 & 0x80);
+                true
+            }
+            ref command => match register_write(command) {
+                Some((register, value)) => self.values.insert(register, value) != Some(value),
+                None => true,
+            },
+        }
+    }
+}
+
+/// Optimize commands in any form, `command` giving the VGM command of each
+/// and `wait` making a wait
+fn optimize_with<T>(items: Vec<T>, command: impl Fn(&T) -> &VgmCommand, wait: impl Fn(u32) -> T) -> Vec<T> {
+    let mut registers = Registers::default();
+    let mut out: Vec<T> = Vec::with_capacity(items.len());
+    // Samples of the wait at the end of `out`, not yet written
+    let mut pending = 0u32;
+
+    for item in items {
+        match *command(&item) {
+            VgmCommand::Wait { samples } => {
+                if let Some(sum) = pending.checked_add(samples) {
+                    pending = sum;
+                } else {
+                    out.push(wait(pending));
+                    pending = samples;
+                }
+                continue;
+            }
+            ref command => {
+                if !registers.apply(command) {
+                    continue;
+                }
+            }
+        }
+        if pending > 0 {
+            out.push(wait(pending));
+            pending = 0;
+        }
+        out.push(item);
+    }
+    if pending > 0 {
+        out.push(wait(pending));
+    }
+    out
+}
+
+/// Drop redundant register writes and join adjacent waits
+///
+/// The commands are taken to play straight through, with no loop point
+/// among them. For a looping song, optimize the parts before and after the
+/// loop point separately (as `optimize_document` does), so that no write the
+/// loop depends on is dropped.
+pub fn optimize(commands: Vec<VgmCommand>) -> Vec<VgmCommand> {
+    optimize_with(commands, |command| command, |samples| VgmCommand::Wait { samples })
+}
+
+/// Optimize the command data of a VGM file, updating the header's loop,
+/// GD3 and end-of-file offsets
+///
+/// Commands that are kept are copied byte for byte; only waits are
+/// written anew.
+pub fn optimize_document(document: &VgmDocument) -> Result<VgmDocument> {
+    let file = [&document.header[..], &document.data].concat();
+    let mut reader = VgmReader::new(&file);
+    let header = reader.parse_header()?;
+    let positions = reader.parse_positions(&header)?;
+
+    // Each command with its bytes as written
+    let ends = positions.iter().skip(1).map(|&(pos, _, _)| pos).chain(std::iter::once(file.len()));
+    let commands: Vec<(VgmCommand, Vec<u8>)> = positions
+        .iter()
+        .zip(ends)
+        .map(|((pos, _, command), end)| (command.clone(), file[*pos..end].to_vec()))
+        .collect();
+
+    let loop_position = header.loop_offset as usize + offset::LOOP_OFFSET;
+    let split = match header.loop_offset {
+        0 => commands.len(),
+        _ => positions.iter().position(|&(pos, _, _)| pos == loop_position).unwrap_or(commands.len()),
+    };
+    let mut after = commands;
+    let before = after.drain(..split).collect();
+
+    let encode = |commands: Vec<(VgmCommand, Vec<u8>)>| -> Vec<u8> {
+        let wait = |samples| {
+            let command = VgmCommand::Wait { samples };
+            let bytes = command.to_bytes();
+            (command, bytes)
+        };
+        optimize_with(commands, |(command, _)| command, wait).into_iter().flat_map(|(_, bytes)| bytes).collect()
+    };
+    let mut data = encode(before);
+    let loop_start = document.header.len() + data.len();
+    data.extend(encode(after));
+
+    let mut out = document.header.clone();
+    let mut put = |at: usize, value: usize| out[at..at + 4].copy_from_slice(&(value as u32).to_le_bytes());
+    let gd3_start = document.header.len() + data.len();
+    if header.loop_offset != 0 {
+        put(offset::LOOP_OFFSET, loop_start - offset::LOOP_OFFSET);
+    }
+    if header.gd3_offset != 0 {
+        put(offset::GD3_OFFSET, gd3_start - offset::GD3_OFFSET);
+    }
+    put(offset::EOF_OFFSET, gd3_start + document.gd3.len() - offset::EOF_OFFSET);
+    Ok(VgmDocument {
+        header: out,
+        data,
+        gd3: document.gd3.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use VgmCommand::*;
+
+    #[test]
+    fn test_optimize() {
+        let commands = vec![
+            Ym2612Write { port: 0, reg: 0x40, data: 0x10 },
+            Wait { samples: 735 },
+            // The same level again, written for another note
+            Ym2612Write { port: 0, reg: 0x40, data: 0x10 },
+            Wait { samples: 735 },
+            // Key-on and the frequency latch are always written
+            Ym2612Write { port: 0, reg: 0x28, data: 0xF0 },
+            Ym2612Write { port: 0, reg: 0x28, data: 0xF0 },
+            Ym2612Write { port: 0, reg: 0xA4, data: 0x22 },
+            Ym2612Write { port: 0, reg: 0xA4, data: 0x22 },
+            // Port 1 is another register
+            Ym2612Write { port: 1, reg: 0x40, data: 0x10 },
+            Sn76489Write { data: 0x9F },
+            Sn76489Write { data: 0x9F },
+            Wait { samples: 10 },
+            Wait { samples: 20 },
+            End,
+        ];
+        let optimized = optimize(commands);
+        let text: Vec<String> = optimized.iter().map(|c| format!("{:?}", c)).collect();
+        assert_eq!(
+            text,
+            [
+                "Ym2612Write { port: 0, reg: 64, data: 16 }",
+                "Wait { samples: 1470 }",
+                "Ym2612Write { port: 0, reg: 40, data: 240 }",
+                "Ym2612Write { port: 0, reg: 40, data: 240 }",
+                "Ym2612Write { port: 0, reg: 164, data: 34 }",
+                "Ym2612Write { port: 0, reg: 164, data: 34 }",
+                "Ym2612Write { port: 1, reg: 64, data: 16 }",
+                "Sn76489Write { data: 159 }",
+                "Sn76489Write { data: 159 }",
+                "Wait { samples: 30 }",
+                "End",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_envelope_shape_resets_ay() {
+        let commands = vec![
+            Ay8910Write { reg: 0x08, data: 0x0F },
+            Ay8910Write { reg: 0x88, data: 0x0F },
+            Ay8910Write { reg: 0x0D, data: 0xB0 },
+            Ay8910Write { reg: 0x08, data: 0x0F },
+            Ay8910Write { reg: 0x88, data: 0x0F },
+        ];
+        let optimized = optimize(commands);
+        // The first chip's bank may have changed; the second chip's has not
+        assert_eq!(optimized.len(), 4);
+        assert!(matches!(optimized[3], Ay8910Write { reg: 0x08, .. }));
+    }
+}
@@ -0,0 +1,416 @@
+//! Real-time playback to register-writer hardware (`vgmck play`)
+//!
+//! The commands of a song are sent to a device as their time comes: the
+//! waits between them are slept through on the host, against the start of
+//! playback so that the timing does not drift. A device is anything that
+//! implements `RegisterSink`; `VgmStream` sends the commands in their VGM
+//! encoding, for boards that run a VGM interpreter, and `Spfm` drives the
+//! FM and SSG chips of an SPFM Light style board, one chip per slot.
+//!
+//! `play_watched` takes new versions of the song while it plays (`vgmck
+//! play --watch`). A song whose instruments changed goes on from the same
+//! time in the new version, with the registers the new version has set by
+//! then written first, so a tweaked FM patch is heard at once; any other
+//! change plays the new version from the start.
+
+use super::optimizer::register_write;
+use super::VgmCommand;
+use crate::error::{Error, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Samples per second in VGM timing
+const SAMPLE_RATE: u64 = 44100;
+
+/// SPFM Light command that resets every slot
+const SPFM_RESET: u8 = 0xFE;
+
+/// A device that takes a song's commands as they play
+pub trait RegisterSink {
+    /// Send a command (any but waits and the end)
+    fn write(&mut self, command: &VgmCommand) -> Result<()>;
+
+    /// Send out anything buffered; called before each wait
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Silence the chips; called once the song has played
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Wire format of a device (`vgmck play --protocol`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Each command in its VGM encoding
+    #[default]
+    Vgm,
+    /// SPFM Light: `slot, port << 1, register, data` for each write
+    Spfm,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vgm" => Ok(Self::Vgm),
+            "spfm" => Ok(Self::Spfm),
+            _ => Err(format!("unknown device protocol '{}' (expected vgm or spfm)", s)),
+        }
+    }
+}
+
+/// Sends each command in its VGM encoding
+pub struct VgmStream<W: Write> {
+    output: W,
+}
+
+impl<W: Write> VgmStream<W> {
+    pub fn new(output: W) -> Self {
+        Self { output }
+    }
+}
+
+impl<W: Write> RegisterSink for VgmStream<W> {
+    fn write(&mut self, command: &VgmCommand) -> Result<()> {
+        Ok(self.output.write_all(&command.to_bytes())?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.output.flush()?)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.write(&VgmCommand::End)?;
+        self.flush()
+    }
+}
+
+/// The chip, port, register and data of a write to a chip an SPFM slot holds
+fn slot_write(command: &VgmCommand) -> Option<(&'static str, u8, u8, u8)> {
+    use VgmCommand::*;
+    Some(match *command {
+        Ym2413Write { reg, data } => ("ym2413", 0, reg, data),
+        Ym2612Write { port, reg, data } => ("ym2612", port, reg, data),
+        Ym2151Write { reg, data } => ("ym2151", 0, reg, data),
+        Ym2203Write { reg, data } => ("ym2203", 0, reg, data),
+        Ym2608Write { port, reg, data } => ("ym2608", port, reg, data),
+        Ym2610Write { port, reg, data } => ("ym2610", port, reg, data),
+        Ym3812Write { reg, data } => ("ym3812", 0, reg, data),
+        Ym3526Write { reg, data } => ("ym3526", 0, reg, data),
+        Y8950Write { reg, data } => ("y8950", 0, reg, data),
+        Ymf262Write { port, reg, data } => ("ymf262", port, reg, data),
+        Ay8910Write { reg, data } if reg < 0x80 => ("ay8910", 0, reg, data),
+        _ => return None,
+    })
+}
+
+/// Drives an SPFM Light style board, with a chip in each slot
+pub struct Spfm<W: Write> {
+    output: W,
+    /// Chip in each slot, by VGM header name (`ym2612`, `ay8910`, ...)
+    slots: Vec<String>,
+}
+
+impl<W: Write> Spfm<W> {
+    pub fn new(output: W, slots: Vec<String>) -> Self {
+        Self { output, slots }
+    }
+}
+
+impl<W: Write> RegisterSink for Spfm<W> {
+    fn write(&mut self, command: &VgmCommand) -> Result<()> {
+        let Some((chip, port, reg, data)) = slot_write(command) else {
+            return Err(Error::Device(format!("SPFM: cannot send {:?}", command)));
+        };
+        let Some(slot) = self.slots.iter().position(|name| name == chip) else {
+            return Err(Error::Device(format!("SPFM: no slot holds the {}", chip)));
+        };
+        Ok(self.output.write_all(&[slot as u8, port << 1, reg, data])?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.output.flush()?)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.output.write_all(&[SPFM_RESET])?;
+        self.flush()
+    }
+}
+
+/// A new version of the song being played
+#[derive(Debug, Clone)]
+pub enum Reload {
+    /// Play the new commands from the start
+    Restart(Vec<VgmCommand>),
+    /// Go on from the same time in the new commands, which differ only in
+    /// their instruments
+    Patch(Vec<VgmCommand>),
+    /// Stop playing
+    Stop,
+}
+
+/// How often `play_watched` asks for a new version once the song has ended
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+/// Play commands to a device in real time, up to the end command, then
+/// reset it (also when a write fails)
+pub fn play(commands: &[VgmCommand], sink: &mut dyn RegisterSink) -> Result<()> {
+    let result = send(commands, 0, 0, Instant::now(), sink, &mut || None);
+    let reset = sink.reset();
+    result.and(reset).map(|_| ())
+}
+
+/// Play commands like `play`, asking `reload` for a new version of the
+/// song at each wait, and after the end until it gives one, until it
+/// returns `Reload::Stop`
+pub fn play_watched(
+    commands: Vec<VgmCommand>,
+    sink: &mut dyn RegisterSink,
+    mut reload: impl FnMut() -> Option<Reload>,
+) -> Result<()> {
+    let result = watch(commands, sink, &mut reload);
+    if result.is_err() {
+        let _ = sink.reset();
+    }
+    result
+}
+
+/// Play new versions of the song as `reload` gives them, until it stops
+fn watch(mut commands: Vec<VgmCommand>, sink: &mut dyn RegisterSink, reload: &mut dyn FnMut() -> Option<Reload>) -> Result<()> {
+    let (mut index, mut samples, mut origin) = (0, 0, Instant::now());
+    loop {
+        let next = match send(&commands, index, samples, origin, sink, reload)? {
+            Some(next) => next,
+            None => {
+                // The song has ended; play the next version from the start
+                sink.reset()?;
+                let next = loop {
+                    match reload() {
+                        Some(Reload::Patch(new)) => break Reload::Restart(new),
+                        Some(next) => break next,
+                        None => thread::sleep(IDLE_POLL),
+                    }
+                };
+                (next, 0, 0)
+            }
+        };
+        match next {
+            (Reload::Patch(new), at, time) => match position(&new, time) {
+                Some((new_index, new_samples)) => {
+                    for write in patch(&commands[..at], &new[..new_index]) {
+                        sink.write(&write)?;
+                    }
+                    commands = new;
+                    index = new_index;
+                    samples = new_samples;
+                    origin = Instant::now() - Duration::from_nanos(time * 1_000_000_000 / SAMPLE_RATE);
+                }
+                // The new version is over by then
+                None => (commands, index, samples) = (new, usize::MAX, 0),
+            },
+            (Reload::Restart(new), ..) => {
+                sink.reset()?;
+                (commands, index, samples, origin) = (new, 0, 0, Instant::now());
+            }
+            (Reload::Stop, ..) => return sink.reset(),
+        }
+    }
+}
+
+/// The index of the wait that `time` samples falls in, with the time it
+/// starts at (None if the song ends first)
+fn position(commands: &[VgmCommand], time: u64) -> Option<(usize, u64)> {
+    let mut samples = 0;
+    for (index, command) in commands.iter().enumerate() {
+        let wait = match *command {
+            VgmCommand::Wait { samples } => samples as u64,
+            VgmCommand::Ym2612Dac { wait, .. } => wait as u64,
+            VgmCommand::End => return None,
+            _ => continue,
+        };
+        if time < samples + wait {
+            return Some((index, samples));
+        }
+        samples += wait;
+    }
+    None
+}
+
+/// Writes that bring registers from their values after `old` to their
+/// values after `new`, in the order `new` last wrote them
+///
+/// Only writes that store a value (see `optimizer`) are compared; key-ons
+/// and the like stay as `old` left them.
+fn patch(old: &[VgmCommand], new: &[VgmCommand]) -> Vec<VgmCommand> {
+    let values = |commands: &[VgmCommand]| {
+        let mut last = HashMap::new();
+        for (index, command) in commands.iter().enumerate() {
+            if let Some((register, value)) = register_write(command) {
+                last.insert(register, (index, value));
+            }
+        }
+        last
+    };
+    let before = values(old);
+    let changed: BTreeMap<usize, &VgmCommand> = values(new)
+        .into_iter()
+        .filter(|(register, (_, value))| before.get(register).map(|&(_, old)| old) != Some(*value))
+        .map(|(_, (index, _))| (index, &new[index]))
+        .collect();
+    changed.into_values().cloned().collect()
+}
+
+/// Send commands from `index`, which plays `samples` into the song, as
+/// their time comes against `origin`, the start of the song, returning the
+/// new version `reload` gives with the index and time it came at (None if
+/// the song ends first)
+fn send(
+    commands: &[VgmCommand],
+    index: usize,
+    mut samples: u64,
+    origin: Instant,
+    sink: &mut dyn RegisterSink,
+    reload: &mut dyn FnMut() -> Option<Reload>,
+) -> Result<Option<(Reload, usize, u64)>> {
+    for (index, command) in commands.iter().enumerate().skip(index) {
+        let wait = match *command {
+            VgmCommand::Wait { samples } => samples,
+            VgmCommand::Ym2612Dac { wait, .. } => {
+                sink.write(command)?;
+                wait as u32
+            }
+            VgmCommand::End => break,
+            _ => {
+                sink.write(command)?;
+                continue;
+            }
+        };
+        sink.flush()?;
+        if let Some(reload) = reload() {
+            let elapsed = origin.elapsed().as_nanos() as u64 * SAMPLE_RATE / 1_000_000_000;
+            return Ok(Some((reload, index, elapsed.max(samples))));
+        }
+        samples += wait as u64;
+        let due = origin + Duration::from_nanos(samples * 1_000_000_000 / SAMPLE_RATE);
+        if let Some(left) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(left);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the time of each write
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<(Duration, VgmCommand)>,
+        start: Option<Instant>,
+        resets: usize,
+    }
+
+    impl RegisterSink for Recorder {
+        fn write(&mut self, command: &VgmCommand) -> Result<()> {
+            let start = *self.start.get_or_insert_with(Instant::now);
+            self.writes.push((start.elapsed(), command.clone()));
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Result<()> {
+            self.resets += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_play() {
+        let commands = [
+            VgmCommand::Ay8910Write { reg: 8, data: 15 },
+            VgmCommand::Wait { samples: 2205 },
+            VgmCommand::Ay8910Write { reg: 8, data: 0 },
+            VgmCommand::End,
+            VgmCommand::Ay8910Write { reg: 8, data: 15 },
+        ];
+        let mut recorder = Recorder::default();
+        play(&commands, &mut recorder).unwrap();
+        assert_eq!(recorder.writes.len(), 2);
+        assert!(recorder.writes[1].0 >= Duration::from_millis(50), "{:?}", recorder.writes[1].0);
+        assert_eq!(recorder.resets, 1);
+
+        let mut bytes = Vec::new();
+        let mut spfm = Spfm::new(&mut bytes, vec!["ym2612".to_string(), "ay8910".to_string()]);
+        spfm.write(&VgmCommand::Ym2612Write { port: 1, reg: 0x30, data: 0x71 }).unwrap();
+        spfm.write(&commands[0]).unwrap();
+        assert!(spfm.write(&VgmCommand::Sn76489Write { data: 0x9F }).is_err());
+        assert!(spfm.write(&VgmCommand::Ym2151Write { reg: 0x08, data: 0 }).is_err());
+        spfm.reset().unwrap();
+        assert_eq!(bytes, [0, 2, 0x30, 0x71, 1, 0, 8, 15, 0xFE]);
+    }
+
+    #[test]
+    fn test_play_watched() {
+        let song = |tl| {
+            vec![
+                VgmCommand::Ym2612Write { port: 0, reg: 0x40, data: tl },
+                VgmCommand::Ym2612Write { port: 0, reg: 0x28, data: 0xF0 },
+                VgmCommand::Wait { samples: 441 },
+                VgmCommand::Ym2612Write { port: 0, reg: 0x28, data: 0 },
+                VgmCommand::Wait { samples: 441 },
+                VgmCommand::End,
+            ]
+        };
+        assert_eq!(position(&song(0), 0), Some((2, 0)));
+        assert_eq!(position(&song(0), 441), Some((4, 441)));
+        assert_eq!(position(&song(0), 882), None);
+
+        // A new patch at the first wait is written without keying on again
+        let mut recorder = Recorder::default();
+        let mut calls = 0;
+        play_watched(song(0x10), &mut recorder, || {
+            calls += 1;
+            match calls {
+                1 => Some(Reload::Patch(song(0x20))),
+                2 | 3 => None,
+                _ => Some(Reload::Stop),
+            }
+        })
+        .unwrap();
+        let writes: Vec<_> = recorder.writes.iter().map(|(_, command)| format!("{:?}", command)).collect();
+        assert_eq!(
+            writes,
+            [
+                "Ym2612Write { port: 0, reg: 64, data: 16 }",
+                "Ym2612Write { port: 0, reg: 40, data: 240 }",
+                "Ym2612Write { port: 0, reg: 64, data: 32 }",
+                "Ym2612Write { port: 0, reg: 40, data: 0 }",
+            ]
+        );
+        assert_eq!(recorder.resets, 2);
+
+        // Any other change starts over
+        let mut recorder = Recorder::default();
+        let mut calls = 0;
+        play_watched(song(0x10), &mut recorder, || {
+            calls += 1;
+            match calls {
+                1 => Some(Reload::Restart(song(0x20))),
+                2 | 3 => None,
+                _ => Some(Reload::Stop),
+            }
+        })
+        .unwrap();
+        assert_eq!(recorder.writes.len(), 2 + 3);
+        assert_eq!(format!("{:?}", recorder.writes[2].1), "Ym2612Write { port: 0, reg: 64, data: 32 }");
+        assert_eq!(recorder.resets, 3);
+    }
+}
@@ -0,0 +1,130 @@
+//! Playback hints (`#LOOP-COUNT`, `#FADE-HINT`)
+//!
+//! VGM players play the loop section twice by default and then fade out.
+//! The header's loop modifier multiplies that count by N/16 and its loop
+//! base subtracts from it; a loop count is written as the modifier that
+//! gives it. There is no header field for the fade, or for looping until
+//! the player is stopped, so both go to an `.m3u` playlist written next to
+//! the file, whose `#EXTINF` length is how long players play the song.
+
+use std::str::FromStr;
+
+/// Times players play the loop section when the header does not say
+pub const DEFAULT_LOOPS: u32 = 2;
+
+/// Highest loop count a loop modifier can give
+pub const MAX_LOOP_COUNT: u32 = 255 * DEFAULT_LOOPS / 16;
+
+/// Longest fade-out `#FADE-HINT` takes, in seconds
+pub const MAX_FADE_SECONDS: f64 = 3600.0;
+
+/// How many times a song plays its loop (`#LOOP-COUNT`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopCount {
+    Times(u32),
+    /// Until the player is stopped
+    Forever,
+}
+
+impl LoopCount {
+    /// The loop modifier header value that plays the loop this many times
+    /// (0, the default, for twice and for forever)
+    pub fn loop_modifier(self) -> u8 {
+        match self {
+            Self::Times(count) if count != DEFAULT_LOOPS => (count * 16 / DEFAULT_LOOPS) as u8,
+            _ => 0,
+        }
+    }
+}
+
+impl FromStr for LoopCount {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("forever") {
+            return Ok(Self::Forever);
+        }
+        match s.parse() {
+            Ok(count) if (1..=MAX_LOOP_COUNT).contains(&count) => Ok(Self::Times(count)),
+            _ => Err(format!("expected 1 to {} times or FOREVER, got '{}'", MAX_LOOP_COUNT, s)),
+        }
+    }
+}
+
+/// Times players play the loop of a file with these header values
+pub fn loops_played(loop_base: i8, loop_modifier: u8) -> u32 {
+    let modifier = match loop_modifier {
+        0 => 16,
+        modifier => modifier as i32,
+    };
+    (DEFAULT_LOOPS as i32 * modifier / 16 - loop_base as i32).max(1) as u32
+}
+
+/// How a song is meant to be played
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlaybackHints {
+    /// Loop count (None leaves the header's loop base and modifier)
+    pub loop_count: Option<LoopCount>,
+    /// Fade-out after the last loop in samples (None leaves it to the player)
+    pub fade: Option<u64>,
+}
+
+impl PlaybackHints {
+    pub fn is_empty(&self) -> bool {
+        self.loop_count.is_none() && self.fade.is_none()
+    }
+
+    /// How long players play a song, fade included, in samples: its length,
+    /// the loop played `loops` times in all (None if it loops forever)
+    pub fn play_samples(&self, total_samples: u64, loop_samples: Option<u64>, loops: u32) -> Option<u64> {
+        let Some(loop_samples) = loop_samples else {
+            return Some(total_samples);
+        };
+        if self.loop_count == Some(LoopCount::Forever) {
+            return None;
+        }
+        let repeats = loop_samples.saturating_mul((loops.max(1) - 1) as u64);
+        Some(total_samples.saturating_add(repeats).saturating_add(self.fade.unwrap_or(0)))
+    }
+}
+
+/// An extended M3U playlist of one file, played for `samples` (None for a
+/// song that plays until it is stopped)
+pub fn m3u(file_name: &str, title: &str, samples: Option<u64>) -> String {
+    let seconds = samples.map_or(-1, |samples| samples.div_ceil(44100) as i64);
+    format!("#EXTM3U\n#EXTINF:{},{}\n{}\n", seconds, title, file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_count() {
+        for count in 1..=MAX_LOOP_COUNT {
+            let modifier = LoopCount::Times(count).loop_modifier();
+            assert_eq!(loops_played(0, modifier), count);
+        }
+        assert_eq!(LoopCount::Times(2).loop_modifier(), 0);
+        assert_eq!(LoopCount::Forever.loop_modifier(), 0);
+        assert_eq!("FOREVER".parse(), Ok(LoopCount::Forever));
+        assert_eq!("3".parse(), Ok(LoopCount::Times(3)));
+        assert!("0".parse::<LoopCount>().is_err());
+        assert!("128".parse::<LoopCount>().is_err());
+        // The loop base takes loops away
+        assert_eq!(loops_played(1, 0x30), 5);
+    }
+
+    #[test]
+    fn test_play_samples() {
+        let hints = PlaybackHints { loop_count: Some(LoopCount::Times(3)), fade: Some(44100) };
+        assert_eq!(hints.play_samples(1000, Some(400), 3), Some(1000 + 800 + 44100));
+        assert_eq!(hints.play_samples(1000, None, 3), Some(1000));
+        let forever = PlaybackHints { loop_count: Some(LoopCount::Forever), fade: None };
+        assert_eq!(forever.play_samples(1000, Some(400), 2), None);
+        let endless = PlaybackHints { loop_count: None, fade: Some(u64::MAX) };
+        assert_eq!(endless.play_samples(1000, Some(u64::MAX), 3), Some(u64::MAX));
+        assert_eq!(m3u("song.vgm", "Song", None), "#EXTM3U\n#EXTINF:-1,Song\nsong.vgm\n");
+        assert_eq!(m3u("song.vgm", "Song", Some(44101)), "#EXTM3U\n#EXTINF:2,Song\nsong.vgm\n");
+    }
+}
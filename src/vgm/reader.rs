@@ -364,6 +364,17 @@ impl<'a> VgmReader<'a> {
 
     /// Parse all VGM commands from the data section
     pub fn parse_commands(&mut self, header: &VgmHeader) -> Result<Vec<VgmCommand>> {
+        Ok(self.parse_opcodes(header)?.into_iter().map(|(_, cmd)| cmd).collect())
+    }
+
+    /// Parse all commands with the opcode each was read from (several
+    /// opcodes parse to the same command, e.g. the wait forms)
+    pub fn parse_opcodes(&mut self, header: &VgmHeader) -> Result<Vec<(u8, VgmCommand)>> {
+        Ok(self.parse_positions(header)?.into_iter().map(|(_, op, cmd)| (op, cmd)).collect())
+    }
+
+    /// Parse all commands with their file offsets and opcodes
+    pub fn parse_positions(&mut self, header: &VgmHeader) -> Result<Vec<(usize, u8, VgmCommand)>> {
         // Data starts at data_offset + 0x34
         let data_start = (header.data_offset as usize) + 0x34;
         self.seek(data_start);
@@ -371,10 +382,11 @@ impl<'a> VgmReader<'a> {
         let mut commands = Vec::new();
 
         while !self.is_eof() {
+            let (pos, op) = (self.pos, self.data[self.pos]);
             match self.parse_command()? {
                 Some(cmd) => {
                     let is_end = matches!(cmd, VgmCommand::End);
-                    commands.push(cmd);
+                    commands.push((pos, op, cmd));
                     if is_end {
                         break;
                     }
@@ -522,14 +534,16 @@ impl<'a> VgmReader<'a> {
                 let _compat = self.read_u8()?; // Should be 0x66
                 let block_type = self.read_u8()?;
                 let size = self.read_u32_le()?;
-                // Skip the data block content
                 let actual_size = (size & 0x7FFF_FFFF) as usize;
-                if self.pos + actual_size <= self.data.len() {
-                    self.pos += actual_size;
-                }
+                let data = if self.pos + actual_size <= self.data.len() {
+                    self.read_bytes(actual_size)?
+                } else {
+                    Vec::new()
+                };
                 VgmCommand::DataBlock {
                     block_type,
                     size: Some(size),
+                    data,
                 }
             }
             opcode::PCM_RAM_WRITE => {
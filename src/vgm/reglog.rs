@@ -0,0 +1,251 @@
+//! Register write log export
+//!
+//! Flattens VGM commands into a list of timestamped register writes, for
+//! people writing their own hardware playback drivers. Two formats are
+//! provided:
+//!
+//! - CSV: `time,chip,index,port,reg,value` with a header line
+//! - Binary: magic `VRLG`, record count (u32 LE), then 10-byte records of
+//!   time (u32 LE, samples at 44100 Hz), chip id (u8, VGM chip numbering),
+//!   `index << 4 | port` (u8), register (u16 LE) and value (u16 LE)
+
+use super::{VgmCommand, VgmReader};
+use crate::chips::chip_id;
+use crate::error::Result;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A single register write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterWrite {
+    /// Time in samples (44100 Hz)
+    pub time: u64,
+    /// Chip name
+    pub chip: &'static str,
+    /// Chip id (VGM header numbering)
+    pub chip_id: u8,
+    /// Chip instance (1 for the second chip of a dual setup)
+    pub index: u8,
+    /// Port (bank) on the chip
+    pub port: u8,
+    /// Register address
+    pub reg: u16,
+    /// Value written
+    pub value: u16,
+}
+
+/// Chip name, id and port of the primary chip behind a write opcode
+pub(super) fn opcode_chip(opcode: u8) -> Option<(&'static str, u8, u8)> {
+    Some(match opcode {
+        0x4F => ("SN76489", chip_id::SN76489, 1),
+        0x50 => ("SN76489", chip_id::SN76489, 0),
+        0x51 => ("YM2413", chip_id::YM2413, 0),
+        0x52 => ("YM2612", chip_id::YM2612, 0),
+        0x53 => ("YM2612", chip_id::YM2612, 1),
+        0x54 => ("YM2151", chip_id::YM2151, 0),
+        0x55 => ("YM2203", chip_id::YM2203, 0),
+        0x56 => ("YM2608", chip_id::YM2608, 0),
+        0x57 => ("YM2608", chip_id::YM2608, 1),
+        0x58 => ("YM2610", chip_id::YM2610, 0),
+        0x59 => ("YM2610", chip_id::YM2610, 1),
+        0x5A => ("YM3812", chip_id::YM3812, 0),
+        0x5B => ("YM3526", chip_id::YM3526, 0),
+        0x5C => ("Y8950", chip_id::Y8950, 0),
+        0x5D => ("YMZ280B", chip_id::YMZ280B, 0),
+        0x5E => ("YMF262", chip_id::YMF262, 0),
+        0x5F => ("YMF262", chip_id::YMF262, 1),
+        _ => return None,
+    })
+}
+
+/// Name of the chip a write opcode writes to, as in the register log,
+/// including the opcodes of second chips (None for other commands)
+pub fn opcode_chip_name(opcode: u8) -> Option<&'static str> {
+    match opcode {
+        0x30 | 0x3F => Some("SN76489"),
+        0x80..=0x8F => Some("YM2612"),
+        0xA0 => Some("AY8910"),
+        0xA1..=0xAF => opcode_chip_name(opcode - 0x50),
+        0xB3 => Some("GB_DMG"),
+        0xB4 => Some("NES_APU"),
+        0xB9 => Some("HUC6280"),
+        0xBB => Some("POKEY"),
+        0xC0 => Some("SEGAPCM"),
+        0xC4 => Some("QSOUND"),
+        0xD0 => Some("YMF278B"),
+        _ => opcode_chip(opcode).map(|(chip, _, _)| chip),
+    }
+}
+
+/// A write to the chip behind a standard write opcode
+fn opcode_write(time: u64, opcode: u8, index: u8, reg: u16, value: u16) -> Option<RegisterWrite> {
+    let (chip, chip_id, port) = opcode_chip(opcode)?;
+    Some(RegisterWrite { time, chip, chip_id, index, port, reg, value })
+}
+
+/// A write to a chip that selects the second chip with the high bit of the register
+fn high_bit_write(time: u64, chip: &'static str, chip_id: u8, reg: u8, value: u8) -> Option<RegisterWrite> {
+    Some(RegisterWrite {
+        time,
+        chip,
+        chip_id,
+        index: reg >> 7,
+        port: 0,
+        reg: (reg & 0x7F) as u16,
+        value: value as u16,
+    })
+}
+
+/// Collect the register writes of a command list, in order
+pub fn register_writes(commands: &[VgmCommand]) -> Vec<RegisterWrite> {
+    let mut writes = Vec::new();
+    let mut time = 0u64;
+
+    for command in commands {
+        let t = time;
+        let write = match *command {
+            VgmCommand::Wait { samples } => {
+                time += samples as u64;
+                None
+            }
+            VgmCommand::Ym2612Dac { wait, .. } => {
+                time += wait as u64;
+                None
+            }
+            VgmCommand::GgStereo { data } => opcode_write(t, 0x4F, 0, 0, data as u16),
+            VgmCommand::Sn76489Write { data } => opcode_write(t, 0x50, 0, 0, data as u16),
+            VgmCommand::Ym2413Write { reg, data } => opcode_write(t, 0x51, 0, reg as u16, data as u16),
+            VgmCommand::Ym2612Write { port, reg, data } => opcode_write(t, 0x52 + port, 0, reg as u16, data as u16),
+            VgmCommand::Ym2151Write { reg, data } => opcode_write(t, 0x54, 0, reg as u16, data as u16),
+            VgmCommand::Ym2203Write { reg, data } => opcode_write(t, 0x55, 0, reg as u16, data as u16),
+            VgmCommand::Ym2608Write { port, reg, data } => opcode_write(t, 0x56 + port, 0, reg as u16, data as u16),
+            VgmCommand::Ym2610Write { port, reg, data } => opcode_write(t, 0x58 + port, 0, reg as u16, data as u16),
+            VgmCommand::Ym3812Write { reg, data } => opcode_write(t, 0x5A, 0, reg as u16, data as u16),
+            VgmCommand::Ym3526Write { reg, data } => opcode_write(t, 0x5B, 0, reg as u16, data as u16),
+            VgmCommand::Y8950Write { reg, data } => opcode_write(t, 0x5C, 0, reg as u16, data as u16),
+            VgmCommand::Ymz280bWrite { reg, data } => opcode_write(t, 0x5D, 0, reg as u16, data as u16),
+            VgmCommand::Ymf262Write { port, reg, data } => opcode_write(t, 0x5E + port, 0, reg as u16, data as u16),
+            VgmCommand::Unknown { opcode, ref bytes } => match (opcode, bytes.as_slice()) {
+                // Second PSG
+                (0x30, &[data]) => opcode_write(t, 0x50, 1, 0, data as u16),
+                (0x3F, &[data]) => opcode_write(t, 0x4F, 1, 0, data as u16),
+                // Second chip of the 0x5x family
+                (0xA1..=0xAF, &[reg, data]) => opcode_write(t, opcode - 0x50, 1, reg as u16, data as u16),
+                _ => None,
+            },
+            VgmCommand::Ay8910Write { reg, data } => high_bit_write(t, "AY8910", chip_id::AY8910, reg, data),
+            VgmCommand::GbDmgWrite { reg, data } => high_bit_write(t, "GB_DMG", chip_id::GB_DMG, reg, data),
+            VgmCommand::NesApuWrite { reg, data } => high_bit_write(t, "NES_APU", chip_id::NES_APU, reg, data),
+            VgmCommand::Huc6280Write { reg, data } => high_bit_write(t, "HUC6280", chip_id::HUC6280, reg, data),
+            VgmCommand::PokeyWrite { reg, data } => high_bit_write(t, "POKEY", chip_id::POKEY, reg, data),
+            VgmCommand::QsoundWrite { reg, data } => Some(RegisterWrite {
+                time: t,
+                chip: "QSOUND",
+                chip_id: chip_id::QSOUND,
+                index: 0,
+                port: 0,
+                reg: reg as u16,
+                value: data,
+            }),
+            VgmCommand::Ymf278Write { port, reg, data } => Some(RegisterWrite {
+                time: t,
+                chip: "YMF278B",
+                chip_id: chip_id::YMF278B,
+                index: port >> 7,
+                port: port & 0x7F,
+                reg: reg as u16,
+                value: data as u16,
+            }),
+            _ => None,
+        };
+        writes.extend(write);
+    }
+
+    writes
+}
+
+/// Write a register log as CSV
+pub fn write_csv<W: Write>(writes: &[RegisterWrite], out: &mut W) -> io::Result<()> {
+    writeln!(out, "time,chip,index,port,reg,value")?;
+    for w in writes {
+        writeln!(out, "{},{},{},{},{},{}", w.time, w.chip, w.index, w.port, w.reg, w.value)?;
+    }
+    Ok(())
+}
+
+/// Write a register log in the binary format
+pub fn write_binary<W: Write>(writes: &[RegisterWrite], out: &mut W) -> io::Result<()> {
+    out.write_all(b"VRLG")?;
+    out.write_all(&(writes.len() as u32).to_le_bytes())?;
+    for w in writes {
+        out.write_all(&(w.time as u32).to_le_bytes())?;
+        out.write_all(&[w.chip_id, (w.index << 4) | w.port])?;
+        out.write_all(&w.reg.to_le_bytes())?;
+        out.write_all(&w.value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write the register log of a VGM or VGZ file; `.csv` outputs get CSV, others the binary format
+pub fn export(vgm_path: &Path, out_path: &Path) -> Result<()> {
+    let data = super::read_file(vgm_path)?;
+    let mut reader = VgmReader::new(&data);
+    let header = reader.parse_header()?;
+    let writes = register_writes(&reader.parse_commands(&header)?);
+
+    let mut out = io::BufWriter::new(std::fs::File::create(out_path)?);
+    let is_csv = out_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    if is_csv {
+        write_csv(&writes, &mut out)?;
+    } else {
+        write_binary(&writes, &mut out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_commands() -> Vec<VgmCommand> {
+        vec![
+            VgmCommand::Sn76489Write { data: 0x9F },
+            VgmCommand::Wait { samples: 735 },
+            VgmCommand::Ym2612Write { port: 1, reg: 0xA4, data: 0x22 },
+            VgmCommand::Ay8910Write { reg: 0x88, data: 15 },
+            VgmCommand::Unknown { opcode: 0x30, bytes: vec![0xBF] },
+            VgmCommand::Wait { samples: 100 },
+            VgmCommand::End,
+        ]
+    }
+
+    #[test]
+    fn test_register_writes() {
+        let writes = register_writes(&sample_commands());
+        assert_eq!(writes.len(), 4);
+        assert_eq!((writes[0].time, writes[0].chip, writes[0].value), (0, "SN76489", 0x9F));
+        assert_eq!((writes[1].time, writes[1].port, writes[1].reg), (735, 1, 0xA4));
+        assert_eq!((writes[2].chip, writes[2].index, writes[2].reg), ("AY8910", 1, 8));
+        assert_eq!((writes[3].chip, writes[3].index), ("SN76489", 1));
+    }
+
+    #[test]
+    fn test_write_formats() {
+        let writes = register_writes(&sample_commands());
+
+        let mut csv = Vec::new();
+        write_csv(&writes, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().next(), Some("time,chip,index,port,reg,value"));
+        assert_eq!(csv.lines().nth(2), Some("735,YM2612,0,1,164,34"));
+
+        let mut bin = Vec::new();
+        write_binary(&writes, &mut bin).unwrap();
+        assert_eq!(&bin[..8], b"VRLG\x04\x00\x00\x00");
+        assert_eq!(bin.len(), 8 + 4 * 10);
+        assert_eq!(&bin[18..28], &[0xDF, 0x02, 0, 0, chip_id::YM2612, 0x01, 0xA4, 0, 0x22, 0]);
+    }
+}
@@ -0,0 +1,135 @@
+//! AY-3-8910 core: three square channels, noise and the envelope generator
+
+use super::Core;
+use crate::vgm::VgmCommand;
+
+/// Master clock cycles per tick
+pub const DIVIDER: u64 = 8;
+
+/// Output level of each amplitude step, 3 dB apart, 0 being silence
+fn level(amplitude: u8) -> f32 {
+    match amplitude {
+        0 => 0.0,
+        a => 10f32.powf((a as f32 - 15.0) * 3.0 / 20.0),
+    }
+}
+
+/// The envelope generator, as set by registers 11 to 13
+#[derive(Debug, Default)]
+struct Envelope {
+    count: u32,
+    /// Step in the current ramp, 0 to 15
+    step: u8,
+    attack: bool,
+    holding: bool,
+    shape: u8,
+}
+
+impl Envelope {
+    /// Restart with a shape
+    fn start(&mut self, shape: u8) {
+        *self = Self { shape, attack: shape & 4 != 0, ..Self::default() };
+    }
+
+    fn level(&self) -> u8 {
+        if self.attack {
+            self.step
+        } else {
+            15 - self.step
+        }
+    }
+
+    /// Count one tick with a period of `period` steps of 16 master cycles
+    fn tick(&mut self, period: u16) {
+        self.count += 1;
+        if self.holding || self.count < (period.max(1) as u32) * 2 {
+            return;
+        }
+        self.count = 0;
+        if self.step < 15 {
+            self.step += 1;
+            return;
+        }
+        let (cont, alternate, hold) = (self.shape & 8 != 0, self.shape & 2 != 0, self.shape & 1 != 0);
+        if !cont {
+            // Shapes 0-7 fall to silence and stay there
+            self.attack = false;
+            self.holding = true;
+        } else if hold {
+            self.attack ^= alternate;
+            self.holding = true;
+        } else {
+            self.step = 0;
+            self.attack ^= alternate;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Ay8910 {
+    registers: [u8; 16],
+    counts: [u16; 3],
+    high: [bool; 3],
+    noise_count: u16,
+    lfsr: u32,
+    envelope: Envelope,
+}
+
+impl Ay8910 {
+    fn tone_period(&self, channel: usize) -> u16 {
+        (self.registers[channel * 2] as u16 | ((self.registers[channel * 2 + 1] & 0x0F) as u16) << 8).max(1)
+    }
+}
+
+impl Core for Ay8910 {
+    fn write(&mut self, command: &VgmCommand) -> bool {
+        let (reg, data) = match *command {
+            // Registers of a second chip have bit 7 set
+            VgmCommand::Ay8910Write { reg, data } if reg < 16 => (reg as usize, data),
+            _ => return false,
+        };
+        self.registers[reg] = data;
+        if reg == 13 {
+            self.envelope.start(data & 0x0F);
+        }
+        true
+    }
+
+    fn run(&mut self, ticks: u32) -> f32 {
+        if self.lfsr == 0 {
+            self.lfsr = 1;
+        }
+        let mixer = self.registers[7];
+        let envelope_period = self.registers[11] as u16 | (self.registers[12] as u16) << 8;
+        let noise_period = ((self.registers[6] & 0x1F) as u16).max(1) * 2;
+        let mut sum = 0.0;
+        for _ in 0..ticks {
+            for channel in 0..3 {
+                self.counts[channel] += 1;
+                if self.counts[channel] >= self.tone_period(channel) {
+                    self.counts[channel] = 0;
+                    self.high[channel] = !self.high[channel];
+                }
+            }
+            self.noise_count += 1;
+            if self.noise_count >= noise_period {
+                self.noise_count = 0;
+                let bit = (self.lfsr ^ self.lfsr >> 3) & 1;
+                self.lfsr = self.lfsr >> 1 | bit << 16;
+            }
+            self.envelope.tick(envelope_period);
+
+            let noise = self.lfsr & 1 != 0;
+            for channel in 0..3 {
+                let tone_on = self.high[channel] || mixer & (1 << channel) != 0;
+                let noise_on = noise || mixer & (8 << channel) != 0;
+                if tone_on && noise_on {
+                    let amplitude = self.registers[8 + channel];
+                    let step = if amplitude & 0x10 != 0 { self.envelope.level() } else { amplitude & 0x0F };
+                    sum += level(step) / 3.0;
+                }
+            }
+        }
+        sum / ticks.max(1) as f32
+    }
+}
@@ -0,0 +1,392 @@
+//! 2A03 core: two pulse channels, the triangle, noise and the DMC level
+//!
+//! Ticks are CPU cycles. The DMC plays no samples; only the level written
+//! to `$4011` is heard.
+
+use super::Core;
+use crate::vgm::VgmCommand;
+
+/// Length counter values by the index written to `$4003` and friends
+const LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16,
+    28, 32, 30,
+];
+
+/// Pulse waveforms by duty
+const DUTIES: [[u8; 8]; 4] =
+    [[0, 1, 0, 0, 0, 0, 0, 0], [0, 1, 1, 0, 0, 0, 0, 0], [0, 1, 1, 1, 1, 0, 0, 0], [1, 0, 0, 1, 1, 1, 1, 1]];
+
+/// Triangle waveform
+const TRIANGLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// Noise periods in CPU cycles (NTSC)
+const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+
+/// Frame counter steps in CPU cycles: quarter frames, the last of which
+/// also ends the sequence; half frames fall on the second and last
+const FOUR_STEP: [u32; 4] = [7457, 14913, 22371, 29829];
+const FIVE_STEP: [u32; 4] = [7457, 14913, 22371, 37281];
+
+/// The volume envelope of a pulse or noise channel
+#[derive(Debug, Default)]
+struct Envelope {
+    start: bool,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn quarter_frame(&mut self, control: u8) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = control & 0x0F;
+        } else if self.divider > 0 {
+            self.divider -= 1;
+        } else {
+            self.divider = control & 0x0F;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if control & 0x20 != 0 {
+                self.decay = 15;
+            }
+        }
+    }
+
+    fn volume(&self, control: u8) -> u8 {
+        if control & 0x10 != 0 {
+            control & 0x0F
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pulse {
+    registers: [u8; 4],
+    timer: u16,
+    step: usize,
+    length: u8,
+    envelope: Envelope,
+    sweep_divider: u8,
+    sweep_reload: bool,
+    /// Whether the sweep negates with the ones' complement (pulse 1)
+    ones_complement: bool,
+}
+
+impl Pulse {
+    fn period(&self) -> u16 {
+        self.registers[2] as u16 | ((self.registers[3] & 7) as u16) << 8
+    }
+
+    fn set_period(&mut self, period: u16) {
+        self.registers[2] = period as u8;
+        self.registers[3] = self.registers[3] & 0xF8 | (period >> 8) as u8 & 7;
+    }
+
+    fn write(&mut self, reg: usize, data: u8, enabled: bool) {
+        self.registers[reg] = data;
+        match reg {
+            1 => self.sweep_reload = true,
+            3 => {
+                if enabled {
+                    self.length = LENGTHS[(data >> 3) as usize];
+                }
+                self.step = 0;
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Period the sweep would change to
+    fn sweep_target(&self) -> u16 {
+        let period = self.period();
+        let change = period >> (self.registers[1] & 7);
+        if self.registers[1] & 8 == 0 {
+            period + change
+        } else if self.ones_complement {
+            period.saturating_sub(change + 1)
+        } else {
+            period.saturating_sub(change)
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period() < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn tick(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn half_frame(&mut self) {
+        let sweep = self.registers[1];
+        if self.sweep_divider == 0 && sweep & 0x80 != 0 && sweep & 7 != 0 && !self.muted() {
+            self.set_period(self.sweep_target());
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = sweep >> 4 & 7;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+        if self.registers[0] & 0x20 == 0 && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.muted() || DUTIES[(self.registers[0] >> 6) as usize][self.step] == 0 {
+            0
+        } else {
+            self.envelope.volume(self.registers[0])
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Triangle {
+    registers: [u8; 4],
+    timer: u16,
+    step: usize,
+    length: u8,
+    linear: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn period(&self) -> u16 {
+        self.registers[2] as u16 | ((self.registers[3] & 7) as u16) << 8
+    }
+
+    fn tick(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period();
+            // Ultrasonic periods hold the level instead of buzzing
+            if self.length > 0 && self.linear > 0 && self.period() >= 2 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear = self.registers[0] & 0x7F;
+        } else if self.linear > 0 {
+            self.linear -= 1;
+        }
+        if self.registers[0] & 0x80 == 0 {
+            self.linear_reload = false;
+        }
+    }
+
+    fn half_frame(&mut self) {
+        if self.registers[0] & 0x80 == 0 && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Noise {
+    registers: [u8; 4],
+    timer: u16,
+    lfsr: u16,
+    length: u8,
+    envelope: Envelope,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self { registers: [0; 4], timer: 0, lfsr: 1, length: 0, envelope: Envelope::default() }
+    }
+}
+
+impl Noise {
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = NOISE_PERIODS[(self.registers[2] & 0x0F) as usize] - 1;
+        let tap = if self.registers[2] & 0x80 != 0 { 6 } else { 1 };
+        let bit = (self.lfsr ^ self.lfsr >> tap) & 1;
+        self.lfsr = self.lfsr >> 1 | bit << 14;
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.lfsr & 1 != 0 {
+            0
+        } else {
+            self.envelope.volume(self.registers[0])
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NesApu {
+    pulses: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    /// DMC output level (`$4011`)
+    dmc: u8,
+    /// Channels enabled by `$4015`
+    enabled: u8,
+    five_step: bool,
+    frame_cycle: u32,
+    /// Whether this cycle is the second of an APU cycle
+    odd: bool,
+}
+
+impl Default for NesApu {
+    fn default() -> Self {
+        let mut pulses = [Pulse::default(), Pulse::default()];
+        pulses[0].ones_complement = true;
+        Self {
+            pulses,
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: 0,
+            enabled: 0,
+            five_step: false,
+            frame_cycle: 0,
+            odd: false,
+        }
+    }
+}
+
+impl NesApu {
+    fn write_register(&mut self, reg: u8, data: u8) {
+        let index = (reg & 3) as usize;
+        let enabled = |bit: u8| self.enabled & bit != 0;
+        match reg {
+            0x00..=0x03 => self.pulses[0].write(index, data, enabled(1)),
+            0x04..=0x07 => self.pulses[1].write(index, data, enabled(2)),
+            0x08..=0x0B => {
+                self.triangle.registers[index] = data;
+                if index == 3 {
+                    if enabled(4) {
+                        self.triangle.length = LENGTHS[(data >> 3) as usize];
+                    }
+                    self.triangle.linear_reload = true;
+                }
+            }
+            0x0C..=0x0F => {
+                self.noise.registers[index] = data;
+                if index == 3 {
+                    if enabled(8) {
+                        self.noise.length = LENGTHS[(data >> 3) as usize];
+                    }
+                    self.noise.envelope.start = true;
+                }
+            }
+            0x11 => self.dmc = data & 0x7F,
+            0x15 => {
+                self.enabled = data;
+                let [pulse1, pulse2] = &mut self.pulses;
+                for (bit, length) in [
+                    (1, &mut pulse1.length),
+                    (2, &mut pulse2.length),
+                    (4, &mut self.triangle.length),
+                    (8, &mut self.noise.length),
+                ] {
+                    if data & bit == 0 {
+                        *length = 0;
+                    }
+                }
+            }
+            0x17 => {
+                self.five_step = data & 0x80 != 0;
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn quarter_frame(&mut self) {
+        for pulse in &mut self.pulses {
+            pulse.envelope.quarter_frame(pulse.registers[0]);
+        }
+        self.noise.envelope.quarter_frame(self.noise.registers[0]);
+        self.triangle.quarter_frame();
+    }
+
+    fn half_frame(&mut self) {
+        for pulse in &mut self.pulses {
+            pulse.half_frame();
+        }
+        self.triangle.half_frame();
+        if self.noise.registers[0] & 0x20 == 0 && self.noise.length > 0 {
+            self.noise.length -= 1;
+        }
+    }
+
+    /// Run the frame counter for one cycle
+    fn frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let steps = if self.five_step { FIVE_STEP } else { FOUR_STEP };
+        if let Some(step) = steps.iter().position(|&cycle| cycle == self.frame_cycle) {
+            self.quarter_frame();
+            if step % 2 == 1 {
+                self.half_frame();
+            }
+            if step == 3 {
+                self.frame_cycle = 0;
+            }
+        }
+    }
+
+    /// The mix of the channels, by the 2A03's nonlinear mixer
+    fn output(&self) -> f32 {
+        let pulses = (self.pulses[0].output() + self.pulses[1].output()) as f32;
+        let pulse_out = if pulses == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulses + 100.0) };
+        let tnd = TRIANGLE[self.triangle.step] as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+        pulse_out + tnd_out
+    }
+}
+
+impl Core for NesApu {
+    fn write(&mut self, command: &VgmCommand) -> bool {
+        match *command {
+            // Registers above $4017 are the FDS's, and bit 7 is a second chip
+            VgmCommand::NesApuWrite { reg, data } if reg < 0x18 => self.write_register(reg, data),
+            _ => return false,
+        }
+        true
+    }
+
+    fn run(&mut self, ticks: u32) -> f32 {
+        let mut sum = 0.0;
+        for _ in 0..ticks {
+            self.triangle.tick();
+            self.noise.tick();
+            if self.odd {
+                for pulse in &mut self.pulses {
+                    pulse.tick();
+                }
+            }
+            self.odd = !self.odd;
+            self.frame_counter();
+            sum += self.output();
+        }
+        sum / ticks.max(1) as f32
+    }
+}
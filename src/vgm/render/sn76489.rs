@@ -0,0 +1,125 @@
+//! SN76489 core: three square channels and a noise channel
+
+use super::Core;
+use crate::vgm::VgmCommand;
+
+/// Master clock cycles per tick
+pub const DIVIDER: u64 = 16;
+
+/// Noise feedback and shift register width of the Sega VDP's PSG, for
+/// headers that leave them out
+const DEFAULT_FEEDBACK: u16 = 0x0009;
+const DEFAULT_SHIFT_WIDTH: u8 = 16;
+
+/// Output level of each attenuation step, 2 dB apart, 15 being silence
+fn level(attenuation: u8) -> f32 {
+    match attenuation {
+        15 => 0.0,
+        a => 10f32.powf(-(a as f32) / 10.0),
+    }
+}
+
+/// A square or noise counter
+#[derive(Debug, Default, Clone, Copy)]
+struct Counter {
+    count: u16,
+    high: bool,
+}
+
+impl Counter {
+    /// Count one tick, returning whether the output went high
+    fn tick(&mut self, period: u16) -> bool {
+        if self.count > 0 {
+            self.count -= 1;
+            return false;
+        }
+        self.count = period.max(1) - 1;
+        self.high = !self.high;
+        self.high
+    }
+}
+
+#[derive(Debug)]
+pub struct Sn76489 {
+    /// Tone periods, and the noise control in the fourth
+    periods: [u16; 4],
+    attenuations: [u8; 4],
+    counters: [Counter; 4],
+    /// Register selected by the last latch byte
+    latched: usize,
+    lfsr: u16,
+    feedback: u16,
+    shift_width: u8,
+}
+
+impl Sn76489 {
+    pub fn new(feedback: u16, shift_width: u8) -> Self {
+        let feedback = if feedback == 0 { DEFAULT_FEEDBACK } else { feedback };
+        let shift_width = if shift_width == 0 { DEFAULT_SHIFT_WIDTH } else { shift_width.min(16) };
+        Self {
+            periods: [0; 4],
+            attenuations: [15; 4],
+            counters: [Counter::default(); 4],
+            latched: 0,
+            lfsr: 1 << (shift_width - 1),
+            feedback,
+            shift_width,
+        }
+    }
+
+    /// Write a byte to the register selected by it or by the last latch
+    fn write_byte(&mut self, data: u8) {
+        if data & 0x80 != 0 {
+            self.latched = (data >> 4 & 7) as usize;
+        }
+        let channel = self.latched >> 1;
+        match (self.latched & 1, channel) {
+            (1, _) => self.attenuations[channel] = data & 0x0F,
+            (_, 3) => {
+                self.periods[3] = (data & 0x07) as u16;
+                self.lfsr = 1 << (self.shift_width - 1);
+            }
+            _ if data & 0x80 != 0 => self.periods[channel] = self.periods[channel] & 0x3F0 | (data & 0x0F) as u16,
+            _ => self.periods[channel] = self.periods[channel] & 0x0F | ((data & 0x3F) as u16) << 4,
+        }
+    }
+
+    /// Shift the noise register
+    fn shift(&mut self) {
+        let white = self.periods[3] & 4 != 0;
+        let bit = if white { (self.lfsr & self.feedback).count_ones() as u16 & 1 } else { self.lfsr & 1 };
+        self.lfsr = self.lfsr >> 1 | bit << (self.shift_width - 1);
+    }
+}
+
+impl Core for Sn76489 {
+    fn write(&mut self, command: &VgmCommand) -> bool {
+        match *command {
+            VgmCommand::Sn76489Write { data } => self.write_byte(data),
+            // Stereo only matters to a stereo mix
+            VgmCommand::GgStereo { .. } => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn run(&mut self, ticks: u32) -> f32 {
+        let mut sum = 0.0;
+        for _ in 0..ticks {
+            for channel in 0..3 {
+                self.counters[channel].tick(self.periods[channel]);
+            }
+            let noise_period = match self.periods[3] & 3 {
+                3 => self.periods[2],
+                rate => 0x10 << rate,
+            };
+            if self.counters[3].tick(noise_period) {
+                self.shift();
+            }
+            let tones: f32 = (0..3).filter(|&c| self.counters[c].high).map(|c| level(self.attenuations[c])).sum();
+            let noise = if self.lfsr & 1 != 0 { level(self.attenuations[3]) } else { 0.0 };
+            sum += (tones + noise) / 4.0;
+        }
+        sum / ticks.max(1) as f32
+    }
+}
@@ -0,0 +1,250 @@
+//! Loop seam check (`vgmck loop-check`)
+//!
+//! A looping song plays its loop section the first time from the chip state
+//! at the loop point and every other time from the state at the end of the
+//! file. Registers that hold different values at the two, and that the loop
+//! does not write again on its first sample, make the loop sound different
+//! after the first time: a wrong volume or instrument until the channel sets
+//! it, or a note left sounding across the seam.
+//!
+//! The check replays the register writes to the loop point and to the end
+//! and compares them. SN76489 writes are followed through their latch to
+//! the tone, volume and noise registers; other chips are compared by
+//! register address as written, so the channel registers of chips that
+//! select a channel first (HuC6280) share addresses.
+
+use super::analyze::format_time;
+use super::reglog::{register_writes, RegisterWrite};
+use super::{VgmCommand, VgmReader};
+use crate::error::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// A register: chip, chip index, port and register number
+type Register = (&'static str, u8, u8, u16);
+
+/// A register that holds another value at the end of the loop than at its
+/// start
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeamChange {
+    pub chip: &'static str,
+    /// Chip instance (1 for the second chip of a dual setup)
+    pub index: u8,
+    pub port: u8,
+    /// Register number; for the SN76489, the latch number (tone 0, volume
+    /// 0, ... noise, volume 3)
+    pub reg: u16,
+    /// Value at the loop start (None if it was not written before)
+    pub start: Option<u16>,
+    /// Value at the end of the loop
+    pub end: u16,
+    /// When the loop first writes the register, in samples from its start
+    pub first_write: u64,
+}
+
+/// What changes across the seam of a looping song
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeamReport {
+    /// Loop start in samples
+    pub loop_start: u64,
+    /// Loop length in samples
+    pub loop_samples: u64,
+    /// Registers that differ, by chip and register
+    pub changes: Vec<SeamChange>,
+}
+
+/// Register values after a list of writes
+#[derive(Debug, Clone, Default)]
+struct State {
+    values: BTreeMap<Register, u16>,
+    /// Register selected by the last SN76489 latch byte, by chip index
+    latched: [u16; 2],
+}
+
+impl State {
+    /// Store a write, returning the register it stored in
+    fn apply(&mut self, write: &RegisterWrite) -> Register {
+        if write.chip != "SN76489" || write.port != 0 {
+            let register = (write.chip, write.index, write.port, write.reg);
+            self.values.insert(register, write.value);
+            return register;
+        }
+        let data = write.value;
+        let latched = &mut self.latched[write.index as usize & 1];
+        if data & 0x80 != 0 {
+            *latched = data >> 4 & 7;
+        }
+        let register = (write.chip, write.index, 0, *latched);
+        let value = self.values.entry(register).or_default();
+        *value = match (*latched, data & 0x80 != 0) {
+            (6, _) => data & 7,
+            (reg, _) if reg & 1 == 1 => data & 0x0F,
+            (_, true) => *value & 0x3F0 | data & 0x0F,
+            (_, false) => *value & 0x0F | (data & 0x3F) << 4,
+        };
+        register
+    }
+}
+
+/// Name of a register in reports
+fn register_name(chip: &str, reg: u16) -> String {
+    match (chip, reg) {
+        ("SN76489", 6) => "noise".to_string(),
+        ("SN76489", reg) if reg & 1 == 1 => format!("volume {}", reg >> 1),
+        ("SN76489", reg) => format!("tone {}", reg >> 1),
+        _ => format!("reg 0x{:02X}", reg),
+    }
+}
+
+/// Compare the register state at the loop point, the command at
+/// `loop_index`, with the state at the end of the commands
+pub fn check(commands: &[VgmCommand], loop_index: usize) -> SeamReport {
+    let end = commands.iter().position(|c| matches!(c, VgmCommand::End)).unwrap_or(commands.len());
+    let intro = register_writes(&commands[..loop_index]);
+    let section = register_writes(&commands[loop_index..end]);
+    let samples = |commands: &[VgmCommand]| -> u64 {
+        commands
+            .iter()
+            .map(|c| match *c {
+                VgmCommand::Wait { samples } => samples as u64,
+                VgmCommand::Ym2612Dac { wait, .. } => wait as u64,
+                _ => 0,
+            })
+            .sum()
+    };
+
+    let mut state = State::default();
+    for write in &intro {
+        state.apply(write);
+    }
+    let start = state.values.clone();
+    let mut first_writes = BTreeMap::new();
+    for write in &section {
+        first_writes.entry(state.apply(write)).or_insert(write.time);
+    }
+
+    let changes = state
+        .values
+        .iter()
+        .filter(|&(register, &value)| start.get(register) != Some(&value))
+        .map(|(&(chip, index, port, reg), &end)| SeamChange {
+            chip,
+            index,
+            port,
+            reg,
+            start: start.get(&(chip, index, port, reg)).copied(),
+            end,
+            first_write: first_writes[&(chip, index, port, reg)],
+        })
+        // Writes on the first sample of the loop set the register anew
+        .filter(|change| change.first_write > 0)
+        .collect();
+    SeamReport { loop_start: samples(&commands[..loop_index]), loop_samples: samples(&commands[loop_index..end]), changes }
+}
+
+/// Check the loop seam of a VGM or VGZ file (None if it does not loop)
+pub fn check_file(path: &Path) -> Result<Option<SeamReport>> {
+    check_bytes(&super::read_file(path)?)
+}
+
+/// Check the loop seam of the contents of an uncompressed VGM file (None if
+/// it does not loop)
+pub fn check_bytes(data: &[u8]) -> Result<Option<SeamReport>> {
+    let mut reader = VgmReader::new(data);
+    let header = reader.parse_header()?;
+    if header.loop_offset == 0 {
+        return Ok(None);
+    }
+    let loop_position = header.loop_offset as usize + super::header::offset::LOOP_OFFSET;
+    let positions = reader.parse_positions(&header)?;
+    let Some(loop_index) = positions.iter().position(|&(pos, _, _)| pos == loop_position) else {
+        return Ok(None);
+    };
+    let commands: Vec<VgmCommand> = positions.into_iter().map(|(_, _, command)| command).collect();
+    Ok(Some(check(&commands, loop_index)))
+}
+
+impl fmt::Display for SeamChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut chip = self.chip.to_string();
+        if self.index > 0 {
+            chip.push_str(" #2");
+        }
+        if self.port > 0 {
+            chip = format!("{} port {}", chip, self.port);
+        }
+        let start = self.start.map_or("unset".to_string(), |value| format!("0x{:02X}", value));
+        write!(
+            f,
+            "{:<16} {:<10} {:>6} -> 0x{:02X}, written {} into the loop; ",
+            chip,
+            register_name(self.chip, self.reg),
+            start,
+            self.end,
+            format_time(self.first_write)
+        )?;
+        match self.start {
+            Some(value) => write!(f, "write 0x{:02X} at the loop point", value),
+            None => write!(f, "write it before the loop point too"),
+        }
+    }
+}
+
+impl fmt::Display for SeamReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Loop from {} to {} ({} long)",
+            format_time(self.loop_start),
+            format_time(self.loop_start + self.loop_samples),
+            format_time(self.loop_samples)
+        )?;
+        if self.changes.is_empty() {
+            return writeln!(f, "No registers change across the loop seam");
+        }
+        let count = self.changes.len();
+        let (noun, verb) = if count == 1 { ("register", "changes") } else { ("registers", "change") };
+        writeln!(f, "{} {} {} across the loop seam (loop start -> end):", count, noun, verb)?;
+        for change in &self.changes {
+            writeln!(f, "  {}", change)?;
+        }
+        writeln!(
+            f,
+            "\nUntil the loop writes them, they play with the end's values after the\nfirst time. In MML, set them again right after `L` (instrument, volume,\npanning, ...), or end the loop with the settings it starts with."
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let wait = VgmCommand::Wait { samples: 100 };
+        let commands = vec![
+            VgmCommand::Ym3812Write { reg: 0x40, data: 0x10 },
+            VgmCommand::Sn76489Write { data: 0x90 },
+            // Loop point
+            VgmCommand::Ym3812Write { reg: 0xA0, data: 0x41 },
+            wait.clone(),
+            VgmCommand::Ym3812Write { reg: 0x40, data: 0x20 },
+            VgmCommand::Ym3812Write { reg: 0xB0, data: 0x31 },
+            VgmCommand::Sn76489Write { data: 0x85 },
+            VgmCommand::Sn76489Write { data: 0x12 },
+            VgmCommand::Sn76489Write { data: 0x90 },
+            wait,
+            VgmCommand::End,
+        ];
+        let report = check(&commands, 2);
+        assert_eq!((report.loop_start, report.loop_samples), (0, 200));
+        // 0xA0 is written on the loop's first sample and volume 0 ends as
+        // it started
+        let changed: Vec<(&str, u16, Option<u16>, u16)> =
+            report.changes.iter().map(|c| (c.chip, c.reg, c.start, c.end)).collect();
+        assert_eq!(changed, [("SN76489", 0, None, 0x125), ("YM3812", 0x40, Some(0x10), 0x20), ("YM3812", 0xB0, None, 0x31)]);
+        assert_eq!(report.changes[1].first_write, 100);
+        assert!(report.to_string().contains("YM3812           reg 0x40     0x10 -> 0x20, written 0:00.002 into the loop; write 0x10 at the loop point"), "{}", report);
+    }
+}
@@ -5,30 +5,75 @@ use super::gd3;
 use super::header::{offset, VgmHeader, VGM_HEADER_SIZE};
 use crate::compiler::Gd3Metadata;
 use crate::error::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Cursor, Seek, SeekFrom, Write};
 use std::path::Path;
 
+/// A finished VGM file in its three parts, for embedders that store or
+/// send them separately
+///
+/// The offsets in `header` are file offsets, so the parts play only when
+/// put back together in order (`to_bytes`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VgmDocument {
+    /// The VGM header
+    pub header: Vec<u8>,
+    /// The command stream, ending with the end marker
+    pub data: Vec<u8>,
+    /// The GD3 tag (empty if there is none)
+    pub gd3: Vec<u8>,
+}
+
+impl VgmDocument {
+    /// The whole file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.header[..], &self.data, &self.gd3].concat()
+    }
+}
+
 /// VGM file writer
-pub struct VgmWriter {
-    file: File,
+///
+/// The file is built in memory and written to its destination (a file or
+/// any `io::Write`) when finalized.
+pub struct VgmWriter<'a> {
+    out: Box<dyn Write + 'a>,
+    /// The file being built
+    file: Cursor<Vec<u8>>,
     header: VgmHeader,
     /// Current position in data section
     data_pos: u64,
     /// Loop offset (position where loop starts)
     loop_offset: Option<u64>,
+    /// Gzip the file when finalizing (VGZ)
+    compressed: bool,
 }
 
-impl VgmWriter {
-    /// Create a new VGM writer
+impl<'a> VgmWriter<'a> {
+    /// Create a new VGM writer; a `.vgz` path is written gzip-compressed
     pub fn new(path: &Path) -> Result<Self> {
-        let file = File::create(path)?;
-        Ok(Self {
-            file,
+        let mut writer = Self::from_writer(File::create(path)?);
+        writer.compressed = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("vgz"));
+        Ok(writer)
+    }
+
+    /// Create a VGM writer that writes the finished file to `out`
+    /// (uncompressed unless `set_compressed` is called)
+    pub fn from_writer<W: Write + 'a>(out: W) -> Self {
+        Self {
+            out: Box::new(out),
+            file: Cursor::new(Vec::new()),
             header: VgmHeader::new(),
             data_pos: VGM_HEADER_SIZE as u64,
             loop_offset: None,
-        })
+            compressed: false,
+        }
+    }
+
+    /// Write the file gzip-compressed (VGZ) regardless of its extension
+    pub fn set_compressed(&mut self, compressed: bool) {
+        self.compressed = compressed;
     }
 
     /// Write the VGM header (call at start)
@@ -104,6 +149,13 @@ impl VgmWriter {
 
     /// Write GD3 tag and finalize file
     pub fn finalize(&mut self, metadata: &Gd3Metadata) -> Result<()> {
+        let document = self.finish(metadata)?;
+        self.write_document(&document)
+    }
+
+    /// Write the GD3 tag and complete the header, returning the file in
+    /// parts instead of writing it to the destination
+    pub fn finish(&mut self, metadata: &Gd3Metadata) -> Result<VgmDocument> {
         // Write end marker
         self.write_end()?;
 
@@ -132,7 +184,24 @@ impl VgmWriter {
         // Rewrite header with updated values
         self.write_header()?;
 
-        self.file.flush()?;
+        let mut data = std::mem::take(self.file.get_mut());
+        let gd3 = data.split_off(gd3_offset as usize);
+        let body = data.split_off(VGM_HEADER_SIZE);
+        Ok(VgmDocument { header: data, data: body, gd3 })
+    }
+
+    /// Write a finished file to the destination, gzipped if compressed
+    pub fn write_document(&mut self, document: &VgmDocument) -> Result<()> {
+        let data = document.to_bytes();
+        if self.compressed {
+            let mut encoder = GzEncoder::new(&mut self.out, Compression::best());
+            encoder.write_all(&data)?;
+            encoder.finish()?;
+        } else {
+            self.out.write_all(&data)?;
+        }
+
+        self.out.flush()?;
         Ok(())
     }
 
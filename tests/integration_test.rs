@@ -30,6 +30,20 @@ fn compile_and_parse(mml: &str) -> VgmJson {
     VgmJson::new(&header, gd3.as_ref(), commands)
 }
 
+/// Helper to compile MML and return the VGM file and the warnings
+fn compile_warnings(mml: &str) -> (Vec<u8>, Vec<String>) {
+    let dir = tempdir().unwrap();
+    let output_path = dir.path().join("test.vgm");
+
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new(mml), &output_path)
+        .expect("Compilation failed");
+
+    let data = std::fs::read(&output_path).expect("Failed to read output VGM");
+    (data, compiler.warnings)
+}
+
 /// Helper to compile MML from file and return parsed VGM JSON
 fn compile_file_and_parse(input_path: &Path) -> VgmJson {
     let dir = tempdir().unwrap();
@@ -68,6 +82,19 @@ where
     vgm.commands.iter().any(|c| predicate(c))
 }
 
+/// Tone periods written to the first SN76489 channel, from its latch and data bytes
+fn psg_tone_periods(vgm: &VgmJson) -> Vec<u16> {
+    let bytes: Vec<u8> = vgm
+        .commands
+        .iter()
+        .filter_map(|c| match c {
+            VgmCommand::Sn76489Write { data } => Some(*data),
+            _ => None,
+        })
+        .collect();
+    bytes.windows(2).filter(|w| w[0] & 0xF0 == 0x80).map(|w| (w[1] as u16) << 4 | (w[0] & 0x0F) as u16).collect()
+}
+
 // =============================================================================
 // SN76489 (PSG) Tests
 // =============================================================================
@@ -123,6 +150,40 @@ C o4g4
     );
 }
 
+#[test]
+fn test_psg_widen() {
+    let mml = r#"
+#EX-PSG ABC
+#PSG-WIDEN A 1
+A v15 o4c4
+"#;
+    let vgm = compile_and_parse(mml);
+
+    // Second chip is enabled in the header
+    assert!(vgm.header.chips["sn76489"].dual, "PSG should be dual");
+
+    // Original is panned left on the first chip, copy right on the second
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::GgStereo { data: 0xFE })));
+    assert!(has_command(&vgm, |c| matches!(
+        c,
+        VgmCommand::Unknown { opcode: 0x3F, bytes } if bytes[..] == [0xEF]
+    )));
+
+    // Copy writes its notes through the second chip
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Unknown { opcode: 0x30, .. })));
+
+    // A pan of -1 swaps the sides; 0 leaves both channels in the center
+    let vgm = compile_and_parse("#EX-PSG ABC\n#PSG-WIDEN A 1 -1\nA v15 o4c4\n");
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::GgStereo { data: 0xEF })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Unknown { opcode: 0x3F, bytes } if bytes[..] == [0xFE])));
+    let vgm = compile_and_parse("#EX-PSG ABC\n#PSG-WIDEN A 1 0\nA v15 o4c4\n");
+    assert!(vgm.header.chips["sn76489"].dual);
+    assert!(!has_command(&vgm, |c| matches!(c, VgmCommand::GgStereo { .. } | VgmCommand::Unknown { opcode: 0x3F, .. })));
+
+    let (_, warnings) = compile_warnings("#EX-PSG ABC\n#PSG-WIDEN A 1 64\nA v15 o4c4\n");
+    assert_eq!(warnings, ["#PSG-WIDEN: pan 64 is out of range (-1 to 1); using 1"]);
+}
+
 // =============================================================================
 // YM2413 (OPLL) Tests
 // =============================================================================
@@ -252,6 +313,29 @@ A o4c4
     );
 }
 
+#[test]
+fn test_sample_list_accent_layers() {
+    let mml = r#"
+#EX-AY8910 ABC
+@SL0 = { '48 0 '1 (5,15,0/9,8,0) 3 }
+A @SL0 o4 c4 @SA1 c4 c+4
+"#;
+    let vgm = compile_and_parse(mml);
+
+    let noise: Vec<u8> = vgm
+        .commands
+        .iter()
+        .filter_map(|c| match c {
+            VgmCommand::Ay8910Write { reg: 6, data } => Some(*data),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(noise, vec![5, 9, 3], "accent should select the second layer");
+
+    // Accented layer sets its own volume
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ay8910Write { reg: 8, data: 8 })));
+}
+
 // =============================================================================
 // NES APU (2A03) Tests
 // =============================================================================
@@ -352,6 +436,32 @@ A @1 o4c4
     );
 }
 
+#[test]
+fn test_fm_hardware_channel_conflicts() {
+    let compile = |mml: &str| {
+        let dir = tempdir().unwrap();
+        Compiler::new().compile(Cursor::new(mml), &dir.path().join("test.vgm"))
+    };
+    let conflict = |mml: &str| match compile(mml) {
+        Err(vgmck::Error::ChannelConflict(message)) => message,
+        other => panic!("expected a channel conflict, got {:?}", other.map(|_| ())),
+    };
+
+    // A supplementary channel takes FM3 away from the 12th melody channel
+    compile("#EX-OPN2 ABC,M\nA c\nM c\n").expect("supplementary channel without conflict");
+    let message = conflict("#EX-OPN2 ABCDEFGHIJKL,M\nA c\nL c\nM c\n");
+    assert!(message.contains("channel M (OPN2 group 2 channel 1)"), "{}", message);
+    assert!(message.contains("channel L (OPN2 group 1 channel 12)"), "{}", message);
+    assert!(message.ends_with("both play OPN2 FM3"), "{}", message);
+
+    // A four-op channel takes two of the 36 two-op channels
+    compile("#EX-OPL3 ABC,D\nA c\nD c\n").expect("four-op channel without conflict");
+    let two_ops = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghij";
+    let message = conflict(&format!("#EX-OPL3 {},k\nk c\ni c\n", two_ops));
+    assert!(message.contains("channel k (OPL3 group 2 channel 1)"), "{}", message);
+    assert!(message.contains("channel i (OPL3 group 1 channel 35)"), "{}", message);
+}
+
 // =============================================================================
 // HuC6280 (PC Engine) Tests
 // =============================================================================
@@ -427,6 +537,133 @@ A @v15 o4c4
     );
 }
 
+#[test]
+fn test_segapcm_samples() {
+    let dir = tempdir().unwrap();
+    std::fs::write(dir.path().join("kick.raw"), vec![0xC0u8; 300]).unwrap();
+    std::fs::write(dir.path().join("pad.raw"), vec![0x40u8; 200]).unwrap();
+    let input = dir.path().join("song.mml");
+    std::fs::write(
+        &input,
+        "#EX-SegaPCM AB\n\
+         @S0 = { \"kick.raw\" }\n\
+         @S1 = { \"pad.raw\" 100 }\n\
+         A @S0 v100 o6 c4 r4\n\
+         B @S1 P64 o6 c4\n",
+    )
+    .unwrap();
+    let vgm = compile_file_and_parse(&input);
+
+    assert!(vgm.header.chips.contains_key("sega_pcm"), "sega_pcm chip should be present");
+    // Both samples padded to whole pages: 512 + 256 bytes after the 8-byte block header
+    assert!(has_command(&vgm, |c| matches!(
+        c,
+        VgmCommand::DataBlock { block_type: 0x80, size: Some(776), .. }
+    )));
+    let writes = |addr: u16| -> Vec<u8> {
+        vgm.commands
+            .iter()
+            .filter_map(|c| match c {
+                VgmCommand::Unknown { opcode: 0xC0, bytes } if u16::from_le_bytes([bytes[0], bytes[1]]) == addr => {
+                    Some(bytes[2])
+                }
+                _ => None,
+            })
+            .collect()
+    };
+    // Channel 0: one-shot sample at 0, keyed off by the rest
+    assert_eq!(writes(0x86), vec![1, 2, 3]);
+    assert_eq!(writes(0x06), vec![1]);
+    // Channel 1: looped sample at 512 (loop at 612), panned right
+    assert_eq!(writes(0x8D), vec![2]);
+    assert_eq!(writes(0x0C), vec![100]);
+    assert_eq!(writes(0x0D), vec![2]);
+    assert_eq!(writes(0x8E), vec![1, 0, 3]);
+    assert_eq!((writes(0x0A), writes(0x0B)), (vec![63], vec![127]));
+}
+
+/// A 16-bit stereo PCM WAV file
+fn wav_file(rate: u32, frames: &[(i16, i16)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for &(l, r) in frames {
+        data.extend_from_slice(&l.to_le_bytes());
+        data.extend_from_slice(&r.to_le_bytes());
+    }
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&[16, 0, 0, 0, 1, 0, 2, 0]);
+    wav.extend_from_slice(&rate.to_le_bytes());
+    wav.extend_from_slice(&(rate * 4).to_le_bytes());
+    wav.extend_from_slice(&[4, 0, 16, 0]);
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(&data);
+    wav
+}
+
+#[test]
+fn test_wav_dac_streams() {
+    let dir = tempdir().unwrap();
+    let frames = [(0, 0), (16384, 16384), (-32768, 0)];
+    std::fs::write(dir.path().join("kick.wav"), wav_file(8000, &frames)).unwrap();
+    let input = dir.path().join("song.mml");
+    std::fs::write(
+        &input,
+        "#EX-OPN2 ABCDEF\n#EX-2A03 G\n#EX-PSG H\n\
+         #WAV 3 \"kick.wav\"\n#WAV 4 \"missing.wav\"\n\
+         F @s3 r4 @s-1 r4\n\
+         G @s3 r4\n\
+         H @s3 c4\n",
+    )
+    .unwrap();
+    let mut compiler = Compiler::new();
+    compiler.compile_file(&input, &dir.path().join("song.vgm")).unwrap();
+    assert_eq!(compiler.warnings.len(), 2, "{:?}", compiler.warnings);
+    assert!(compiler.warnings[0].contains("missing.wav"));
+    assert!(compiler.warnings[1].contains("channel H (line 8, bar 1:1): PSG has no DAC"));
+    assert_eq!(compiler.wav_samples[&3].data, vec![128, 192, 64]);
+
+    let vgm = compile_file_and_parse(&input);
+    // One bank per DAC: YM2612 PCM and NES DPCM
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::DataBlock { block_type: 0x00, size: Some(3), .. })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::DataBlock { block_type: 0x07, size: Some(3), .. })));
+    assert!(has_command(&vgm, |c| matches!(
+        c,
+        VgmCommand::DacStreamSetup { stream_id: 0, chip_type: 20, port: 0, reg: 0x11 }
+    )));
+    assert!(has_command(&vgm, |c| matches!(
+        c,
+        VgmCommand::DacStreamSetup { stream_id: 1, chip_type: 2, port: 0, reg: 0x2A }
+    )));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2612Write { port: 0, reg: 0x2B, data: 0x80 })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::DacStreamFreq { stream_id: 1, frequency: 8000 })));
+    assert_eq!(count_commands(&vgm, |c| matches!(c, VgmCommand::DacStreamFast { block_id: 0, .. })), 2);
+    assert_eq!(count_commands(&vgm, |c| matches!(c, VgmCommand::DacStreamStop { stream_id: 1 })), 1);
+}
+
+#[test]
+fn test_opn_fm_and_ssg() {
+    let mml = r#"
+#EX-OPN ABC,DEF
+@x0 = $71 20 31 $88 6 42 0   2 25 31 10 8 58 0   1 30 28 12 10 74 0   $51 15 31 6 4 26 $0A   $3A $C0
+A @0 v100 o4 a4
+D v15 o4 a4
+"#;
+    let vgm = compile_and_parse(mml);
+
+    assert_eq!(vgm.header.chips["ym2203"].clock, 3993600);
+    assert!(!has_command(&vgm, |c| matches!(c, VgmCommand::Ym2612Write { .. })));
+    // FM: operator registers from @x0, then key on channel 0
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2203Write { reg: 0x30, data: 0x71 })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2203Write { reg: 0x28, data: 0xF0 })));
+    // SSG: A4 at half the master clock is period 283 on channel A
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2203Write { reg: 0, data: 27 })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2203Write { reg: 1, data: 1 })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2203Write { reg: 8, data: 15 })));
+}
+
 // =============================================================================
 // GD3 Metadata Tests
 // =============================================================================
@@ -445,6 +682,38 @@ A o4c4
     assert_eq!(gd3.title_jp, "Test Song Title");
 }
 
+#[test]
+fn test_lyrics_in_notes() {
+    let mml = r#"
+#EX-PSG AB
+"Lyrics follow
+A l4 "Twin-" c "kle; *a" c r "きらきら" c
+B l4 r2 "back" c
+"#;
+    let vgm = compile_and_parse(mml);
+
+    let gd3 = vgm.gd3.expect("GD3 should be present");
+    assert_eq!(
+        gd3.notes,
+        "Lyrics follow\n[00:00.00]Twin-\n[00:00.50]kle; *a\n[00:01.00]back\n[00:01.50]きらきら\n"
+    );
+}
+
+#[test]
+fn test_lyrics_file() {
+    let mml = r#"
+#EX-PSG A
+#LYRICS LRC
+A l2 "one" c "two" c
+"#;
+    let dir = tempdir().unwrap();
+    let output = dir.path().join("song.vgm");
+    Compiler::new().compile(Cursor::new(mml), &output).expect("Compilation failed");
+
+    let lrc = std::fs::read_to_string(dir.path().join("song.lrc")).unwrap();
+    assert_eq!(lrc, "[00:00.00]one\n[00:01.00]two\n");
+}
+
 #[test]
 fn test_gd3_all_fields() {
     let mml = r#"
@@ -601,6 +870,158 @@ D o4c4
     );
 }
 
+#[test]
+fn test_compiled_events() {
+    use vgmck::compiler::event::EventSource;
+
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_events(Cursor::new("#EX-PSG AB\nA o4 c4 v10 d8\nB r4 o5 c8\n"))
+        .expect("Compilation failed");
+    let events: Vec<_> = compiler.events().collect();
+    assert_eq!(events.len(), 8);
+
+    assert_eq!((events[0].time, events[0].channel, events[0].chip), (0, Some('A'), Some("PSG")));
+    assert_eq!(events[0].source, EventSource::NoteOn { key: 48, duration: 22050 });
+    assert_eq!(events[1].source, EventSource::Rest);
+    assert_eq!(events[3].describe(), "A PSG: Volume 10");
+    assert_eq!(events[5].time, 22050);
+    assert_eq!(events[5].source, EventSource::NoteOn { key: 60, duration: 11025 });
+    assert!(events.windows(2).all(|w| w[0].time <= w[1].time));
+}
+
+#[test]
+fn test_compile_song_ast() {
+    use vgmck::compiler::ast::{Command, Directive, Envelope, Length, Note, Song, Track};
+
+    let song = Song::new()
+        .directive(Directive::Title("Generated".to_string()))
+        .directive(Directive::chip("PSG", "AB"))
+        .envelope(Envelope::new("@v", 0, vec![15, 12, 10, 8]).loop_at(3))
+        .track(Track::new("A").extend([
+            Command::Tempo(150),
+            Command::Octave(4),
+            Command::DefaultLength(Length::from(8)),
+            Command::macro_command("@v", 0),
+            Command::Repeat { commands: vec![Note::new('c').into(), Note::new('f').sharp().into()], count: 2 },
+            Command::Tuplet(vec![Command::note('g', 8), Command::note('a', 8), Command::note('b', 8)]),
+            Command::Note(Note::new('b').flat().length(Length::dotted(4, 1)).bend(15)),
+            Command::Rest(None),
+        ]))
+        .track(Track::new("B").push(Command::Volume(10)).push(Command::note('c', 2)));
+
+    let mml = song.to_string();
+    assert_eq!(
+        mml,
+        "#TITLE Generated\n#EX-PSG AB\n@v0 = { 15 12 10 | 8 }\n\
+         A t150 o4 l8 @v0 [c f+]2 {g8 a8 b8} b-4.~+15 r\nB v10 c2\n"
+    );
+
+    // The same bytes as compiling the MML text
+    let dir = tempdir().unwrap();
+    let (from_song, from_text) = (dir.path().join("song.vgm"), dir.path().join("text.vgm"));
+    Compiler::new().compile_song(&song, &from_song).unwrap();
+    Compiler::new().compile(Cursor::new(mml), &from_text).unwrap();
+    assert_eq!(std::fs::read(&from_song).unwrap(), std::fs::read(&from_text).unwrap());
+
+    // Text that would not render as written is rejected, not compiled
+    let error = |song: &Song| match Compiler::new().compile_song(song, &from_song) {
+        Err(vgmck::Error::InvalidSong(message)) => message,
+        other => panic!("expected an invalid song, got {:?}", other.map(|_| ())),
+    };
+    let title = song.clone().directive(Directive::Title("Two\nA c".to_string()));
+    assert_eq!(error(&title), "#TITLE \"Two\\nA c\" has a line break");
+    let sample = Envelope { text: Some("kick\" } A c ;".to_string()), ..Envelope::new("@S", 0, vec![]) };
+    assert_eq!(error(&song.clone().envelope(sample)), "@S0 \"kick\\\" } A c ;\" has a `\"`");
+    let composer = song.clone().directive(Directive::Composer("\"Someone\"".to_string()));
+    assert!(error(&composer).starts_with("#COMPOSER"));
+    // Raw MML may hold lyrics, but not a line break
+    assert!(song.clone().track(Track::new("A").push(Command::Mml("\"la\" c".to_string()))).validate().is_ok());
+    let mml = song.clone().track(Track::new("A").push(Command::Repeat { commands: vec![Command::Mml("c\n#EOF".to_string())], count: 2 }));
+    assert_eq!(error(&mml), "MML of track A \"c\\n#EOF\" has a line break");
+}
+
+#[test]
+fn test_repl_session() {
+    use vgmck::compiler::session::{Entry, Session};
+
+    let mut session = Session::new();
+    assert!(session.define("#EX-PSG AB").unwrap().is_empty());
+    assert!(session.define("@v0 = { 15 10 5 }").unwrap().is_empty());
+    let warnings = session.define("#OPTION PSG lfo=1").unwrap();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+
+    // Snippets hear the current envelopes, but not earlier snippets or definition warnings
+    let dir = tempdir().unwrap();
+    let output = dir.path().join("repl.vgm");
+    let Entry::Snippet(first) = session.enter("A @v0 o4 c8", Some(&output)).unwrap() else {
+        panic!("channel line should be a snippet");
+    };
+    assert!(first.warnings.is_empty(), "{:?}", first.warnings);
+    assert_eq!(first.events().filter(|e| e.describe().contains("Volume")).count(), 3);
+    assert!(output.exists());
+
+    let second = session.compile("B o5 d16").unwrap();
+    assert!(second.events().all(|e| e.channel == Some('B')));
+    assert_eq!(second.total_samples, 5512);
+    assert_eq!(session.definitions().len(), 3);
+}
+
+#[test]
+fn test_reproducible_output() {
+    // Chips that write at the start of the file, which must come in the same order every time
+    let mml = "#EX-Pokey A\n#EX-QSound B\n#EX-DMG C\n#EX-HuC6280 D\nA o4c\nB o4c\nC o4c\nD o4c\n";
+    let dir = tempdir().unwrap();
+    let outputs: Vec<Vec<u8>> = (0..4)
+        .map(|i| {
+            let output = dir.path().join(format!("{}.vgm", i));
+            Compiler::new().compile(Cursor::new(mml), &output).unwrap();
+            std::fs::read(&output).unwrap()
+        })
+        .collect();
+    assert!(outputs.windows(2).all(|w| w[0] == w[1]));
+
+    // `@jitter` without a seed fails a reproducible build
+    let jitter = "#EX-PSG A\nA v15 @jitter8 l8 c d e f g a b\n";
+    let output = dir.path().join("random.vgm");
+    let mut compiler = Compiler::new();
+    compiler.reproducible = true;
+    let result = compiler.compile(Cursor::new(jitter), &output);
+    assert!(matches!(result, Err(vgmck::Error::NotReproducible(_))), "{:?}", result.map(|_| ()));
+    let (_, warnings) = compile_warnings(jitter);
+    assert!(warnings.is_empty(), "{:?}", warnings);
+
+    // With a seed it gives the same bytes every time, and other bytes for another seed
+    let seeded = |seed: u64| {
+        let mut compiler = Compiler::new();
+        compiler.reproducible = true;
+        compiler.set_seed(seed);
+        compiler.compile(Cursor::new(jitter), &output).unwrap();
+        std::fs::read(&output).unwrap()
+    };
+    assert_eq!(seeded(7), seeded(7));
+    assert_ne!(seeded(7), seeded(8));
+    assert_ne!(seeded(7), compile_warnings("#EX-PSG A\nA v15 l8 c d e f g a b\n").0);
+}
+
+#[test]
+fn test_channel_latency() {
+    use vgmck::compiler::event::EventSource;
+
+    let mml = "#EX-PSG ABC\n#LATENCY A=+10ms B=-100 C\nA o4 c1\nB r8 o4 c4\n";
+    let mut compiler = Compiler::new();
+    compiler.compile_events(Cursor::new(mml)).expect("Compilation failed");
+    let first = |ch: char| compiler.events().find(|e| e.channel == Some(ch)).map(|e| e.time);
+
+    // 10 ms is 441 samples; B's rest shifts from 0 to the start, its note 100 samples earlier
+    assert_eq!(first('A'), Some(441));
+    assert_eq!(first('B'), Some(0));
+    let note = compiler.events().find(|e| e.channel == Some('B') && matches!(e.source, EventSource::NoteOn { .. }));
+    assert_eq!(note.map(|e| e.time), Some(11025 - 100));
+    assert_eq!(compiler.total_samples, 88200 + 441);
+    assert_eq!(compiler.warnings.len(), 1, "{:?}", compiler.warnings);
+}
+
 // =============================================================================
 // Clock Rate Tests
 // =============================================================================
@@ -621,6 +1042,39 @@ A o4c4
     assert_eq!(chip.clock, 4000000, "Clock should be 4MHz");
 }
 
+#[test]
+fn test_option_directive() {
+    let mml = r#"
+#EX-PSG ABC
+#OPTION psg clock=4000000 stereo=on negate=off lfo=on
+#OPTION OPN2 clock=7670454
+A o4c4
+"#;
+    let (data, warnings) = compile_warnings(mml);
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert!(warnings[0].contains("PSG has no option 'lfo'"));
+    assert!(warnings[1].contains("'OPN2' is not enabled"));
+
+    let header = VgmReader::new(&data).parse_header().unwrap();
+    let chip = &header.chips["sn76489"];
+    assert_eq!(chip.clock, 4000000);
+    // Stereo on clears the "stereo disabled" flag, leaving the divider flag
+    assert_eq!(chip.extra["flags"], 0x08);
+}
+
+#[test]
+fn test_option_directive_fm() {
+    // OPN2 options go by their own names, with the LFO written at the start
+    let (_, warnings) = compile_warnings("#EX-OPN2 A\n#OPTION OPN2 clock=7670454 lfo=on feedback=9\nA o4c4\n");
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("OPN2 has no option 'feedback' (options: clock, lfo)"));
+
+    let vgm = compile_and_parse("#EX-OPN2 A\n#OPTION OPN2 lfo=8\nA o4c4\n");
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2612Write { port: 0, reg: 0x22, data: 0x0F })));
+    let vgm = compile_and_parse("#EX-OPN2 A\nA o4c4\n");
+    assert!(!has_command(&vgm, |c| matches!(c, VgmCommand::Ym2612Write { reg: 0x22, .. })));
+}
+
 // =============================================================================
 // Tempo Tests
 // =============================================================================
@@ -666,6 +1120,50 @@ A @v0 o4c2
     );
 }
 
+#[test]
+fn test_macro_retrigger_mode() {
+    let loudest = |mml: &str| {
+        let vgm = compile_and_parse(mml);
+        count_commands(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { data: 0x90 }))
+    };
+
+    // Envelope restarts on each note by default
+    let retrigger = loudest(
+        r#"
+#EX-PSG A
+@v0 = 15 14 13 12 11 10 9 8 7 6 5 4 3 2 1 0
+A @v0 o4 l16 cc
+"#,
+    );
+    assert_eq!(retrigger, 2);
+
+    // MRE0 continues the envelope across notes
+    let legato = loudest(
+        r#"
+#EX-PSG A
+@v0 = 15 14 13 12 11 10 9 8 7 6 5 4 3 2 1 0
+A MRE0 @v0 o4 l16 cc
+"#,
+    );
+    assert_eq!(legato, 1);
+}
+
+#[test]
+fn test_undefined_envelope_warning() {
+    let mml = r#"
+#EX-PSG AB
+@v0 = 15 14 13
+A @v0 o4c4
+B o4c4
+B @v7 EN3 o4c4
+"#;
+    let (_, warnings) = compile_warnings(mml);
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert!(warnings[0].contains("channel B (line 6, bar 1:2)"), "{}", warnings[0]);
+    assert!(warnings[0].contains("@v7"));
+    assert!(warnings[1].contains("@EN3"));
+}
+
 // =============================================================================
 // Direct Register Write Tests
 // =============================================================================
@@ -709,6 +1207,340 @@ A *a *a
     );
 }
 
+#[test]
+fn test_text_macro_nested() {
+    // The comment must not end up in the channel, and *b expands *a
+    let mml = r#"
+#EX-PSG A
+*a o4 l4 c ; comment with notes
+*b *a d
+A *b
+"#;
+    let vgm = compile_and_parse(mml);
+    assert_eq!(vgm.header.total_samples, 44100);
+}
+
+#[test]
+fn test_text_macro_recursive_warning() {
+    let mml = r#"
+#EX-PSG A
+*a c *a
+A *a
+"#;
+    let (_, warnings) = compile_warnings(mml);
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("line 4: text macro *a"));
+}
+
+#[test]
+fn test_named_text_macros_with_arguments() {
+    let text = |mml: &str| {
+        let mut compiler = Compiler::new();
+        compiler.compile_events(Cursor::new(mml)).unwrap();
+        let text = compiler.channels[0].as_ref().unwrap().text.clone();
+        (text, compiler.warnings)
+    };
+
+    // Longest name wins; single-character macros and tuplets are unchanged
+    let (expanded, warnings) = text("#EX-PSG A\n*k v8\n*kick = o2 c\n*A d\nA *kick *k *A{cde}\n");
+    assert_eq!(expanded, " o2 c  v8  d{cde}");
+    assert!(warnings.is_empty(), "{:?}", warnings);
+
+    // Arguments, also expanding macros, and macros inside the body
+    let mml = "#EX-PSG A\n*oct = o%1\n*riff = *oct{%1} l8 %2 %2\n*g = g\nA *riff{4,c} *riff{3,*g}\n";
+    assert_eq!(text(mml).0, " o4 l8 c c o3 l8 g g");
+
+    // Wrong argument counts and cycles through arguments are reported
+    let (expanded, warnings) = text("#EX-PSG A\n*two = %1%2\n*loop = *two{*loop,c}\nA *two{c} *loop\n");
+    assert_eq!(expanded, " c c");
+    assert!(warnings[0].contains("text macro *two takes 2 arguments, got 1"), "{:?}", warnings);
+    assert!(warnings[1].contains("text macro *loop uses itself"), "{:?}", warnings);
+
+    // A macro used in its own argument is not a cycle
+    let (expanded, warnings) = text("#EX-PSG A\n*r = %1 %1\nA *r{*r{c}} d\n");
+    assert_eq!(expanded, " c c c c d");
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    let vgm = compile_and_parse("#EX-PSG A\n*r = %1 %1\nA *r{*r{c}} d\n");
+    assert_eq!(vgm.header.total_samples, 5 * 22050);
+}
+
+#[test]
+fn test_octave_normalization() {
+    // PSG `o4 c` sounds three octaves above the AY-3-8910's
+    let mml = "#EX-PSG A\n#EX-AY8910 B\n*T t150\n*m o4 c\nAB *T *m\n";
+    let (_, warnings) = compile_warnings(mml);
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("text macro *m"), "{}", warnings[0]);
+    assert!(warnings[0].contains("AY8910 +0, PSG +3"), "{}", warnings[0]);
+
+    // Normalized, both play C4: period 427 (0x1AB)
+    let vgm = compile_and_parse(&format!("#NORMALIZE-OCTAVE\n{}", mml));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { data: 0x8B })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { data: 0x1A })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ay8910Write { reg: 0, data: 0xAB })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ay8910Write { reg: 1, data: 1 })));
+}
+
+#[test]
+fn test_number_formats() {
+    use vgmck::compiler::envelope::MacroType;
+
+    let mml = "#EX-PSG A\n#LOOP-BASE %11\n@v0 = { 15 $0E 0x0D %1100 1_1 -$0_A }\nA l1_6 v0x0F c %1 c\n";
+    let dir = tempdir().unwrap();
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new(mml), &dir.path().join("test.vgm"))
+        .expect("Compilation failed");
+    assert_eq!(compiler.macro_env[MacroType::Volume as usize][0].data, vec![15, 14, 13, 12, 11, -10]);
+    assert_eq!(compiler.loop_base, 3);
+    assert_eq!(compiler.total_samples, 11024); // two 16th notes
+
+    // The `%1` before the second `c` is not a command
+    assert_eq!(compiler.warnings, vec!["channel A (line 4, bar 1:1.25): unknown command `%1`"]);
+
+    let (_, warnings) = compile_warnings("#EX-PSG A\n#LOOP-BASE %12\n@v0 = { $ 99999999999999999999 }\nA c%\n");
+    assert_eq!(
+        warnings,
+        vec![
+            "line 2: `%12` is not a binary number",
+            "line 3: `$` has no digits",
+            "line 3: `99999999999999999999` is too large",
+            "channel A (line 4, bar 1:1): `%` has no digits",
+        ]
+    );
+}
+
+#[test]
+fn test_conditions_inside_loops_and_macros() {
+    // Conditions are applied before loops run, also when they come from a macro
+    for mml in [
+        "#EX-PSG A\nA l4 [c ?(B) d ?]2 e\n",
+        "#EX-PSG A\n*m ?(B) d ?\nA l4 [c *m]2 e\n",
+        "#EX-PSG A\nA l4 [c ?B d ?.]2 e\n",
+    ] {
+        let vgm = compile_and_parse(mml);
+        assert_eq!(vgm.header.total_samples, 66150, "{}", mml);
+    }
+}
+
+#[test]
+fn test_named_condition_across_lines() {
+    // The block opened on the first line is closed on the second
+    let shared = |group: &str| format!("#EX-PSG AB\nAB l4 c ?({}) d\nAB e ? f\n", group);
+
+    let (_, warnings) = compile_warnings(&shared("A"));
+    assert!(warnings.is_empty(), "{:?}", warnings);
+
+    // A plays all four notes
+    assert_eq!(compile_and_parse(&shared("A")).header.total_samples, 88200);
+    // Neither channel is in the group, so both skip `d e`
+    assert_eq!(compile_and_parse(&shared("C")).header.total_samples, 44100);
+}
+
+#[test]
+fn test_named_condition_warnings() {
+    let mml = r#"
+#EX-PSG A
+A c ?(A) d ?(B) e ?
+A f ?(A) g
+"#;
+    let (_, warnings) = compile_warnings(mml);
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert!(warnings[0].contains("nested"));
+    assert!(warnings[0].contains("channel A (line 3, bar 1:3)"), "{}", warnings[0]);
+    assert!(warnings[1].contains("unterminated"));
+    assert!(warnings[1].contains("channel A (line 4, bar 1:4)"), "{}", warnings[1]);
+}
+
+#[test]
+fn test_structure_lints() {
+    let mml = r#"
+#EX-PSG ABC
+A l8 [cd]1 e {fg
+B ?A c ?. [d e
+C c d ! e f
+"#;
+    let (_, warnings) = compile_warnings(mml);
+    assert_eq!(warnings.len(), 4, "{:?}", warnings);
+    assert!(warnings[0].contains("channel A (line 3, bar 1:2): loop `]1` plays its body only once"), "{}", warnings[0]);
+    assert!(warnings[1].contains("channel A (line 3, bar 1:2.5): unterminated tuplet"), "{}", warnings[1]);
+    assert!(warnings[2].contains("channel B (line 4, bar 1:1): unterminated loop"), "{}", warnings[2]);
+    assert!(warnings[3].contains("channel C (line 5, bar 1:3): `e` is after `!`"), "{}", warnings[3]);
+}
+
+const STEMS_MML: &str = r#"
+#TITLE Stems
+#EX-PSG ABC
+#SFX blip = C
+A l4 v15 c d e f
+B l4 v15 r2 g
+C c
+"#;
+
+#[test]
+fn test_solo_channel() {
+    let solo = |ch: char| {
+        let mut compiler = Compiler::new();
+        compiler.report = false;
+        compiler.set_solo(ch).unwrap();
+        let mut data = Vec::new();
+        compiler.compile_to_writer(Cursor::new(STEMS_MML), &mut data).unwrap();
+        let mut reader = VgmReader::new(&data);
+        let header = reader.parse_header().unwrap();
+        let commands = reader.parse_commands(&header).unwrap();
+        (header.total_samples, commands)
+    };
+    let (a_samples, a) = solo('A');
+    let (b_samples, b) = solo('B');
+    assert_eq!((a_samples, b_samples), (88200, 88200));
+
+    // Tone latch writes of a PSG channel (0x80 | channel << 5)
+    let tone_writes = |commands: &[VgmCommand], channel: u8| {
+        commands
+            .iter()
+            .filter(|c| matches!(c, VgmCommand::Sn76489Write { data } if data & 0xF0 == 0x80 | channel << 5))
+            .count()
+    };
+    assert_eq!((tone_writes(&a, 0), tone_writes(&a, 1)), (4, 0));
+    assert_eq!((tone_writes(&b, 0), tone_writes(&b, 1)), (0, 1));
+}
+
+#[cfg(feature = "render")]
+#[test]
+fn test_render_stems() {
+    let dir = tempdir().unwrap();
+    let output = dir.path().join("song.vgm");
+    let stems = dir.path().join("stems");
+    let mut compiler = Compiler::new();
+    compiler.stems_dir = Some(stems.clone());
+    compiler.compile(Cursor::new(STEMS_MML), &output).expect("Compilation failed");
+    assert!(!Compiler::stem_path(&stems, &output, 'C').exists(), "#SFX channels get no stem");
+
+    // Each stem is the whole song; B is silent until its note at the half
+    let samples = |ch: char| -> Vec<i16> {
+        let wav = std::fs::read(Compiler::stem_path(&stems, &output, ch)).expect("missing stem");
+        assert_eq!(&wav[..4], b"RIFF");
+        wav[44..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    };
+    let (a, b) = (samples('A'), samples('B'));
+    assert_eq!((a.len(), b.len()), (88200, 88200));
+    assert!(a[..44100].iter().any(|&s| s.unsigned_abs() > 1000));
+    assert!(b[..44100].iter().all(|&s| s == 0));
+    assert!(b[44100..].iter().any(|&s| s.unsigned_abs() > 1000));
+}
+
+#[test]
+fn test_syntax_diagnostics() {
+    use vgmck::compiler::diagnostic::code;
+
+    let mml = "#EX-PSG A\nA o4x4 c %1 d q8\n";
+    let dir = tempdir().unwrap();
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new(mml), &dir.path().join("test.vgm"))
+        .expect("Compilation failed");
+
+    let found: Vec<_> = compiler
+        .diagnostics
+        .iter()
+        .map(|d| (d.code, d.message.as_str(), d.span.and_then(|s| s.column)))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (code::MISSING_ARGUMENT, "`x4` needs a value: write `x address,value`", Some(5)),
+            (code::UNKNOWN_COMMAND, "unknown command `%1`", Some(10)),
+            (code::UNKNOWN_COMMAND, "unknown command `q`", Some(15)),
+        ]
+    );
+    assert_eq!(
+        compiler.diagnostics[1].render(),
+        "channel A (line 2, bar 1:2): unknown command `%1`\n  2 | A o4x4 c %1 d q8\n    |          ^^"
+    );
+
+    // A channel without a chip stops compilation at its first use
+    let mut compiler = Compiler::new();
+    match compiler.compile(Cursor::new("#EX-PSG A\nA c\n  B d\n"), &dir.path().join("test.vgm")) {
+        Err(vgmck::Error::MmlSyntax { file, line, column, message }) => {
+            assert_eq!((file, line, column), (None, 3, Some(3)));
+            assert!(message.starts_with("channel B is not declared"), "{}", message);
+        }
+        other => panic!("expected a syntax error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_strict_mode() {
+    use vgmck::compiler::diagnostic::code;
+
+    let mml = "#EX-PSG A\n#TEMPOO 120\nA v20 @v3 c\n";
+    let dir = tempdir().unwrap();
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new(mml), &dir.path().join("test.vgm"))
+        .expect("Compilation failed");
+    let codes: Vec<_> = compiler.diagnostics.iter().map(|d| d.code).collect();
+    assert_eq!(codes, vec![code::UNKNOWN_DIRECTIVE, code::OUT_OF_RANGE, code::UNDEFINED_MACRO]);
+
+    // Strict mode stops at the first of them
+    let mut compiler = Compiler::new();
+    compiler.set_strict(true);
+    match compiler.compile(Cursor::new(mml), &dir.path().join("test.vgm")) {
+        Err(vgmck::Error::MmlSyntax { line, message, .. }) => {
+            assert_eq!((line, message.as_str()), (2, "unknown directive #TEMPOO [W0001]"));
+        }
+        other => panic!("expected a syntax error, got {:?}", other.map(|_| ())),
+    }
+
+    let mut compiler = Compiler::new();
+    compiler.set_strict(true);
+    match compiler.compile(Cursor::new("#EX-PSG A\nA c @v3 d\n"), &dir.path().join("test.vgm")) {
+        Err(vgmck::Error::MmlSyntax { line, column, message, .. }) => {
+            assert_eq!((line, column), (2, Some(5)));
+            assert!(message.starts_with("channel A (bar 1:2): envelope @v3 is not defined"), "{}", message);
+        }
+        other => panic!("expected a syntax error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_diagnostic_codes() {
+    use vgmck::compiler::diagnostic::{code, Severity, SourceSpan};
+
+    let mml = "#EX-PSG AB\n*1cd\nA c [d\n  B e ?B f\nB *1 ! g\n";
+    let dir = tempdir().unwrap();
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new(mml), &dir.path().join("test.vgm"))
+        .expect("Compilation failed");
+
+    let diagnostics = &compiler.diagnostics;
+    assert_eq!(diagnostics.len(), 3, "{:?}", diagnostics);
+    assert_eq!(compiler.warnings.len(), 3);
+
+    let lint = &diagnostics[0];
+    assert_eq!((lint.code, lint.severity, lint.channel), (code::UNTERMINATED_LOOP, Severity::Warning, Some('A')));
+    assert_eq!(lint.span, Some(SourceSpan { line: 3, column: Some(5), end_column: Some(6) }));
+    assert!(lint.suggestion.as_deref().unwrap().contains("]2"));
+    assert_eq!(lint.to_string(), compiler.warnings[0]);
+
+    // Indented line: columns count from the start of the source line
+    let cond = &diagnostics[1];
+    assert_eq!(cond.code, code::UNTERMINATED_CONDITION);
+    assert_eq!(cond.span.and_then(|s| s.column), Some(7));
+
+    // Text after a text macro has no column
+    let stop = &diagnostics[2];
+    assert_eq!(stop.code, code::UNREACHABLE_AFTER_STOP);
+    assert_eq!(stop.span, Some(SourceSpan { line: 5, column: None, end_column: None }));
+
+    let json: serde_json::Value = serde_json::from_str(&lint.to_json()).unwrap();
+    assert_eq!(json["code"], "L0002");
+    assert_eq!(json["severity"], "warning");
+    assert_eq!(json["span"]["line"], 3);
+    assert!(json.get("file").is_none());
+}
+
 // =============================================================================
 // MML Loop Tests
 // =============================================================================
@@ -927,25 +1759,67 @@ fn test_include_text_macro() {
     );
 }
 
-// =============================================================================
-// BUG-001 Regression Tests: FM Operator Data
-// =============================================================================
-
-/// Regression test for BUG-001: FM operator data not written to VGM for OPN2/YM2612
-///
-/// This test verifies that when using an FM instrument (@x envelope), the compiler
-/// writes the operator register data (0x30-0x9F, 0xB0, 0xB4) to the VGM output.
 #[test]
-fn test_opn2_fm_operator_registers_written() {
-    // Define a simple FM instrument with @x envelope
-    // @x0 = Op1(7 values) Op2(7 values) Op3(7 values) Op4(7 values) ALG/FB PAN/LFO
-    // Values: DT1/MUL, TL, RS/AR, AM/D1R, D2R, SL/RR, SSG-EG (x4), ALG/FB, PAN/LFO
-    let mml = r#"
-#EX-OPN2 ABCDEF
+fn test_channel_file() {
+    let dir = tempdir().unwrap();
 
-; Define FM instrument @x0 with basic parameters
-; 4 operators x 7 values each + algorithm/feedback + panning
-@x0 = 1 0 31 0 0 15 0   ; Op1: MUL=1, TL=0, AR=31, D1R=0, D2R=0, SL/RR=15
+    // Channel text kept in its own file
+    let part_path = dir.path().join("part.txt");
+    let mut part_file = std::fs::File::create(&part_path).unwrap();
+    writeln!(part_file, "o4 l8 cdef").unwrap();
+    writeln!(part_file).unwrap();
+    writeln!(part_file, "gab>c").unwrap();
+
+    let main_path = dir.path().join("main.mml");
+    let mut main_file = std::fs::File::create(&main_path).unwrap();
+    writeln!(main_file, "#EX-PSG A").unwrap();
+    writeln!(main_file, "#CHANNEL-FILE A part.txt").unwrap();
+
+    let output_path = dir.path().join("main.vgm");
+    let mut compiler = Compiler::new();
+    compiler.compile_file(&main_path, &output_path).expect("Compilation failed");
+    assert_eq!(compiler.dependencies, vec![part_path]);
+
+    let vgm = compile_file_and_parse(&main_path);
+    let write_count = count_commands(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { .. }));
+    assert!(
+        write_count >= 8,
+        "Should have writes for all notes from the channel file, got {}",
+        write_count
+    );
+    // 8 eighth notes at the default tempo
+    assert_eq!(vgm.header.total_samples, 88200);
+
+    // A channel file named in an included file is next to that file
+    std::fs::create_dir(dir.path().join("parts")).unwrap();
+    std::fs::write(dir.path().join("parts/bass.txt"), "o3 l4 c g\n").unwrap();
+    std::fs::write(dir.path().join("parts/bass.mml"), "#CHANNEL-FILE A bass.txt\n").unwrap();
+    std::fs::write(&main_path, "#EX-PSG A\n#INCLUDE parts/bass.mml\n").unwrap();
+    let mut compiler = Compiler::new();
+    compiler.compile_file(&main_path, &output_path).expect("Compilation failed");
+    assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+    assert_eq!(compile_file_and_parse(&main_path).header.total_samples, 44100);
+}
+
+// =============================================================================
+// BUG-001 Regression Tests: FM Operator Data
+// =============================================================================
+
+/// Regression test for BUG-001: FM operator data not written to VGM for OPN2/YM2612
+///
+/// This test verifies that when using an FM instrument (@x envelope), the compiler
+/// writes the operator register data (0x30-0x9F, 0xB0, 0xB4) to the VGM output.
+#[test]
+fn test_opn2_fm_operator_registers_written() {
+    // Define a simple FM instrument with @x envelope
+    // @x0 = Op1(7 values) Op2(7 values) Op3(7 values) Op4(7 values) ALG/FB PAN/LFO
+    // Values: DT1/MUL, TL, RS/AR, AM/D1R, D2R, SL/RR, SSG-EG (x4), ALG/FB, PAN/LFO
+    let mml = r#"
+#EX-OPN2 ABCDEF
+
+; Define FM instrument @x0 with basic parameters
+; 4 operators x 7 values each + algorithm/feedback + panning
+@x0 = 1 0 31 0 0 15 0   ; Op1: MUL=1, TL=0, AR=31, D1R=0, D2R=0, SL/RR=15
       1 0 31 0 0 15 0   ; Op2
       1 0 31 0 0 15 0   ; Op3
       1 0 31 0 0 15 0   ; Op4
@@ -1232,6 +2106,141 @@ A @0 @v0 o4c1
     );
 }
 
+#[test]
+fn test_export_fm_instruments() {
+    use vgmck::compiler::instrument::{self, InstrumentFormat};
+
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("song.mml");
+    std::fs::write(
+        &input,
+        "#EX-OPN2 A\n\
+         @x3 = $71 20 31 $88 6 42 0   2 25 31 10 8 58 0   1 30 28 12 10 74 0   $41 15 31 6 4 26 $0A   $3A $C7\n\
+         @x4 = 1 2 3\n\
+         A @3 c\n",
+    )
+    .unwrap();
+    let mut compiler = Compiler::new();
+    compiler.parse_file(&input).unwrap();
+
+    // @x4 is too short to be an OPN2 voice
+    let voices = instrument::fm_voices(&compiler.macro_env);
+    assert_eq!(voices.len(), 1);
+    let (id, voice) = &voices[0];
+    assert_eq!((*id, voice.alg, voice.fb, voice.ams, voice.fms), (3, 2, 7, 0, 7));
+    assert_eq!((voice.ops[0].detune(), voice.ops[0].mul, voice.ops[0].am), (-3, 1, true));
+    assert_eq!(voice.ops[3].ssg_eg, 0x0A);
+
+    let opm = instrument::export(&compiler.macro_env, dir.path(), "song", InstrumentFormat::Opm).unwrap();
+    assert_eq!(opm, vec![dir.path().join("song.opm")]);
+    let text = std::fs::read_to_string(&opm[0]).unwrap();
+    assert!(text.contains("CH: 64   7   2   0   7 120   0"), "{}", text);
+    // C1 is the third @x operator (register order is M1 M2 C1 C2)
+    assert!(text.contains("C1:  28  12  10  10   4  30   0   1   0   0   0"), "{}", text);
+
+    let dmp = instrument::export(&compiler.macro_env, dir.path(), "song", InstrumentFormat::Dmp).unwrap();
+    let data = std::fs::read(&dmp[0]).unwrap();
+    assert_eq!(data.len(), 7 + 4 * 11);
+    assert_eq!(&data[..7], &[11, 2, 1, 7, 7, 2, 0]);
+    // Operators 1 to 4, so the second is the third @x operator (TL 30)
+    let tls: Vec<u8> = data[7..].chunks(11).map(|op| op[1]).collect();
+    assert_eq!(tls, [20, 30, 25, 15]);
+
+    let fui = instrument::export(&compiler.macro_env, dir.path(), "song", InstrumentFormat::Fui).unwrap();
+    assert_eq!(fui, vec![dir.path().join("song.3.fui")]);
+    let data = std::fs::read(&fui[0]).unwrap();
+    assert_eq!(&data[..8], b"FINS\x8f\x00\x01\x00");
+    assert_eq!(&data[data.len() - 4..], b"EN\x00\x00");
+    let operators = &data[data.len() - 4 - 4 * 8..data.len() - 4];
+    let tls: Vec<u8> = operators.chunks(8).map(|op| op[1]).collect();
+    assert_eq!(tls, [20, 30, 25, 15]);
+}
+
+#[test]
+fn test_load_fm_instruments() {
+    use vgmck::compiler::envelope::MacroType;
+    use vgmck::compiler::instrument::{self, InstrumentFormat};
+
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("song.mml");
+    std::fs::write(
+        &input,
+        "#EX-OPN2 A\n\
+         @x3 = $71 20 31 $88 6 42 0   2 25 31 10 8 58 0   1 30 28 12 10 74 0   $51 15 31 6 4 26 $0A   $3A $C7\n\
+         A @3 c\n",
+    )
+    .unwrap();
+    let mut compiler = Compiler::new();
+    compiler.parse_file(&input).unwrap();
+    for format in [InstrumentFormat::Opm, InstrumentFormat::Dmp, InstrumentFormat::Fui] {
+        instrument::export(&compiler.macro_env, dir.path(), "song", format).unwrap();
+    }
+    // TFI: algorithm, feedback, then mul, detune (3 = none), tl, rs, ar, dr, sr, rr, sl, ssg per operator
+    let mut tfi = vec![4, 5];
+    for op in 0..4u8 {
+        tfi.extend_from_slice(&[op + 1, 3 + op, 10 * op, 1, 31, 12, 4, 7, 2, 0]);
+    }
+    std::fs::write(dir.path().join("bass.tfi"), &tfi).unwrap();
+
+    let loader = dir.path().join("load.mml");
+    std::fs::write(
+        &loader,
+        "#EX-OPN2 A\n\
+         #LOADINST 0 song.opm\n\
+         #LOADINST 1 \"song.opm\" 3\n\
+         #LOADINST 2 song.3.dmp\n\
+         #LOADINST 3 song.3.fui\n\
+         #LOADINST 4 bass.tfi\n\
+         #LOADINST 5 missing.dmp\n\
+         A @0 c\n",
+    )
+    .unwrap();
+    let mut loaded = Compiler::new();
+    loaded.parse_file(&loader).unwrap();
+
+    let original = &compiler.macro_env[MacroType::Option as usize][3].data;
+    let env = |n: usize| loaded.macro_env[MacroType::Option as usize][n].data.clone();
+    // Panning is set by the channel, not the patch
+    let mut expected = original.clone();
+    expected[29] &= 0x3F;
+    assert_eq!(env(2), expected);
+    assert_eq!(env(3), expected);
+    // VOPM has no SSG-EG
+    expected[27] = 0;
+    assert_eq!(env(0), expected);
+    assert_eq!(env(1), expected);
+
+    // The second operator of the file is the third @x operator
+    let tfi_voice = env(4);
+    assert_eq!(tfi_voice.len(), 30);
+    assert_eq!(&tfi_voice[14..21], &[0x12, 10, 0x5F, 12, 4, 0x27, 0]);
+    assert_eq!(tfi_voice[28], 5 << 3 | 4);
+
+    // Files store operators 1 to 4; @x holds them as 1, 3, 2, 4
+    let tls = [11u8, 22, 33, 44];
+    let mut dmp = vec![11, 2, 1, 0, 0, 4, 0];
+    let mut fm = vec![0xF4, 4 << 4, 0, 0];
+    let mut tfi = vec![4, 0];
+    for tl in tls {
+        dmp.extend_from_slice(&[1, tl, 31, 0, 0, 15, 0, 0, 3, 0, 0]);
+        fm.extend_from_slice(&[0x31, tl, 31, 0, 0, 15, 0, 0]);
+        tfi.extend_from_slice(&[1, 3, tl, 0, 31, 0, 0, 15, 0, 0]);
+    }
+    let mut fui = b"FINS\x8f\x00\x01\x00FM".to_vec();
+    fui.extend_from_slice(&(fm.len() as u16).to_le_bytes());
+    fui.extend_from_slice(&fm);
+    fui.extend_from_slice(b"EN\x00\x00");
+    for (name, data) in [("order.dmp", dmp), ("order.fui", fui), ("order.tfi", tfi)] {
+        std::fs::write(dir.path().join(name), data).unwrap();
+        let voice = instrument::load(&dir.path().join(name), None).unwrap();
+        let slots: Vec<i16> = voice.to_opn2().chunks(7).take(4).map(|op| op[1]).collect();
+        assert_eq!(slots, [11, 33, 22, 44], "{}", name);
+    }
+
+    assert!(env(5).is_empty());
+    assert!(loaded.warnings.iter().any(|w| w.contains("missing.dmp")), "{:?}", loaded.warnings);
+}
+
 // =============================================================================
 // BUG-002 Regression Tests: Multi-channel Routing
 // =============================================================================
@@ -1591,3 +2600,1937 @@ C o4g4
     assert!(has_ch_b, "BUG-002: AY8910 channel B should write to tone/volume registers 2-3/9");
     assert!(has_ch_c, "BUG-002: AY8910 channel C should write to tone/volume registers 4-5/10");
 }
+
+#[test]
+fn test_arpeggio_absolute_and_fixed_modes() {
+    let tones = |mml: &str| {
+        let vgm = compile_and_parse(mml);
+        vgm.commands
+            .iter()
+            .filter_map(|c| match c {
+                VgmCommand::Sn76489Write { data } if data & 0xF0 == 0xE0 => Some(data & 0x0F),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Fixed values go to the noise channel unchanged
+    let fixed = tones(
+        r#"
+#EX-PSG ,A
+@EN0 = F 2 1 0
+A EN0 v15 l4 c
+"#,
+    );
+    assert_eq!(fixed[1..], [2, 1, 0]);
+
+    // Absolute notes ignore the written pitch
+    let absolute = compile_and_parse(
+        r#"
+#EX-PSG A
+@EN0 = A 48
+A EN0 v15 l4 o2c
+"#,
+    );
+    let plain = compile_and_parse(
+        r#"
+#EX-PSG A
+A v15 l4 o4c
+"#,
+    );
+    let last_tone = |vgm: &VgmJson| {
+        vgm.commands.iter().rev().find_map(|c| match c {
+            VgmCommand::Sn76489Write { data } if data & 0x90 == 0x80 => Some(*data),
+            _ => None,
+        })
+    };
+    assert_eq!(last_tone(&absolute), last_tone(&plain));
+}
+
+#[test]
+fn test_tie_slide() {
+    let tone_writes = |mml: &str| {
+        let vgm = compile_and_parse(mml);
+        count_commands(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { data } if data & 0x90 == 0x80))
+    };
+
+    // Classic behavior changes pitch instantly
+    let classic = tone_writes(
+        r#"
+#EX-PSG A
+A v15 o4 l4 c&g
+"#,
+    );
+    assert_eq!(classic, 2);
+
+    // With #TIE-SLIDE the pitch moves over the given number of frames
+    let slide = tone_writes(
+        r#"
+#EX-PSG A
+#TIE-SLIDE 4
+A v15 o4 l4 c&g
+"#,
+    );
+    assert_eq!(slide, 2 + 4);
+}
+
+#[test]
+fn test_mono_forces_center_panning() {
+    let stereo = compile_and_parse(
+        r#"
+#EX-PSG A
+@P0 = 1 -1
+A P-1 o4c4 @P0 c4
+"#,
+    );
+    assert!(has_command(&stereo, |c| matches!(c, VgmCommand::GgStereo { .. })));
+
+    let mono = compile_and_parse(
+        r#"
+#MONO
+#EX-PSG A
+@P0 = 1 -1
+A P-1 o4c4 @P0 c4
+"#,
+    );
+    assert!(!has_command(&mono, |c| matches!(c, VgmCommand::GgStereo { .. })));
+
+    // Every chip's pan registers stay centered: one-sided writes are only in the stereo song
+    let one_sided = |c: &VgmCommand| match *c {
+        VgmCommand::GgStereo { data } => data != 0xFF,
+        VgmCommand::Ym2612Write { reg: 0xB4..=0xB6, data, .. } => data & 0xC0 != 0xC0,
+        VgmCommand::Ymf262Write { reg: 0xC0..=0xC8, data, .. } => matches!(data & 0x30, 0x10 | 0x20),
+        VgmCommand::GbDmgWrite { reg: 0x15, data } => data != 0xFF,
+        _ => false,
+    };
+    for song in [
+        "#EX-PSG A\n#AUTOMATE A pan 0=-1 1=1\nA v15 o4 l1 c c\n",
+        "#EX-OPN2 A\nA P-1 o4c4 P1 c4\n",
+        "#EX-OPL3 A\nA P-1 o4c4 P1 c4\n",
+        "#EX-DMG A\nA P-1 o4c4 P1 c4\n",
+    ] {
+        assert!(has_command(&compile_and_parse(song), one_sided), "{}", song);
+        let (_, warnings) = compile_warnings(&format!("#MONO\n{}", song));
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(!has_command(&compile_and_parse(&format!("#MONO\n{}", song)), one_sided), "{}", song);
+    }
+}
+
+#[test]
+fn test_directive_table() {
+    use vgmck::compiler::directive;
+
+    let (ex, suffix) = directive::find("EX-PSG").expect("#EX- not found");
+    assert_eq!(ex.name, "EX-");
+    assert_eq!(suffix, "PSG");
+
+    let (title, suffix) = directive::find("TITLE-E").expect("#TITLE-E not found");
+    assert_eq!(title.name, "TITLE-E");
+    assert_eq!(suffix, "");
+
+    assert!(directive::find("NO-SUCH-DIRECTIVE").is_none());
+    assert!(directive::directives().iter().all(|d| !d.description.is_empty()));
+}
+
+#[test]
+fn test_tokenizer_spans() {
+    use vgmck::compiler::token::{tokenize, Length, Shift, TokenKind};
+
+    let tokens = tokenize("o4 c+8. @v12 [d]3");
+    let kinds: Vec<_> = tokens.iter().map(|t| t.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TokenKind::Octave(4),
+            TokenKind::Note {
+                letter: 2,
+                shift: Shift { steps: 1, octaves: 0 },
+                length: Length { value: 8, dots: 1 },
+            },
+            TokenKind::Macro { name: "@v".to_string(), value: 12 },
+            TokenKind::LoopStart,
+            TokenKind::Note { letter: 3, shift: Shift::default(), length: Length::default() },
+            TokenKind::LoopEnd(3),
+        ]
+    );
+    let spans: Vec<_> = tokens.iter().map(|t| (t.span.start, t.span.end)).collect();
+    assert_eq!(spans, vec![(0, 2), (3, 7), (8, 12), (13, 14), (14, 15), (15, 17)]);
+}
+
+#[test]
+fn test_deeply_nested_loops() {
+    let mml = format!(
+        "#EX-PSG A\nA v15 o4 l16 {}c{}]2\n",
+        "[".repeat(200),
+        "]1".repeat(199)
+    );
+    let vgm = compile_and_parse(&mml);
+    assert_eq!(
+        count_commands(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { data: 0x9F })),
+        2
+    );
+}
+
+#[test]
+fn test_channel_defaults() {
+    let with_defaults = compile_and_parse(
+        r#"
+#DEFAULT o4 l8 t150
+#DEFAULT B o2 l16
+#EX-PSG AB
+A v15 c d e
+B v15 c d e
+"#,
+    );
+    let explicit = compile_and_parse(
+        r#"
+#EX-PSG AB
+A v15 t150 o4 l8 c d e
+B v15 t150 o2 l16 c d e
+"#,
+    );
+    assert_eq!(format!("{:?}", with_defaults.commands), format!("{:?}", explicit.commands));
+    assert_eq!(with_defaults.header.total_samples, explicit.header.total_samples);
+}
+
+#[test]
+fn test_normalized_volume_mode() {
+    let commands = |mml: &str| format!("{:?}", compile_and_parse(mml).commands);
+
+    // OPN2 volumes range 0-127, so normalized 15 is 127 and 8 is 68
+    let absolute = commands(
+        r#"
+#EX-OPN2 A
+@v0 = 127 68
+A v68 o4c4 @v0 c4
+"#,
+    );
+    let per_envelope = commands(
+        r#"
+#EX-OPN2 A
+@v0 = N 15 8
+A v68 o4c4 @v0 c4
+"#,
+    );
+    let global = commands(
+        r#"
+#VOLUME-MODE NORMALIZED
+#EX-OPN2 A
+@v0 = 15 8
+A v8 o4c4 @v0 c4
+"#,
+    );
+    assert_eq!(absolute, per_envelope);
+    assert_eq!(absolute, global);
+
+    // `A` keeps an envelope in chip units under #VOLUME-MODE NORMALIZED
+    let override_absolute = commands(
+        r#"
+#VOLUME-MODE NORMALIZED
+#EX-OPN2 A
+@v0 = A 127 68
+A v8 o4c4 @v0 c4
+"#,
+    );
+    assert_eq!(absolute, override_absolute);
+
+    // QSound volumes range 0-4095, so normalized 15 is 4095
+    let normalized = commands("#VOLUME-MODE NORMALIZED\n#EX-QSound A\nA v8 o4c4 v15 c4\n");
+    assert_eq!(normalized, commands("#EX-QSound A\nA v2184 o4c4 v4095 c4\n"));
+    // Channel 1's volume register ($06) gets $0FFF at the key-on
+    let (vgm, warnings) = compile_warnings("#VOLUME-MODE NORMALIZED\n#EX-QSound A\nA v15 o4c4\n");
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    assert!(vgm.windows(4).any(|w| w == [0xC4, 0x0F, 0xFF, 0x06]));
+}
+
+#[test]
+fn test_channel_loop_mismatch_warning() {
+    let compile = |mml: &str| {
+        let dir = tempdir().unwrap();
+        let mut compiler = Compiler::new();
+        compiler
+            .compile(Cursor::new(mml), &dir.path().join("test.vgm"))
+            .expect("Compilation failed");
+        compiler
+    };
+
+    let matching = compile(
+        r#"
+#EX-PSG AB
+A o4 c4 L d4
+B o4 e4 L f4
+"#,
+    );
+    assert_eq!(matching.channel_loops(), vec![('A', 22050), ('B', 22050)]);
+    assert!(matching.warnings.is_empty());
+
+    let mismatched = compile(
+        r#"
+#EX-PSG AB
+A o4 c4 L d4
+B o4 e4 f4 L g4
+"#,
+    );
+    assert_eq!(mismatched.channel_loops(), vec![('A', 22050), ('B', 44100)]);
+    assert_eq!(mismatched.warnings.len(), 1);
+    assert!(mismatched.warnings[0].contains("A at 22050"));
+}
+
+#[test]
+fn test_null_chip() {
+    let vgm = compile_and_parse(
+        r#"
+#EX-NULL AB
+@v0 = 15 10 5
+A v15 @v0 o4 l4 c d e f
+B x$10,$20 o2 c1
+"#,
+    );
+    assert!(vgm
+        .commands
+        .iter()
+        .all(|c| matches!(c, VgmCommand::Wait { .. } | VgmCommand::End)));
+    assert_eq!(vgm.header.total_samples, 88200);
+
+    // Muting one part leaves the other chip's output intact
+    let muted = compile_and_parse(
+        r#"
+#EX-PSG A
+#EX-NULL B
+A v15 o4 c4
+B v15 o4 c4
+"#,
+    );
+    let solo = compile_and_parse(
+        r#"
+#EX-PSG A
+A v15 o4 c4
+"#,
+    );
+    assert_eq!(format!("{:?}", muted.commands), format!("{:?}", solo.commands));
+}
+
+#[test]
+fn test_perf_counters() {
+    let dir = tempdir().unwrap();
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    compiler
+        .compile(
+            Cursor::new("#EX-PSG AB\nA v15 o4 l8 cdefgab\nB v15 o3 l4 cdef\n"),
+            &dir.path().join("test.vgm"),
+        )
+        .expect("Compilation failed");
+
+    let perf = compiler.perf();
+    assert_eq!(perf.channels, 2);
+    assert!(perf.events > 0);
+    assert_eq!(perf.total(), perf.parse + perf.compile + perf.write);
+}
+
+#[test]
+fn test_register_log_export() {
+    let dir = tempdir().unwrap();
+    let vgm_path = dir.path().join("test.vgm");
+    let csv_path = dir.path().join("test.csv");
+    let bin_path = dir.path().join("test.rlg");
+
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new("#EX-PSG A\n#EX-OPLL B\nA l4 c d\nB l4 c d\n"), &vgm_path)
+        .expect("Compilation failed");
+
+    vgmck::vgm::reglog::export(&vgm_path, &csv_path).expect("CSV export failed");
+    vgmck::vgm::reglog::export(&vgm_path, &bin_path).expect("Binary export failed");
+
+    let csv = std::fs::read_to_string(&csv_path).unwrap();
+    let rows: Vec<&str> = csv.lines().skip(1).collect();
+    assert!(rows.iter().any(|r| r.contains(",SN76489,")));
+    assert!(rows.iter().any(|r| r.contains(",YM2413,")));
+
+    // Times are non-decreasing and the second note starts a quarter note in
+    let times: Vec<u64> = rows.iter().map(|r| r.split(',').next().unwrap().parse().unwrap()).collect();
+    assert!(times.windows(2).all(|w| w[0] <= w[1]));
+    assert!(times.contains(&22050));
+
+    let bin = std::fs::read(&bin_path).unwrap();
+    assert_eq!(&bin[..4], b"VRLG");
+    assert_eq!(u32::from_le_bytes(bin[4..8].try_into().unwrap()) as usize, rows.len());
+    assert_eq!(bin.len(), 8 + rows.len() * 10);
+}
+
+#[test]
+fn test_frequency_literal() {
+    use vgmck::compiler::token::{tokenize, Length, TokenKind};
+
+    let kinds: Vec<_> = tokenize("f=440 f=261.63,8. f4").into_iter().map(|t| t.kind).collect();
+    assert_eq!(
+        kinds[..2],
+        [
+            TokenKind::Frequency { millihertz: 440_000, length: Length::default() },
+            TokenKind::Frequency { millihertz: 261_630, length: Length { value: 8, dots: 1 } },
+        ]
+    );
+    assert!(matches!(kinds[2], TokenKind::Note { letter: 5, .. }));
+
+    // SN76489 period 3579545 / (32 * 440) = 254
+    let vgm = compile_and_parse("#EX-PSG A\nA v15 l4 f=440 r f=440,2\n");
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { data: 0x8E })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { data: 0x0F })));
+    assert_eq!(vgm.header.total_samples, 88200);
+
+    // YM2612 at 7670454 Hz: 440 Hz is F-number 1083 in block 4
+    let vgm = compile_and_parse("#EX-OPN2 A\nA l4 f=440\n");
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2612Write { port: 0, reg: 0xA4, data: 0x24 })));
+    assert!(has_command(&vgm, |c| matches!(c, VgmCommand::Ym2612Write { port: 0, reg: 0xA0, data: 0x3B })));
+
+    // Chips without a pitch formula warn and rest
+    let (_, warnings) = compile_warnings("#EX-NULL A\nA f=440\n");
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("f="));
+
+    // Frequencies past i64 millihertz are reported instead of overflowing
+    let (_, warnings) = compile_warnings("#EX-PSG A\nA f=99999999999999999999\n");
+    assert!(warnings.iter().any(|w| w.contains("`99999999999999999999` is too large")), "{:?}", warnings);
+    let (_, warnings) = compile_warnings("#EX-PSG A\nA f=9223372036854776\n");
+    assert!(warnings.iter().any(|w| w.contains("`9223372036854776` is too large")), "{:?}", warnings);
+}
+
+#[test]
+fn test_sfx_outputs() {
+    let dir = tempdir().unwrap();
+    let output_path = dir.path().join("song.vgm");
+    let mml = r#"
+#TITLE Song
+#EX-PSG ABC
+#SFX jump = C
+@v0 = { 15 12 8 }
+A v15 o4 l4 c d e f
+C @v0 o5 l16 c e g
+"#;
+
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new(mml), &output_path)
+        .expect("Compilation failed");
+    assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+
+    let parse = |path: &Path| {
+        let data = std::fs::read(path).expect("Failed to read output VGM");
+        let mut reader = VgmReader::new(&data);
+        let header = reader.parse_header().unwrap();
+        let gd3 = reader.parse_gd3(&header).unwrap();
+        let commands = reader.parse_commands(&header).unwrap();
+        VgmJson::new(&header, gd3.as_ref(), commands)
+    };
+    let song = parse(&output_path);
+    let sfx_path = Compiler::sfx_path(&output_path, "jump");
+    assert_eq!(sfx_path, dir.path().join("song.jump.vgm"));
+    let sfx = parse(&sfx_path);
+
+    // Channel A (volume 15 = 0x90) only in the song, channel C (0xD0) only in the effect
+    let has_data = |vgm: &VgmJson, data: u8| has_command(vgm, |c| matches!(c, VgmCommand::Sn76489Write { data: d } if *d == data));
+    assert!(has_data(&song, 0x90) && !has_data(&song, 0xD0));
+    assert!(has_data(&sfx, 0xD0) && !has_data(&sfx, 0x90));
+    assert_eq!(song.header.total_samples, 88200);
+    assert_eq!(sfx.header.total_samples, 16536);
+    assert_eq!(sfx.gd3.unwrap().title, "jump");
+}
+
+#[test]
+fn test_decompile_round_trip() {
+    let dir = tempdir().unwrap();
+    let mml = "#TITLE Probe\n#EX-PSG ABC\n#EX-AY8910 DEF\n\
+               A v15 o4 l8 c d e r g4 L c+16 d16\n\
+               B v10 o3 l4 c c\n\
+               D v12 o4 l8 c e g\n";
+    let vgm_path = dir.path().join("song.vgz");
+    Compiler::new().compile(Cursor::new(mml), &vgm_path).expect("Compilation failed");
+
+    let mml_path = dir.path().join("song.mml");
+    vgmck::vgm::decompile::decompile_file(&vgm_path, &mml_path).unwrap();
+    let decompiled = std::fs::read_to_string(&mml_path).unwrap();
+
+    // An eighth note at tempo 120 is 15 frames: 12 (8) + 3 (32)
+    assert!(decompiled.contains("#TITLE Probe\n"), "{}", decompiled);
+    assert!(decompiled.contains("#EX-PSG ABC\n#EX-AY8910 DEF\n"), "{}", decompiled);
+    assert!(
+        decompiled.contains("A t150 v15 o4 c8^32 d8^32 e8^32 r8^32 g4^16 L c+16^96 d16^48\n"),
+        "{}",
+        decompiled
+    );
+    assert!(decompiled.contains("B t150 v10 o3 c4^16 c4^16 r4^16 L r8^32\n"), "{}", decompiled);
+    assert!(decompiled.contains("D t150 v12 o4 c8^32 e8^32 g8^32 r4^8^16^32 L r8^32\n"), "{}", decompiled);
+    // Silent channels are left out
+    assert!(!decompiled.contains("\nC "), "{}", decompiled);
+
+    // The decompiled MML keeps the song's length and loop point
+    let recompiled = compile_file_and_parse(&mml_path);
+    assert_eq!(recompiled.header.total_samples, 77175);
+    assert_eq!(recompiled.header.loop_samples, Some(77175 - 66150));
+}
+
+#[test]
+fn test_vgz_output() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let dir = tempdir().unwrap();
+    let mml = "#EX-PSG A\n#SFX hit = B\n#EX-PSG B\nA o4 l4 cdef\n";
+    let compile = |name: &str, gzip: bool| {
+        let path = dir.path().join(name);
+        let mut compiler = Compiler::new();
+        compiler.gzip = gzip;
+        compiler.compile(Cursor::new(mml), &path).expect("Compilation failed");
+        std::fs::read(path).unwrap()
+    };
+    let gunzip = |data: &[u8]| {
+        assert_eq!(&data[..2], &[0x1F, 0x8B]);
+        let mut out = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut out).unwrap();
+        out
+    };
+
+    let plain = compile("song.vgm", false);
+    // By extension, or forced for any name
+    assert_eq!(gunzip(&compile("song.vgz", false)), plain);
+    assert_eq!(gunzip(&compile("forced.vgm", true)), plain);
+
+    // #SFX outputs follow the song's extension and compression
+    let sfx = std::fs::read(dir.path().join("song.hit.vgz")).unwrap();
+    assert_eq!(&gunzip(&sfx)[..4], b"Vgm ");
+}
+
+#[test]
+fn test_compile_to_writer() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let mml = "#TITLE Buffer\n#EX-PSG A\nA o4 l4 c d L e f\n";
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("song.vgm");
+    Compiler::new().compile(Cursor::new(mml), &path).expect("Compilation failed");
+    let file = std::fs::read(&path).unwrap();
+
+    // Same bytes as the file, without touching the file system
+    let mut buffer = Vec::new();
+    Compiler::new()
+        .compile_to_writer(Cursor::new(mml), &mut buffer)
+        .expect("Compilation failed");
+    assert_eq!(buffer, file);
+
+    let mut compiler = Compiler::new();
+    compiler.gzip = true;
+    let mut gzipped = Vec::new();
+    compiler.compile_to_writer(Cursor::new(mml), &mut gzipped).expect("Compilation failed");
+    let mut plain = Vec::new();
+    GzDecoder::new(gzipped.as_slice()).read_to_end(&mut plain).unwrap();
+    assert_eq!(plain, file);
+}
+
+#[test]
+fn test_compile_file_to_writer() {
+    let dir = tempdir().unwrap();
+    std::fs::write(dir.path().join("chips.mml"), "#EX-PSG ABC\n").unwrap();
+    let input = dir.path().join("song.mml");
+    std::fs::write(&input, "#INCLUDE chips.mml\n#SFX blip = C\n#LYRICS LRC\nA o4 l4 \"la\" c d\nC e\n").unwrap();
+
+    // Includes resolve as for compile_file, and only the compiled file is
+    // produced: no #SFX output or lyrics next to the input
+    let mut buffer = Vec::new();
+    Compiler::new().compile_file_to_writer(&input, &mut buffer).expect("Compilation failed");
+    let mut names: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    assert_eq!(names, ["chips.mml", "song.mml"]);
+
+    let output = dir.path().join("out.vgm");
+    Compiler::new().compile_file(&input, &output).expect("Compilation failed");
+    assert_eq!(buffer, std::fs::read(&output).unwrap());
+    let analysis = vgmck::vgm::analyze::analyze_bytes(&buffer).unwrap();
+    assert_eq!(analysis.total_samples, 44100);
+}
+
+#[test]
+fn test_pitch_envelope() {
+    // o4 c is period 0x35 on the PSG; offsets 16, 32 then 48 raise the high bits
+    let vgm = compile_and_parse("#EX-PSG A\n@EP0 = { 0 16 32 | 48 }\nA v15 o4 l8 @EP0 c EPOF c\n");
+    let writes: Vec<u8> = vgm
+        .commands
+        .iter()
+        .filter_map(|c| match c {
+            VgmCommand::Sn76489Write { data } => Some(*data),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(writes[..7], [0x90, 0x85, 0x03, 0x03, 0x04, 0x05, 0x06]);
+    assert!(writes[7..].iter().take_while(|&&d| d != 0x9F).all(|&d| d == 0x06));
+
+    // After EPOF the note is written once
+    let second = writes.iter().rposition(|&d| d == 0x90).unwrap();
+    assert_eq!(writes[second..], [0x90, 0x85, 0x03, 0x9F]);
+}
+
+#[test]
+fn test_frame_rate_command() {
+    let volume_writes = |vgm: &VgmJson| count_commands(vgm, |c| matches!(c, VgmCommand::Sn76489Write { data } if data & 0xF0 == 0x90));
+
+    // A quarter note at t120 is 30 frames at 60 Hz and 15 frames at 30 Hz, plus the note off
+    let song_rate = compile_and_parse("#EX-PSG A\n@v0 = { | 15 14 }\nA @v0 o4 c4\n");
+    let half_rate = compile_and_parse("#EX-PSG A\n@v0 = { | 15 14 }\nA R30 @v0 o4 c4\n");
+    assert_eq!(volume_writes(&song_rate), 30 + 1);
+    assert_eq!(volume_writes(&half_rate), 15 + 1);
+
+    // `@w` waits count frames at the channel's rate; R0 restores the song rate
+    let vgm = compile_and_parse("#EX-PSG A\nA R50 @w10 R0 @w10\n");
+    assert_eq!(vgm.header.total_samples, 10 * 882 + 10 * 735);
+
+    // Rates above the sample rate are clamped to a frame per sample
+    let (vgm, warnings) = compile_warnings("#EX-PSG A\n@v0 = { | 15 14 }\nA R50000 @v0 o4 c4\n");
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("frame rate `R50000` is above 44100 Hz; clamped to 44100"), "{:?}", warnings);
+    let header = VgmReader::new(&vgm).parse_header().unwrap();
+    assert_eq!(header.total_samples, 22050);
+    let (vgm, warnings) = compile_warnings("#EX-PSG A\nA R-1 c R0 d R-99999999999 e\n");
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert_eq!(VgmReader::new(&vgm).parse_header().unwrap().total_samples, 3 * 22050);
+    let (_, warnings) = compile_warnings("#EX-PSG A\n#RATE 99999\nA c\n");
+    assert!(warnings.iter().any(|w| w == "#RATE: 99999 Hz is above 44100 Hz; clamped to 44100"), "{:?}", warnings);
+}
+
+#[test]
+fn test_basic_octave_and_noise_options() {
+    fn first_tone(vgm: &VgmJson) -> Vec<u8> {
+        vgm.commands
+            .iter()
+            .filter_map(|c| match c {
+                VgmCommand::Sn76489Write { data } => Some(*data),
+                _ => None,
+            })
+            .filter(|&data| data & 0x90 == 0x80)
+            .take(1)
+            .collect()
+    }
+
+    // `o:n` moves the chip's basic octave, so the same note plays an octave lower per step
+    let default = compile_and_parse("#EX-PSG A\nA o4 l4 c\n");
+    let moved = compile_and_parse("#EX-PSG A o:1\nA o5 l4 c\n");
+    assert_eq!(first_tone(&default).len(), 1);
+    assert_eq!(first_tone(&default), first_tone(&moved));
+
+    // `N:1` plays the 2A03 noise channel with short noise (mode bit of $400E)
+    let noise_period = |mml: &str| -> Vec<u8> {
+        let vgm = compile_and_parse(mml);
+        vgm.commands
+            .iter()
+            .filter_map(|c| match c {
+                VgmCommand::NesApuWrite { reg: 0x0E, data } => Some(*data),
+                _ => None,
+            })
+            .collect()
+    };
+    let long = noise_period("#EX-2A03 AB,C,D\nD o4 l4 c\n");
+    let short = noise_period("#EX-2A03 AB,C,D N:1\nD o4 l4 c\n");
+    assert!(!long.is_empty());
+    assert_eq!(short, long.iter().map(|data| data | 0x80).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_text_chip_options() {
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("song.mml");
+    std::fs::write(&input, "#EX-PSG A H=3579545,W=wave.bin R=\"my rom.bin\" F=9\n#OPTION PSG feedback=3\nA c\n").unwrap();
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_file(&input, &dir.path().join("song.vgm"))
+        .expect("Compilation failed");
+
+    let options = &compiler.chips["PSG"].options;
+    assert_eq!((options.get('H'), options.get('F')), (3579545, 3));
+    assert_eq!(options.get_str('W'), Some("wave.bin"));
+    assert_eq!(options.get_str('R'), Some("my rom.bin"));
+    assert_eq!(options.get_path('R'), Some(dir.path().join("my rom.bin")));
+    assert_eq!(options.get_str('H'), None);
+}
+
+#[test]
+fn test_check_file() {
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("song.mml");
+    std::fs::write(&input, "#EX-PSG AB\n#SFX jump = B\nA c [d\nB e %1\n").unwrap();
+    let mut compiler = Compiler::new();
+    compiler.check_file(&input).expect("Check failed");
+
+    // The song and the #SFX channels are checked, and nothing is written
+    let messages: Vec<_> = compiler.diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "channel A (line 3, bar 1:2): unterminated loop `[`, close it with `]N`",
+            "channel B (line 4, bar 1:2): #SFX jump: unknown command `%1`",
+        ]
+    );
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_errors_reported_in_one_pass() {
+    use vgmck::compiler::diagnostic::{code, Severity};
+
+    // Two undeclared channels and a typo: all three are found before compilation fails
+    let mml = "#EX-PSG A\nA c %1 d\nB e\nA f\nC g\n";
+    let dir = tempdir().unwrap();
+    let output = dir.path().join("test.vgm");
+    let mut compiler = Compiler::new();
+    match compiler.compile(Cursor::new(mml), &output) {
+        Err(vgmck::Error::MmlSyntax { line, message, .. }) => {
+            assert_eq!(line, 3);
+            assert!(message.starts_with("channel B is not declared"), "{}", message);
+        }
+        other => panic!("expected a syntax error, got {:?}", other.map(|_| ())),
+    }
+    let found: Vec<_> = compiler
+        .diagnostics
+        .iter()
+        .map(|d| (d.severity, d.code, d.span.map(|s| s.line)))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (Severity::Error, code::ERROR, Some(3)),
+            (Severity::Error, code::ERROR, Some(5)),
+            (Severity::Warning, code::UNKNOWN_COMMAND, Some(2)),
+        ]
+    );
+    assert_eq!(compiler.error_count(), 2);
+    assert!(!output.exists());
+
+    // The channels after a bad line are still compiled
+    assert!(compiler.events().any(|e| e.channel == Some('A') && e.time > 0));
+}
+
+#[test]
+fn test_dump_events() {
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_events(Cursor::new("#EX-PSG AB\nA o4 l8 c v10 d\nB r4 o5 g+\n"))
+        .expect("Compilation failed");
+
+    let records = compiler.dump_events();
+    let lines: Vec<String> = records.iter().map(|r| r.to_string()).collect();
+    assert_eq!(lines[0], "        0      0      1:1  A PSG: note on o4 c (key 48) for 11025 samples");
+    assert!(lines.contains(&"    11025     15    1:1.5  A PSG: Volume 10".to_string()), "{:#?}", lines);
+    assert!(lines.contains(&"    22050     30      1:2  B PSG: note on o5 g+ (key 68) for 22050 samples".to_string()), "{:#?}", lines);
+    assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+}
+
+#[test]
+fn test_automation_lane() {
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_events(Cursor::new("#EX-PSG ABC\n#AUTOMATE C volume 0=15 0.5=13 1=15\nC o4 l1 c c\n"))
+        .expect("Compilation failed");
+    let lines: Vec<String> = compiler.dump_events().iter().map(|r| r.to_string()).collect();
+    let volumes: Vec<&String> = lines.iter().filter(|l| l.contains("C PSG: Volume")).collect();
+    assert_eq!(volumes.len(), 5, "{:#?}", volumes);
+    assert!(volumes[0].starts_with("        0 "), "{:#?}", volumes);
+    assert!(volumes[0].ends_with("Volume 15"), "{:#?}", volumes);
+    assert!(volumes[2].contains("16905     23   1:1.77  C PSG: Volume 13"), "{:#?}", volumes);
+    assert!(volumes[4].ends_with("Volume 15"), "{:#?}", volumes);
+
+    // Bad lanes are warnings, not errors
+    let (_, warnings) = compile_warnings("#EX-PSG ABC\n#AUTOMATE C loudness 0=1\n#AUTOMATE C v 0:xx=1\nC c\n");
+    assert!(warnings.iter().any(|w| w.contains("unknown parameter 'loudness'")), "{:?}", warnings);
+    assert!(warnings.iter().any(|w| w.contains("expected time=value, got '0:xx=1'")), "{:?}", warnings);
+}
+
+#[test]
+fn test_json_round_trip() {
+    let dir = tempdir().unwrap();
+    let original = dir.path().join("song.vgm");
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(
+            Cursor::new("#TITLE Round trip\n#EX-PSG ABC\n#EX-OPN2 DEF\nA o4 l8 c d L e f\nD o3 l4 c g\n"),
+            &original,
+        )
+        .expect("Compilation failed");
+
+    let json_path = dir.path().join("song.json");
+    std::fs::write(&json_path, vgmck::vgm::json::vgm_to_json(&original).unwrap()).unwrap();
+    let copy = dir.path().join("copy.vgm");
+    vgmck::vgm::json::json_to_vgm(&json_path, &copy).unwrap();
+
+    let before = VgmJson::parse(&std::fs::read(&original).unwrap()).unwrap();
+    let after = VgmJson::parse(&std::fs::read(&copy).unwrap()).unwrap();
+    assert!(before.header.loop_command.is_some());
+    assert_eq!(serde_json::to_value(&before).unwrap(), serde_json::to_value(&after).unwrap());
+    assert_eq!(std::fs::read(&original).unwrap(), std::fs::read(&copy).unwrap());
+
+    // An edited command list is written as given
+    let mut edited: VgmJson = serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+    edited.commands.insert(0, VgmCommand::Sn76489Write { data: 0x9F });
+    edited.header.loop_command = edited.header.loop_command.map(|i| i + 1);
+    let mut data = Vec::new();
+    edited.write(&mut vgmck::vgm::VgmWriter::from_writer(&mut data)).unwrap();
+    let reparsed = VgmJson::parse(&data).unwrap();
+    assert!(matches!(reparsed.commands[0], VgmCommand::Sn76489Write { data: 0x9F }));
+    assert_eq!(reparsed.header.loop_command, edited.header.loop_command);
+}
+
+#[test]
+fn test_compile_to_document() {
+    let mml = "#TITLE Parts\n#EX-PSG A\nA o4 l4 c d L e f\n";
+    let mut buffer = Vec::new();
+    Compiler::new()
+        .compile_to_writer(Cursor::new(mml), &mut buffer)
+        .expect("Compilation failed");
+
+    let document = Compiler::new().compile_to_document(Cursor::new(mml)).expect("Compilation failed");
+    assert_eq!(document.to_bytes(), buffer);
+    assert!(document.header.starts_with(b"Vgm "));
+    assert_eq!(document.data.last(), Some(&0x66));
+    assert!(document.gd3.starts_with(b"Gd3 "));
+
+    // The GD3 offset in the header points just past the data
+    let gd3_offset = u32::from_le_bytes(document.header[0x14..0x18].try_into().unwrap()) as usize + 0x14;
+    assert_eq!(gd3_offset, document.header.len() + document.data.len());
+}
+
+#[test]
+fn test_loop_lcm() {
+    // Without #LOOP-LCM the loop is the last `L` to the longest channel
+    let vgm = compile_and_parse("#EX-PSG ABC\nA o4 l4 L c d e\nB o3 l4 L g a\n");
+    assert_eq!(vgm.header.total_samples, 66150);
+    assert_eq!(vgm.header.loop_samples, Some(66150));
+
+    // Three beats against two loop every six
+    let vgm = compile_and_parse("#EX-PSG ABC\n#LOOP-LCM\nA o4 l4 r4 L c d e\nB o3 l4 L g a\n");
+    assert_eq!(vgm.header.total_samples, 22050 * 7);
+    assert_eq!(vgm.header.loop_samples, Some(22050 * 6));
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_events(Cursor::new("#EX-PSG ABC\n#LOOP-LCM\nA o4 l4 r4 L c d e\nB o3 l4 L g a\n"))
+        .expect("Compilation failed");
+    let notes: Vec<String> = compiler
+        .dump_events()
+        .iter()
+        .filter(|r| r.description.starts_with("note on"))
+        .map(|r| format!("{} {}", r.time / 22050, &r.description[8..12]))
+        .collect();
+    assert_eq!(
+        notes,
+        ["0 o3 g", "1 o4 c", "1 o3 a", "2 o4 d", "2 o3 g", "3 o4 e", "3 o3 a", "4 o4 c", "4 o3 g", "5 o4 d", "5 o3 a", "6 o4 e", "6 o3 g"]
+    );
+
+    // A loop longer than the cap keeps the last `L`
+    let (vgm, warnings) = compile_warnings("#EX-PSG ABC\n#LOOP-LCM 1\nA o4 l4 L c d e\nB o3 l4 L g a\n");
+    assert_eq!(VgmReader::new(&vgm).parse_header().unwrap().total_samples, 66150);
+    assert!(warnings.iter().any(|w| w.contains("#LOOP-LCM")), "{:?}", warnings);
+}
+
+#[test]
+fn test_portamento() {
+    let tone_writes = |mml: &str| {
+        let vgm = compile_and_parse(mml);
+        count_commands(&vgm, |c| matches!(c, VgmCommand::Sn76489Write { data } if data & 0xF0 == 0x80))
+    };
+
+    // `/` alone is legato: one pitch change, no slide
+    assert_eq!(tone_writes("#EX-PSG A\nA v15 o4 l4 c/g\n"), 2);
+
+    // With a time the pitch slides into the note over that many frames
+    assert_eq!(tone_writes("#EX-PSG A\nA v15 o4 l4 @/0,4 c/g\n"), 2 + 4);
+    assert_eq!(tone_writes("#EX-PSG A\nA v15 o4 l4 @/0,4 c4 g4\n"), 2);
+
+    // Glissando steps through the scale; a step without a time stops at the note
+    assert_eq!(tone_writes("#EX-PSG A\nA v15 o4 l4 @/1,0,1 c/e\n"), 2 + 3);
+
+    // `&` ties slide over the `@/` time too
+    assert_eq!(tone_writes("#EX-PSG A\nA v15 o4 l4 @/0,4 c&g\n"), 2 + 4);
+}
+
+#[test]
+fn test_pitch_quantize() {
+    let periods = |mode: &str| {
+        psg_tone_periods(&compile_and_parse(&format!("#EX-PSG A\n#PITCH-QUANTIZE {}\nA o4 l8 c d e f g a b >c d e f g a b\n", mode)))
+    };
+
+    let down = periods("DOWN");
+    assert_eq!(down, [53, 47, 42, 40, 35, 31, 28, 26, 23, 21, 20, 17, 15, 14]);
+    let nearest = periods("NEAREST");
+    assert_eq!(nearest, [53, 48, 42, 40, 36, 32, 28, 27, 24, 21, 20, 18, 16, 14]);
+
+    // Diffusion stays within one step of the nearest value, but not on it
+    let diffuse = periods("DIFFUSE");
+    assert_eq!(diffuse.len(), nearest.len());
+    assert!(diffuse.iter().zip(&nearest).all(|(d, n)| d.abs_diff(*n) <= 1));
+    assert_ne!(diffuse, nearest);
+
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_events(Cursor::new("#EX-PSG A\n#PITCH-QUANTIZE UP\nA c\n"))
+        .expect("Compilation failed");
+    assert!(compiler.warnings.iter().any(|w| w.contains("#PITCH-QUANTIZE: unknown mode 'UP'")));
+}
+
+#[test]
+fn test_vibrato_macro() {
+    let periods = |mml: &str| psg_tone_periods(&compile_and_parse(mml));
+
+    // Two frames of delay, then a triangle of 3 either side over 8 frames
+    let vibrato = periods("#EX-PSG A\n@MP0 = 2 3 8\nA @MP0 o4 l4 c\n");
+    assert_eq!(vibrato[..9], [53, 54, 56, 55, 53, 52, 50, 51, 53]);
+
+    // MPOF stops it from the next note
+    let off = periods("#EX-PSG A\n@MP0 = 2 3 8\nA @MP0 o4 l4 c MPOF e\n");
+    assert_eq!(off.last(), Some(&42));
+    assert_eq!(off.len(), vibrato.len() + 1);
+}
+
+#[test]
+fn test_chip_aliases() {
+    for (alias, canonical) in [("NES", "2A03"), ("famicom", "2A03"), ("GAMEBOY", "DMG"), ("GB", "DMG"), ("GENESIS", "OPN2")] {
+        let song = |name: &str| format!("#EX-{} A\nA o4 l8 c d e\n", name);
+        let by_alias = compile_and_parse(&song(alias));
+        let by_name = compile_and_parse(&song(canonical));
+        assert_eq!(format!("{:?}", by_alias.commands), format!("{:?}", by_name.commands), "{}", alias);
+
+        // The chip is known by its canonical name, and #OPTION finds it by either
+        let mut compiler = Compiler::new();
+        let mml = format!("#EX-{} A\n#OPTION {} clock=1000000\nA c\n", alias, alias);
+        compiler.compile_events(Cursor::new(mml)).unwrap();
+        assert_eq!(compiler.chips.keys().collect::<Vec<_>>(), [canonical]);
+        assert!(compiler.warnings.iter().all(|w| !w.contains("#OPTION")), "{:?}", compiler.warnings);
+    }
+
+    let names: Vec<&str> = vgmck::chips::list_chips().iter().map(|info| info.name).collect();
+    assert!(names.contains(&"2A03") && !names.contains(&"NES"));
+    assert!(vgmck::chips::create_chip("MEGADRIVE").is_ok());
+    assert!(vgmck::chips::create_chip("NOPE").is_err());
+}
+
+#[test]
+fn test_volume_slide_and_fade() {
+    // PSG volume writes (attenuation of channel 0 or 1) with their times
+    let volumes = |mml: &str, channel: u8| {
+        let vgm = compile_and_parse(mml);
+        let mut time = 0u64;
+        let mut writes = Vec::new();
+        for command in &vgm.commands {
+            match command {
+                VgmCommand::Wait { samples } => time += *samples as u64,
+                VgmCommand::Sn76489Write { data } if data & 0xF0 == 0x90 | channel << 5 => writes.push((time, data & 15)),
+                _ => {}
+            }
+        }
+        (vgm, writes)
+    };
+
+    // One step per frame from v15 down to silence
+    let (_, slide) = volumes("#EX-PSG A\nA v15 @vs0,15 c1\n", 0);
+    let expected: Vec<(u64, u8)> = (0..=15).map(|i| (i * 735, i as u8)).collect();
+    assert_eq!(slide[..16], expected[..]);
+
+    // A slide longer than the note stops at the end of the channel, and a
+    // volume command stops it too
+    let (vgm, slide) = volumes("#EX-PSG ABC\nA v15 @vs0,300 c4\n", 0);
+    assert_eq!(vgm.header.total_samples, 22050);
+    assert!(slide.iter().all(|&(t, _)| t <= 22050), "{:?}", slide);
+    let (vgm, slide) = volumes("#EX-PSG A\nA v15 @vs0,5000 c4 v12 c4\n", 0);
+    assert_eq!(vgm.header.total_samples, 44100);
+    // The note-off, `v12` and the last note-off
+    assert_eq!(slide.iter().filter(|&&(t, _)| t >= 22050).map(|&(_, v)| v).collect::<Vec<_>>(), [15, 3, 15]);
+
+    // The fade scales down the volume every frame and ends the song
+    let (vgm, fade) = volumes("#EX-PSG AB\nA L v8 c1 c1 c1\nB v15 c1 c1 @fade30 c1\n", 1);
+    assert_eq!(vgm.header.total_samples, 176400 + 30 * 735);
+    assert_eq!(vgm.header.loop_offset, None);
+    let during: Vec<u8> = fade.iter().filter(|&&(t, _)| t > 176400).map(|&(_, v)| v).collect();
+    assert!(during.windows(2).all(|w| w[0] < w[1]), "{:?}", fade);
+    assert_eq!(during.last(), Some(&15));
+}
+
+#[test]
+fn test_remap_channels() {
+    let dir = tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("other.mml"),
+        "#EX-PSG AB\n*x ?B e ?.\n#REMAP A->B B->A\nA l4 c\nB ?A d ?. *x\n",
+    )
+    .unwrap();
+    let input = dir.path().join("song.mml");
+    std::fs::write(&input, "#EX-PSG AB\nA o4 l4 c\n#REMAP A->Q B->R\n#INCLUDE other.mml\n#REMAP\nB o4 l4 g\n").unwrap();
+
+    let mut compiler = Compiler::new();
+    compiler.parse_file(&input).unwrap();
+    assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+    let text = |c: usize| compiler.channels[c].as_ref().map(|ch| ch.text.clone());
+    let (q, r) = (vgmck::compiler::channel_index('Q').unwrap(), vgmck::compiler::channel_index('R').unwrap());
+
+    // The include's own #REMAP swaps its A and B on top of the outer map and
+    // ends with it; the bare #REMAP then ends the outer one
+    assert_eq!(text(0).as_deref(), Some(" o4 l4 c"));
+    assert_eq!(text(1).as_deref(), Some(" o4 l4 g"));
+    assert_eq!(text(q).as_deref(), Some(" ?R d ?.  ?R e ?."));
+    assert_eq!(text(r).as_deref(), Some(" l4 c"));
+}
+
+#[test]
+fn test_conditional_blocks() {
+    let mml = "#IFNDEF SMS\n#DEFINE GENESIS\n#ENDIF\n\
+               #IFDEF GENESIS\n#EX-OPN2 A\n#ELSE\n#EX-PSG A\n#ENDIF\n#IFDEF NONE\n#EOF\n#ENDIF\nA o4 l4 c\n";
+    let commands = |defines: &[&str]| {
+        let mut compiler = Compiler::new();
+        defines.iter().for_each(|name| compiler.define(name));
+        let mut vgm = Vec::new();
+        compiler.compile_to_writer(Cursor::new(mml), &mut vgm).unwrap();
+        assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+        let mut reader = VgmReader::new(&vgm);
+        let header = reader.parse_header().unwrap();
+        reader.parse_commands(&header).unwrap()
+    };
+
+    // GENESIS is defined by the file unless SMS is given; #EOF in the
+    // skipped branch does not stop reading
+    let genesis = commands(&[]);
+    assert!(genesis.iter().any(|c| matches!(c, VgmCommand::Ym2612Write { .. })));
+    assert!(!genesis.iter().any(|c| matches!(c, VgmCommand::Sn76489Write { .. })));
+    let sms = commands(&["SMS"]);
+    assert!(sms.iter().any(|c| matches!(c, VgmCommand::Sn76489Write { .. })));
+    assert!(!sms.iter().any(|c| matches!(c, VgmCommand::Ym2612Write { .. })));
+
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("open.mml");
+    std::fs::write(&input, "#IFDEF X\n#ELSE\n#ELSE\n").unwrap();
+    let mut compiler = Compiler::new();
+    compiler.parse_file(&input).unwrap();
+    assert_eq!(compiler.warnings.len(), 2, "{:?}", compiler.warnings);
+    assert!(compiler.warnings[1].contains("line 1: #IFDEF without #ENDIF"), "{:?}", compiler.warnings);
+}
+
+#[test]
+fn test_converter_signature() {
+    use vgmck::compiler::ConverterSignature;
+
+    let converter = |policy: ConverterSignature| {
+        let mut compiler = Compiler::new();
+        compiler.converter_signature = policy;
+        let mut vgm = Vec::new();
+        let mml = "#PROGRAMER me\n#PROGRAMER vgmck 0.0.1\n#EX-PSG A\nA c\n";
+        compiler.compile_to_writer(Cursor::new(mml), &mut vgm).unwrap();
+        let mut reader = VgmReader::new(&vgm);
+        let header = reader.parse_header().unwrap();
+        reader.parse_gd3(&header).unwrap().unwrap().converter
+    };
+
+    let signature = ConverterSignature::signature();
+    assert_eq!(converter(ConverterSignature::Off), "me\nvgmck 0.0.1");
+    assert_eq!(converter(ConverterSignature::Append), format!("me\n{}", signature));
+    assert_eq!(converter(ConverterSignature::Replace), signature);
+    assert_eq!(ConverterSignature::Append.apply(&ConverterSignature::Append.apply("")), signature);
+    assert!("sign".parse::<ConverterSignature>().is_err());
+}
+
+#[test]
+fn test_track_sections() {
+    let mml = "#EX-PSG A\n#TRACK 2\n#TITLE Two\nA l4 c d\n#TRACK 1\n#TITLE One\nA l4 c\n#TRACK 2\nA e\n";
+    let song = |track: Option<u32>| {
+        let mut compiler = Compiler::new();
+        compiler.track = track;
+        let result = compiler.compile_to_writer(Cursor::new(mml), std::io::sink());
+        (compiler, result)
+    };
+
+    // The first section read is the default, and sections of one number add up
+    let (compiler, result) = song(None);
+    result.unwrap();
+    assert_eq!(compiler.tracks, [2, 1]);
+    assert_eq!(compiler.gd3_text[vgmck::compiler::gd3::TITLE_EN], "Two");
+    assert_eq!(compiler.total_samples, 3 * 22050);
+    let (compiler, result) = song(Some(1));
+    result.unwrap();
+    assert_eq!(compiler.gd3_text[vgmck::compiler::gd3::TITLE_EN], "One");
+    assert_eq!(compiler.total_samples, 22050);
+    assert!(matches!(song(Some(3)).1, Err(vgmck::Error::UnknownTrack(3))));
+
+    let output = Path::new("dir/song.vgz");
+    assert_eq!(Compiler::track_path(output, 2), Path::new("dir/song_02.vgz"));
+}
+
+#[test]
+fn test_echo_channel() {
+    // PSG tone channel writes of the first chip: (time, channel, volume)
+    let volumes = |mml: &str| {
+        let (vgm, warnings) = compile_warnings(mml);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let mut reader = VgmReader::new(&vgm);
+        let header = reader.parse_header().unwrap();
+        let mut time = 0;
+        let mut writes = Vec::new();
+        for command in reader.parse_commands(&header).unwrap() {
+            match command {
+                VgmCommand::Wait { samples } => time += samples as u64,
+                VgmCommand::Sn76489Write { data } if data & 0x90 == 0x90 && data & 0x60 != 0x60 => {
+                    writes.push((time, (data >> 5) & 3, 15 - (data & 15)));
+                }
+                _ => {}
+            }
+        }
+        (header.total_samples, writes)
+    };
+
+    // The echo takes C's free channel, 10 frames later and 4 steps quieter
+    let (total, writes) = volumes("#EX-PSG AB\nA @echo4,10 v15 l4 c\n");
+    assert_eq!(total, 22050 + 7350);
+    assert!(writes.contains(&(0, 0, 15)), "{:?}", writes);
+    assert!(writes.contains(&(7350, 2, 11)), "{:?}", writes);
+
+    // In a looping song the echo ends with the loop
+    let (total, writes) = volumes("#EX-PSG AB\nA L @echo4,10 v15 l4 c\n");
+    assert_eq!(total, 22050);
+    assert!(writes.iter().all(|&(time, _, _)| time <= 22050), "{:?}", writes);
+
+    let (_, warnings) = compile_warnings("#EX-PSG ABC\nA @echo4,10 c\n#EX-OPN C\nC @echo2,0 c\n");
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("@echo"), "{:?}", warnings);
+}
+
+#[test]
+fn test_delays_round_trip() {
+    use vgmck::vgm::delay::generate_delay;
+
+    // Every short delay, the edges of the 0x61 range and a pseudo-random spread
+    let mut delays: Vec<u64> = (0..=4000).chain(65000..=67500).chain(130_000..=133_000).collect();
+    let mut seed = 12345u64;
+    for _ in 0..2000 {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        delays.push((seed >> 40) % 1_000_000);
+    }
+
+    // Each delay, ended by a PSG write to tell them apart
+    let mut data = Vec::new();
+    let mut writer = vgmck::vgm::VgmWriter::from_writer(&mut data);
+    writer.write_header().unwrap();
+    for &delay in &delays {
+        let bytes = generate_delay(delay);
+        assert!(bytes.len() as u64 <= 3 * delay.div_ceil(65535), "{} takes {:?}", delay, bytes);
+        writer.write_delay(delay).unwrap();
+        writer.write_data(&[0x50, 0x9F]).unwrap();
+    }
+    writer.finalize(&vgmck::compiler::Gd3Metadata::default()).unwrap();
+    drop(writer);
+
+    let mut reader = VgmReader::new(&data);
+    let header = reader.parse_header().unwrap();
+    let mut read = Vec::new();
+    let mut samples = 0;
+    for command in reader.parse_commands(&header).unwrap() {
+        match command {
+            VgmCommand::Wait { samples: wait } => samples += wait as u64,
+            VgmCommand::Sn76489Write { .. } => read.push(std::mem::take(&mut samples)),
+            _ => {}
+        }
+    }
+    assert_eq!(read, delays);
+}
+
+#[test]
+fn test_noise_map() {
+    let commands = |mml: &str| {
+        let mut compiler = Compiler::new();
+        let mut vgm = Vec::new();
+        compiler.compile_to_writer(Cursor::new(mml), &mut vgm).unwrap();
+        assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+        let mut reader = VgmReader::new(&vgm);
+        let header = reader.parse_header().unwrap();
+        reader.parse_commands(&header).unwrap()
+    };
+
+    // PSG noise: the written degree picks the noise control value, in every octave
+    let mml = "#EX-PSG A,N\n@NMAP0 = { 4 5 6 7 4 5 6 7 4 5 6 7 }\nN v15 @NMAP0 l4 o3 c d e f > c\n";
+    let noise: Vec<u8> = commands(mml)
+        .into_iter()
+        .filter_map(|command| match command {
+            VgmCommand::Sn76489Write { data } if data & 0xF0 == 0xE0 => Some(data & 7),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(noise, [4, 6, 4, 5, 4]);
+
+    // AY: the noise period follows the degree, and the tone plays as written
+    let mml = "#EX-AY8910 A\n@NMAP0 = { 3 0 9 }\nA v15 @NMAP0 l4 o4 c d\n";
+    let writes: Vec<(u8, u8)> = commands(mml)
+        .into_iter()
+        .filter_map(|command| match command {
+            VgmCommand::Ay8910Write { reg, data } if reg == 0x06 || reg == 0x00 => Some((reg, data)),
+            _ => None,
+        })
+        .collect();
+    let periods: Vec<u8> = writes.iter().filter(|&&(reg, _)| reg == 0x06).map(|&(_, data)| data).collect();
+    assert_eq!(periods, [3, 9]);
+    assert!(writes.iter().any(|&(reg, data)| reg == 0x00 && data != 0), "{:?}", writes);
+}
+
+#[test]
+fn test_chord_groups() {
+    use vgmck::compiler::event::EventSource;
+
+    let mut compiler = Compiler::new();
+    let mml = "#EX-PSG ABC\n#GROUP X = ABC\nX o4 l4 'ceg' 'df'2 '<b>d'\n";
+    compiler.compile_events(Cursor::new(mml)).unwrap();
+    assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+    let played = |ch: char| -> Vec<(u64, Option<i32>)> {
+        compiler
+            .events()
+            .filter(|e| e.channel == Some(ch))
+            .filter_map(|e| match e.source {
+                EventSource::NoteOn { key, .. } => Some((e.time as u64, Some(key))),
+                EventSource::Rest => Some((e.time as u64, None)),
+                _ => None,
+            })
+            .collect()
+    };
+
+    // Each channel takes its voice of every chord, and rests where a chord has none
+    assert_eq!(played('A'), [(0, Some(48)), (22050, Some(50)), (66150, Some(47))]);
+    assert_eq!(played('B'), [(0, Some(52)), (22050, Some(53)), (66150, Some(50))]);
+    assert_eq!(played('C'), [(0, Some(55)), (22050, None), (66150, None)]);
+
+    // The octave is back after each chord, and extra notes are dropped with a warning
+    let mut compiler = Compiler::new();
+    compiler.compile_events(Cursor::new("#EX-PSG AB\n#GROUP X = AB\nX o4 'c>c' c 'ceg'\n")).unwrap();
+    let text = |c: usize| compiler.channels[c].as_ref().unwrap().text.clone();
+    assert_eq!((text(0).trim().to_string(), text(1).trim().to_string()), ("o4 c c c".into(), "o4 >c< c e".into()));
+    assert_eq!(compiler.warnings.len(), 1, "{:?}", compiler.warnings);
+    assert!(compiler.warnings[0].contains("3 notes for 2 channels"), "{:?}", compiler.warnings);
+}
+
+#[test]
+fn test_groove() {
+    use vgmck::compiler::event::EventSource;
+
+    let starts = |mml: &str| -> Vec<u64> {
+        let mut compiler = Compiler::new();
+        compiler.compile_events(Cursor::new(mml)).unwrap();
+        assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+        compiler
+            .events()
+            .filter(|e| matches!(e.source, EventSource::NoteOn { .. } | EventSource::Rest))
+            .map(|e| e.time as u64)
+            .collect()
+    };
+
+    // Eighths at 120 BPM are 11025 samples; swung 7:5 they alternate 12862 and 9187
+    let swung = starts("#EX-PSG A\n#GROOVE 1 = 7 5\nA l8 @g1 c d e4 f g @g-1 a b\n");
+    assert_eq!(swung, [0, 12862, 22050, 44100, 56962, 66150, 77175]);
+
+    // A longer pattern ends where the straight notes would
+    let shuffle = starts("#EX-PSG A\n#GROOVE 0 = 2 1 2 1 1 1\nA l16 @g0 cccccc c\n");
+    assert_eq!(shuffle[6], 6 * 5512);
+    assert_eq!(shuffle[1], 5512 * 6 * 2 / 8);
+
+    let (_, warnings) = compile_warnings("#EX-PSG A\n#GROOVE 1 = 7 0\nA @g2 c\n");
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+}
+
+#[test]
+fn test_wide_channels() {
+    use vgmck::compiler::event::EventSource;
+
+    let mut compiler = Compiler::new();
+    let mml = "#EX-PSG AB\n#WIDE chan=A depth=2\nA o4 l8 c \"la\"\n";
+    compiler.compile_events(Cursor::new(mml)).unwrap();
+    assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+    let notes = |copy: bool| -> Vec<(i64, EventSource)> {
+        compiler
+            .events()
+            .filter(|e| (e.channel == Some('A')) != copy && e.channel.is_some())
+            .filter(|e| matches!(e.source, EventSource::NoteOn { .. } | EventSource::NoteChange { .. }))
+            .map(|e| (e.time, e.source))
+            .collect()
+    };
+
+    // The original plays as written; the copy on the second chip swings
+    // either side of its pitch every frame
+    assert_eq!(notes(false), [(0, EventSource::NoteOn { key: 48, duration: 11025 })]);
+    let copy = notes(true);
+    assert_eq!(copy[0], (0, EventSource::NoteOn { key: 48, duration: 11025 }));
+    let pitches: Vec<(i64, i32)> = copy[1..]
+        .iter()
+        .map(|&(time, source)| match source {
+            EventSource::NoteChange { value, .. } => (time, value),
+            other => panic!("{:?}", other),
+        })
+        .collect();
+    assert_eq!(pitches.len(), 15);
+    let base = pitches[0].1 - 2;
+    for (frame, &(time, value)) in pitches.iter().enumerate() {
+        assert_eq!(time, frame as i64 * 735);
+        assert_eq!(value, if frame % 2 == 0 { base + 2 } else { base - 2 });
+    }
+    assert_eq!(compiler.lyrics.len(), 1);
+
+    // The OPN has no second chip to play on
+    let (_, warnings) = compile_warnings("#EX-OPN A\n#WIDE chan=A depth=2\nA c\n");
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("no second chip"), "{:?}", warnings);
+}
+
+#[test]
+fn test_note_range_clamping() {
+    let mut compiler = Compiler::new();
+    let mut vgm = Vec::new();
+    let mml = "#EX-PSG A o:2\nA o0 c o12 c o4 c\n";
+    compiler.compile_to_writer(Cursor::new(mml), &mut vgm).unwrap();
+
+    // Too low and too high for the 10-bit period, each reported where written
+    assert_eq!(compiler.warnings.len(), 2, "{:?}", compiler.warnings);
+    assert!(compiler.warnings[0].contains("line 2"), "{:?}", compiler.warnings);
+    assert!(compiler.warnings[0].contains("key 0 (o0) needs a PSG period of"), "{:?}", compiler.warnings);
+    assert!(compiler.warnings[0].contains("clamped to 1023"), "{:?}", compiler.warnings);
+    assert!(compiler.warnings[1].contains("clamped to 1"), "{:?}", compiler.warnings);
+
+    // The clamped periods are written rather than their low bits
+    let mut reader = VgmReader::new(&vgm);
+    let header = reader.parse_header().unwrap();
+    let writes: Vec<u8> = reader
+        .parse_commands(&header)
+        .unwrap()
+        .into_iter()
+        .filter_map(|command| match command {
+            VgmCommand::Sn76489Write { data } => Some(data),
+            _ => None,
+        })
+        .collect();
+    assert!(writes.windows(2).any(|w| w == [0x8F, 0x3F]), "{:02X?}", writes);
+    assert!(writes.windows(2).any(|w| w == [0x81, 0x00]), "{:02X?}", writes);
+}
+
+#[test]
+fn test_header_sample_limits() {
+    // 500 whole rests at 1 BPM run past the 32-bit sample count
+    let mut compiler = Compiler::new();
+    let result = compiler.compile_to_writer(Cursor::new("#EX-PSG A\nA t1 [r1]500\n"), &mut Vec::new());
+    match result {
+        Err(vgmck::Error::SongTooLong(samples)) => assert_eq!(samples, 500 * 10584000),
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+
+    // Fast-forwarding past the loop point leaves no loop to play
+    let (vgm, warnings) = compile_warnings("#EX-PSG A\nA c4 L c4 @!0\n");
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("skips past the loop point"), "{:?}", warnings);
+    let header = VgmReader::new(&vgm).parse_header().unwrap();
+    assert_eq!(header.loop_samples, 0);
+}
+
+#[test]
+fn test_gd3_text_fields() {
+    use vgmck::compiler::diagnostic::code;
+
+    let mml = r#"
+#TEXT5 System JP
+#TEXT11 Nowhere
+#TEXT Nothing
+#CONVERTER Someone
+#COPYRIGHT 2024 Someone
+#LICENSE CC BY 4.0
+#EX-PSG A
+A c
+"#;
+    let dir = tempdir().unwrap();
+    let output = dir.path().join("test.vgm");
+    let mut compiler = Compiler::new();
+    compiler.compile(Cursor::new(mml), &output).expect("Compilation failed");
+    let lines: Vec<_> = compiler
+        .diagnostics
+        .iter()
+        .map(|d| (d.code, d.span.map(|s| s.line)))
+        .collect();
+    assert_eq!(lines, [(code::UNKNOWN_GD3_FIELD, Some(3)), (code::UNKNOWN_GD3_FIELD, Some(4))]);
+    assert!(compiler.warnings[0].contains("no GD3 field 11 (they are 0 to 10)"), "{:?}", compiler.warnings);
+
+    let gd3 = VgmJson::parse(&std::fs::read(&output).unwrap()).unwrap().gd3.expect("GD3 should be present");
+    assert_eq!(gd3.system_jp, "System JP");
+    assert_eq!(gd3.converter, "Someone");
+    assert_eq!(gd3.notes, "Copyright: 2024 Someone\nLicense: CC BY 4.0");
+
+    let mut compiler = Compiler::new();
+    compiler.set_strict(true);
+    match compiler.compile(Cursor::new(mml), &output) {
+        Err(vgmck::Error::MmlSyntax { line, message, .. }) => {
+            assert_eq!(line, 3);
+            assert!(message.ends_with("[W0002]"), "{}", message);
+        }
+        other => panic!("expected a syntax error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_chip_declaration_order() {
+    // Chips are set up in the order of their #EX- lines, not by name
+    let first_write = |mml: &str| {
+        let vgm = compile_and_parse(mml);
+        vgm.commands
+            .iter()
+            .find(|c| matches!(c, VgmCommand::GbDmgWrite { .. } | VgmCommand::Ay8910Write { .. }))
+            .cloned()
+    };
+    assert!(matches!(first_write("#EX-DMG B\n#EX-AY8930 A\nA o4 c\nB o4 c\n"), Some(VgmCommand::GbDmgWrite { .. })));
+    assert!(matches!(first_write("#EX-AY8930 A\n#EX-DMG B\nA o4 c\nB o4 c\n"), Some(VgmCommand::Ay8910Write { .. })));
+
+    // and the same song always compiles to the same bytes
+    let dir = tempdir().unwrap();
+    let mml = "#EX-DMG B\n#EX-AY8930 A\n#EX-OPN2 C\n#EX-PSG D\nA o4 c\nB o4 c\nC o4 c\nD o4 c\n";
+    let compile = |name: &str| {
+        let path = dir.path().join(name);
+        Compiler::new().compile(Cursor::new(mml), &path).expect("Compilation failed");
+        std::fs::read(path).unwrap()
+    };
+    assert_eq!(compile("a.vgm"), compile("b.vgm"));
+}
+
+#[test]
+fn test_optimize() {
+    let mml = "#EX-OPN2 AB\n#EX-GI-AY C\n@v0 = { 100 100 100 | 90 }\nA @v0 o4 l8 cdef L gab>c\nB @v0 o3 l4 c L e\nC @v0 o5 l4 c L d\n";
+    let dir = tempdir().unwrap();
+    let compile = |optimize: bool| {
+        let path = dir.path().join(format!("{}.vgm", optimize));
+        let mut compiler = Compiler::new();
+        compiler.optimize = optimize;
+        compiler.compile(Cursor::new(mml), &path).expect("Compilation failed");
+        std::fs::read(path).unwrap()
+    };
+    let (plain, optimized) = (compile(false), compile(true));
+    assert!(optimized.len() < plain.len(), "{} >= {} bytes", optimized.len(), plain.len());
+
+    // Same length, loop and tag; the loop starts at the same time
+    let loop_time = |json: &VgmJson| {
+        json.commands[..json.header.loop_command.expect("no loop")]
+            .iter()
+            .map(|c| match c {
+                VgmCommand::Wait { samples } => *samples as u64,
+                _ => 0,
+            })
+            .sum::<u64>()
+    };
+    let (plain, optimized) = (VgmJson::parse(&plain).unwrap(), VgmJson::parse(&optimized).unwrap());
+    assert_eq!(plain.header.total_samples, optimized.header.total_samples);
+    assert_eq!(plain.header.loop_samples, optimized.header.loop_samples);
+    assert_eq!(loop_time(&plain), loop_time(&optimized));
+    assert!(optimized.gd3.is_some());
+    assert!(matches!(optimized.commands.last(), Some(VgmCommand::End)));
+}
+
+#[test]
+fn test_sample_memory_budget() {
+    let dir = tempdir().unwrap();
+    // Each takes most of a bank, so no two share one
+    std::fs::write(dir.path().join("big.raw"), vec![0x80u8; 40000]).unwrap();
+    let song = |count: usize| {
+        let mut mml = String::from("#EX-SegaPCM A\n");
+        for i in 0..count {
+            mml.push_str(&format!("@S{} = {{ \"big.raw\" }}\n", i));
+        }
+        mml + "A @S0 o6 c4\n"
+    };
+    let input = dir.path().join("song.mml");
+    let output = dir.path().join("song.vgm");
+
+    std::fs::write(&input, song(8)).unwrap();
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    compiler.compile_file(&input, &output).unwrap();
+    let memory = &compiler.sample_memory()[0];
+    assert_eq!(memory.0, "SegaPCM");
+    assert_eq!((memory.1.used, memory.1.capacity), (7 * 65536 + 40192, 8 * 65536));
+    assert_eq!(memory.1.samples.len(), 8);
+
+    // A ninth sample needs a ninth bank
+    std::fs::write(&input, song(9)).unwrap();
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    let error = compiler.compile_file(&input, &output).unwrap_err().to_string();
+    assert!(error.contains("SegaPCM sample memory holds 524288 bytes but the samples take 564480"), "{}", error);
+    assert!(error.contains("@S8 40192, bank padding 202752)"), "{}", error);
+}
+
+#[test]
+fn test_compile_report() {
+    let dir = tempdir().unwrap();
+    let input = dir.path().join("song.mml");
+    let output = dir.path().join("song.vgm");
+    std::fs::write(&input, "#EX-PSG ABC\nA t120 l4 o4 c d L e f\nB t120 l2 o3 c r\n").unwrap();
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    compiler.compile_file(&input, &output).unwrap();
+
+    let report = compiler.compile_report();
+    let channels: Vec<_> = report.channels.iter().map(|c| (c.channel, c.chip.as_str(), c.notes, c.loop_point)).collect();
+    assert_eq!(channels, [('A', "PSG", 4, Some(44100)), ('B', "PSG", 1, None), ('C', "PSG", 0, None)]);
+    // Four quarter notes at 120 BPM
+    assert_eq!(report.channels[0].samples, 88200);
+    assert_eq!((report.total_samples, report.loop_samples), (88200, Some(44100)));
+    assert!(report.register_writes > 0);
+    // Every PSG write is two bytes
+    assert_eq!(report.chip_bytes["SN76489"], report.register_writes * 2);
+    // The longest gap is B's half note: C has nothing to play
+    assert_eq!(report.longest_wait, 22050);
+
+    let text = report.to_string();
+    assert!(text.starts_with("Channel  Chip        Notes     Length       Loop\n"), "{}", text);
+    assert!(text.contains("\n   A     PSG             4   0:02.000   0:01.000\n"), "{}", text);
+    assert!(text.contains("\nLength 0:02.000, loop 0:01.000\n"), "{}", text);
+}
+
+#[test]
+fn test_note_off_overlap() {
+    use vgmck::compiler::event::EventSource;
+    let sources = |mml: &str| -> Vec<(i64, bool)> {
+        let mut compiler = Compiler::new();
+        compiler.report = false;
+        compiler.compile_events(Cursor::new(mml)).unwrap();
+        compiler
+            .events()
+            .filter_map(|e| match e.source {
+                EventSource::NoteOn { .. } => Some((e.time, true)),
+                EventSource::NoteOff => Some((e.time, false)),
+                _ => None,
+            })
+            .collect()
+    };
+    // Each note's note-off falls on the next note-on, and is written first
+    let song = "#EX-PSG A\nA t120 l4 o4 c d e\n";
+    assert_eq!(
+        sources(song),
+        [(0, true), (22050, false), (22050, true), (44100, false), (44100, true), (66150, false)]
+    );
+    assert_eq!(sources(&format!("#NOTE-OFF-OVERLAP OFF-BEFORE-ON\n{}", song)), sources(song));
+    // Dropped, only the last note is keyed off
+    assert_eq!(
+        sources(&format!("#NOTE-OFF-OVERLAP DROP-OFF\n{}", song)),
+        [(0, true), (22050, true), (44100, true), (66150, false)]
+    );
+    // A gap from `@q` keeps the note-offs
+    assert_eq!(sources("#NOTE-OFF-OVERLAP DROP-OFF\n#EX-PSG A\nA t120 l4 @q1 o4 c d\n").len(), 4);
+
+    let (_, warnings) = compile_warnings(&format!("#NOTE-OFF-OVERLAP LEGATO\n{}", song));
+    assert!(warnings.iter().any(|w| w.contains("#NOTE-OFF-OVERLAP: unknown policy 'LEGATO'")), "{:?}", warnings);
+}
+
+#[test]
+fn test_nsf_export() {
+    use vgmck::vgm::nsf;
+    let compile = |mml: &str| -> Vec<u8> {
+        let mut compiler = Compiler::new();
+        compiler.report = false;
+        let mut vgm = Vec::new();
+        compiler.compile_to_writer(Cursor::new(mml), &mut vgm).unwrap();
+        vgm
+    };
+
+    let data = nsf::from_vgm(&compile("#TITLE Fanfare\n#EX-2A03 AB\nA t120 o4 l4 L c e g\nB t120 o3 l2 c\n")).unwrap();
+    assert_eq!(&data[..6], b"NESM\x1A\x01");
+    // Load, init and play addresses
+    assert_eq!(&data[0x08..0x0E], [0x00, 0x80, 0x00, 0x80, 0x12, 0x80]);
+    assert_eq!(&data[0x0E..0x16], b"Fanfare\0");
+    assert_eq!(&data[0x70..0x78], [0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(data[0x7A], 0, "NTSC");
+
+    // The stream follows the 4 KB player bank: writes first, a jump back to the loop last
+    let stream = &data[0x1080..];
+    assert!(stream.len() > 4);
+    assert!(stream[0] < 0x18);
+    let jump = &stream[stream.len() - 4..];
+    assert_eq!(jump[..2], [0xFF, 1]);
+    let target = u16::from_le_bytes([jump[2], jump[3]]);
+    assert!((0x9000..0xA000).contains(&target), "{:04X}", target);
+
+    let pal = nsf::from_vgm(&compile("#RATE 50\n#EX-2A03 A\nA o4 c\n")).unwrap();
+    assert_eq!(pal[0x7A], 1, "PAL");
+
+    let error = nsf::from_vgm(&compile("#EX-PSG A\nA o4 c\n")).unwrap_err();
+    assert!(error.to_string().contains("2A03 alone (it uses sn76489)"), "{}", error);
+}
+
+#[cfg(feature = "render")]
+#[test]
+fn test_render_preview() {
+    use vgmck::vgm::render;
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    let mut vgm = Vec::new();
+    compiler
+        .compile_to_writer(Cursor::new("#EX-2A03 AB\n#EX-OPLL A\nA t120 o4 l4 c e g\nB t120 o3 l2. c\n"), &mut vgm)
+        .unwrap();
+    let rendered = render::render_bytes(&vgm).unwrap();
+    assert_eq!(rendered.samples.len() as i64, compiler.total_samples);
+    assert_eq!(rendered.silent_chips, ["ym2413"]);
+    assert!(rendered.samples.iter().any(|&s| s.unsigned_abs() > 1000), "the 2A03 is heard");
+
+    let dir = tempdir().unwrap();
+    let (vgm_path, wav_path) = (dir.path().join("song.vgm"), dir.path().join("song.wav"));
+    std::fs::write(&vgm_path, &vgm).unwrap();
+    assert_eq!(render::render_file(&vgm_path, &wav_path).unwrap(), ["ym2413"]);
+    let wav = std::fs::read(&wav_path).unwrap();
+    assert_eq!(&wav[..4], b"RIFF");
+    assert_eq!(wav.len(), 44 + rendered.samples.len() * 2);
+}
+
+#[test]
+fn test_include_files_in_memory() {
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    compiler.include_files.insert("chips.mml".into(), "#EX-PSG AB\n".to_string());
+    compiler.include_files.insert("bass.mml".into(), "o2 c4 c4\n".to_string());
+    let mml = "#INCLUDE chips.mml\n#CHANNEL-FILE B bass.mml\nA o4 c4 e4\n";
+    let mut vgm = Vec::new();
+    compiler.compile_to_writer(Cursor::new(mml), &mut vgm).unwrap();
+    assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+    let notes: Vec<(char, u64)> = compiler.compile_report().channels.iter().map(|c| (c.channel, c.notes)).collect();
+    assert_eq!(notes, [('A', 2), ('B', 2)]);
+}
+
+#[test]
+fn test_play_to_device() {
+    use vgmck::vgm::play::{self, VgmStream};
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    let mut vgm = Vec::new();
+    compiler.compile_to_writer(Cursor::new("#EX-PSG A\nA t240 v15 o4 l16 c\n"), &mut vgm).unwrap();
+    let mut reader = VgmReader::new(&vgm);
+    let header = reader.parse_header().unwrap();
+    let commands = reader.parse_commands(&header).unwrap();
+
+    // The device gets the writes, in VGM encoding, and the end command
+    let mut sent = Vec::new();
+    let start = std::time::Instant::now();
+    play::play(&commands, &mut VgmStream::new(&mut sent)).unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(62), "{:?}", start.elapsed());
+    let writes: Vec<u8> = commands
+        .iter()
+        .filter(|c| matches!(c, VgmCommand::Sn76489Write { .. }))
+        .flat_map(VgmCommand::to_bytes)
+        .chain([0x66])
+        .collect();
+    assert_eq!(sent, writes);
+}
+
+#[test]
+fn test_legacy_format_import() {
+    use vgmck::vgm::{analyze, json};
+    let dir = tempdir().unwrap();
+
+    // A GYMX file looping at frame 2: a key-on, two frames, a PSG write, a frame
+    let gym_path = dir.path().join("song.gym");
+    let mut gym = b"GYMX".to_vec();
+    gym.resize(428, 0);
+    gym[4..9].copy_from_slice(b"Intro");
+    gym[420] = 2;
+    gym.extend_from_slice(&[0x01, 0x28, 0xF0, 0x00, 0x00, 0x03, 0x9F, 0x00]);
+    std::fs::write(&gym_path, &gym).unwrap();
+
+    let vgm = vgmck::vgm::read_file(&gym_path).unwrap();
+    let mut reader = VgmReader::new(&vgm);
+    let header = reader.parse_header().unwrap();
+    assert_eq!(header.total_samples, 3 * 735);
+    assert_eq!(header.loop_samples, 2 * 735);
+    assert_eq!(header.chips["ym2612"].clock, 7670453);
+    assert_eq!(reader.parse_gd3(&header).unwrap().unwrap().title, "Intro");
+    let parsed: VgmJson = serde_json::from_str(&json::vgm_to_json(&gym_path).unwrap()).unwrap();
+    assert_eq!(parsed.header.loop_command, Some(2));
+    assert_eq!(analyze::analyze_file(&gym_path).unwrap().total_samples, 3 * 735);
+
+    // A raw GYM file is known by its extension, a DRO file by its magic
+    std::fs::write(&gym_path, &gym[428..]).unwrap();
+    assert_eq!(analyze::analyze_file(&gym_path).unwrap().total_samples, 3 * 735);
+
+    let dro_path = dir.path().join("capture.bin");
+    let mut dro = b"DBRAWOPL".to_vec();
+    dro.extend_from_slice(&[2, 0, 0, 0, 2, 0, 0, 0, 100, 0, 0, 0, 0, 0, 0, 0x10, 0x11, 1, 0xB0]);
+    dro.extend_from_slice(&[0x00, 0x31, 0x10, 99]);
+    std::fs::write(&dro_path, &dro).unwrap();
+    let parsed: VgmJson = serde_json::from_str(&json::vgm_to_json(&dro_path).unwrap()).unwrap();
+    assert_eq!(parsed.header.total_samples, 4410);
+    assert_eq!(parsed.header.chips["ym3812"].clock, 3579545);
+    assert!(matches!(parsed.commands[0], VgmCommand::Ym3812Write { reg: 0xB0, data: 0x31 }));
+}
+
+#[test]
+fn test_meter_positions() {
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_events(Cursor::new("#EX-PSG AB\n#METER 3/4\nA t120 l4 c c c c t240 l4 c c c @v9 c\n"))
+        .expect("Compilation failed");
+    let diagnostic = compiler.diagnostics.iter().find(|d| d.message.contains("@v9")).expect("no warning");
+    assert_eq!(diagnostic.position.as_deref(), Some("3:2"), "{}", diagnostic);
+    assert!(diagnostic.to_string().contains("bar 3:2"), "{}", diagnostic);
+
+    let lines: Vec<String> = compiler.dump_events().iter().map(|r| r.to_string()).collect();
+    let notes: Vec<&String> = lines.iter().filter(|l| l.contains("note on")).collect();
+    assert!(notes[3].contains("    2:1  A"), "{:#?}", notes);
+    assert!(notes[7].contains("    3:2  A"), "{:#?}", notes);
+
+    let (_, warnings) = compile_warnings("#METER 4\n");
+    assert!(warnings.iter().any(|w| w.contains("expected a time signature such as 3/4, got '4'")), "{:?}", warnings);
+}
+
+#[test]
+fn test_unsupported_macro_warnings() {
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_events(Cursor::new(
+            "#EX-OPL2 ABCDEFGHI\n#EX-PSG J\n@@1 = { 1 2 }\n#AUTOMATE B pan 0=1\nA c P1 d P2 e\nB c\nJ P1 c @@1 d e\n",
+        ))
+        .expect("Compilation failed");
+    let unsupported: Vec<&str> =
+        compiler.diagnostics.iter().filter(|d| d.code == "L0015").map(|d| d.message.as_str()).collect();
+    assert_eq!(
+        unsupported,
+        ["chip OPL2 does not support `P`, ignored on this channel", "chip PSG does not support `@@` envelopes, ignored on this channel"]
+    );
+    let opl2 = compiler.diagnostics.iter().find(|d| d.code == "L0015").unwrap();
+    assert_eq!(opl2.channel, Some('A'));
+    assert_eq!(opl2.position.as_deref(), Some("1:2"));
+    assert!(compiler.warnings.iter().any(|w| w == "#AUTOMATE: chip OPL2 does not support panning on channel B, lane ignored"), "{:?}", compiler.warnings);
+}
+
+#[test]
+fn test_loop_mode_compact() {
+    // Four copies of a three-note phrase keep one, looped 4 times as often
+    let unrolled = compile_and_parse("#EX-PSG ABC\nA l8 o4 g L [c d e]4\n");
+    let compact = compile_and_parse("#EX-PSG ABC\n#LOOP-MODE compact\nA l8 o4 g L [c d e]4\n");
+    assert_eq!(unrolled.header.loop_samples, Some(132300));
+    assert_eq!(compact.header.loop_samples, Some(33075));
+    assert_eq!(compact.header.loop_modifier, Some(64));
+    assert_eq!(compact.header.total_samples, 11025 + 33075);
+    assert!(compact.commands.len() < unrolled.commands.len());
+
+    // Setup writes at the loop point go with the first copy, kept in front
+    // of the loop, which then plays once less
+    let compact = compile_and_parse("#EX-OPL2 ABCDEFGHI\n#LOOP-MODE compact\nA l8 o4 v10 L [c d e]4\n");
+    assert_eq!(compact.header.loop_samples, Some(33075));
+    assert_eq!(compact.header.loop_modifier, Some(56));
+
+    // Songs play as long compacted as unrolled, also with the note-off of
+    // the note before the loop written at the loop point
+    let played = |vgm: &VgmJson| {
+        let loops = vgmck::vgm::playback::loops_played(vgm.header.loop_base.unwrap_or(0), vgm.header.loop_modifier.unwrap_or(0));
+        vgm.header.total_samples as u64 + vgm.header.loop_samples.unwrap() as u64 * (loops as u64 - 1)
+    };
+    for song in [
+        "#EX-OPL2 ABCDEFGHI\nA l8 o4 v10 L [c d e]4\n",
+        "#EX-PSG ABC\nA v15 l8 o4 g L [c d e]4\n",
+        "#EX-PSG ABC\nA v15 l8 o4 g L [c d e r]4\n",
+        "#EX-PSG ABC\n@v0 = { 15 12 10 }\nA @v0 l8 o4 g L [c d e]4\n",
+        "#EX-2A03 A\nA v15 l8 o4 g L [c d e]4\n",
+    ] {
+        let unrolled = compile_and_parse(song);
+        let song = song.replacen('\n', "\n#LOOP-MODE compact\n", 1);
+        let (_, warnings) = compile_warnings(&song);
+        assert!(warnings.is_empty(), "{}: {:?}", song, warnings);
+        let compact = compile_and_parse(&song);
+        assert_eq!(compact.header.loop_samples, Some(unrolled.header.loop_samples.unwrap() / 4), "{}", song);
+        assert_eq!(played(&compact), played(&unrolled), "{}", song);
+    }
+
+    let (_, warnings) = compile_warnings("#EX-PSG ABC\n#LOOP-MODE compact\nA l8 o4 [c d e]4\n");
+    assert!(warnings.iter().any(|w| w == "#LOOP-MODE compact: the song has no loop (`L`), written as is"), "{:?}", warnings);
+
+    let (_, warnings) = compile_warnings("#EX-PSG ABC\n#LOOP-MODE folded\nA c\n");
+    assert!(warnings.iter().any(|w| w == "#LOOP-MODE: unknown loop mode 'folded' (expected unroll or compact)"), "{:?}", warnings);
+}
+
+#[test]
+fn test_transpose_directive() {
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_events(Cursor::new(
+            "#EX-PSG ABC\n#TRANSPOSE +2\n#TRANSPOSE B-C -12\n#TRANSPOSE D-B 1\nA o4 c\nB o4 c\nC o4 K1 c K0 c\n",
+        ))
+        .expect("Compilation failed");
+    let notes: Vec<String> = compiler
+        .dump_events()
+        .iter()
+        .filter(|r| r.description.starts_with("note on"))
+        .map(|r| format!("{} {}", r.channel.unwrap(), &r.description[8..]))
+        .collect();
+    // Channel transposes add to the global one, and `K` to both
+    assert_eq!(
+        notes,
+        [
+            "A o4 d (key 50) for 22050 samples",
+            "B o3 d (key 38) for 22050 samples",
+            "C o3 d+ (key 39) for 22050 samples",
+            "C o3 d (key 38) for 22050 samples"
+        ]
+    );
+    assert!(compiler.warnings.iter().any(|w| w == "#TRANSPOSE: 'D-B' is not a channel or range of channels"), "{:?}", compiler.warnings);
+}
+
+#[test]
+fn test_loop_seam_check() {
+    let dir = tempdir().unwrap();
+    let output_path = dir.path().join("test.vgm");
+    let mut compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new("#EX-OPN2 ABCDEF\nA l8 o4 @0 v10 c L c d P1 e\n"), &output_path)
+        .expect("Compilation failed");
+    let report = vgmck::vgm::seam::check_file(&output_path).unwrap().expect("the song loops");
+    assert_eq!((report.loop_start, report.loop_samples), (11025, 33075));
+    // The panning set in the loop is not set again at its start
+    let changes: Vec<(&str, u16, Option<u16>, u16, u64)> =
+        report.changes.iter().map(|c| (c.chip, c.reg, c.start, c.end, c.first_write)).collect();
+    assert_eq!(changes, [("YM2612", 0xB4, Some(0xC0), 0x40, 22050)]);
+
+    // Setting it right after `L` fixes the seam
+    compiler = Compiler::new();
+    compiler
+        .compile(Cursor::new("#EX-OPN2 ABCDEF\nA l8 o4 @0 v10 c L P3 c d P1 e\n"), &output_path)
+        .expect("Compilation failed");
+    let report = vgmck::vgm::seam::check_file(&output_path).unwrap().expect("the song loops");
+    assert!(report.changes.is_empty(), "{}", report);
+}
+
+#[test]
+fn test_global_events_written_once() {
+    // Tremolo/vibrato depth writes (0xBD) of an OPL3, which writes every event it gets
+    let depth_writes = |commands: &[VgmCommand]| {
+        commands.iter().filter(|c| matches!(c, VgmCommand::Ymf262Write { port: 0, reg: 0xBD, .. })).count()
+    };
+    let vgm = compile_and_parse("#EX-OPL3 ABC\nA @G3 c\nB @G3 c\nC @G3 c @G1 c @G1 c\n");
+    assert_eq!(depth_writes(&vgm.commands), 2);
+
+    // A stem keeps the depth another channel sets
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    compiler.set_solo('B').unwrap();
+    let mut data = Vec::new();
+    compiler.compile_to_writer(Cursor::new("#EX-OPL3 AB\nA @G3 c\nB c\n"), &mut data).unwrap();
+    let mut reader = VgmReader::new(&data);
+    let header = reader.parse_header().unwrap();
+    let commands = reader.parse_commands(&header).unwrap();
+    assert!(commands.iter().any(|c| matches!(c, VgmCommand::Ymf262Write { port: 0, reg: 0xBD, data: 0xC0 })), "{:?}", commands);
+}
+
+#[test]
+fn test_scales_over_32_steps() {
+    let periods = |mml: &str| psg_tone_periods(&compile_and_parse(mml));
+
+    // 53-EDO: a sharp is one step of 22.6 cents, and g is 31 steps up
+    let mut steps = vec!['.'; 53];
+    for (letter, step) in "cdefgab".chars().zip([0, 9, 17, 22, 31, 39, 48]) {
+        steps[step] = letter;
+    }
+    let scale: String = steps.into_iter().collect();
+    let mml = format!("#EX-PSG ABC\n#SCALE {}\n#EQUAL-TEMPERAMENT\nA o1 c c+ g\n", scale);
+    assert_eq!(periods(&mml), [427, 422, 285]);
+    assert_eq!(periods("#EX-PSG ABC\nA o1 c c+ g\n"), [427, 403, 285]);
+
+    // Cents for the twelve steps of the default scale give equal temperament
+    let cents = "#SCALE-CENTS 0 100 200 300 400 500 600 700 800 900 1000 1100\n";
+    assert_eq!(periods(&format!("#EX-PSG ABC\n{}A o1 c c+ g\n", cents)), [427, 403, 285]);
+    // and for 72 steps, 72-EDO
+    let cents: Vec<String> = (0..72).map(|i| format!("{:.2}", i as f64 * 1200.0 / 72.0)).collect();
+    let mml = format!("#EX-PSG ABC\n#SCALE c{}\n#SCALE-CENTS {}\nA o1 c c+ c++++++\n", ".".repeat(71), cents.join(" "));
+    assert_eq!(periods(&mml), [427, 423, 403]);
+
+    let mut compiler = Compiler::new();
+    compiler.compile_events(Cursor::new("#EX-PSG ABC\n#SCALE-CENTS 0 100 200\nA c\n")).expect("Compilation failed");
+    assert!(compiler.warnings.iter().any(|w| w == "#SCALE-CENTS: 3 pitches for the 12 steps of #SCALE"), "{:?}", compiler.warnings);
+}
+
+#[test]
+fn test_note_bend_cents() {
+    let periods = |mml: &str| psg_tone_periods(&compile_and_parse(mml));
+
+    // A bend of 100 cents plays the sharp (403); the bend lasts for its note only
+    assert_eq!(periods("#EX-PSG ABC\nA o1 c c~+100 c c4~-15 c\n"), [427, 403, 427, 431, 427]);
+    // and is applied before detune
+    assert_eq!(periods("#EX-PSG ABC\nA o1 D2 c~+100\n"), [401]);
+
+    use vgmck::compiler::token::{tokenize, TokenKind};
+    let kinds: Vec<_> = tokenize("c8.~-20").into_iter().map(|t| t.kind).collect();
+    assert_eq!(kinds[1], TokenKind::Bend(-20));
+
+    // A cent is below the resolution of a low PSG period
+    let mut compiler = Compiler::new();
+    compiler.compile_events(Cursor::new("#EX-PSG ABC\nA o1 c~+1\n")).expect("Compilation failed");
+    let lint = compiler.diagnostics.iter().find(|d| d.code == "L0016").expect("no L0016");
+    assert!(lint.message.contains("`~+1` is below PSG's pitch resolution at key 12 (o1)"), "{}", lint.message);
+}
+
+#[test]
+fn test_loop_count_and_fade_hint() {
+    let dir = tempdir().unwrap();
+    let output = dir.path().join("song.vgm");
+    let mut compiler = Compiler::new();
+    let mml = "#TITLE Theme\n#EX-PSG ABC\n#LOOP-COUNT 3\n#FADE-HINT 8\nA t120 c L d e\n";
+    compiler.compile(Cursor::new(mml), &output).expect("Compilation failed");
+
+    // Players playing the loop twice by default play it 3 times with a
+    // loop modifier of 24
+    let header = VgmReader::new(&std::fs::read(&output).unwrap()).parse_header().unwrap();
+    assert_eq!((header.loop_base, header.loop_modifier), (0, 24));
+    let report = compiler.compile_report();
+    assert_eq!(report.loops_played, 3);
+    // 0.5 s of intro, three 1 s loops and the fade
+    assert_eq!(report.play_samples(), Some(22050 + 3 * 44100 + 8 * 44100));
+    let playlist = std::fs::read_to_string(dir.path().join("song.m3u")).unwrap();
+    assert_eq!(playlist, "#EXTM3U\n#EXTINF:12,Theme\nsong.vgm\n");
+
+    let mut compiler = Compiler::new();
+    let mml = "#EX-PSG ABC\n#LOOP-MODIFIER 32\n#LOOP-COUNT FOREVER\nA c L d\n";
+    compiler.compile(Cursor::new(mml), &output).expect("Compilation failed");
+    assert_eq!(compiler.compile_report().play_samples(), None);
+    let playlist = std::fs::read_to_string(dir.path().join("song.m3u")).unwrap();
+    assert_eq!(playlist, "#EXTM3U\n#EXTINF:-1,song\nsong.vgm\n");
+    assert!(compiler.warnings.iter().any(|w| w == "#LOOP-COUNT replaces #LOOP-BASE and #LOOP-MODIFIER"), "{:?}", compiler.warnings);
+
+    let (_, warnings) = compile_warnings("#EX-PSG ABC\n#LOOP-COUNT 40\n#FADE-HINT soon\nA c\n");
+    assert!(warnings.iter().any(|w| w == "#LOOP-COUNT: expected 1 to 31 times or FOREVER, got '40'"), "{:?}", warnings);
+    assert!(warnings.iter().any(|w| w == "#FADE-HINT: expected a number of seconds, got 'soon'"), "{:?}", warnings);
+
+    // Fades that would not fit in a sample count are left out
+    for (fade, warning) in [
+        ("1e400", "#FADE-HINT: 1e400 seconds is longer than 3600 seconds, ignored"),
+        ("1e30", "#FADE-HINT: 1e30 seconds is longer than 3600 seconds, ignored"),
+        ("NaN", "#FADE-HINT: expected a number of seconds, got 'NaN'"),
+    ] {
+        let mut compiler = Compiler::new();
+        let mml = format!("#EX-PSG ABC\n#LOOP-COUNT 3\n#FADE-HINT {}\nA t120 c L d e\n", fade);
+        compiler.compile(Cursor::new(mml), &output).expect("Compilation failed");
+        assert_eq!(compiler.compile_report().play_samples(), Some(22050 + 3 * 44100));
+        assert!(compiler.warnings.iter().any(|w| w == warning), "{:?}", compiler.warnings);
+    }
+}
+
+#[test]
+fn test_named_instruments() {
+    let mut compiler = Compiler::new();
+    compiler.report = false;
+    let library = "; shared instruments\n\
+                   @INST \"pluck\" {\n    @v = { 15 12 | 9 } ; decays\n    @EN = { 0 12\n        0 }\n}\n\
+                   @INST \"soft\" { @v = { 8 } }\n\
+                   A c\n";
+    compiler.include_files.insert("lib.vli".into(), library.to_string());
+    let mml = "#EX-PSG ABC\n#INSTLIB lib.vli\n@v0 = { 4 }\n@INST \"quiet\" {\n    @v0\n}\nA @\"pluck\" c @\"soft\" d @\"quiet\" e @\"nope\" f\n";
+    compiler.compile_events(Cursor::new(mml)).expect("Compilation failed");
+
+    // Envelopes in blocks take the highest free numbers
+    assert_eq!(compiler.instruments["pluck"], [("@v".to_string(), 255), ("@EN".to_string(), 255)]);
+    assert_eq!(compiler.instruments["soft"], [("@v".to_string(), 254)]);
+    assert_eq!(compiler.instruments["quiet"], [("@v".to_string(), 0)]);
+    let volumes: Vec<(i64, String)> = compiler
+        .dump_events()
+        .into_iter()
+        .filter(|r| r.description.starts_with("Volume") && r.time % 22050 == 0)
+        .map(|r| (r.time, r.description))
+        .collect();
+    let expected = [(0, "Volume 15"), (22050, "Volume 8"), (44100, "Volume 4"), (66150, "Volume 4")];
+    assert_eq!(volumes, expected.map(|(t, v)| (t, v.to_string())));
+
+    assert!(compiler.warnings.iter().any(|w| w == "line 8: #INSTLIB files hold only @INST blocks, ignored"), "{:?}", compiler.warnings);
+    let lint = compiler.diagnostics.iter().find(|d| d.code == "L0013").expect("no L0013");
+    assert_eq!(lint.message, "instrument \"nope\" is not defined");
+}